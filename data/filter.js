@@ -0,0 +1,7 @@
+document.querySelector("input.filter").addEventListener("input", function (e) {
+    var text = e.target.value.toLowerCase();
+    var rows = document.querySelectorAll("table.listing tr[data-name]");
+    rows.forEach(function (row) {
+        row.hidden = row.dataset.name.toLowerCase().indexOf(text) === -1;
+    });
+});
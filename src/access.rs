@@ -0,0 +1,114 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Per-path access rules.
+
+use crate::ip_filter::IpFilter;
+use crate::jwt::{self, Claims};
+use glob::{MatchOptions, Pattern};
+use std::net::IpAddr;
+use std::path::{Component, Path};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Authentication requirement of a rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Requirement {
+    /// Clients must authenticate.
+    Auth,
+    /// Anonymous clients are accepted.
+    None,
+}
+
+/// Access rule for the paths matching a glob pattern. A pattern ending with
+/// `/**` also matches the directory it designates.
+#[derive(Debug)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub require: Option<Requirement>,
+    pub ip_filter: Option<IpFilter>,
+    /// Names and values of the claims clients must authenticate with a
+    /// JSON Web Token having.
+    pub claims: Vec<(String, String)>,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        if self.pattern.matches_with(path, MATCH_OPTIONS) {return true}
+        match self.pattern.as_str().strip_suffix("/**") {
+            Some(dir) => dir == path || (dir.is_empty() && path == "/"),
+            None => false,
+        }
+    }
+}
+
+/// Decision for a request.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    RequireAuth,
+}
+
+/// Ordered access rules. The first rule matching a path applies.
+#[derive(Debug, Default)]
+pub struct AccessRules {
+    rules: Vec<Rule>,
+}
+
+impl AccessRules {
+    pub fn new(rules: Vec<Rule>) -> AccessRules {
+        AccessRules {rules}
+    }
+
+    /// Returns whether some rule requires authentication.
+    pub fn require_auth(&self) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.require == Some(Requirement::Auth) || !rule.claims.is_empty()
+        })
+    }
+
+    /// Decides whether a request for the absolute path `path` from `client`
+    /// may proceed. `claims` are those of the token the client authenticated
+    /// with, if any. `auth_by_default` tells whether authentication is
+    /// required when no rule says otherwise.
+    pub fn check(&self, path: &Path, client: IpAddr, authenticated: bool,
+        claims: Option<&Claims>, auth_by_default: bool) -> Decision
+    {
+        let path = normalize(path);
+        let rule = self.rules.iter().find(|rule| rule.matches(&path));
+        let ip_allowed = rule.and_then(|rule| rule.ip_filter.as_ref())
+            .is_none_or(|filter| filter.allows(client));
+        if !ip_allowed {return Decision::Deny}
+        let required_claims = rule.map_or(&[][..], |rule| &rule.claims);
+        let require_auth = match rule.and_then(|rule| rule.require) {
+            Some(require) => require == Requirement::Auth,
+            None => auth_by_default,
+        } || !required_claims.is_empty();
+        if require_auth && !authenticated {
+            return Decision::RequireAuth
+        }
+        let has_claims = required_claims.iter().all(|(name, value)| {
+            claims.is_some_and(|claims| jwt::has_claim(claims, name, value))
+        });
+        if has_claims {Decision::Allow} else {Decision::Deny}
+    }
+}
+
+/// Returns `path` with `/` separators and without trailing slash.
+fn normalize(path: &Path) -> String {
+    let mut normalized = String::new();
+    for part in path.components() {
+        if let Component::Normal(part) = part {
+            normalized.push('/');
+            normalized.push_str(&part.to_string_lossy());
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
@@ -0,0 +1,160 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Per-directory access control files.
+//!
+//! A `.servedir-access` file applies to the directory containing it and its
+//! subtree. Each line holds one directive:
+//!
+//! ```text
+//! # Comment
+//! allow 10.0.0.0/8
+//! deny all
+//! require-auth
+//! ```
+//!
+//! `allow` and `deny` lines are evaluated in order and the first one matching
+//! the client decides. Files found along the path of a request all apply, so
+//! a subdirectory can add restrictions but not lift those of its parents.
+
+use crate::access::Decision;
+use crate::cidr::Cidr;
+use crate::ip_filter::{IpFilter, Rule};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+/// Name of access control files.
+pub const FILE_NAME: &str = ".servedir-access";
+
+/// Directives read from an access control file.
+#[derive(Debug)]
+struct AccessFile {
+    ip_filter: IpFilter,
+    require_auth: bool,
+}
+
+impl AccessFile {
+    fn parse(contents: &str) -> Result<AccessFile, String> {
+        let mut rules = Vec::new();
+        let mut require_auth = false;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {continue}
+            let mut words = line.split_whitespace();
+            let directive = words.next().unwrap_or_default();
+            let arg = words.next();
+            if words.next().is_some() {return Err(line.to_owned())}
+            match (directive, arg) {
+                ("require-auth", None) => require_auth = true,
+                ("allow", Some(net)) => rules.extend(networks(net)
+                    .ok_or_else(|| line.to_owned())?
+                    .into_iter()
+                    .map(Rule::Allow)),
+                ("deny", Some(net)) => rules.extend(networks(net)
+                    .ok_or_else(|| line.to_owned())?
+                    .into_iter()
+                    .map(Rule::Deny)),
+                _ => return Err(line.to_owned()),
+            }
+        }
+        Ok(AccessFile {ip_filter: IpFilter::new(rules), require_auth})
+    }
+
+    /// Reads the access control file of `dir`, if any.
+    fn read(dir: &Path) -> io::Result<Option<AccessFile>> {
+        let path = dir.join(FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if matches!(e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        AccessFile::parse(&contents)
+            .map(Some)
+            .map_err(|line| io::Error::new(io::ErrorKind::InvalidData,
+                format!("Invalid directive in {}: {}", path.display(), line)))
+    }
+}
+
+fn networks(s: &str) -> Option<Vec<Cidr>> {
+    if s == "all" {
+        Some(vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()])
+    } else {
+        s.parse().ok().map(|net| vec![net])
+    }
+}
+
+/// Decides whether a request for `resource`, relative to `root`, may proceed
+/// according to the access control files of `root` and of the directories
+/// leading to `resource`. Unreadable or invalid files deny access.
+pub fn check(root: &Path, resource: &Path, client: IpAddr,
+    authenticated: bool) -> Decision
+{
+    Checker::new(root, client, authenticated).check(resource)
+}
+
+/// Checks requests for many resources relative to the same root, reading
+/// the access control file of each directory once.
+pub struct Checker<'a> {
+    root: &'a Path,
+    client: IpAddr,
+    authenticated: bool,
+    /// Outcome of the directories checked so far: `None` if access is
+    /// denied, or else whether authentication is required.
+    dirs: HashMap<PathBuf, Option<bool>>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(root: &'a Path, client: IpAddr, authenticated: bool)
+        -> Checker<'a>
+    {
+        Checker {root, client, authenticated, dirs: HashMap::new()}
+    }
+
+    /// Decides whether a request for `resource` may proceed, like `check`.
+    pub fn check(&mut self, resource: &Path) -> Decision {
+        let inherited = match resource.parent() {
+            Some(parent) => self.dir_outcome(parent),
+            None => Some(false),
+        };
+        match inherited.and_then(|auth| self.apply(resource, auth)) {
+            None => Decision::Deny,
+            Some(true) if !self.authenticated => Decision::RequireAuth,
+            Some(_) => Decision::Allow,
+        }
+    }
+
+    fn dir_outcome(&mut self, dir: &Path) -> Option<bool> {
+        if let Some(&outcome) = self.dirs.get(dir) {
+            return outcome
+        }
+        let inherited = match dir.parent() {
+            Some(parent) => self.dir_outcome(parent),
+            None => Some(false),
+        };
+        let outcome = inherited.and_then(|auth| self.apply(dir, auth));
+        self.dirs.insert(dir.to_owned(), outcome);
+        outcome
+    }
+
+    /// Applies the access control file of `dir`, if any, to the outcome of
+    /// its parent, which requires authentication if `require_auth` is true.
+    fn apply(&self, dir: &Path, require_auth: bool) -> Option<bool> {
+        match AccessFile::read(&self.root.join(dir)) {
+            Ok(Some(file)) => {
+                if !file.ip_filter.allows(self.client) {return None}
+                Some(require_auth || file.require_auth)
+            }
+            Ok(None) => Some(require_auth),
+            Err(e) => {
+                error!("Failed to read access file: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Append-only log of the requests that may change served directories.
+//!
+//! Each line of the log is a JSON object with an `event` field:
+//!
+//! - `request` is written and synced before a request is processed, and the
+//!   request is rejected if it cannot be written.
+//! - `change` describes a change made by the request, as reported to
+//!   webhooks.
+//! - `response` gives the status the request was answered with.
+//!
+//! Events carry the ID of the request, the name of the authenticated user,
+//! if any, and the address of the client.
+
+use crate::upload::Change;
+use http::{Method, Request};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Client making a request.
+#[derive(Clone, Debug, Serialize)]
+pub struct Actor {
+    /// ID of the request, as logged.
+    pub request: String,
+    /// Name of the authenticated user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub client: IpAddr,
+}
+
+impl Actor {
+    /// Returns the actor attached to `request`, or an anonymous one.
+    pub fn of<B>(request: &Request<B>, client: IpAddr) -> Actor {
+        request.extensions().get::<Actor>().cloned()
+            .unwrap_or_else(|| Actor {
+                request: crate::logging::request_id(request.headers()),
+                user: None,
+                client,
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a, T> {
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(flatten)]
+    details: T,
+    #[serde(flatten)]
+    actor: Option<&'a Actor>,
+}
+
+#[derive(Serialize)]
+struct RequestDetails<'a> {
+    method: &'a str,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ResponseDetails<'a> {
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+}
+
+/// Audit log file.
+#[derive(Debug)]
+pub struct Log {
+    file: Mutex<File>,
+}
+
+impl Log {
+    /// Opens the log at `path`, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Log> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Log {file: Mutex::new(file)})
+    }
+
+    /// Records a request about to be processed.
+    pub fn request(&self, actor: &Actor, method: &Method, path: &str,
+        size: Option<u64>) -> io::Result<()>
+    {
+        let details = RequestDetails {method: method.as_str(), path, size};
+        self.write(&Entry {
+            event: "request",
+            details,
+            timestamp: Some(now()),
+            actor: Some(actor),
+        })
+    }
+
+    /// Records a change made by a request.
+    pub fn change(&self, change: &Change) {
+        let entry = Entry {
+            event: "change",
+            details: change,
+            timestamp: None,
+            actor: None,
+        };
+        if let Err(e) = self.write(&entry) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+
+    /// Records the status a request was answered with.
+    pub fn response(&self, actor: &Actor, method: &Method, path: &str,
+        status: u16)
+    {
+        let details = ResponseDetails {method: method.as_str(), path, status};
+        let entry = Entry {
+            event: "response",
+            details,
+            timestamp: Some(now()),
+            actor: Some(actor),
+        };
+        if let Err(e) = self.write(&entry) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+
+    fn write<T: Serialize>(&self, entry: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+/// Returns whether requests with `method` may change served directories.
+pub fn is_mutating(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
@@ -0,0 +1,596 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Client authentication.
+//!
+//! Users of the htpasswd file authenticate with the Basic scheme, or with
+//! the Digest scheme of RFC 7616 using SHA-256, which does not send
+//! passwords. Digest authentication needs entries in the format of
+//! `htdigest`, `USER:servedir:HA1`, where `HA1` is the hexadecimal SHA-256
+//! digest of `USER:servedir:PASSWORD`, as Basic authentication also
+//! accepts.
+//!
+//! Digest nonces are not stored: they carry their creation time, random
+//! bytes making each unique, and a MAC.
+//! They expire after a few minutes, and the nonce counts used with each
+//! are remembered until then to reject replayed requests.
+
+use crate::session::Sessions;
+use crate::{jwt, oidc};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use ring::rand::{SecureRandom, SystemRandom};
+use http::{HeaderMap, Method, Request};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::error;
+
+/// Minimum delay between checks of the modification time of the htpasswd
+/// file.
+const HTPASSWD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Protection space of credentials.
+const REALM: &str = "servedir";
+
+/// Number of seconds digest nonces are valid for.
+const NONCE_LIFETIME: u64 = 300;
+
+/// Number of random bytes in digest nonces.
+const NONCE_RANDOM_LEN: usize = 16;
+
+/// Scheme of the `Authorization` header with which users of the htpasswd
+/// file authenticate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scheme {
+    Basic,
+    Digest,
+}
+
+impl Scheme {
+    pub fn parse(s: &str) -> Option<Scheme> {
+        match s {
+            "basic" => Some(Scheme::Basic),
+            "digest" => Some(Scheme::Digest),
+            _ => None,
+        }
+    }
+}
+
+/// Requests for which clients must authenticate when no access rule says
+/// otherwise.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Scope {
+    /// Requests modifying files, e.g. to let anyone download files.
+    Write,
+    /// Requests reading files, e.g. to let anyone drop files.
+    Read,
+    #[default]
+    All,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Option<Scope> {
+        match s {
+            "write" => Some(Scope::Write),
+            "read" => Some(Scope::Read),
+            "all" => Some(Scope::All),
+            _ => None,
+        }
+    }
+}
+
+/// Authenticated client.
+pub struct User {
+    /// User name, if the credentials carry one.
+    pub name: Option<String>,
+    /// Claims of the JSON Web Token the user authenticated with, if any.
+    pub claims: Option<jwt::Claims>,
+}
+
+impl User {
+    /// Returns the user authenticated with a token having `claims`, named by
+    /// its subject.
+    fn with_claims(claims: jwt::Claims) -> User {
+        let name = claims.get("sub")
+            .and_then(|sub| sub.as_str())
+            .map(str::to_owned);
+        User {name, claims: Some(claims)}
+    }
+}
+
+/// Checks credentials with the configured methods.
+#[derive(Default)]
+pub struct Authenticator {
+    pub tokens: Option<BearerTokens>,
+    pub htpasswd: Option<Htpasswd>,
+    /// Nonces of Digest authentication, which replaces Basic
+    /// authentication if set.
+    pub digest: Option<Nonces>,
+    pub jwt: Option<jwt::Validator>,
+    /// Provider with which browser users log in.
+    pub oidc: Option<Arc<oidc::Provider>>,
+    /// Whether browser users log in with the login form.
+    pub login_form: bool,
+    /// Sessions of users logged in with a browser.
+    pub sessions: Option<Arc<Sessions>>,
+    pub scope: Scope,
+}
+
+impl Authenticator {
+    /// Returns whether clients must authenticate.
+    pub fn is_enabled(&self) -> bool {
+        self.tokens.is_some() || self.htpasswd.is_some() || self.jwt.is_some()
+            || self.oidc.is_some()
+    }
+
+    /// Returns whether clients must authenticate to send requests with
+    /// `method`, when no access rule says otherwise.
+    pub fn is_required(&self, method: &Method) -> bool {
+        let write = crate::audit::is_mutating(method);
+        self.is_enabled() && match self.scope {
+            Scope::Write => write,
+            Scope::Read => !write,
+            Scope::All => true,
+        }
+    }
+
+    /// Returns the user identified by the credentials of `request`, if any.
+    pub fn authenticate<B>(&self, request: &Request<B>) -> Option<User> {
+        let (scheme, credentials) = match authorization(request.headers()) {
+            Some(authorization) => authorization,
+            None => {
+                let sessions = self.sessions.as_ref()?;
+                let claims = sessions.get(request.headers())?;
+                return Some(User::with_claims(claims))
+            }
+        };
+        if scheme.eq_ignore_ascii_case("bearer") {
+            if self.tokens.as_ref().is_some_and(|t| t.accept(credentials)) {
+                return Some(User {name: None, claims: None})
+            }
+            let claims = self.jwt.as_ref()?.validate(credentials)?;
+            return Some(User::with_claims(claims))
+        } else if scheme.eq_ignore_ascii_case("digest") {
+            let htpasswd = self.htpasswd.as_ref()?;
+            let nonces = self.digest.as_ref()?;
+            let name = nonces.verify(htpasswd, request, credentials)?;
+            return Some(User {name: Some(name), claims: None})
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            if self.digest.is_some() {return None}
+            let htpasswd = self.htpasswd.as_ref()?;
+            let credentials = base64::engine::general_purpose::STANDARD
+                .decode(credentials).ok()?;
+            let credentials = String::from_utf8(credentials).ok()?;
+            let i = credentials.find(':')?;
+            let (name, password) = (&credentials[..i], &credentials[i + 1..]);
+            if htpasswd.verify(name, password) {
+                return Some(User {name: Some(name.to_owned()), claims: None})
+            }
+        }
+        None
+    }
+
+    /// Returns whether authenticating `request` may involve computing a
+    /// password hash, which should not be done on the event loop.
+    pub fn hashes_password<B>(&self, request: &Request<B>) -> bool {
+        self.htpasswd.is_some() && self.digest.is_none()
+            && authorization(request.headers())
+                .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+    }
+
+    /// Returns the `WWW-Authenticate` challenges for the enabled methods,
+    /// in response to `request`.
+    pub fn challenges<B>(&self, request: &Request<B>) -> Vec<String> {
+        let mut challenges = Vec::new();
+        match (&self.htpasswd, &self.digest) {
+            (Some(_), Some(nonces)) => {
+                let stale = authorization(request.headers())
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("digest"))
+                    .and_then(|(_, credentials)| {
+                        parse_params(credentials).remove("nonce")
+                    })
+                    .is_some_and(|nonce| nonces.is_stale(&nonce));
+                challenges.push(format!("Digest realm=\"{}\", qop=\"auth\", \
+                    algorithm=SHA-256, nonce=\"{}\"{}", REALM, nonces.issue(),
+                    if stale {", stale=true"} else {""}));
+            }
+            (Some(_), None) => challenges.push(format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"", REALM)),
+            (None, _) => {}
+        }
+        if self.tokens.is_some() || self.jwt.is_some() {
+            challenges.push(format!("Bearer realm=\"{}\"", REALM));
+        }
+        challenges
+    }
+
+    /// Reads again the credential files.
+    pub fn reload(&self) {
+        if let Some(tokens) = &self.tokens {
+            if let Err(e) = tokens.reload() {
+                error!("Failed to reload tokens: {}", e);
+            }
+        }
+        if let Some(htpasswd) = &self.htpasswd {
+            if let Err(e) = htpasswd.reload() {
+                error!("Failed to reload htpasswd file: {}", e);
+            }
+        }
+    }
+}
+
+/// Returns the scheme and credentials of the `Authorization` header.
+fn authorization(headers: &HeaderMap) -> Option<(&str, &str)> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let i = value.find(' ')?;
+    Some((&value[..i], value[i + 1..].trim()))
+}
+
+/// Tokens accepted in `Authorization: Bearer` headers.
+pub struct BearerTokens {
+    fixed: Vec<String>,
+    file: Option<PathBuf>,
+    from_file: RwLock<Vec<String>>,
+}
+
+impl BearerTokens {
+    /// Accepts the `fixed` tokens and those listed in `file`, one per line.
+    pub fn new(fixed: Vec<String>, file: Option<PathBuf>)
+        -> io::Result<BearerTokens>
+    {
+        let tokens = BearerTokens {fixed, file, from_file: Default::default()};
+        tokens.reload()?;
+        Ok(tokens)
+    }
+
+    /// Reads the token file again.
+    pub fn reload(&self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let tokens = fs::read_to_string(file)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        *self.from_file.write().unwrap() = tokens;
+        Ok(())
+    }
+
+    fn accept(&self, token: &str) -> bool {
+        let from_file = self.from_file.read().unwrap();
+        self.fixed.iter().chain(from_file.iter())
+            .fold(false, |found, t| constant_time_eq(t, token) | found)
+    }
+}
+
+/// Users and password hashes read from an Apache htpasswd file, reloaded
+/// when the file changes. Bcrypt (`$2y$`, `$2a$`, `$2b$`) and SHA-1
+/// (`{SHA}`) hashes are supported.
+pub struct Htpasswd {
+    path: PathBuf,
+    state: Mutex<HtpasswdState>,
+}
+
+struct HtpasswdState {
+    modified: Option<SystemTime>,
+    checked: Instant,
+    hashes: HashMap<String, String>,
+    /// Digests of credentials already verified, to avoid running bcrypt on
+    /// every request.
+    verified: HashMap<String, [u8; 32]>,
+}
+
+impl Htpasswd {
+    pub fn open(path: &Path) -> io::Result<Htpasswd> {
+        let (modified, hashes) = read_htpasswd(path)?;
+        let state = HtpasswdState {
+            modified,
+            checked: Instant::now(),
+            hashes,
+            verified: HashMap::new(),
+        };
+        Ok(Htpasswd {path: path.to_owned(), state: Mutex::new(state)})
+    }
+
+    /// Reads the file again.
+    pub fn reload(&self) -> io::Result<()> {
+        let (modified, hashes) = read_htpasswd(&self.path)?;
+        let mut state = self.state.lock().unwrap();
+        state.modified = modified;
+        state.hashes = hashes;
+        state.verified.clear();
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = {
+            let mut state = self.state.lock().unwrap();
+            if state.checked.elapsed() < HTPASSWD_CHECK_INTERVAL {return}
+            state.checked = Instant::now();
+            let modified = fs::metadata(&self.path)
+                .and_then(|meta| meta.modified())
+                .ok();
+            if modified == state.modified {return}
+            modified
+        };
+        if modified.is_some() {
+            if let Err(e) = self.reload() {
+                error!("Failed to reload htpasswd file: {}", e);
+            }
+        }
+    }
+
+    /// Returns the hexadecimal HA1 digest of user `name` for Digest
+    /// authentication, if the file has one.
+    fn digest_ha1(&self, name: &str) -> Option<String> {
+        self.reload_if_modified();
+        let state = self.state.lock().unwrap();
+        let (realm, ha1) = state.hashes.get(name)?.split_once(':')?;
+        Some(ha1.to_ascii_lowercase()).filter(|_| realm == REALM)
+    }
+
+    /// Returns whether `password` is the password of user `name`.
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        self.reload_if_modified();
+        let digest = credentials_digest(name, password);
+        let hash = {
+            let state = self.state.lock().unwrap();
+            if state.verified.get(name) == Some(&digest) {return true}
+            match state.hashes.get(name) {
+                Some(hash) => hash.clone(),
+                None => return false,
+            }
+        };
+        let valid = verify_hash(name, &hash, password);
+        if valid {
+            let mut state = self.state.lock().unwrap();
+            if state.hashes.get(name) == Some(&hash) {
+                state.verified.insert(name.to_owned(), digest);
+            }
+        }
+        valid
+    }
+}
+
+fn read_htpasswd(path: &Path)
+    -> io::Result<(Option<SystemTime>, HashMap<String, String>)>
+{
+    let modified = fs::metadata(path)?.modified().ok();
+    let hashes = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].to_owned(), line[i + 1..].to_owned()))
+        })
+        .collect();
+    Ok((modified, hashes))
+}
+
+fn verify_hash(name: &str, hash: &str, password: &str) -> bool {
+    if let Some((realm, ha1)) = hash.split_once(':') {
+        let actual = sha256_hex(&format!("{}:{}:{}", name, realm, password));
+        constant_time_eq(&actual, &ha1.to_ascii_lowercase())
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(expected) = hash.strip_prefix("{SHA}") {
+        let actual = base64::engine::general_purpose::STANDARD
+            .encode(sha1::Sha1::digest(password.as_bytes()));
+        constant_time_eq(&actual, expected)
+    } else {
+        false
+    }
+}
+
+fn credentials_digest(name: &str, password: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0]);
+    hasher.update(password.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Compares strings in a time independent of the position of the first
+/// difference.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes())
+        .fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Issues and checks the nonces of Digest authentication.
+pub struct Nonces {
+    key: [u8; 32],
+    /// Expiration time and last nonce count of the nonces in use.
+    counts: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+impl Nonces {
+    pub fn new() -> Nonces {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).expect("No source of randomness");
+        Nonces {key, counts: Mutex::new(HashMap::new())}
+    }
+
+    /// Returns the MAC of a nonce without its tag, i.e. its creation time
+    /// followed by random bytes.
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(data);
+        mac
+    }
+
+    /// Returns a new nonce.
+    fn issue(&self) -> String {
+        let created = crate::signing::unix_time_now();
+        let mut random = [0; NONCE_RANDOM_LEN];
+        SystemRandom::new().fill(&mut random)
+            .expect("No source of randomness");
+        let mut nonce = created.to_be_bytes().to_vec();
+        nonce.extend_from_slice(&random);
+        let tag = self.mac(&nonce).finalize().into_bytes();
+        nonce.extend_from_slice(&tag);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(nonce)
+    }
+
+    /// Returns the creation time of `nonce` if this issued it.
+    fn created(&self, nonce: &str) -> Option<u64> {
+        let nonce = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(nonce).ok()?;
+        if nonce.len() < 8 + NONCE_RANDOM_LEN {return None}
+        let (data, tag) = nonce.split_at(8 + NONCE_RANDOM_LEN);
+        self.mac(data).verify_slice(tag).ok()?;
+        Some(u64::from_be_bytes(data[..8].try_into().ok()?))
+    }
+
+    /// Returns whether `nonce` was issued by this but expired.
+    fn is_stale(&self, nonce: &str) -> bool {
+        self.created(nonce).is_some_and(|created| {
+            created + NONCE_LIFETIME <= crate::signing::unix_time_now()
+        })
+    }
+
+    /// Records the use of `nonce` with count `nc`. Returns false if it was
+    /// already used with this count or a later one.
+    fn record(&self, nonce: &str, expires: u64, nc: u32) -> bool {
+        let now = crate::signing::unix_time_now();
+        let mut counts = self.counts.lock().unwrap();
+        counts.retain(|_, &mut (expires, _)| expires > now);
+        let (_, last) = counts.entry(nonce.to_owned()).or_insert((expires, 0));
+        if nc <= *last {return false}
+        *last = nc;
+        true
+    }
+
+    /// Returns the name of the user authenticated by the Digest
+    /// `credentials` of `request`, if valid.
+    fn verify<B>(&self, htpasswd: &Htpasswd, request: &Request<B>,
+        credentials: &str) -> Option<String>
+    {
+        let params = parse_params(credentials);
+        let param = |name: &str| params.get(name).map(String::as_str);
+        let algorithm = param("algorithm")?;
+        let valid = algorithm.eq_ignore_ascii_case("SHA-256")
+            && param("realm")? == REALM
+            && param("qop")? == "auth"
+            && param("userhash").is_none_or(|h| h.eq_ignore_ascii_case("false"))
+            && Some(param("uri")?) == request.uri().path_and_query()
+                .map(|p| p.as_str());
+        if !valid {return None}
+        let (name, nonce) = (param("username")?, param("nonce")?);
+        let (cnonce, nc) = (param("cnonce")?, param("nc")?);
+        let (uri, response) = (param("uri")?, param("response")?);
+        let created = self.created(nonce)?;
+        let expires = created + NONCE_LIFETIME;
+        if expires <= crate::signing::unix_time_now() {return None}
+        let ha1 = htpasswd.digest_ha1(name)?;
+        let ha2 = sha256_hex(&format!("{}:{}", request.method(), uri));
+        let expected = sha256_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce,
+            nc, cnonce, ha2));
+        if !constant_time_eq(&expected, &response.to_ascii_lowercase()) {
+            return None
+        }
+        let nc = u32::from_str_radix(nc, 16).ok()?;
+        if !self.record(nonce, expires, nc) {return None}
+        Some(name.to_owned())
+    }
+}
+
+/// Parses the comma-separated `name=value` parameters of credentials, whose
+/// values may be quoted.
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|&c| c == ',' || c.is_whitespace()).is_some() {}
+        let name = std::iter::from_fn(|| {
+            chars.next_if(|&c| c != '=' && c != ',')
+        }).collect::<String>();
+        match chars.next() {
+            Some('=') => {}
+            Some(_) => continue,
+            None => break,
+        }
+        while chars.next_if(|&c| c.is_whitespace()).is_some() {}
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            while chars.next_if(|&c| c != ',').is_some() {}
+            value
+        } else {
+            std::iter::from_fn(|| chars.next_if(|&c| c != ','))
+                .collect::<String>().trim().to_owned()
+        };
+        params.insert(name.trim().to_ascii_lowercase(), value);
+    }
+    params
+}
+
+fn sha256_hex(s: &str) -> String {
+    crate::checksum::to_hex(&Sha256::digest(s.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_params;
+
+    #[test]
+    fn params_are_parsed() {
+        let params = parse_params(
+            r#"username="alice", realm="files", nc=00000001, qop=auth"#);
+        assert_eq!(params.len(), 4);
+        assert_eq!(params["username"], "alice");
+        assert_eq!(params["realm"], "files");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let params = parse_params("Nonce=abc, QOP = auth");
+        assert_eq!(params["nonce"], "abc");
+        assert_eq!(params["qop"], "auth");
+    }
+
+    #[test]
+    fn quoted_values_keep_commas_and_escapes() {
+        let params = parse_params(
+            r#"uri="/a,b?c=d", realm="say \"hi\"", opaque=" x ""#);
+        assert_eq!(params["uri"], "/a,b?c=d");
+        assert_eq!(params["realm"], r#"say "hi""#);
+        assert_eq!(params["opaque"], " x ");
+    }
+
+    #[test]
+    fn malformed_params_do_not_affect_others() {
+        let params = parse_params(r#"a="x"y, flag, b=1, c="unterminated"#);
+        assert_eq!(params["a"], "x");
+        assert_eq!(params["b"], "1");
+        assert_eq!(params["c"], "unterminated");
+        assert!(!params.contains_key("flag"));
+        assert!(!params.contains_key("y, flag, b"));
+        assert_eq!(params.len(), 3);
+    }
+
+    #[test]
+    fn empty_input_has_no_params() {
+        for s in ["", " ", ",,", "novalue", "=", "a=", "a=\"\""] {
+            let params = parse_params(s);
+            assert!(params.values().all(String::is_empty), "{}", s);
+        }
+    }
+}
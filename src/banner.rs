@@ -0,0 +1,92 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Machine-readable description of a started server.
+//!
+//! With `--print-config json`, a JSON line is written to the standard output
+//! once the server is ready, and logs go to the standard error instead.
+//! Scripts can read it to learn where the server listens, e.g. the port
+//! picked by the system with `--port 0`.
+
+use crate::Config;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Address a server listens at.
+#[derive(Debug, Serialize)]
+pub struct Endpoint {
+    pub address: SocketAddr,
+    pub url: String,
+}
+
+/// Options in effect, including defaults.
+#[derive(Debug, Serialize)]
+struct Options<'a> {
+    writable: bool,
+    api: bool,
+    listings: bool,
+    redirects: bool,
+    base_path: &'a str,
+    etag: &'static str,
+    no_cache: bool,
+    authentication: bool,
+    max_header_size: usize,
+    max_uri_length: usize,
+    max_upload_size: Option<u64>,
+    max_downloads: Option<u64>,
+    chunk_size: usize,
+    log_requests: bool,
+    verbose_errors: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Banner<'a> {
+    event: &'static str,
+    version: &'static str,
+    pid: u32,
+    endpoints: &'a [Endpoint],
+    /// Addresses at which HTTP requests are redirected to HTTPS.
+    redirect_http: &'a [SocketAddr],
+    tls: bool,
+    roots: Vec<&'a Path>,
+    options: Options<'a>,
+}
+
+/// Writes the description of the server running with `config` as a JSON
+/// line.
+pub fn print(config: &Config, endpoints: &[Endpoint],
+    redirect_http: &[SocketAddr], tls: bool) -> io::Result<()>
+{
+    let banner = Banner {
+        event: "started",
+        version: crate::APP_VERSION,
+        pid: std::process::id(),
+        endpoints,
+        redirect_http,
+        tls,
+        roots: config.sites.roots().collect(),
+        options: Options {
+            writable: config.writable,
+            api: config.api,
+            listings: config.listings,
+            redirects: config.redirects,
+            base_path: &config.base_path,
+            etag: config.etag.name(),
+            no_cache: config.no_cache,
+            authentication: config.auth.is_enabled(),
+            max_header_size: config.max_header_size,
+            max_uri_length: config.max_uri_length,
+            max_upload_size: config.max_upload_size,
+            max_downloads: config.max_downloads,
+            chunk_size: config.chunk_size,
+            log_requests: config.log_requests,
+            verbose_errors: config.verbose_errors,
+        },
+    };
+    let mut line = serde_json::to_vec(&banner).map_err(io::Error::other)?;
+    line.push(b'\n');
+    let mut out = io::stdout().lock();
+    out.write_all(&line)?;
+    out.flush()
+}
@@ -0,0 +1,222 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Opening of files without leaving the served directory.
+//!
+//! Lexical checks on request paths do not account for symbolic links, which
+//! may also be replaced between a check and the use of a path. On Linux,
+//! paths are resolved by the kernel with `openat2` and `RESOLVE_BENEATH`.
+//! Elsewhere, or when `openat2` is not available, the canonical path is
+//! checked to be inside the served directory. Directories opened this way
+//! are listed through their descriptor, so their path is not resolved again.
+//! Only regular files and directories are opened, without blocking on
+//! special files such as FIFOs. Trees are walked the same way, without
+//! following symbolic links, and skipping those leading out of the served
+//! directory.
+//!
+//! Paths to write to are only checked when resolved, so a directory replaced
+//! with a symbolic link before the write may still lead out of the served
+//! directory.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Served directory.
+#[derive(Debug)]
+pub struct RootDir {
+    canonical: PathBuf,
+    #[cfg(target_os = "linux")]
+    dir: File,
+}
+
+impl RootDir {
+    pub fn open(path: &Path) -> io::Result<RootDir> {
+        Ok(RootDir {
+            canonical: path.canonicalize()?,
+            #[cfg(target_os = "linux")]
+            dir: File::open(path)?,
+        })
+    }
+
+    /// Returns the canonical path of the served directory.
+    pub fn path(&self) -> &Path {
+        &self.canonical
+    }
+
+    /// Opens `resource`, relative to the served directory, for reading. Fails
+    /// with `PermissionDenied` if resolving the path leaves the directory or
+    /// if it is neither a regular file nor a directory.
+    pub fn open_file(&self, resource: &Path) -> io::Result<File> {
+        #[cfg(target_os = "linux")]
+        match self.open_beneath(resource) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => {}
+            res => return res.and_then(check_type),
+        }
+        let path = self.canonical.join(resource).canonicalize()?;
+        if !path.starts_with(&self.canonical) {return Err(escape_error())}
+        let mut options = fs::OpenOptions::new();
+        options.read(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NONBLOCK);
+        }
+        options.open(path).and_then(check_type)
+    }
+
+    /// Returns the path at which to create or remove `resource`, relative to
+    /// the served directory. Its parent directory must exist and be inside
+    /// the served directory when this is called; the resource itself may be
+    /// a symbolic link, which is then replaced or removed rather than
+    /// followed.
+    pub fn writable_path(&self, resource: &Path) -> io::Result<PathBuf> {
+        let name = resource.file_name().ok_or_else(escape_error)?;
+        let parent = resource.parent().unwrap_or_else(|| Path::new(""));
+        let parent = self.canonical.join(parent).canonicalize()?;
+        if !parent.starts_with(&self.canonical) {return Err(escape_error())}
+        Ok(parent.join(name))
+    }
+
+    /// Lists the directory `resource`, relative to the served directory.
+    pub fn read_dir(&self, resource: &Path) -> io::Result<fs::ReadDir> {
+        let dir = self.open_file(resource)?;
+        read_dir(&dir, &self.canonical.join(resource))
+    }
+
+    /// Walks the tree below the directory `resource`, relative to the served
+    /// directory, breadth first.
+    pub fn walk(&self, resource: &Path) -> Walk<'_> {
+        Walk {
+            root: self,
+            pending: VecDeque::from(vec![resource.to_owned()]),
+            entries: None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_beneath(&self, resource: &Path) -> io::Result<File> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let resource = if resource == Path::new("") {
+            Path::new(".")
+        } else {
+            resource
+        };
+        let resource = CString::new(resource.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // `open_how` cannot be built with a struct expression.
+        let mut how: libc::open_how = unsafe {std::mem::zeroed()};
+        how.flags = (libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NONBLOCK)
+            as u64;
+        how.resolve = libc::RESOLVE_BENEATH;
+        let fd = unsafe {
+            libc::syscall(libc::SYS_openat2, self.dir.as_raw_fd(),
+                resource.as_ptr(), &how as *const libc::open_how,
+                std::mem::size_of::<libc::open_how>())
+        };
+        if fd >= 0 {
+            return Ok(unsafe {File::from_raw_fd(fd as _)})
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EXDEV) => Err(escape_error()),
+            _ => Err(e),
+        }
+    }
+}
+
+/// Fails if `file` is neither a regular file nor a directory, as reading
+/// other files may block.
+fn check_type(file: File) -> io::Result<File> {
+    let file_type = file.metadata()?.file_type();
+    if file_type.is_file() || file_type.is_dir() {
+        Ok(file)
+    } else {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            "Not a regular file or directory"))
+    }
+}
+
+fn escape_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied,
+        "Path resolves outside of the served directory")
+}
+
+/// Lists `dir`, opened from `path` with `RootDir::open_file`, through its
+/// descriptor. `path` is listed instead where descriptors cannot be named,
+/// e.g. without `/proc`.
+pub fn read_dir(dir: &File, path: &Path) -> io::Result<fs::ReadDir> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let fd_path = format!("/proc/self/fd/{}", dir.as_raw_fd());
+        match fs::read_dir(fd_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::PermissionDenied => {}
+            res => return res,
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = dir;
+    path.read_dir()
+}
+
+/// Entry of a tree below the served directory.
+pub struct WalkEntry {
+    /// Path relative to the served directory.
+    pub path: PathBuf,
+    /// Metadata of the entry itself, not of the target of a symbolic link.
+    pub meta: Metadata,
+}
+
+/// Walk of a tree below the served directory, entering directories but not
+/// symbolic links to them.
+pub struct Walk<'a> {
+    root: &'a RootDir,
+    pending: VecDeque<PathBuf>,
+    /// Directory being listed.
+    entries: Option<(PathBuf, fs::ReadDir)>,
+}
+
+impl Iterator for Walk<'_> {
+    type Item = WalkEntry;
+
+    fn next(&mut self) -> Option<WalkEntry> {
+        loop {
+            let (dir, entries) = match &mut self.entries {
+                Some(entries) => entries,
+                None => {
+                    let dir = self.pending.pop_front()?;
+                    if let Ok(entries) = self.root.read_dir(&dir) {
+                        self.entries = Some((dir, entries));
+                    }
+                    continue
+                }
+            };
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(_)) => continue,
+                None => {
+                    self.entries = None;
+                    continue
+                }
+            };
+            let path = dir.join(entry.file_name());
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if meta.is_dir() {
+                self.pending.push_back(path.clone());
+            } else if meta.file_type().is_symlink()
+                && self.root.open_file(&path).is_err()
+            {
+                continue
+            }
+            return Some(WalkEntry {path, meta})
+        }
+    }
+}
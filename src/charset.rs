@@ -0,0 +1,112 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Detection of the charset of text files.
+//!
+//! A byte order mark gives the encoding away. Otherwise, the start of the
+//! file is checked to be valid UTF-8, then EUC-JP, then Shift_JIS. Text that
+//! is none of these is taken to be Latin-1, or Windows-1252 if it uses the
+//! bytes that only the latter assigns to characters. These are heuristics:
+//! short samples of legacy encodings may be mistaken for one another.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Number of bytes of files inspected.
+const SAMPLE_LEN: usize = 4096;
+
+const BOMS: &[(&[u8], &str)] = &[
+    (b"\xef\xbb\xbf", "utf-8"),
+    (b"\xff\xfe\x00\x00", "utf-32le"),
+    (b"\x00\x00\xfe\xff", "utf-32be"),
+    (b"\xff\xfe", "utf-16le"),
+    (b"\xfe\xff", "utf-16be"),
+];
+
+/// Returns the charset of text starting with `sample`, if there is any
+/// text.
+pub fn detect(sample: &[u8]) -> Option<&'static str> {
+    if sample.is_empty() {
+        return None
+    }
+    if let Some((_, charset)) = BOMS.iter()
+        .find(|(bom, _)| sample.starts_with(bom))
+    {
+        return Some(charset)
+    }
+    let charset = match std::str::from_utf8(sample) {
+        Ok(_) => "utf-8",
+        // The last character may be cut.
+        Err(e) if e.error_len().is_none() => "utf-8",
+        Err(_) if is_euc_jp(sample) => "euc-jp",
+        Err(_) if is_shift_jis(sample) => "shift_jis",
+        Err(_) if sample.iter().any(|b| (0x80..0xa0).contains(b)) =>
+            "windows-1252",
+        Err(_) => "iso-8859-1",
+    };
+    Some(charset)
+}
+
+/// Returns the charset of `file`, if it is not empty. The file position is
+/// reset to the start.
+pub fn detect_file(mut file: &File) -> io::Result<Option<&'static str>> {
+    let mut sample = Vec::with_capacity(SAMPLE_LEN);
+    file.take(SAMPLE_LEN as u64).read_to_end(&mut sample)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(detect(&sample))
+}
+
+/// Returns whether `sample` is EUC-JP text with at least one multibyte
+/// character.
+fn is_euc_jp(sample: &[u8]) -> bool {
+    let mut multibyte = false;
+    let mut bytes = sample.iter();
+    while let Some(&b) = bytes.next() {
+        let trail = match b {
+            0x00..=0x7f => continue,
+            // Half-width katakana.
+            0x8e => 0xa1..=0xdf,
+            // JIS X 0212, whose characters take two more bytes.
+            0x8f => {
+                match bytes.next() {
+                    Some(0xa1..=0xfe) | None => {}
+                    Some(_) => return false,
+                }
+                0xa1..=0xfe
+            }
+            0xa1..=0xfe => 0xa1..=0xfe,
+            _ => return false,
+        };
+        match bytes.next() {
+            Some(t) if trail.contains(t) => multibyte = true,
+            Some(_) => return false,
+            None => break,
+        }
+    }
+    multibyte
+}
+
+/// Returns whether `sample` is Shift_JIS text with at least one multibyte
+/// character. Most of these must start with the lead bytes of kana and of
+/// common kanji, as Latin-1 text with accents can be valid Shift_JIS.
+fn is_shift_jis(sample: &[u8]) -> bool {
+    let (mut multibyte, mut common) = (0, 0);
+    let mut bytes = sample.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            0x00..=0x7f | 0xa1..=0xdf => continue,
+            0x81..=0x9f | 0xe0..=0xfc => {}
+            _ => return false,
+        }
+        match bytes.next() {
+            Some(0x40..=0x7e | 0x80..=0xfc) => {
+                multibyte += 1;
+                if b <= 0x9f {
+                    common += 1;
+                }
+            }
+            Some(_) => return false,
+            None => break,
+        }
+    }
+    multibyte > 0 && common * 2 >= multibyte
+}
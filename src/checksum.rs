@@ -0,0 +1,312 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Digests of served files.
+//!
+//! Digests are computed when first needed and cached until files change.
+//! Responses wait for them with `fill`, which reads files on a blocking
+//! thread of the runtime rather than on the thread serving requests. The
+//! cache may be kept in a file so that digests are not computed again after
+//! a restart. It is then saved from a background thread shortly after it
+//! changes, and entries of files that changed or disappeared meanwhile are
+//! dropped when it is loaded.
+
+use base64::Engine;
+use futures::{future, Future};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Delay between a change of a persistent cache and its saving.
+const SAVE_DELAY: Duration = Duration::from_secs(10);
+
+/// Supported digest algorithms.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Algorithm {
+    Blake3,
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    /// Parses an algorithm name as given in the `checksum` query parameter.
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match &*name.to_lowercase() {
+            "blake3" => Some(Algorithm::Blake3),
+            "md5" => Some(Algorithm::Md5),
+            "sha256" | "sha-256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Hasher {
+        use sha2::Digest;
+        match algorithm {
+            Algorithm::Blake3 => Hasher::Blake3(Default::default()),
+            Algorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        use sha2::Digest;
+        match self {
+            Hasher::Blake3(h) => {h.update(data);}
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        use sha2::Digest;
+        match self {
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+struct Entry {
+    modified: SystemTime,
+    len: u64,
+    digest: Vec<u8>,
+}
+
+type Entries = HashMap<(PathBuf, Algorithm), Entry>;
+
+/// Entry as saved in a cache file.
+#[derive(Deserialize, Serialize)]
+struct SavedEntry {
+    path: PathBuf,
+    algorithm: String,
+    /// Modification time, in seconds and nanoseconds since the Unix epoch.
+    modified: (u64, u32),
+    len: u64,
+    digest: String,
+}
+
+/// Digests of files, invalidated when a file is modified.
+#[derive(Default)]
+pub struct Cache {
+    entries: Arc<Mutex<Entries>>,
+    /// Set when entries change, if the cache is saved to a file.
+    changed: Option<Arc<AtomicBool>>,
+    /// File the cache is saved to.
+    file: Option<PathBuf>,
+}
+
+impl Cache {
+    /// Loads the cache saved at `path`, if any, to save it there as it
+    /// changes once `start_saving` is called.
+    pub fn persistent(path: PathBuf) -> io::Result<Cache> {
+        let entries = match fs::read(&path) {
+            Ok(saved) => load(&saved).unwrap_or_else(|e| {
+                warn!("Ignoring invalid digest cache {}: {}", path.display(),
+                    e);
+                HashMap::new()
+            }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Cache {
+            entries: Arc::new(Mutex::new(entries)),
+            changed: Some(Arc::new(AtomicBool::new(false))),
+            file: Some(path),
+        })
+    }
+
+    /// Starts the thread saving a persistent cache. It must be started once
+    /// the server is detached.
+    pub fn start_saving(&self) {
+        let (changed, path) = match (&self.changed, &self.file) {
+            (Some(changed), Some(path)) => (changed.clone(), path.clone()),
+            _ => return,
+        };
+        let entries = self.entries.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_DELAY);
+            if !changed.swap(false, Ordering::Relaxed) {
+                continue
+            }
+            if let Err(e) = save(&entries.lock().unwrap(), &path) {
+                warn!("Failed to save digest cache {}: {}", path.display(),
+                    e);
+            }
+        });
+    }
+
+    /// Computes the digests of `file`, found at `path`, that are not cached
+    /// or are stale, on a blocking thread of the runtime, so that `digest`
+    /// then returns them without reading the file.
+    pub fn fill(&self, path: &Path, file: &File, meta: &Metadata,
+        algorithms: &[Algorithm])
+        -> Box<dyn Future<Item = (), Error = io::Error> + Send>
+    {
+        let modified = match meta.modified() {
+            Ok(modified) => modified,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let len = meta.len();
+        let missing = {
+            let entries = self.entries.lock().unwrap();
+            algorithms.iter()
+                .copied()
+                .filter(|&algorithm| entries
+                    .get(&(path.to_owned(), algorithm))
+                    .is_none_or(|entry| entry.modified != modified
+                        || entry.len != len))
+                .collect::<Vec<_>>()
+        };
+        if missing.is_empty() {
+            return Box::new(future::ok(()))
+        }
+        let file = match file.try_clone() {
+            Ok(file) => file,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let entries = self.entries.clone();
+        let changed = self.changed.clone();
+        let path = path.to_owned();
+        let digests = future::poll_fn(move || tokio_threadpool::blocking(|| {
+            missing.iter()
+                .map(|&algorithm| Ok((algorithm, compute(&file, algorithm)?)))
+                .collect::<io::Result<Vec<_>>>()
+        }));
+        let filled = digests
+            .map_err(io::Error::other)
+            .and_then(|digests| digests)
+            .map(move |digests| {
+                let mut entries = entries.lock().unwrap();
+                for (algorithm, digest) in digests {
+                    let entry = Entry {modified, len, digest};
+                    entries.insert((path.clone(), algorithm), entry);
+                }
+                if let Some(changed) = changed {
+                    changed.store(true, Ordering::Relaxed);
+                }
+            });
+        Box::new(filled)
+    }
+
+    /// Returns the digest of `file`, found at `path`, computing it if it is
+    /// not cached or is stale. The file position is reset to the start.
+    pub fn digest(&self, path: &Path, file: &File, meta: &Metadata,
+        algorithm: Algorithm) -> io::Result<Vec<u8>>
+    {
+        let modified = meta.modified()?;
+        let key = (path.to_owned(), algorithm);
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.modified == modified && entry.len == meta.len() {
+                return Ok(entry.digest.clone())
+            }
+        }
+        let digest = compute(file, algorithm)?;
+        let entry = Entry {modified, len: meta.len(), digest: digest.clone()};
+        self.entries.lock().unwrap().insert(key, entry);
+        if let Some(changed) = &self.changed {
+            changed.store(true, Ordering::Relaxed);
+        }
+        Ok(digest)
+    }
+}
+
+/// Parses a saved cache, keeping the entries of files that did not change.
+fn load(saved: &[u8]) -> serde_json::Result<Entries> {
+    let saved: Vec<SavedEntry> = serde_json::from_slice(saved)?;
+    let entries = saved.into_iter().filter_map(|saved| {
+        let algorithm = Algorithm::from_name(&saved.algorithm)?;
+        let (secs, nanos) = saved.modified;
+        let modified = UNIX_EPOCH.checked_add(Duration::new(secs, nanos))?;
+        let meta = fs::metadata(&saved.path).ok()?;
+        if meta.modified().ok()? != modified || meta.len() != saved.len {
+            return None
+        }
+        let digest = from_hex(&saved.digest)?;
+        let entry = Entry {modified, len: saved.len, digest};
+        Some(((saved.path, algorithm), entry))
+    });
+    Ok(entries.collect())
+}
+
+/// Writes the cache to a temporary file renamed to `path`.
+fn save(entries: &Entries, path: &Path) -> io::Result<()> {
+    let saved = entries.iter().filter_map(|((file, algorithm), entry)| {
+        let modified = entry.modified.duration_since(UNIX_EPOCH).ok()?;
+        Some(SavedEntry {
+            path: file.clone(),
+            algorithm: algorithm.name().to_owned(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+            len: entry.len,
+            digest: to_hex(&entry.digest),
+        })
+    }).collect::<Vec<_>>();
+    let saved = serde_json::to_vec(&saved).map_err(io::Error::other)?;
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, saved)?;
+    fs::rename(&temp, path)
+}
+
+fn compute(mut file: &File, algorithm: Algorithm) -> io::Result<Vec<u8>> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(hasher.finish())
+}
+
+/// Formats a digest as lowercase hexadecimal.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses hexadecimal digits.
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {return None}
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Formats a SHA-256 digest as the value of a `Repr-Digest` header.
+pub fn repr_digest(digest: &[u8]) -> String {
+    format!("sha-256=:{}:", base64::engine::general_purpose::STANDARD
+        .encode(digest))
+}
+
+/// Formats a SHA-256 digest as the value of a legacy `Digest` header.
+pub fn legacy_digest(digest: &[u8]) -> String {
+    format!("SHA-256={}", base64::engine::general_purpose::STANDARD
+        .encode(digest))
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! IP networks in CIDR notation.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// IP network, e.g. `192.168.0.0/16`. A bare address denotes a network
+/// containing only this address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns whether `ip` belongs to this network. IPv4-mapped IPv6
+    /// addresses are treated as IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses to IPv4 addresses.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full = usize::from(prefix_len / 8);
+    let rest = prefix_len % 8;
+    if a[..full] != b[..full] {return false}
+    if rest == 0 {return true}
+    let mask = !0_u8 << (8 - rest);
+    a[full] & mask == b[full] & mask
+}
+
+/// Error returned when parsing an invalid network.
+#[derive(Debug)]
+pub struct ParseCidrError;
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid IP network")
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Cidr, ParseCidrError> {
+        let (address, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| ParseCidrError)?;
+        let max_len = if address.is_ipv4() {32} else {128};
+        let prefix_len = match prefix_len {
+            Some(len) if len.bytes().all(|b| b.is_ascii_digit()) =>
+                len.parse().ok()
+                    .filter(|&len| len <= max_len)
+                    .ok_or(ParseCidrError)?,
+            Some(_) => return Err(ParseCidrError),
+            None => max_len,
+        };
+        // Networks of IPv4-mapped addresses are IPv4 networks.
+        match canonical(address) {
+            IpAddr::V4(v4) if max_len == 128 && prefix_len >= 96 => {
+                let prefix_len = prefix_len - 96;
+                Ok(Cidr {address: IpAddr::V4(v4), prefix_len})
+            }
+            _ => Ok(Cidr {address, prefix_len}),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefix_eq, Cidr};
+    use std::net::IpAddr;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_are_parsed() {
+        assert_eq!(cidr("192.168.0.0/16").to_string(), "192.168.0.0/16");
+        assert_eq!(cidr("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("::/128").to_string(), "::/128");
+    }
+
+    #[test]
+    fn mapped_networks_are_ipv4_networks() {
+        assert_eq!(cidr("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("::ffff:10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!(cidr("::ffff:0:0/96").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::ffff:0:0/80").to_string(), "::ffff:0.0.0.0/80");
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        for s in ["", "/8", "10.0.0.0/", "10.0.0.0/33", "::/129",
+            "10.0.0.0/256", "10.0.0.0/-1", "10.0.0.0/+8", "10.0.0.0/8/8",
+            "10.0.0.0 /8", "10.0.0/8", "10.0.0.256", "example.com/8",
+            "fe80::1%eth0/64"]
+        {
+            assert!(s.parse::<Cidr>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn addresses_are_matched_by_prefix() {
+        let net = cidr("192.168.0.0/23");
+        assert!(net.contains(ip("192.168.0.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.0")));
+        assert!(!net.contains(ip("192.167.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+        assert!(cidr("fd00::/8").contains(ip("fdff::1")));
+        assert!(!cidr("fd00::/8").contains(ip("fe00::1")));
+    }
+
+    #[test]
+    fn mapped_addresses_are_matched_as_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(!cidr("::/0").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn prefixes_are_compared_bit_by_bit() {
+        assert!(prefix_eq(&[0xff, 0x00], &[0x00, 0xff], 0));
+        assert!(prefix_eq(&[0b1010_0000], &[0b1011_1111], 3));
+        assert!(!prefix_eq(&[0b1010_0000], &[0b1011_1111], 4));
+        assert!(prefix_eq(&[1, 2, 3, 4], &[1, 2, 3, 4], 32));
+        assert!(!prefix_eq(&[1, 2, 3, 4], &[1, 2, 3, 5], 32));
+        assert!(prefix_eq(&[1, 2, 3, 4], &[1, 2, 3, 5], 31));
+    }
+}
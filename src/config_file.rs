@@ -0,0 +1,213 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Configuration file.
+//!
+//! ```toml
+//! [[rule]]
+//! path = "/private/**"
+//! require = "auth"
+//!
+//! [[rule]]
+//! path = "/internal/**"
+//! allow = ["10.0.0.0/8"]
+//!
+//! [[rule]]
+//! path = "/admin/**"
+//! require-claim = {groups = "admin"}
+//! ```
+//!
+//! Within a rule, `deny` networks take precedence over `allow` networks, and
+//! clients outside the `allow` networks, if any, are rejected. Clients must
+//! authenticate with a JSON Web Token having the claims a rule requires,
+//! or containing the value among others for array claims.
+//!
+//! The file may also declare several servers run by the same process, each
+//! with options named as on the command line, where they are given to every
+//! server. `root` is the directory served.
+//!
+//! ```toml
+//! [[server]]
+//! root = "/srv/www"
+//! port = 443
+//! tls-cert = "/etc/servedir/cert.pem"
+//! tls-key = "/etc/servedir/key.pem"
+//!
+//! [[server]]
+//! root = "/srv/uploads"
+//! port = 8080
+//! writable = true
+//! allow = ["10.0.0.0/8"]
+//! ```
+
+use crate::access::{self, AccessRules, Requirement};
+use crate::cidr::Cidr;
+use crate::ip_filter::{self, IpFilter};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+    #[serde(default)]
+    server: Vec<toml::Table>,
+}
+
+/// Options that apply to the whole process, which servers cannot set.
+const PROCESS_OPTIONS: &[&str] = &["config", "check", "print-config",
+    "daemon", "pid-file", "log-file", "log-format", "verbose", "quiet",
+    "threads", "blocking-threads", "user", "group", "chroot", "sandbox",
+    "stdin", "stdin-replay"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    path: String,
+    require: Option<RequirementSpec>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default, rename = "require-claim")]
+    require_claim: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RequirementSpec {
+    Auth,
+    None,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(io::Error),
+    Parse(toml::de::Error),
+    InvalidPattern(String),
+    InvalidNetwork(String),
+    InvalidServerOption(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(_) => f.write_str("Failed to read file"),
+            ConfigError::Parse(_) => f.write_str("Invalid syntax"),
+            ConfigError::InvalidPattern(p) =>
+                write!(f, "Invalid path pattern {:?}", p),
+            ConfigError::InvalidNetwork(n) =>
+                write!(f, "Invalid network {:?}", n),
+            ConfigError::InvalidServerOption(name) =>
+                write!(f, "Invalid server option {:?}", name),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::InvalidPattern(_)
+                | ConfigError::InvalidNetwork(_)
+                | ConfigError::InvalidServerOption(_)
+                => None,
+        }
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Read)?;
+        toml::from_str(&contents).map_err(ConfigError::Parse)
+    }
+
+    /// Returns the access rules declared in `[[rule]]` tables.
+    pub fn access_rules(&self) -> Result<AccessRules, ConfigError> {
+        let rules = self.rule.iter()
+            .map(|spec| {
+                let pattern = glob::Pattern::new(&spec.path).map_err(|_| {
+                    ConfigError::InvalidPattern(spec.path.clone())
+                })?;
+                let require = spec.require.map(|r| match r {
+                    RequirementSpec::Auth => Requirement::Auth,
+                    RequirementSpec::None => Requirement::None,
+                });
+                let mut ip_rules = Vec::new();
+                for net in &spec.deny {
+                    ip_rules.push(ip_filter::Rule::Deny(parse_network(net)?));
+                }
+                for net in &spec.allow {
+                    ip_rules.push(ip_filter::Rule::Allow(parse_network(net)?));
+                }
+                let ip_filter = if ip_rules.is_empty() {
+                    None
+                } else {
+                    Some(IpFilter::new(ip_rules))
+                };
+                let claims = spec.require_claim.iter()
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                Ok(access::Rule {pattern, require, ip_filter, claims})
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AccessRules::new(rules))
+    }
+
+    /// Returns the command-line arguments of the servers declared in
+    /// `[[server]]` tables.
+    pub fn server_args(&self) -> Result<Vec<Vec<OsString>>, ConfigError> {
+        self.server.iter()
+            .map(|options| {
+                let mut args = Vec::new();
+                for (name, value) in options {
+                    if PROCESS_OPTIONS.contains(&name.as_str()) {
+                        return Err(ConfigError::InvalidServerOption(
+                            name.clone()))
+                    }
+                    let values = match value {
+                        toml::Value::Array(values) => values.iter().collect(),
+                        value => vec![value],
+                    };
+                    for value in values {
+                        push_arg(&mut args, name, value)?;
+                    }
+                }
+                Ok(args)
+            })
+            .collect()
+    }
+}
+
+/// Adds the argument setting the option `name` to `value`.
+fn push_arg(args: &mut Vec<OsString>, name: &str, value: &toml::Value)
+    -> Result<(), ConfigError>
+{
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(n) => n.to_string(),
+        toml::Value::Float(x) => x.to_string(),
+        toml::Value::Boolean(true) if name != "root" => {
+            args.push(format!("--{}", name).into());
+            return Ok(())
+        }
+        toml::Value::Boolean(false) if name != "root" => return Ok(()),
+        _ => return Err(ConfigError::InvalidServerOption(name.to_owned())),
+    };
+    if name != "root" {
+        args.push(format!("--{}", name).into());
+    }
+    args.push(value.into());
+    Ok(())
+}
+
+fn parse_network(net: &str) -> Result<Cidr, ConfigError> {
+    net.parse().map_err(|_| ConfigError::InvalidNetwork(net.to_owned()))
+}
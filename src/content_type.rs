@@ -0,0 +1,83 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Content types of served files set by patterns.
+//!
+//! Each override is given as `PATTERN=TYPE`, e.g. `*.vtt=text/vtt`, or as
+//! `PATTERN=charset=CHARSET` to keep the default type of the matching files
+//! but declare their charset. Patterns are matched against file names,
+//! ignoring case, and the first matching override applies.
+
+use glob::{MatchOptions, Pattern};
+use mime::Mime;
+use std::path::Path;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+#[derive(Debug)]
+enum Override {
+    Type(Mime),
+    Charset(String),
+}
+
+/// Content types overriding the defaults.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    rules: Vec<(Pattern, Override)>,
+}
+
+impl Overrides {
+    /// Adds an override given as `PATTERN=TYPE` or
+    /// `PATTERN=charset=CHARSET`. Returns false if it is invalid.
+    pub fn add(&mut self, spec: &str) -> bool {
+        let (pattern, value) = match spec.split_once('=') {
+            Some((pattern, value)) => (pattern.trim(), value.trim()),
+            None => return false,
+        };
+        let pattern = match Pattern::new(pattern) {
+            Ok(pattern) => pattern,
+            Err(_) => return false,
+        };
+        let value = match value.strip_prefix("charset=") {
+            Some(charset) => {
+                let valid = !charset.is_empty() && format!(
+                    "text/plain; charset={}", charset).parse::<Mime>().is_ok();
+                if !valid {
+                    return false
+                }
+                Override::Charset(charset.to_owned())
+            }
+            None => match value.parse() {
+                Ok(mime) => Override::Type(mime),
+                Err(_) => return false,
+            },
+        };
+        self.rules.push((pattern, value));
+        true
+    }
+
+    /// Returns the content type set for the file at `path`, if any.
+    pub fn get(&self, path: &Path) -> Option<Mime> {
+        let name = path.file_name()?.to_string_lossy();
+        let rule = self.rules.iter()
+            .find(|(pattern, _)| pattern.matches_with(&name, MATCH_OPTIONS));
+        match rule? {
+            (_, Override::Type(mime)) => Some(mime.clone()),
+            (_, Override::Charset(charset)) =>
+                Some(with_charset(crate::get_content_type(path), charset)),
+        }
+    }
+}
+
+/// Returns `content_type` with its charset set to `charset`.
+pub fn with_charset(content_type: Mime, charset: &str) -> Mime {
+    let suffix = content_type.suffix()
+        .map_or(String::new(), |suffix| format!("+{}", suffix));
+    format!("{}/{}{}; charset={}", content_type.type_(),
+        content_type.subtype(), suffix, charset)
+        .parse()
+        .unwrap_or(content_type)
+}
@@ -0,0 +1,151 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Detachment of the server from the terminal it was started from.
+//!
+//! The process that started the server waits until the server is ready or
+//! has failed to start, so that its exit status reflects the outcome. The
+//! working directory is kept so that relative paths given on the command line
+//! remain valid.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files used by the detached server.
+#[derive(Debug, Default)]
+pub struct Settings<'a> {
+    /// File to write the process ID to.
+    pub pid_file: Option<&'a Path>,
+    /// File to append the output of the server to, instead of discarding it.
+    pub log_file: Option<&'a Path>,
+}
+
+#[derive(Debug)]
+pub enum DaemonError {
+    LogFile(io::Error),
+    PidFile(io::Error),
+    Fork(io::Error),
+    #[cfg(not(unix))]
+    Unsupported,
+}
+
+impl fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DaemonError::LogFile(_) => f.write_str("Failed to open log file"),
+            DaemonError::PidFile(_) =>
+                f.write_str("Failed to write process ID file"),
+            DaemonError::Fork(_) => f.write_str("Failed to detach process"),
+            #[cfg(not(unix))]
+            DaemonError::Unsupported =>
+                f.write_str("Running as a daemon is not supported on this \
+                    platform"),
+        }
+    }
+}
+
+impl Error for DaemonError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DaemonError::LogFile(e)
+                | DaemonError::PidFile(e)
+                | DaemonError::Fork(e)
+                => Some(e),
+            #[cfg(not(unix))]
+            DaemonError::Unsupported => None,
+        }
+    }
+}
+
+/// Detached server process. The process ID file is removed when dropped.
+pub struct Daemon {
+    ready: Option<File>,
+    pid_file: Option<PathBuf>,
+}
+
+impl Daemon {
+    /// Lets the process that started the server exit successfully.
+    pub fn notify_ready(&mut self) {
+        use std::io::Write;
+        if let Some(mut ready) = self.ready.take() {
+            let _ = ready.write_all(b"1");
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pid_file {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Runs the rest of the program in a new session, in the background. The
+/// calling process exits once `Daemon::notify_ready` is called, or fails if
+/// the daemon exits first. Must be called before any thread is started.
+#[cfg(unix)]
+pub fn detach(settings: &Settings) -> Result<Daemon, DaemonError> {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::process;
+
+    fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+        if res == -1 {Err(io::Error::last_os_error())} else {Ok(res)}
+    }
+
+    let output = match settings.log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }.map_err(DaemonError::LogFile)?;
+    let null = File::open("/dev/null").map_err(DaemonError::Fork)?;
+    let mut fds = [0; 2];
+    check(unsafe {libc::pipe(fds.as_mut_ptr())}).map_err(DaemonError::Fork)?;
+    let (mut reader, writer) = unsafe {
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+    if check(unsafe {libc::fork()}).map_err(DaemonError::Fork)? != 0 {
+        drop(writer);
+        let mut status = [0];
+        if reader.read(&mut status).unwrap_or(0) == 1 {
+            process::exit(0)
+        }
+        eprintln!("Error: The server failed to start");
+        if let Some(path) = settings.log_file {
+            eprintln!("See {} for details", path.display());
+        }
+        process::exit(1)
+    }
+    drop(reader);
+    check(unsafe {libc::setsid()}).map_err(DaemonError::Fork)?;
+    // Forking again ensures the daemon cannot acquire a controlling
+    // terminal.
+    if check(unsafe {libc::fork()}).map_err(DaemonError::Fork)? != 0 {
+        unsafe {libc::_exit(0)}
+    }
+    unsafe {libc::umask(0o022)};
+    if let Some(path) = settings.pid_file {
+        let mut file = File::create(path).map_err(DaemonError::PidFile)?;
+        writeln!(file, "{}", process::id()).map_err(DaemonError::PidFile)?;
+    }
+    let redirections = [
+        (null.as_raw_fd(), libc::STDIN_FILENO),
+        (output.as_raw_fd(), libc::STDOUT_FILENO),
+        (output.as_raw_fd(), libc::STDERR_FILENO),
+    ];
+    for &(from, to) in &redirections {
+        check(unsafe {libc::dup2(from, to)}).map_err(DaemonError::Fork)?;
+    }
+    Ok(Daemon {
+        ready: Some(writer),
+        pid_file: settings.pid_file.map(Path::to_owned),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn detach(_: &Settings) -> Result<Daemon, DaemonError> {
+    Err(DaemonError::Unsupported)
+}
@@ -0,0 +1,228 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Changes to the files of a served directory, for incremental sync
+//! clients.
+//!
+//! `GET /_api/diff` returns a token identifying the current state of the
+//! files, all listed as added. `GET /_api/diff?since=TOKEN` returns the
+//! files added, modified and removed since that state, with a new token.
+//! States are snapshots of the sizes and modification times of files, taken
+//! when requested. The last few are kept in memory, so tokens of older
+//! states, or from before a restart unless nothing changed, are answered
+//! with `410 Gone`, after which clients sync from scratch.
+//!
+//! `since` may also be a time, in seconds since the Unix epoch or in RFC
+//! 3339 format. Changes are then found from the last snapshot taken by then,
+//! if any, or else from the modification times of files, in which case
+//! removed files are unknown and `complete` is false.
+//!
+//! Snapshots and changes only include files the client may read, and are
+//! taken on the blocking pool without leaving the served directory.
+
+use crate::beneath::RootDir;
+use crate::vhost::Site;
+use crate::{jwt, url_path, Config, ReadAccess, ServerFuture};
+use futures::{future, Future};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+/// Path of the diff endpoint.
+pub const ENDPOINT: &str = "/_api/diff";
+
+/// Number of snapshots kept for each served directory.
+const MAX_SNAPSHOTS: usize = 16;
+
+/// Size and modification time of a file.
+type Version = (u64, SystemTime);
+
+/// Files of a served directory at some point.
+struct Snapshot {
+    token: String,
+    taken: SystemTime,
+    /// Files by path relative to the served directory.
+    files: BTreeMap<PathBuf, Version>,
+}
+
+/// Recent snapshots of the served directories.
+#[derive(Default)]
+pub struct Snapshots {
+    by_root: Mutex<HashMap<PathBuf, VecDeque<Arc<Snapshot>>>>,
+}
+
+impl Snapshots {
+    /// Answers a diff request from `client` for the files of `site`.
+    pub fn send(self: &Arc<Self>, config: &Arc<Config>, site: &Arc<Site>,
+        client: IpAddr, authenticated: bool, request: &Request<Body>)
+        -> ServerFuture<Response<Body>>
+    {
+        let query = request.uri().query().unwrap_or("");
+        let since = form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "since")
+            .map(|(_, value)| value.into_owned());
+        let snapshots = self.clone();
+        let (config, site) = (config.clone(), site.clone());
+        let claims = request.extensions().get::<jwt::Claims>().cloned();
+        let body = crate::blocking(move || {
+            let mut access = ReadAccess::new(&config, &site, client,
+                authenticated, claims.as_ref());
+            snapshots.changes(&site, since.as_deref(), &mut access)
+        });
+        Box::new(body.then(|body| {
+            let body = match body {
+                Ok(Some(body)) => body,
+                Ok(None) => return crate::gone(),
+                Err(e) => return crate::io_error(e),
+            };
+            let res = Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_TYPE,
+                    mime::APPLICATION_JSON.to_string())
+                .body(body.to_string().into());
+            Box::new(future::result(res))
+        }))
+    }
+
+    /// Returns the changes to the files of `site` readable with `access`
+    /// since the state or time `since`, or since nothing if `None`. Returns
+    /// `None` if that state is unknown.
+    fn changes(&self, site: &Site, since: Option<&str>,
+        access: &mut ReadAccess) -> Option<Value>
+    {
+        let current = self.take(site, access);
+        let empty = BTreeMap::new();
+        let mut body = match since {
+            None => diff(&empty, &current, access),
+            Some(since) => match self.find(&site.root, since) {
+                Some(earlier) => diff(&earlier.files, &current, access),
+                None => modified_since(&current, parse_time(since)?),
+            },
+        };
+        body["token"] = current.token.clone().into();
+        Some(body)
+    }
+
+    /// Takes a snapshot of the files of `site` readable with `access` and
+    /// keeps it.
+    fn take(&self, site: &Site, access: &mut ReadAccess) -> Arc<Snapshot> {
+        let mut files = BTreeMap::new();
+        scan(&site.root_dir, access, &mut files);
+        let mut hasher = blake3::Hasher::new();
+        for (path, (size, modified)) in &files {
+            let modified = modified.duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update(&[0]);
+            hasher.update(&size.to_le_bytes());
+            hasher.update(&modified.as_nanos().to_le_bytes());
+        }
+        let token = hasher.finalize().to_hex()[..32].to_owned();
+        let snapshot = Arc::new(Snapshot {
+            token,
+            taken: SystemTime::now(),
+            files,
+        });
+        let mut by_root = self.by_root.lock().unwrap();
+        let snapshots = by_root.entry(site.root.clone()).or_default();
+        snapshots.retain(|s| s.token != snapshot.token);
+        if snapshots.len() == MAX_SNAPSHOTS {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot.clone());
+        snapshot
+    }
+
+    /// Returns the snapshot of `root` identified by `since`, or the last one
+    /// taken by the time `since` designates.
+    fn find(&self, root: &Path, since: &str) -> Option<Arc<Snapshot>> {
+        let by_root = self.by_root.lock().unwrap();
+        let snapshots = by_root.get(root)?;
+        if let Some(snapshot) = snapshots.iter().find(|s| s.token == since) {
+            return Some(snapshot.clone())
+        }
+        let time = parse_time(since)?;
+        snapshots.iter()
+            .filter(|s| s.taken <= time)
+            .max_by_key(|s| s.taken)
+            .cloned()
+    }
+}
+
+/// Returns the changes from `earlier` to `current`. Snapshots may have been
+/// taken for other clients, so only removed files readable with `access` are
+/// listed.
+fn diff(earlier: &BTreeMap<PathBuf, Version>, current: &Snapshot,
+    access: &mut ReadAccess) -> Value
+{
+    let mut added = Vec::new();
+    let mut modified = Vec::new();
+    for (path, version) in &current.files {
+        match earlier.get(path) {
+            None => added.push(entry(path, version)),
+            Some(old) if old != version => modified.push(entry(path, version)),
+            Some(_) => {}
+        }
+    }
+    let removed = earlier.keys()
+        .filter(|path| {
+            !current.files.contains_key(*path) && access.allows(path)
+        })
+        .map(|path| url_path::encode(path))
+        .collect::<Vec<_>>();
+    json!({
+        "complete": true,
+        "added": added,
+        "modified": modified,
+        "removed": removed,
+    })
+}
+
+/// Returns the files of `current` modified after `time`.
+fn modified_since(current: &Snapshot, time: SystemTime) -> Value {
+    let modified = current.files.iter()
+        .filter(|(_, (_, modified))| *modified > time)
+        .map(|(path, version)| entry(path, version))
+        .collect::<Vec<_>>();
+    json!({
+        "complete": false,
+        "added": [],
+        "modified": modified,
+        "removed": [],
+    })
+}
+
+fn entry(path: &Path, (size, modified): &Version) -> Value {
+    json!({
+        "path": url_path::encode(path),
+        "size": size,
+        "modified":
+            humantime::format_rfc3339_seconds(*modified).to_string(),
+    })
+}
+
+/// Parses a time in seconds since the Unix epoch or in RFC 3339 format.
+fn parse_time(s: &str) -> Option<SystemTime> {
+    match s.parse::<u64>() {
+        Ok(secs) => UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_rfc3339_weak(s).ok(),
+    }
+}
+
+/// Adds the files of `root_dir` readable with `access` to `files`.
+fn scan(root_dir: &RootDir, access: &mut ReadAccess,
+    files: &mut BTreeMap<PathBuf, Version>)
+{
+    for entry in root_dir.walk(Path::new("")) {
+        if !entry.meta.is_file() || !access.allows(&entry.path) {
+            continue
+        }
+        let modified = entry.meta.modified().unwrap_or(UNIX_EPOCH);
+        files.insert(entry.path, (entry.meta.len(), modified));
+    }
+}
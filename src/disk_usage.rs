@@ -0,0 +1,90 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Total size of the files below directories.
+//!
+//! Sizes are computed when a listing asks for them, within a time budget
+//! shared by the directories of the listing. Trees are walked beneath the
+//! served directory, without following symbolic links. Complete totals are
+//! kept for a while so that listing a directory again does not walk its tree
+//! again.
+
+use crate::beneath::RootDir;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time complete totals are kept.
+const TTL: Duration = Duration::from_secs(60);
+
+/// Maximum number of totals kept.
+const MAX_ENTRIES: usize = 4096;
+
+/// Total size of the files below a directory.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Usage {
+    pub bytes: u64,
+    /// Whether all files were counted before the deadline.
+    pub complete: bool,
+}
+
+/// Cache of directory sizes.
+#[derive(Debug)]
+pub struct DiskUsage {
+    /// Time allowed to compute the sizes of the directories of a listing.
+    pub budget: Duration,
+    totals: Mutex<HashMap<PathBuf, (Instant, u64)>>,
+}
+
+impl DiskUsage {
+    pub fn new(budget: Duration) -> DiskUsage {
+        DiskUsage {budget, totals: Default::default()}
+    }
+
+    /// Returns the total size of the files below `dir`, relative to
+    /// `root_dir`, counting until `deadline`.
+    pub fn measure(&self, root_dir: &RootDir, dir: &Path, deadline: Instant)
+        -> Usage
+    {
+        let key = root_dir.path().join(dir);
+        if let Some((created, bytes)) = self.totals.lock().unwrap().get(&key) {
+            if created.elapsed() < TTL {
+                return Usage {bytes: *bytes, complete: true}
+            }
+        }
+        let usage = walk(root_dir, dir, Some(deadline));
+        if usage.complete {
+            let mut totals = self.totals.lock().unwrap();
+            if totals.len() >= MAX_ENTRIES {
+                totals.retain(|_, (created, _)| created.elapsed() < TTL);
+                if totals.len() >= MAX_ENTRIES {
+                    totals.clear();
+                }
+            }
+            totals.insert(key, (Instant::now(), usage.bytes));
+        }
+        usage
+    }
+}
+
+/// Returns the total size of the files below `dir`, however long counting
+/// takes.
+pub fn total(dir: &Path) -> u64 {
+    RootDir::open(dir)
+        .map_or(0, |root_dir| walk(&root_dir, Path::new(""), None).bytes)
+}
+
+fn walk(root_dir: &RootDir, dir: &Path, deadline: Option<Instant>) -> Usage {
+    let mut usage = Usage {bytes: 0, complete: true};
+    for entry in root_dir.walk(dir) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            usage.complete = false;
+            return usage
+        }
+        if entry.meta.is_file() {
+            usage.bytes += entry.meta.len();
+        }
+    }
+    usage
+}
@@ -0,0 +1,109 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Limits on the number of downloads of a resource.
+//!
+//! Downloads are counted in bytes of the resource served, so that responses
+//! to range requests count for the part of the resource they cover.
+
+use futures::{Async, Poll, Stream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Count {
+    /// Bytes of the resource sent in complete responses.
+    served: u64,
+    in_progress: u64,
+}
+
+/// Numbers of downloads, by key.
+#[derive(Default)]
+pub struct Counter {
+    counts: Mutex<HashMap<String, Count>>,
+}
+
+impl Counter {
+    /// Starts a download of `bytes` of the resource identified by `key`, of
+    /// length `len`, unless `max` downloads are completed or in progress.
+    pub fn start(self: &Arc<Self>, key: String, max: u64, len: u64,
+        bytes: u64) -> Option<Download>
+    {
+        // Each response for an empty resource counts as a download.
+        let (len, bytes) = if len == 0 {(1, 1)} else {(len, bytes)};
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_default();
+        if count.served / len + count.in_progress >= max {return None}
+        count.in_progress += 1;
+        Some(Download {counter: self.clone(), key, bytes, completed: false})
+    }
+}
+
+/// Download in progress. Dropping it before completion gives the download
+/// back.
+pub struct Download {
+    counter: Arc<Counter>,
+    key: String,
+    /// Bytes of the resource sent.
+    bytes: u64,
+    completed: bool,
+}
+
+impl Download {
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let mut counts = self.counter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            count.in_progress -= 1;
+            if self.completed {
+                count.served += self.bytes;
+            }
+        }
+    }
+}
+
+/// Stream completing a download once `len` bytes are produced or it ends,
+/// as the consumer may stop polling once it has received the expected length.
+pub struct Counted<S> {
+    inner: S,
+    remaining: u64,
+    download: Option<Download>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, len: u64, download: Download) -> Counted<S> {
+        Counted {inner, remaining: len, download: Some(download)}
+    }
+}
+
+impl<S> Stream for Counted<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let item = self.inner.poll()?;
+        let done = match &item {
+            Async::Ready(Some(chunk)) => {
+                let len = chunk.as_ref().len() as u64;
+                self.remaining = self.remaining.saturating_sub(len);
+                self.remaining == 0
+            }
+            Async::Ready(None) => true,
+            Async::NotReady => false,
+        };
+        if done {
+            if let Some(download) = self.download.take() {
+                download.complete();
+            }
+        }
+        Ok(item)
+    }
+}
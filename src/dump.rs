@@ -0,0 +1,164 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Dump of requests and responses, to debug clients.
+//!
+//! With `--dump-requests`, the request line and headers of each request are
+//! written to the standard error or a file as soon as it is received, and
+//! the status line and headers of its response once it is ready. Lines start
+//! with `>` for requests and `<` for responses, followed by the request ID.
+//! With `--dump-body-limit`, the beginning of bodies is also written once
+//! they are sent.
+//!
+//! Credentials, i.e. the values of the `Authorization`, `Cookie` and
+//! `Set-Cookie` headers, are redacted.
+
+use futures::{Async, Poll, Stream};
+use http::header::{self, HeaderMap, HeaderName};
+use http::{Request, Response};
+use hyper::{Body, Chunk};
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+const REDACTED: [HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// Destination of dumps.
+pub struct Dumper {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Number of bytes of bodies dumped.
+    body_limit: usize,
+}
+
+impl Dumper {
+    /// Dumps to the file at `dest`, created if needed, or to the standard
+    /// error if `dest` is `-`.
+    pub fn open(dest: &Path, body_limit: usize) -> io::Result<Arc<Dumper>> {
+        let out: Box<dyn Write + Send> = if dest == Path::new("-") {
+            Box::new(io::stderr())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(dest)?)
+        };
+        Ok(Arc::new(Dumper {out: Mutex::new(out), body_limit}))
+    }
+
+    /// Dumps the head of `request`, and its body once received.
+    pub fn request(self: &Arc<Self>, id: &str, request: &mut Request<Body>) {
+        let mut text = format!("> {} {} {} {:?}\n", id, request.method(),
+            request.uri(), request.version());
+        write_headers(&mut text, '>', id, request.headers());
+        self.write(&text);
+        if self.body_limit > 0 && crate::has_body(request.headers()) {
+            let body = std::mem::take(request.body_mut());
+            *request.body_mut() = self.wrap('>', id, body);
+        }
+    }
+
+    /// Dumps the head of `res`, and returns it with a body dumped once sent.
+    pub fn response(self: &Arc<Self>, id: &str, res: Response<Body>)
+        -> Response<Body>
+    {
+        let mut text = format!("< {} {:?} {}\n", id, res.version(),
+            res.status());
+        write_headers(&mut text, '<', id, res.headers());
+        self.write(&text);
+        if self.body_limit == 0 {return res}
+        res.map(|body| self.wrap('<', id, body))
+    }
+
+    fn wrap(self: &Arc<Self>, prefix: char, id: &str, body: Body) -> Body {
+        Body::wrap_stream(DumpedBody {
+            body,
+            dumper: self.clone(),
+            prefix,
+            id: id.to_owned(),
+            start: Vec::new(),
+            len: 0,
+            done: false,
+        })
+    }
+
+    fn write(&self, text: &str) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(text.as_bytes()).and_then(|_| out.flush())
+        {
+            warn!("Failed to dump request: {}", e);
+        }
+    }
+}
+
+fn write_headers(text: &mut String, prefix: char, id: &str,
+    headers: &HeaderMap)
+{
+    for (name, value) in headers {
+        let value = if REDACTED.contains(name) {
+            "<redacted>".into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        let _ = writeln!(text, "{} {} {}: {}", prefix, id, name, value);
+    }
+}
+
+/// Body keeping its first bytes, which are dumped when it ends or is
+/// dropped.
+struct DumpedBody {
+    body: Body,
+    dumper: Arc<Dumper>,
+    prefix: char,
+    id: String,
+    start: Vec<u8>,
+    /// Number of bytes sent so far.
+    len: u64,
+    done: bool,
+}
+
+impl DumpedBody {
+    fn dump(&mut self) {
+        if self.done {return}
+        self.done = true;
+        if self.len == 0 {return}
+        let start = String::from_utf8_lossy(&self.start);
+        let truncated = if self.start.len() as u64 == self.len {
+            ""
+        } else {
+            " (truncated)"
+        };
+        let text = format!("{} {} body ({} bytes){}: {}\n", self.prefix,
+            self.id, self.len, truncated, start.escape_debug());
+        self.dumper.write(&text);
+    }
+}
+
+impl Stream for DumpedBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let item = self.body.poll()?;
+        match &item {
+            Async::Ready(Some(chunk)) => {
+                let limit = self.dumper.body_limit;
+                let kept = (limit - self.start.len()).min(chunk.len());
+                self.start.extend_from_slice(&chunk[..kept]);
+                self.len += chunk.len() as u64;
+            }
+            Async::Ready(None) => self.dump(),
+            Async::NotReady => {}
+        }
+        Ok(item)
+    }
+}
+
+impl Drop for DumpedBody {
+    fn drop(&mut self) {
+        self.dump();
+    }
+}
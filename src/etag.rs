@@ -0,0 +1,148 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Entity tags of served files and the preconditions using them.
+//!
+//! Tags are strong and derived from the inode, size and modification time
+//! of files by default. They may instead be digests of their content, which
+//! are the same on every server and survive a file being rewritten with the
+//! same content, or weak tags, which `If-Range` and `If-Match` never match.
+//!
+//! `PUT` and `DELETE` requests may be made conditional with `If-Match`,
+//! `If-Unmodified-Since` and `If-None-Match`, so that an editor saving a
+//! file does not overwrite changes it has not seen.
+
+use crate::checksum::{self, Algorithm};
+use crate::range::Validators;
+use http::header::{HeaderMap, HeaderName, IF_MATCH, IF_NONE_MATCH};
+use http::header::IF_UNMODIFIED_SINCE;
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Way entity tags are derived from files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Policy {
+    Inode,
+    Hash,
+    Weak,
+}
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "inode" => Some(Policy::Inode),
+            "hash" => Some(Policy::Hash),
+            "weak" => Some(Policy::Weak),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::Inode => "inode",
+            Policy::Hash => "hash",
+            Policy::Weak => "weak",
+        }
+    }
+
+    /// Returns the tag of `file`, found at `path`. The file position is
+    /// reset to the start.
+    pub fn tag(self, checksums: &checksum::Cache, path: &Path, file: &File,
+        meta: &Metadata) -> io::Result<String>
+    {
+        match self {
+            Policy::Inode =>
+                Ok(format!("\"{}{}\"", inode(meta), version(meta))),
+            Policy::Hash =>
+                checksums.digest(path, file, meta, Algorithm::Blake3)
+                    .map(|digest| format!("\"{}\"", checksum::to_hex(&digest))),
+            Policy::Weak => Ok(format!("W/\"{}\"", version(meta))),
+        }
+    }
+}
+
+/// Returns the size and modification time of a file, in hexadecimal.
+fn version(meta: &Metadata) -> String {
+    let stamp = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("{:x}-{:x}", meta.len(), stamp)
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    format!("{:x}-", meta.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> String {
+    String::new()
+}
+
+/// Returns whether the preconditions of a request with `headers` hold for a
+/// resource, given whether it exists and the validators of its current
+/// version, if it has any.
+pub fn preconditions_hold(headers: &HeaderMap, exists: bool,
+    validators: Option<&Validators>) -> bool
+{
+    let header = |name: HeaderName| headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim);
+    let etag = validators.map(|v| v.etag.as_str());
+    if let Some(if_match) = header(IF_MATCH) {
+        let holds = if if_match == "*" {
+            exists
+        } else {
+            etag.is_some_and(|etag| tags(if_match).any(|tag| {
+                !is_weak(tag) && !is_weak(etag) && tag == etag
+            }))
+        };
+        if !holds {
+            return false
+        }
+    } else if let Some(since) = header(IF_UNMODIFIED_SINCE)
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    {
+        let modified = validators.and_then(|v| v.modified);
+        if modified.is_some_and(|modified| seconds(modified) > seconds(since)) {
+            return false
+        }
+    }
+    if let Some(if_none_match) = header(IF_NONE_MATCH) {
+        let matches = if if_none_match == "*" {
+            exists
+        } else {
+            etag.is_some_and(|etag| tags(if_none_match)
+                .any(|tag| opaque(tag) == opaque(etag)))
+        };
+        if matches {
+            return false
+        }
+    }
+    true
+}
+
+/// Returns whether any of the preconditions checked for writes is present.
+pub fn has_preconditions(headers: &HeaderMap) -> bool {
+    headers.contains_key(IF_MATCH) || headers.contains_key(IF_NONE_MATCH)
+        || headers.contains_key(IF_UNMODIFIED_SINCE)
+}
+
+fn tags(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|tag| !tag.is_empty())
+}
+
+fn is_weak(tag: &str) -> bool {
+    tag.starts_with("W/")
+}
+
+/// Returns a tag without its weakness indicator.
+fn opaque(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn seconds(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
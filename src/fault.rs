@@ -0,0 +1,74 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Artificial latency and failures, to test how clients cope with slow or
+//! flaky servers.
+//!
+//! Responses are delayed by a fixed duration plus a random one up to the
+//! jitter. A fraction of the requests, picked at random, fail with `503
+//! Service Unavailable` instead of being processed. Faults can be limited to
+//! the paths matching glob patterns, e.g. `/assets/**`.
+
+use crate::problem::Problem;
+use crate::ServerFuture;
+use futures::Future;
+use glob::{MatchOptions, Pattern};
+use http::{Response, StatusCode};
+use hyper::Body;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Faults injected into responses.
+#[derive(Debug)]
+pub struct Faults {
+    delay: Duration,
+    /// Maximum random delay added to `delay`.
+    jitter: Duration,
+    /// Fraction of requests failing.
+    fail_rate: f64,
+    /// Patterns of the paths faults apply to. No pattern matches all paths.
+    paths: Vec<Pattern>,
+}
+
+impl Faults {
+    pub fn new(delay: Duration, jitter: Duration, fail_rate: f64,
+        paths: Vec<Pattern>) -> Faults
+    {
+        Faults {delay, jitter, fail_rate, paths}
+    }
+
+    /// Returns whether faults apply to requests for the URL path `path`.
+    pub fn applies(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self.paths.iter().any(|p| p.matches_with(path, MATCH_OPTIONS))
+    }
+
+    /// Returns the response `process` returns, delayed, unless the request
+    /// fails, in which case `process` is not called.
+    pub fn inject<F>(&self, process: F) -> ServerFuture<Response<Body>>
+    where
+        F: FnOnce() -> ServerFuture<Response<Body>>,
+    {
+        let res = if self.fail_rate > 0.0 && random() < self.fail_rate {
+            Problem::new(StatusCode::SERVICE_UNAVAILABLE, "Injected failure")
+                .respond()
+        } else {
+            process()
+        };
+        let delay = self.delay + self.jitter.mul_f64(random());
+        if delay.is_zero() {return res}
+        Box::new(Delay::new(Instant::now() + delay).then(|_| res))
+    }
+}
+
+/// Returns a random number in [0, 1).
+fn random() -> f64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness");
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
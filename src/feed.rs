@@ -0,0 +1,165 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Atom feed of the most recently modified files below a directory.
+//!
+//! The tree is scanned on the blocking pool when the feed is requested,
+//! within the time allowed for searches, for files the client may read,
+//! without leaving the served directory. Entries are identified by the URL
+//! of their file, and are updated when the file is modified.
+
+use crate::{jwt, url_path, Config, ReadAccess, ServerFuture};
+use crate::beneath::RootDir;
+use crate::vhost::Site;
+use futures::{future, Future};
+use http::{Request, Response};
+use hyper::Body;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use url::form_urlencoded;
+
+/// Path of the feed endpoint.
+pub const ENDPOINT: &str = "/_feed.atom";
+
+/// Maximum number of entries in a feed.
+const MAX_ENTRIES: usize = 50;
+
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+/// Recently modified file.
+struct Recent {
+    modified: SystemTime,
+    /// Path relative to the served directory.
+    path: PathBuf,
+    size: u64,
+}
+
+/// Answers a feed request from `client`.
+pub fn send(config: &Arc<Config>, site: &Arc<Site>, client: IpAddr,
+    authenticated: bool, request: &Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let query = request.uri().query().unwrap_or("");
+    let mut base = PathBuf::new();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        if key == "path" {
+            base = match crate::sanitize_path(Path::new(&*value)) {
+                Some(path) => path.to_owned(),
+                None => return crate::bad_request(),
+            };
+        }
+    }
+    let claims = request.extensions().get::<jwt::Claims>();
+    let mut access = ReadAccess::new(config, site, client, authenticated,
+        claims);
+    if let Some(res) = crate::deny(config, access.check(&base), request) {
+        return res
+    }
+    let origin = crate::request_origin(config, request);
+    let (config, site) = (config.clone(), site.clone());
+    let claims = claims.cloned();
+    let res = crate::blocking(move || {
+        let mut access = ReadAccess::new(&config, &site, client,
+            authenticated, claims.as_ref());
+        let recent = scan(&site.root_dir, &base, &mut access,
+            config.search_timeout);
+        let mut out = Vec::new();
+        write_feed(&origin, &base, &recent, &mut out).unwrap();
+        Response::builder()
+            .header(http::header::CONTENT_TYPE, "application/atom+xml")
+            .body(out.into())
+    });
+    Box::new(res.then(|res| match res {
+        Ok(res) => Box::new(future::result(res)),
+        Err(e) => crate::io_error(e),
+    }))
+}
+
+/// Returns the most recently modified files below `base` readable with
+/// `access`, most recent first.
+fn scan(root_dir: &RootDir, base: &Path, access: &mut ReadAccess,
+    timeout: Duration) -> Vec<Recent>
+{
+    let deadline = Instant::now() + timeout;
+    let mut recent = BinaryHeap::new();
+    for entry in root_dir.walk(base) {
+        if Instant::now() >= deadline {
+            break
+        }
+        if !entry.meta.is_file() {
+            continue
+        }
+        let modified = match entry.meta.modified() {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if !access.allows(&entry.path) {
+            continue
+        }
+        recent.push(Reverse((modified, entry.path, entry.meta.len())));
+        if recent.len() > MAX_ENTRIES {
+            recent.pop();
+        }
+    }
+    recent.into_sorted_vec().into_iter()
+        .map(|Reverse((modified, path, size))| Recent {modified, path, size})
+        .collect()
+}
+
+fn write_feed<W: Write>(origin: &str, base: &Path, recent: &[Recent],
+    out: W) -> Result<(), xml::writer::Error>
+{
+    let mut out = xml::EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(out);
+    let out = &mut out;
+    let mut base_url = format!("{}{}", origin, url_path::encode(base));
+    if !base_url.ends_with('/') {
+        base_url.push('/');
+    }
+    let updated = recent.first().map_or(SystemTime::UNIX_EPOCH,
+        |file| file.modified);
+    let self_url = format!("{}{}?{}", origin, ENDPOINT,
+        form_urlencoded::Serializer::new(String::new())
+            .append_pair("path", &format!("/{}", base.display()))
+            .finish());
+    nestxml::element(out, "feed").attr("xmlns", ATOM_NAMESPACE).write(|out| {
+        nestxml::element(out, "title")
+            .text(&format!("Recent files in /{}", base.display()))?;
+        nestxml::element(out, "id").text(&self_url)?;
+        nestxml::element(out, "updated").text(&rfc3339(updated))?;
+        nestxml::element(out, "link")
+            .attr("rel", "self")
+            .attr("href", self_url.as_str())
+            .empty()?;
+        nestxml::element(out, "link").attr("href", base_url.as_str()).empty()?;
+        nestxml::element(out, "author").write(|out| {
+            nestxml::element(out, "name").text(crate::APP_NAME)
+        })?;
+        for file in recent {
+            let url = format!("{}{}", origin, url_path::encode(&file.path));
+            nestxml::element(out, "entry").write(|out| {
+                nestxml::element(out, "title")
+                    .text(&format!("/{}", file.path.display()))?;
+                nestxml::element(out, "id").text(&url)?;
+                nestxml::element(out, "updated")
+                    .text(&rfc3339(file.modified))?;
+                nestxml::element(out, "link")
+                    .attr("href", url.as_str())
+                    .attr("length", file.size.to_string())
+                    .empty()?;
+                nestxml::element(out, "summary")
+                    .text(&crate::pretty_size(file.size))
+            })?;
+        }
+        Ok(())
+    })
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! In-memory cache of the contents of small files.
+//!
+//! Entries are validated against the modification time and size of the file
+//! being served, and the least recently used ones are evicted when the cache
+//! is full.
+
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Fraction of the cache capacity a single file may use at most.
+const MAX_FILE_FRACTION: u64 = 8;
+
+struct Entry {
+    modified: SystemTime,
+    data: Bytes,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// Paths by last use, least recent first.
+    uses: BTreeMap<u64, PathBuf>,
+    size: u64,
+    clock: u64,
+}
+
+/// Least recently used file contents, up to a total size.
+pub struct FileCache {
+    capacity: u64,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl FileCache {
+    /// Creates a cache holding up to `capacity` bytes.
+    pub fn new(capacity: u64) -> FileCache {
+        FileCache {
+            capacity,
+            state: Default::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the contents of `file`, found at `path`, reading and caching
+    /// them if needed. Returns `None` if the file is too large to be cached.
+    pub fn get(&self, path: &Path, file: &File, meta: &Metadata)
+        -> io::Result<Option<Bytes>>
+    {
+        let len = meta.len();
+        if len > self.capacity / MAX_FILE_FRACTION {return Ok(None)}
+        let modified = meta.modified()?;
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            state.clock += 1;
+            if let Some(entry) = state.entries.get_mut(path) {
+                if entry.modified == modified && entry.data.len() as u64 == len
+                {
+                    state.uses.remove(&entry.last_use);
+                    state.uses.insert(state.clock, path.to_owned());
+                    entry.last_use = state.clock;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Some(entry.data.clone()))
+                }
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = Bytes::from(read(file, len)?);
+        self.insert(path, modified, data.clone());
+        Ok(Some(data))
+    }
+
+    fn insert(&self, path: &Path, modified: SystemTime, data: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(old) = state.entries.remove(path) {
+            state.uses.remove(&old.last_use);
+            state.size -= old.data.len() as u64;
+        }
+        let len = data.len() as u64;
+        while state.size + len > self.capacity {
+            let (&last_use, _) = match state.uses.iter().next() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            let path = state.uses.remove(&last_use).unwrap();
+            let entry = state.entries.remove(&path).unwrap();
+            state.size -= entry.data.len() as u64;
+        }
+        state.clock += 1;
+        state.uses.insert(state.clock, path.to_owned());
+        state.entries.insert(path.to_owned(), Entry {
+            modified,
+            data,
+            last_use: state.clock,
+        });
+        state.size += len;
+    }
+
+    /// Returns the number of requests served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests for cacheable files that were not
+    /// cached.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Reads the `len` bytes of `file` and resets its position to the start.
+fn read(mut file: &File, len: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut data)?;
+    file.seek(SeekFrom::Start(0))?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+            "File changed while being read"))
+    }
+    Ok(data)
+}
@@ -0,0 +1,90 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Streaming of files in chunks of a configurable size, or as a whole from
+//! a memory mapping.
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, Poll, Stream};
+use std::fs::File;
+use std::io;
+use tokio_io::AsyncRead;
+
+/// Number of chunks sharing each allocated buffer.
+const CHUNKS_PER_BUFFER: usize = 4;
+
+/// Default size of the chunks read from files.
+pub const DEFAULT_CHUNK_SIZE: usize = 128 * 1024;
+
+/// Stream of the contents of a file.
+///
+/// Chunks are split off a buffer large enough for several of them, so that
+/// most reads need no allocation.
+pub struct FileStream<R> {
+    file: R,
+    buf: BytesMut,
+    chunk_size: usize,
+    /// Number of bytes left to read, if the stream ends before the file.
+    remaining: Option<u64>,
+}
+
+impl<R: AsyncRead> FileStream<R> {
+    /// Returns a stream reading `file` in chunks of up to `chunk_size` bytes.
+    pub fn new(file: R, chunk_size: usize) -> FileStream<R> {
+        FileStream {file, buf: BytesMut::new(), chunk_size, remaining: None}
+    }
+
+    /// Ends the stream after `len` bytes.
+    pub fn limit(mut self, len: u64) -> FileStream<R> {
+        self.remaining = Some(len);
+        self
+    }
+}
+
+impl<R: AsyncRead> Stream for FileStream<R> {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        if self.buf.capacity() < self.chunk_size {
+            self.buf = BytesMut::with_capacity(
+                CHUNKS_PER_BUFFER * self.chunk_size);
+        }
+        let len = match self.remaining {
+            Some(0) => return Ok(Async::Ready(None)),
+            Some(remaining) => remaining.min(self.chunk_size as u64) as usize,
+            None => self.chunk_size,
+        };
+        self.buf.resize(len, 0);
+        let n = match self.file.poll_read(&mut self.buf) {
+            Ok(Async::Ready(n)) => n,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        };
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= n as u64;
+        }
+        let chunk = self.buf.split_to(n).freeze();
+        self.buf.clear();
+        if n == 0 {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(chunk)))
+        }
+    }
+}
+
+/// Returns the `len` bytes of `file` read through a memory mapping, blocking
+/// until they are read from the disk.
+///
+/// The file must not be truncated while it is being copied, which would
+/// raise `SIGBUS` on Unix systems.
+pub fn map(file: &File, len: u64) -> io::Result<Bytes> {
+    // SAFETY: The mapping is only read while copying it, and callers opt in
+    // to the risk of the file being truncated meanwhile.
+    let map = unsafe {memmap2::Mmap::map(file)?};
+    if map.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+            "File changed while being read"))
+    }
+    Ok(Bytes::from(&map[..]))
+}
@@ -0,0 +1,85 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Client address resolution behind trusted reverse proxies.
+
+use crate::cidr::{self, Cidr};
+use http::HeaderMap;
+use std::net::IpAddr;
+
+/// Returns the address of the client that originated a request received
+/// from `peer`.
+///
+/// Forwarding headers are only honored when `peer` is a trusted proxy. The
+/// chain of forwarded addresses is then walked from the closest hop, and
+/// the first address that is not a trusted proxy is the client.
+pub fn client_ip(trusted: &[Cidr], peer: IpAddr, headers: &HeaderMap)
+    -> IpAddr
+{
+    let is_trusted = |ip| trusted.iter().any(|net| net.contains(ip));
+    let peer = cidr::canonical(peer);
+    if !is_trusted(peer) {return peer}
+    let chain = forwarded_chain(headers);
+    let mut client = peer;
+    for hop in chain.iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = *ip;
+                if !is_trusted(client) {break}
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+/// Returns the forwarded addresses, from the original client to the closest
+/// proxy. Unusable entries (e.g. obfuscated identifiers) are `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>();
+    let forwarded = values(http::header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded.into_iter()
+            .map(|element| {
+                element.split(';')
+                    .filter_map(|pair| {
+                        let i = pair.find('=')?;
+                        let name = pair[..i].trim();
+                        if name.eq_ignore_ascii_case("for") {
+                            Some(pair[i + 1..].trim())
+                        } else {
+                            None
+                        }
+                    })
+                    .next()
+                    .and_then(parse_node)
+            })
+            .collect()
+    }
+    let forwarded_for = values(http::header::HeaderName::from_static(
+        "x-forwarded-for"));
+    if !forwarded_for.is_empty() {
+        return forwarded_for.into_iter().map(parse_node).collect()
+    }
+    values(http::header::HeaderName::from_static("x-real-ip")).into_iter()
+        .take(1)
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses a node identifier, optionally quoted and with a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    let ip = if node.starts_with('[') {
+        &node[1..node.find(']')?]
+    } else if node.matches(':').count() == 1 {
+        &node[..node.find(':')?]
+    } else {
+        node
+    };
+    ip.parse().ok().map(cidr::canonical)
+}
@@ -0,0 +1,458 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! JSON API to manage the files of the served directory.
+//!
+//! Operations are `POST` requests to `/_api/fs/<operation>` with a JSON
+//! body. Paths are relative to the served directory:
+//!
+//! - `mkdir`: `{"path": "a/b"}` creates a directory in an existing one.
+//! - `move` (or `rename`): `{"from": "a", "to": "b", "overwrite": false}`.
+//! - `copy`: `{"from": "a", "to": "b", "overwrite": false}` copies a file or
+//!   a directory tree, leaving out symbolic links.
+//! - `delete`: `{"path": "a", "recursive": false}`.
+//!
+//! With a trash directory, the files discarded from the served directory are
+//! listed by `GET /_api/trash`, and `POST /_api/trash/restore` with
+//! `{"id": "..."}` puts one back where it was, unless something else took
+//! its place.
+//!
+//! Responses are JSON objects, with an `error` message on failure. Paths are
+//! checked like those of `PUT` and `DELETE` requests, and an existing
+//! directory is never overwritten. Symbolic links cannot be moved or copied,
+//! and each file moved or copied, including those of directory trees, must
+//! pass the upload filter under its new name. Operations run on the blocking
+//! pool.
+
+use crate::audit::Actor;
+use crate::jwt::Claims;
+use crate::problem;
+use crate::trash::Trash;
+use crate::vhost::Site;
+use crate::{disk_usage, sniff, upload, upload_filter, Config, ServerFuture};
+use futures::{future, Future, Stream};
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Path under which operations are requested.
+pub const ENDPOINT: &str = "/_api/fs/";
+
+/// Path of the list of discarded files, under which they are restored.
+pub const TRASH_ENDPOINT: &str = "/_api/trash";
+
+/// Prefix of the paths of all operations.
+const PREFIX: &str = "/_api/";
+
+/// Maximum size of request bodies.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MakeDir {
+    path: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Transfer {
+    from: String,
+    to: String,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Remove {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Restore {
+    id: String,
+}
+
+/// Failed operation, with whether its message is internal.
+struct ApiError(StatusCode, String, bool);
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> ApiError {
+        let status = match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            io::ErrorKind::AlreadyExists
+                | io::ErrorKind::DirectoryNotEmpty => StatusCode::CONFLICT,
+            io::ErrorKind::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let message = e.to_string();
+        problem::log(status, &message);
+        ApiError(status, message, true)
+    }
+}
+
+fn error<T>(status: StatusCode, message: &str) -> Result<T, ApiError> {
+    Err(ApiError(status, message.to_owned(), false))
+}
+
+/// Client and directory an operation applies to.
+struct Context<'a> {
+    config: &'a Config,
+    site: &'a Site,
+    client: IpAddr,
+    authenticated: bool,
+    claims: Option<Claims>,
+    actor: Actor,
+}
+
+impl Context<'_> {
+    /// Returns the resource designated by `path`, relative to the served
+    /// directory, and the path at which to change it.
+    fn resolve(&self, path: &str) -> Result<(PathBuf, PathBuf), ApiError> {
+        let req_path = Path::new("/").join(path);
+        let resource = match crate::sanitize_path(&req_path) {
+            Some(resource) if resource != Path::new("") => resource,
+            _ => return error(StatusCode::BAD_REQUEST, "Invalid path"),
+        };
+        if !upload::may_write(self.config, self.site, resource, self.client,
+            self.authenticated, self.claims.as_ref())
+        {
+            return error(StatusCode::FORBIDDEN, "Forbidden")
+        }
+        match self.site.root_dir.writable_path(resource) {
+            Ok(target) => Ok((resource.to_owned(), target)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                error(StatusCode::CONFLICT, "Parent directory not found"),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn change(&self, action: &'static str, resource: &Path)
+        -> upload::Change
+    {
+        upload::Change::new(action, resource, &self.actor)
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<(), ApiError> {
+        match &self.config.upload_quota {
+            Some(quota) if !quota.reserve(&self.site.root, bytes) =>
+                Err(io::Error::from(io::ErrorKind::QuotaExceeded).into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        if let Some(quota) = &self.config.upload_quota {
+            quota.release(&self.site.root, bytes);
+        }
+    }
+
+    /// Checks that the upload filter accepts the file `from` stored at `to`,
+    /// or each file of the tree `from` if `is_dir` is true.
+    fn check_filter(&self, from: &Path, to: &Path, is_dir: bool)
+        -> Result<(), ApiError>
+    {
+        match &self.config.upload_filter {
+            Some(filter) if is_dir => check_tree(filter, from, to),
+            Some(filter) => check_file(filter, from, to),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Checks that `filter` accepts each file of the tree `from` stored below
+/// `to`. Symbolic links are left out, as they are not copied.
+fn check_tree(filter: &upload_filter::Filter, from: &Path, to: &Path)
+    -> Result<(), ApiError>
+{
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            check_tree(filter, &entry.path(), &target)?;
+        } else if file_type.is_file() {
+            check_file(filter, &entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `filter` accepts the name of `to` and the content of `from`.
+fn check_file(filter: &upload_filter::Filter, from: &Path, to: &Path)
+    -> Result<(), ApiError>
+{
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
+    File::open(from)?.take(sniff::SNIFF_LEN as u64).read_to_end(&mut head)?;
+    if !filter.accepts_name(&name) || !filter.accepts_content(&name, &head) {
+        return error(StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "File name or type not allowed")
+    }
+    Ok(())
+}
+
+/// Returns whether `path` is that of the trash or of one of its operations.
+pub fn is_trash_endpoint(path: &str) -> bool {
+    path.strip_prefix(TRASH_ENDPOINT)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Answers an operation request.
+pub fn handle(config: &Arc<Config>, client: IpAddr,
+    authenticated: bool, request: Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    if let Some(res) = upload::check_writer(config, authenticated, &request) {
+        return res
+    }
+    let operation = request.uri().path()[PREFIX.len()..].to_owned();
+    let method_allowed = match operation.as_str() {
+        "trash" => matches!(*request.method(), Method::GET | Method::HEAD),
+        _ => request.method() == Method::POST,
+    };
+    if !method_allowed {
+        return crate::method_not_allowed(config)
+    }
+    let (head, body) = request.into_parts();
+    let body = body.map_err(io::Error::other)
+        .fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > MAX_BODY_LEN {
+                return Err(io::Error::from(io::ErrorKind::FileTooLarge))
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+    let config = config.clone();
+    let res = body.then(move |body| crate::blocking(move || {
+        let request = Request::from_parts(head, Body::empty());
+        let site = config.sites.select(&request);
+        let actor = Actor::of(&request, client);
+        let context = Context {
+            config: &config,
+            site,
+            client,
+            authenticated,
+            claims: request.extensions().get().cloned(),
+            actor,
+        };
+        let res = match body {
+            Ok(body) => run(&context, &operation, &body),
+            Err(ref e) if e.kind() == io::ErrorKind::FileTooLarge =>
+                error(StatusCode::PAYLOAD_TOO_LARGE, "Request too large"),
+            Err(e) => Err(e.into()),
+        };
+        let (status, value) = match res {
+            Ok(res) => res,
+            Err(ApiError(status, _, true)) if !config.verbose_errors => {
+                let message = status.canonical_reason().unwrap_or("Error");
+                (status, json!({"error": message}))
+            }
+            Err(ApiError(status, message, _)) =>
+                (status, json!({"error": message})),
+        };
+        Response::builder().status(status)
+            .header(http::header::CONTENT_TYPE,
+                mime::APPLICATION_JSON.to_string())
+            .body(value.to_string().into())
+    }));
+    Box::new(res.then(|res| match res {
+        Ok(res) => Box::new(future::result(res)),
+        Err(e) => crate::io_error(e),
+    }))
+}
+
+fn run(context: &Context, operation: &str, body: &[u8])
+    -> Result<(StatusCode, serde_json::Value), ApiError>
+{
+    let invalid = |e: serde_json::Error|
+        ApiError(StatusCode::BAD_REQUEST, e.to_string(), false);
+    match operation {
+        "fs/mkdir" => {
+            let req: MakeDir = serde_json::from_slice(body).map_err(invalid)?;
+            let (resource, target) = context.resolve(&req.path)?;
+            fs::create_dir(target)?;
+            upload::report(context.config, context.change("mkdir", &resource));
+            Ok((StatusCode::CREATED, json!({"path": req.path})))
+        }
+        "fs/move" | "fs/rename" => {
+            let req: Transfer = serde_json::from_slice(body)
+                .map_err(invalid)?;
+            transfer(context, &req, false)?;
+            Ok((StatusCode::OK, json!({"from": req.from, "to": req.to})))
+        }
+        "fs/copy" => {
+            let req: Transfer = serde_json::from_slice(body)
+                .map_err(invalid)?;
+            transfer(context, &req, true)?;
+            Ok((StatusCode::CREATED, json!({"from": req.from, "to": req.to})))
+        }
+        "fs/delete" => {
+            let req: Remove = serde_json::from_slice(body).map_err(invalid)?;
+            remove(context, &req)?;
+            Ok((StatusCode::OK, json!({"path": req.path})))
+        }
+        "trash" => list_trash(context, trash(context)?),
+        "trash/restore" => {
+            let req: Restore = serde_json::from_slice(body).map_err(invalid)?;
+            let path = restore(context, trash(context)?, &req.id)?;
+            Ok((StatusCode::OK, json!({"id": req.id, "path": path})))
+        }
+        _ => error(StatusCode::NOT_FOUND, "Unknown operation"),
+    }
+}
+
+/// Moves or copies a file or directory.
+fn transfer(context: &Context, req: &Transfer, copy: bool)
+    -> Result<(), ApiError>
+{
+    let (source_resource, from) = context.resolve(&req.from)?;
+    let (resource, to) = context.resolve(&req.to)?;
+    let source = fs::symlink_metadata(&from)?;
+    if source.file_type().is_symlink() {
+        return error(StatusCode::FORBIDDEN, "Source is a symbolic link")
+    }
+    if to.starts_with(&from) {
+        return error(StatusCode::CONFLICT,
+            "Destination is inside the source")
+    }
+    let replaced = match fs::symlink_metadata(&to) {
+        Ok(meta) if meta.is_dir() =>
+            return error(StatusCode::CONFLICT, "Destination is a directory"),
+        Ok(_) if !req.overwrite =>
+            return error(StatusCode::CONFLICT, "Destination exists"),
+        Ok(meta) => Some(if meta.is_file() {meta.len()} else {0}),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    context.check_filter(&from, &to, source.is_dir())?;
+    let action = if copy {"copy"} else {"move"};
+    let change = context.change(action, &resource)
+        .with_source(&source_resource);
+    let set_aside = |context: &Context| match replaced {
+        Some(replaced) => upload::set_aside(context.config, &context.site.root,
+            &resource, &to, replaced),
+        None => Ok(()),
+    };
+    if !copy {
+        set_aside(context)?;
+        fs::rename(&from, &to)?;
+        context.release(replaced.unwrap_or(0));
+        upload::report(context.config, change);
+        return Ok(())
+    }
+    let len = if source.is_dir() {disk_usage::total(&from)} else {source.len()};
+    context.reserve(len)?;
+    let temp = upload::temp_path(&to);
+    let copied = if source.is_dir() {
+        copy_dir(&from, &temp)
+    } else {
+        fs::copy(&from, &temp).map(|_| ())
+    };
+    let copied = copied
+        .and_then(|()| set_aside(context))
+        .and_then(|()| fs::rename(&temp, &to));
+    match copied {
+        Ok(()) => {
+            context.release(replaced.unwrap_or(0));
+            upload::report(context.config, change.with_size(len));
+            Ok(())
+        }
+        Err(e) => {
+            let _ = if source.is_dir() {
+                fs::remove_dir_all(&temp)
+            } else {
+                fs::remove_file(&temp)
+            };
+            context.release(len);
+            Err(e.into())
+        }
+    }
+}
+
+/// Copies the directories and files of the tree `from` to `to`.
+pub fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file or directory.
+fn remove(context: &Context, req: &Remove) -> Result<(), ApiError> {
+    let (resource, target) = context.resolve(&req.path)?;
+    let len = upload::remove(context.config, &context.site.root, &resource,
+        &target, req.recursive)?;
+    upload::report(context.config,
+        context.change("delete", &resource).with_size(len));
+    Ok(())
+}
+
+fn trash<'a>(context: &Context<'a>) -> Result<&'a Trash, ApiError> {
+    match &context.config.trash {
+        Some(trash) => Ok(trash),
+        None => error(StatusCode::NOT_FOUND, "Unknown operation"),
+    }
+}
+
+/// Lists the items discarded from the served directory that the client may
+/// restore.
+fn list_trash(context: &Context, trash: &Trash)
+    -> Result<(StatusCode, serde_json::Value), ApiError>
+{
+    let items = trash.list(&context.site.root)?.into_iter()
+        .filter(|(_, info)| {
+            let resource = Path::new(info.path.trim_start_matches('/'));
+            upload::may_write(context.config, context.site, resource,
+                context.client, context.authenticated,
+                context.claims.as_ref())
+        })
+        .map(|(id, info)| json!({
+            "id": id,
+            "path": info.path,
+            "discarded": info.discarded,
+            "reason": info.reason,
+            "size": info.size,
+            "directory": info.directory,
+        }))
+        .collect::<Vec<_>>();
+    Ok((StatusCode::OK, json!({"items": items})))
+}
+
+/// Puts the discarded item `id` back where it was and returns its path.
+fn restore(context: &Context, trash: &Trash, id: &str)
+    -> Result<String, ApiError>
+{
+    let info = trash.item(&context.site.root, id)?;
+    let (resource, target) = context.resolve(&info.path)?;
+    match fs::symlink_metadata(&target) {
+        Ok(_) => return error(StatusCode::CONFLICT, "Destination exists"),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    context.reserve(info.size)?;
+    if let Err(e) = trash.restore(id, &target, info.directory) {
+        context.release(info.size);
+        return Err(e.into())
+    }
+    upload::report(context.config,
+        context.change("restore", &resource).with_size(info.size));
+    Ok(info.path)
+}
@@ -0,0 +1,188 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Client connections to `http://` and `https://` URLs.
+//!
+//! Servers reached over TLS are authenticated with the certificate
+//! authorities trusted by the system, read from the file named by the
+//! `SSL_CERT_FILE` environment variable or from the usual location of the
+//! system bundle. The bundle is read by the first client created, which must
+//! happen before the process is confined to the served directories. Threads
+//! resolving host names are only started by the first connection, so clients
+//! may be created before the server is detached.
+
+use futures::{future, Future, Poll};
+use http::Uri;
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::client::HttpConnector;
+use hyper::Client;
+use rustls::ClientConfig;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, TlsConnector};
+use tracing::warn;
+
+/// Usual locations of the bundle of trusted certificate authorities.
+const CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+    "/usr/local/etc/ssl/cert.pem",
+];
+
+/// Number of threads resolving host names for each client.
+const DNS_THREADS: usize = 4;
+
+/// Returns a client for `http://` and `https://` URLs.
+pub fn client() -> Client<Connector> {
+    Client::builder().build(Connector::new())
+}
+
+/// Returns whether responses from `uri` cannot be tampered with on the way,
+/// i.e. it is an `https://` URL or an `http://` URL of the local host.
+pub fn is_secure(uri: &Uri) -> bool {
+    match uri.scheme_part().map(|s| s.as_str()) {
+        Some("https") => true,
+        Some("http") => uri.host().is_some_and(|host| {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            host.eq_ignore_ascii_case("localhost")
+                || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+        }),
+        _ => false,
+    }
+}
+
+/// Connects to servers, over TLS for `https://` URLs.
+#[derive(Clone)]
+pub struct Connector {
+    /// Created on first use, as it starts threads.
+    http: Arc<OnceLock<HttpConnector>>,
+    /// `None` if no certificate authority could be read.
+    tls: Option<TlsConnector>,
+}
+
+impl Connector {
+    fn new() -> Connector {
+        let tls = tls_config().clone().map(TlsConnector::from);
+        Connector {http: Default::default(), tls}
+    }
+
+    fn http(&self) -> &HttpConnector {
+        self.http.get_or_init(|| {
+            let mut http = HttpConnector::new(DNS_THREADS);
+            http.enforce_http(false);
+            http
+        })
+    }
+}
+
+impl Connect for Connector {
+    type Transport = Connection;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (Connection, Connected),
+        Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let tls = match dst.scheme() {
+            "https" => match &self.tls {
+                Some(tls) => Some(tls.clone()),
+                None => return Box::new(future::err(io::Error::other(
+                    "No trusted certificate authorities"))),
+            },
+            _ => None,
+        };
+        let host = dst.host().to_owned();
+        let connecting = self.http().connect(dst);
+        let res = connecting.and_then(move |(tcp, connected)| {
+            let tls = match tls {
+                Some(tls) => tls,
+                None => return future::Either::A(future::ok(
+                    (Connection::Plain(tcp), connected))),
+            };
+            let name = match webpki::DNSNameRef::try_from_ascii_str(&host) {
+                Ok(name) => name,
+                Err(_) => return future::Either::A(future::err(
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                        "Invalid host name for TLS"))),
+            };
+            future::Either::B(tls.connect(name, tcp).map(move |stream| {
+                (Connection::Tls(Box::new(stream)), connected)
+            }))
+        });
+        Box::new(res)
+    }
+}
+
+/// Connection to a server.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Connection::Plain(stream) => AsyncWrite::shutdown(stream),
+            Connection::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+/// Returns the TLS configuration of clients, reading the trusted certificate
+/// authorities the first time.
+fn tls_config() -> &'static Option<Arc<ClientConfig>> {
+    static CONFIG: OnceLock<Option<Arc<ClientConfig>>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let mut config = ClientConfig::new();
+        let bundles = std::env::var("SSL_CERT_FILE").ok().into_iter()
+            .chain(CA_BUNDLES.iter().map(|&path| path.to_owned()));
+        for path in bundles {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((added, _)) if added > 0 => break,
+                _ => warn!("No certificate authority found in {}", path),
+            }
+        }
+        if config.root_store.is_empty() {
+            warn!("No trusted certificate authorities, https:// URLs cannot \
+                be reached");
+            return None
+        }
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+        Some(Arc::new(config))
+    })
+}
@@ -0,0 +1,203 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Messages of generated pages in several languages.
+//!
+//! English, French, German and Spanish are built in. A catalog file adds or
+//! replaces languages, with a table of messages per lowercase language tag:
+//!
+//! ```toml
+//! [pt-br]
+//! filename = "Nome"
+//! size = "Tamanho"
+//! item = "{} item"
+//! items = "{} itens"
+//! ```
+//!
+//! `{}` stands for a number of items or for a search query. Messages missing
+//! from a language are shown in English. See `data/lang` for the names of
+//! all messages.
+
+use crate::language;
+use http::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Built-in catalogs besides English.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("de", include_str!("../data/lang/de.toml")),
+    ("es", include_str!("../data/lang/es.toml")),
+    ("fr", include_str!("../data/lang/fr.toml")),
+];
+
+/// Messages in a language.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Messages {
+    #[serde(skip)]
+    tag: String,
+    pub directory_contents: String,
+    pub contents_of: String,
+    pub filename: String,
+    pub size: String,
+    pub permissions: String,
+    pub owner: String,
+    pub filter: String,
+    pub show_sizes: String,
+    pub search: String,
+    pub search_contents: String,
+    pub download: String,
+    pub previous: String,
+    pub next: String,
+    pub item: String,
+    pub items: String,
+    pub search_results: String,
+    pub results_for: String,
+    pub no_matches: String,
+    pub search_timed_out: String,
+    pub search_truncated: String,
+}
+
+impl Default for Messages {
+    fn default() -> Messages {
+        Messages {
+            tag: "en".into(),
+            directory_contents: "Directory contents".into(),
+            contents_of: "Contents of ".into(),
+            filename: "Filename".into(),
+            size: "Size".into(),
+            permissions: "Permissions".into(),
+            owner: "Owner".into(),
+            filter: "Filter this page".into(),
+            show_sizes: "Show directory sizes".into(),
+            search: "Search".into(),
+            search_contents: " Search contents".into(),
+            download: "Download".into(),
+            previous: "Previous".into(),
+            next: "Next".into(),
+            item: "{} item".into(),
+            items: "{} items".into(),
+            search_results: "Search results".into(),
+            results_for: "Results for \"{}\" in ".into(),
+            no_matches: "No matching files.".into(),
+            search_timed_out:
+                "Search timed out; results may be incomplete.".into(),
+            search_truncated:
+                "Too many matches; only the first results are shown.".into(),
+        }
+    }
+}
+
+impl Messages {
+    /// Returns the lowercase tag of the language of the messages.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the text giving a number of items.
+    pub fn count(&self, items: usize) -> String {
+        let message = if items == 1 {&self.item} else {&self.items};
+        message.replace("{}", &items.to_string())
+    }
+
+    /// Returns the title of the results of searching for `query`.
+    pub fn results_for(&self, query: &str) -> String {
+        self.results_for.replace("{}", query)
+    }
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Read(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CatalogError::Read(_) => f.write_str("Failed to read file"),
+            CatalogError::Parse(_) => f.write_str("Failed to parse file"),
+        }
+    }
+}
+
+impl Error for CatalogError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CatalogError::Read(e) => Some(e),
+            CatalogError::Parse(e) => Some(e),
+        }
+    }
+}
+
+/// Messages by language.
+#[derive(Debug)]
+pub struct Catalog {
+    languages: HashMap<String, Arc<Messages>>,
+    /// Language of all pages, if it does not depend on requests.
+    fixed: Option<Arc<Messages>>,
+}
+
+impl Default for Catalog {
+    fn default() -> Catalog {
+        let mut languages = HashMap::new();
+        languages.insert("en".to_owned(), Arc::new(Messages::default()));
+        let mut catalog = Catalog {languages, fixed: None};
+        for (tag, source) in BUILT_IN {
+            let messages = toml::from_str(source)
+                .expect("Built-in catalogs are valid");
+            catalog.insert(tag, messages);
+        }
+        catalog
+    }
+}
+
+impl Catalog {
+    /// Adds the languages of a catalog file, replacing the built-in messages
+    /// of the languages it defines.
+    pub fn load(&mut self, path: &Path) -> Result<(), CatalogError> {
+        let contents = fs::read_to_string(path).map_err(CatalogError::Read)?;
+        let languages: HashMap<String, Messages> = toml::from_str(&contents)
+            .map_err(CatalogError::Parse)?;
+        for (tag, messages) in languages {
+            self.insert(&tag, messages);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, tag: &str, mut messages: Messages) {
+        let tag = tag.to_ascii_lowercase();
+        messages.tag = tag.clone();
+        self.languages.insert(tag, Arc::new(messages));
+    }
+
+    /// Shows all pages in the language `tag`. Returns false if the language
+    /// is unknown.
+    pub fn fix(&mut self, tag: &str) -> bool {
+        self.fixed = self.languages.get(&tag.to_ascii_lowercase()).cloned();
+        self.fixed.is_some()
+    }
+
+    /// Returns whether the language depends on the `Accept-Language` header
+    /// of requests.
+    pub fn negotiates(&self) -> bool {
+        self.fixed.is_none()
+    }
+
+    /// Returns the messages in the language of the page answering a request
+    /// with `headers`, defaulting to English.
+    pub fn select(&self, headers: &HeaderMap) -> Arc<Messages> {
+        if let Some(messages) = &self.fixed {
+            return messages.clone()
+        }
+        language::candidates(headers)
+            .find_map(|tag| self.languages.get(&tag))
+            .unwrap_or(&self.languages["en"])
+            .clone()
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Icons of directory entries, drawn with inline SVG.
+
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+/// Kind of directory entry, as shown by its icon.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Directory,
+    Symlink,
+    Image,
+    Audio,
+    Video,
+    Archive,
+    Code,
+    Text,
+    File,
+}
+
+impl Kind {
+    /// Returns the kind of the entry named `name`, guessing the kind of
+    /// files from their extension.
+    pub fn of(name: &Path, is_dir: bool, is_symlink: bool) -> Kind {
+        if is_symlink {
+            return Kind::Symlink
+        }
+        if is_dir {
+            return Kind::Directory
+        }
+        let extension = match name.extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension.to_ascii_lowercase(),
+            None => return Kind::File,
+        };
+        match extension.as_str() {
+            "avif" | "bmp" | "gif" | "ico" | "jpeg" | "jpg" | "png" | "svg"
+                | "tif" | "tiff" | "webp" => Kind::Image,
+            "aac" | "flac" | "m4a" | "mp3" | "ogg" | "opus" | "wav" =>
+                Kind::Audio,
+            "avi" | "m4v" | "mkv" | "mov" | "mp4" | "webm" => Kind::Video,
+            "7z" | "bz2" | "gz" | "jar" | "rar" | "tar" | "tgz" | "xz"
+                | "zip" | "zst" => Kind::Archive,
+            "c" | "cpp" | "cs" | "css" | "go" | "h" | "hpp" | "htm" | "html"
+                | "java" | "js" | "json" | "kt" | "py" | "rb" | "rs" | "sh"
+                | "swift" | "toml" | "ts" | "xml" | "yaml" | "yml" =>
+                Kind::Code,
+            "csv" | "log" | "md" | "pdf" | "rst" | "txt" => Kind::Text,
+            _ => Kind::File,
+        }
+    }
+
+    /// Returns the SVG path data drawing the icon, in a 16x16 box.
+    fn paths(self) -> &'static [&'static str] {
+        match self {
+            Kind::Directory => &["M1.5 3.5h5l1.5 2h6.5v7.5h-13z"],
+            Kind::Symlink =>
+                &["M3 13V9a4 4 0 0 1 4-4h6", "M10 2l3 3-3 3"],
+            Kind::Image => &[
+                "M1.5 2.5h13v11h-13z",
+                "M1.5 11.5l4-4 3 3 2-2 4 4",
+                "M10.5 5.5h.01",
+            ],
+            Kind::Audio =>
+                &["M6 12V3l7-1.5v9", "M6 12a2 2 0 1 1-4 0 2 2 0 0 1 4 0z",
+                    "M13 10.5a2 2 0 1 1-4 0 2 2 0 0 1 4 0z"],
+            Kind::Video => &["M1.5 3.5h13v9h-13z", "M6.5 5.5v5l4-2.5z"],
+            Kind::Archive => &[
+                "M3.5 1.5h9v13h-9z",
+                "M8 1.5v2M8 5v2M8 8.5v2",
+            ],
+            Kind::Code => &["M5 4L1 8l4 4", "M11 4l4 4-4 4", "M9.5 2l-3 12"],
+            Kind::Text => &[
+                "M3.5 1.5h6l3 3v10h-9z",
+                "M5.5 7.5h5M5.5 10h5M5.5 12.5h3",
+            ],
+            Kind::File => &["M3.5 1.5h6l3 3v10h-9z", "M9.5 1.5v3h3"],
+        }
+    }
+}
+
+/// Writes the icon of an entry of `kind`.
+pub fn write<W: Write>(kind: Kind, out: &mut xml::EventWriter<W>)
+    -> Result<(), xml::writer::Error>
+{
+    nestxml::element(out, "svg")
+        .attr("class", "icon")
+        .attr("width", "16")
+        .attr("height", "16")
+        .attr("viewBox", "0 0 16 16")
+        .attr("fill", "none")
+        .attr("stroke", "currentColor")
+        .attr("stroke-linecap", "round")
+        .attr("stroke-linejoin", "round")
+        .attr("aria-hidden", "true")
+        .write(|out| {
+            for path in kind.paths() {
+                nestxml::element(out, "path").attr("d", *path).empty()?;
+            }
+            Ok(())
+        })
+}
@@ -0,0 +1,188 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Inverted index of the contents of text files.
+//!
+//! Files are found and read without leaving the served directory.
+
+use crate::vhost::Site;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Files larger than this are not indexed.
+const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+struct Document {
+    path: PathBuf,
+    modified: SystemTime,
+    term_count: u32,
+    terms: Vec<String>,
+}
+
+/// Index mapping terms to the documents containing them.
+#[derive(Default)]
+pub struct ContentIndex {
+    next_id: u32,
+    documents: HashMap<u32, Document>,
+    ids: HashMap<PathBuf, u32>,
+    postings: HashMap<String, HashMap<u32, u32>>,
+}
+
+impl ContentIndex {
+    /// Builds the index of the files of `site` and keeps it up to date by
+    /// rescanning them every `refresh` period.
+    pub fn spawn(site: Arc<Site>, refresh: Duration)
+        -> Arc<RwLock<ContentIndex>>
+    {
+        let mut index = ContentIndex::default();
+        index.update(&site, indexable_files(&site));
+        let index = Arc::new(RwLock::new(index));
+        let shared = index.clone();
+        thread::spawn(move || loop {
+            thread::sleep(refresh);
+            let files = indexable_files(&site);
+            let mut index = shared.write().unwrap();
+            index.update(&site, files);
+        });
+        index
+    }
+
+    fn update(&mut self, site: &Site, files: Vec<(PathBuf, SystemTime)>) {
+        let mut seen = HashSet::with_capacity(files.len());
+        for (path, modified) in files {
+            let stale = match self.ids.get(&path) {
+                Some(id) => self.documents[id].modified != modified,
+                None => true,
+            };
+            if stale {
+                self.remove(&path);
+                let mut contents = Vec::new();
+                let read = site.root_dir.open_file(&path)
+                    .and_then(|file| {
+                        file.take(MAX_FILE_SIZE).read_to_end(&mut contents)
+                    });
+                if read.is_ok() {
+                    self.insert(path.clone(), modified, &contents);
+                }
+            }
+            seen.insert(path);
+        }
+        let removed = self.ids.keys()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in removed {
+            self.remove(&path);
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, modified: SystemTime, contents: &[u8])
+    {
+        let text = String::from_utf8_lossy(contents);
+        let is_html = path.extension().and_then(|e| e.to_str())
+            .is_some_and(|e| e == "htm" || e == "html");
+        let text = if is_html {strip_tags(&text)} else {text.into_owned()};
+        let mut frequencies = HashMap::<String, u32>::new();
+        let mut term_count = 0;
+        for term in tokenize(&text) {
+            *frequencies.entry(term).or_insert(0) += 1;
+            term_count += 1;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        for (term, frequency) in &frequencies {
+            self.postings.entry(term.clone()).or_default()
+                .insert(id, *frequency);
+        }
+        let terms = frequencies.into_keys().collect();
+        self.ids.insert(path.clone(), id);
+        self.documents.insert(id, Document {path, modified, term_count, terms});
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let doc = match self.ids.remove(path) {
+            Some(id) => self.documents.remove(&id).map(|doc| (id, doc)),
+            None => None,
+        };
+        let (id, doc) = match doc {
+            Some(doc) => doc,
+            None => return,
+        };
+        for term in doc.terms {
+            let now_empty = self.postings.get_mut(&term).is_some_and(|docs| {
+                docs.remove(&id);
+                docs.is_empty()
+            });
+            if now_empty {
+                self.postings.remove(&term);
+            }
+        }
+    }
+
+    /// Returns the documents below `base` matching `query`, best matches
+    /// first.
+    pub fn search(&self, query: &str, base: &Path) -> Vec<PathBuf> {
+        let doc_count = self.documents.len() as f64;
+        let mut scores = HashMap::<u32, f64>::new();
+        for term in tokenize(query) {
+            let docs = match self.postings.get(&term) {
+                Some(docs) => docs,
+                None => continue,
+            };
+            let idf = (1.0 + doc_count / docs.len() as f64).ln();
+            for (id, frequency) in docs {
+                let doc = &self.documents[id];
+                let tf = f64::from(*frequency) / f64::from(doc.term_count);
+                *scores.entry(*id).or_insert(0.0) += tf * idf;
+            }
+        }
+        let mut scores = scores.into_iter()
+            .map(|(id, score)| (&self.documents[&id].path, score))
+            .filter(|(path, _)| path.starts_with(base))
+            .collect::<Vec<_>>();
+        scores.sort_by(|(p1, s1), (p2, s2)| {
+            s2.partial_cmp(s1).unwrap().then_with(|| p1.cmp(p2))
+        });
+        scores.into_iter().map(|(path, _)| path.clone()).collect()
+    }
+}
+
+fn is_indexable(path: &Path) -> bool {
+    matches!(path.extension().and_then(|e| e.to_str()),
+        Some("htm" | "html" | "markdown" | "md" | "rst" | "txt"))
+}
+
+/// Returns the relative paths and modification times of the files to index.
+fn indexable_files(site: &Site) -> Vec<(PathBuf, SystemTime)> {
+    site.root_dir.walk(Path::new(""))
+        .filter(|entry| entry.meta.is_file()
+            && entry.meta.len() <= MAX_FILE_SIZE && is_indexable(&entry.path))
+        .filter_map(|entry| Some((entry.path, entry.meta.modified().ok()?)))
+        .collect()
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
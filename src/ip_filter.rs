@@ -0,0 +1,40 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Client filtering by IP network.
+
+use crate::cidr::Cidr;
+use std::net::IpAddr;
+
+/// Rule allowing or denying clients from a network.
+#[derive(Clone, Copy, Debug)]
+pub enum Rule {
+    Allow(Cidr),
+    Deny(Cidr),
+}
+
+/// Ordered list of rules. The first rule matching a client decides. Clients
+/// matching no rule are allowed unless the list contains allow rules.
+#[derive(Debug)]
+pub struct IpFilter {
+    rules: Vec<Rule>,
+    default_allow: bool,
+}
+
+impl IpFilter {
+    pub fn new(rules: Vec<Rule>) -> IpFilter {
+        let default_allow = !rules.iter()
+            .any(|rule| matches!(rule, Rule::Allow(_)));
+        IpFilter {rules, default_allow}
+    }
+
+    /// Returns whether requests from `ip` are accepted.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.rules.iter()
+            .find_map(|rule| match rule {
+                Rule::Allow(net) if net.contains(ip) => Some(true),
+                Rule::Deny(net) if net.contains(ip) => Some(false),
+                _ => None,
+            })
+            .unwrap_or(self.default_allow)
+    }
+}
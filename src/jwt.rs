@@ -0,0 +1,364 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Validation of JSON Web Tokens sent as bearer tokens.
+//!
+//! Tokens must be signed with one of the keys of a JSON Web Key Set, read
+//! from an `https://` URL, an `http://` URL of the local host or a file,
+//! using RS256, RS384, RS512, PS256, PS384, PS512, ES256, ES384 or EdDSA.
+//! The set is read again periodically, and when a token names a key it
+//! lacks, as after the identity provider rotated its keys, from a
+//! background thread started once the server is detached.
+//!
+//! Tokens must not be expired, and must come from the configured issuer and
+//! be intended for the configured audience, if any. Their `sub` claim names
+//! the user.
+
+use base64::Engine;
+use futures::{Future, Stream};
+use http::Uri;
+use crate::https;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Claims of a token.
+pub type Claims = Map<String, Value>;
+
+/// Delay between periodic reads of the key set.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Minimum delay between reads of the key set.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum time to fetch the key set.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of seconds clocks of servers may differ by.
+const LEEWAY: u64 = 60;
+
+#[derive(Debug)]
+pub enum JwtError {
+    InvalidUrl,
+    Fetch(String),
+    InvalidKeySet(serde_json::Error),
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JwtError::InvalidUrl => f.write_str("The key set must be read \
+                from an https:// URL, an http:// URL of the local host or a \
+                file"),
+            JwtError::Fetch(e) => write!(f, "Failed to read key set: {}", e),
+            JwtError::InvalidKeySet(_) => f.write_str("Invalid key set"),
+        }
+    }
+}
+
+impl Error for JwtError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JwtError::InvalidKeySet(e) => Some(e),
+            JwtError::InvalidUrl | JwtError::Fetch(_) => None,
+        }
+    }
+}
+
+/// Location of a key set.
+#[derive(Clone, Debug)]
+enum Source {
+    Url(Uri),
+    File(String),
+}
+
+#[derive(Deserialize)]
+struct KeySet {
+    keys: Vec<KeySpec>,
+}
+
+#[derive(Deserialize)]
+struct KeySpec {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Debug)]
+enum PublicKey {
+    Rsa {n: Vec<u8>, e: Vec<u8>},
+    /// Uncompressed P-256 or P-384 point.
+    Ec {curve: &'static str, point: Vec<u8>},
+    Ed25519(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Key {
+    id: Option<String>,
+    key: PublicKey,
+}
+
+impl Key {
+    fn parse(spec: KeySpec) -> Option<Key> {
+        if spec.usage.as_ref().is_some_and(|usage| usage != "sig") {
+            return None
+        }
+        let field = |value: &Option<String>| decode(value.as_ref()?);
+        let key = match (spec.kty.as_str(), spec.crv.as_deref()) {
+            ("RSA", _) => PublicKey::Rsa {
+                n: field(&spec.n)?,
+                e: field(&spec.e)?,
+            },
+            ("EC", Some(curve @ "P-256")) | ("EC", Some(curve @ "P-384")) => {
+                let mut point = vec![4];
+                point.extend(field(&spec.x)?);
+                point.extend(field(&spec.y)?);
+                let curve = if curve == "P-256" {"P-256"} else {"P-384"};
+                PublicKey::Ec {curve, point}
+            }
+            ("OKP", Some("Ed25519")) => PublicKey::Ed25519(field(&spec.x)?),
+            _ => return None,
+        };
+        Some(Key {id: spec.kid, key})
+    }
+
+    /// Returns whether `signature` of `message` was made with this key using
+    /// the JWS algorithm `algorithm`.
+    fn verify(&self, algorithm: &str, message: &[u8], signature: &[u8])
+        -> bool
+    {
+        let rsa = |params| match &self.key {
+            PublicKey::Rsa {n, e} => RsaPublicKeyComponents {n, e}
+                .verify(params, message, signature).is_ok(),
+            _ => false,
+        };
+        let unparsed = |algorithm, key: &[u8]| {
+            UnparsedPublicKey::new(algorithm, key)
+                .verify(message, signature).is_ok()
+        };
+        match (algorithm, &self.key) {
+            ("RS256", _) => rsa(&signature::RSA_PKCS1_2048_8192_SHA256),
+            ("RS384", _) => rsa(&signature::RSA_PKCS1_2048_8192_SHA384),
+            ("RS512", _) => rsa(&signature::RSA_PKCS1_2048_8192_SHA512),
+            ("PS256", _) => rsa(&signature::RSA_PSS_2048_8192_SHA256),
+            ("PS384", _) => rsa(&signature::RSA_PSS_2048_8192_SHA384),
+            ("PS512", _) => rsa(&signature::RSA_PSS_2048_8192_SHA512),
+            ("ES256", PublicKey::Ec {curve: "P-256", point}) =>
+                unparsed(&signature::ECDSA_P256_SHA256_FIXED, point),
+            ("ES384", PublicKey::Ec {curve: "P-384", point}) =>
+                unparsed(&signature::ECDSA_P384_SHA384_FIXED, point),
+            ("EdDSA", PublicKey::Ed25519(key)) =>
+                unparsed(&signature::ED25519, key),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Checks tokens against a key set and the expected claims.
+pub struct Validator {
+    issuer: Option<String>,
+    audience: Option<String>,
+    keys: Arc<RwLock<Vec<Key>>>,
+    /// Requests to read the key set again.
+    refresh: mpsc::SyncSender<()>,
+    /// Work of the thread keeping the key set up to date, until started.
+    updater: Mutex<Option<Updater>>,
+}
+
+impl Validator {
+    /// Reads the key set at `url`, a URL or file path.
+    pub fn new(url: &str, issuer: Option<String>, audience: Option<String>)
+        -> Result<Validator, JwtError>
+    {
+        let source = if url.contains("://") {
+            let uri = url.parse::<Uri>().map_err(|_| JwtError::InvalidUrl)?;
+            if !https::is_secure(&uri) {
+                return Err(JwtError::InvalidUrl)
+            }
+            Source::Url(uri)
+        } else {
+            Source::File(url.to_owned())
+        };
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .map_err(|e| JwtError::Fetch(e.to_string()))?;
+        let keys = Arc::new(RwLock::new(fetch(&source, &mut runtime)?));
+        let (refresh, requests) = mpsc::sync_channel(1);
+        let updater = Updater {
+            source,
+            keys: keys.clone(),
+            requests,
+            loaded: Instant::now(),
+        };
+        Ok(Validator {
+            issuer,
+            audience,
+            keys,
+            refresh,
+            updater: Mutex::new(Some(updater)),
+        })
+    }
+
+    /// Keeps the key set up to date from a background thread. The thread is
+    /// spawned here, so this must be called once the server is detached.
+    pub fn start_refreshing(&self) {
+        if let Some(updater) = self.updater.lock().unwrap().take() {
+            thread::spawn(move || updater.run());
+        }
+    }
+
+    /// Returns the claims of `token` if it is valid.
+    pub fn validate(&self, token: &str) -> Option<Claims> {
+        let mut parts = token.splitn(3, '.');
+        let (header, payload) = (parts.next()?, parts.next()?);
+        let signature = decode(parts.next()?)?;
+        let message = &token[..header.len() + 1 + payload.len()];
+        let header = serde_json::from_slice::<Header>(&decode(header)?).ok()?;
+        let verified = {
+            let keys = self.keys.read().unwrap();
+            let mut candidates = keys.iter()
+                .filter(|key| header.kid.is_none() || key.id == header.kid)
+                .peekable();
+            if candidates.peek().is_none() {
+                let _ = self.refresh.try_send(());
+                return None
+            }
+            candidates.any(|key| {
+                key.verify(&header.alg, message.as_bytes(), &signature)
+            })
+        };
+        if !verified {return None}
+        let claims = serde_json::from_slice::<Claims>(&decode(payload)?)
+            .ok()?;
+        Some(claims).filter(|claims| self.accept(claims))
+    }
+
+    /// Returns whether the claims of a token with a valid signature are as
+    /// expected.
+    fn accept(&self, claims: &Claims) -> bool {
+        let now = crate::signing::unix_time_now();
+        let time = |name| claims.get(name).and_then(Value::as_u64);
+        let expired = time("exp").is_none_or(|exp| exp + LEEWAY <= now);
+        let early = time("nbf").is_some_and(|nbf| nbf > now + LEEWAY);
+        let issuer_ok = self.issuer.as_ref().is_none_or(|issuer| {
+            claims.get("iss").and_then(Value::as_str) == Some(issuer)
+        });
+        let audience_ok = self.audience.as_ref().is_none_or(|audience| {
+            match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) =>
+                    auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            }
+        });
+        !expired && !early && issuer_ok && audience_ok
+    }
+}
+
+/// Returns whether `claims` has a claim `name` equal to `value`, or an array
+/// claim containing it.
+pub fn has_claim(claims: &Claims, name: &str, value: &str) -> bool {
+    let matches = |claim: &Value| match claim {
+        Value::String(s) => s == value,
+        Value::Bool(_) | Value::Number(_) =>
+            serde_json::from_str::<Value>(value).ok().as_ref() == Some(claim),
+        _ => false,
+    };
+    match claims.get(name) {
+        Some(Value::Array(claims)) => claims.iter().any(matches),
+        Some(claim) => matches(claim),
+        None => false,
+    }
+}
+
+/// Keeps a key set up to date.
+struct Updater {
+    source: Source,
+    keys: Arc<RwLock<Vec<Key>>>,
+    requests: mpsc::Receiver<()>,
+    /// Time the key set was last read.
+    loaded: Instant,
+}
+
+impl Updater {
+    /// Reads the key set again periodically and when requested.
+    fn run(self) {
+        let mut runtime = match tokio::runtime::current_thread::Runtime::new()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                warn!("Failed to start key set updates: {}", e);
+                return
+            }
+        };
+        let mut last = self.loaded;
+        loop {
+            thread::sleep(MIN_REFRESH_INTERVAL);
+            let timeout = REFRESH_INTERVAL.saturating_sub(last.elapsed());
+            match self.requests.recv_timeout(timeout) {
+                Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+            last = Instant::now();
+            match fetch(&self.source, &mut runtime) {
+                Ok(keys) => {
+                    info!("Key set reloaded");
+                    *self.keys.write().unwrap() = keys;
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+}
+
+fn fetch(source: &Source, runtime: &mut tokio::runtime::current_thread::Runtime)
+    -> Result<Vec<Key>, JwtError>
+{
+    let body = match source {
+        Source::Url(uri) => get(runtime, uri).map_err(JwtError::Fetch)?,
+        Source::File(path) => fs::read(path)
+            .map_err(|e| JwtError::Fetch(e.to_string()))?,
+    };
+    let set = serde_json::from_slice::<KeySet>(&body)
+        .map_err(JwtError::InvalidKeySet)?;
+    Ok(set.keys.into_iter().filter_map(Key::parse).collect())
+}
+
+/// Returns the body of the successful response to a GET request for `uri`.
+pub fn get(runtime: &mut tokio::runtime::current_thread::Runtime, uri: &Uri)
+    -> Result<Vec<u8>, String>
+{
+    let request = https::client().get(uri.clone())
+        .and_then(|res| {
+            let status = res.status();
+            res.into_body().concat2().map(move |body| (status, body))
+        });
+    let request = tokio::timer::Timeout::new(request, FETCH_TIMEOUT);
+    let (status, body) = runtime.block_on(request)
+        .map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("Responded with {}", status))
+    }
+    Ok(body.to_vec())
+}
+
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(s).ok()
+}
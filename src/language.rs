@@ -0,0 +1,97 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Selection of language variants of files.
+//!
+//! The variant of `page.html` in a language is named after its lowercase
+//! tag, e.g. `page.fr.html` or `page.pt-br.html`. Languages are tried in the
+//! order of preference of the client, each followed by its more general
+//! forms, e.g. `fr-ch` then `fr`.
+
+use crate::beneath::RootDir;
+use http::header::{self, HeaderMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+
+/// Maximum number of languages considered, bounding the files looked up.
+const MAX_LANGUAGES: usize = 8;
+
+/// Variant of a file in a language.
+pub struct Variant {
+    /// Path relative to the served directory.
+    pub resource: PathBuf,
+    pub file: File,
+    pub meta: Metadata,
+    pub language: String,
+}
+
+/// Returns the variant of `resource` in the language the client prefers
+/// according to `headers`, if any. No variant is looked up for languages
+/// preferred less than `default`, the language of `resource`.
+pub fn select(root_dir: &RootDir, resource: &Path, default: Option<&str>,
+    headers: &HeaderMap)
+    -> Option<Variant>
+{
+    let name = resource.file_name()?;
+    for language in candidates(headers) {
+        if default == Some(language.as_str()) {
+            return None
+        }
+        let resource = resource.with_file_name(variant_name(name, &language));
+        let file = match root_dir.open_file(&resource) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if let Some(meta) = file.metadata().ok().filter(Metadata::is_file) {
+            return Some(Variant {resource, file, meta, language})
+        }
+    }
+    None
+}
+
+/// Returns the lowercase language tags of `Accept-Language` by decreasing
+/// preference, each followed by its more general forms.
+pub fn candidates(headers: &HeaderMap) -> impl Iterator<Item = String> {
+    preferences(headers).into_iter().flat_map(|tag| {
+        let prefixes = tag.match_indices('-')
+            .map(|(i, _)| tag[..i].to_owned())
+            .rev()
+            .collect::<Vec<_>>();
+        Some(tag).into_iter().chain(prefixes)
+    })
+}
+
+/// Returns the lowercase language tags of `Accept-Language`, by decreasing
+/// preference.
+fn preferences(headers: &HeaderMap) -> Vec<String> {
+    let mut tags = headers.get_all(header::ACCEPT_LANGUAGE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let weight = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let valid = !tag.is_empty() && tag.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-');
+            Some((tag, weight)).filter(|_| valid && weight > 0.0)
+        })
+        .collect::<Vec<_>>();
+    tags.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    tags.into_iter().map(|(tag, _)| tag).take(MAX_LANGUAGES).collect()
+}
+
+/// Inserts `language` before the extension of `name`.
+fn variant_name(name: &OsStr, language: &str) -> OsString {
+    let path = Path::new(name);
+    let mut variant = path.file_stem().unwrap_or(name).to_owned();
+    variant.push(".");
+    variant.push(language);
+    if let Some(extension) = path.extension() {
+        variant.push(".");
+        variant.push(extension);
+    }
+    variant
+}
@@ -0,0 +1,68 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Termination of the server when requested or no longer needed.
+
+use futures::sync::oneshot;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Triggers the graceful shutdown of the server.
+pub struct Lifetime {
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    max_requests: Option<u64>,
+    requests: AtomicU64,
+    last_request: Mutex<Instant>,
+}
+
+impl Lifetime {
+    /// Returns the lifetime of a server shutting down after `max_requests`
+    /// requests if given, and the receiver notified of the shutdown.
+    pub fn new(max_requests: Option<u64>)
+        -> (Lifetime, oneshot::Receiver<()>)
+    {
+        let (sender, receiver) = oneshot::channel();
+        let lifetime = Lifetime {
+            sender: Mutex::new(Some(sender)),
+            max_requests,
+            requests: AtomicU64::new(0),
+            last_request: Mutex::new(Instant::now()),
+        };
+        (lifetime, receiver)
+    }
+
+    /// Requests the graceful shutdown of the server. Only the first call has
+    /// an effect.
+    pub fn shutdown(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Records the reception of a request.
+    pub fn on_request(&self) {
+        *self.last_request.lock().unwrap() = Instant::now();
+        let n = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_requests == Some(n) {
+            info!("Exiting after {} requests", n);
+            self.shutdown();
+        }
+    }
+
+    /// Shuts the server down once no request has been received for `limit`.
+    pub fn exit_when_idle(self: &Arc<Self>, limit: Duration) {
+        let lifetime = self.clone();
+        thread::spawn(move || loop {
+            let idle = lifetime.last_request.lock().unwrap().elapsed();
+            if idle >= limit {
+                info!("Exiting after being idle for {:?}", limit);
+                lifetime.shutdown();
+                return
+            }
+            thread::sleep(limit - idle);
+        });
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! TCP listeners with configurable socket options.
+
+use futures::{Async, Future, Poll, Stream};
+use socket2::{Domain, Socket, Type};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error};
+
+/// Default maximum number of pending connections.
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// Delay before accepting connections again after a failure, e.g. when out
+/// of file descriptors.
+const ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Socket options of a listener.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Allows several sockets to listen on the same address, with incoming
+    /// connections distributed among them.
+    pub reuse_port: bool,
+    /// Restricts IPv6 sockets to IPv6, instead of also accepting IPv4
+    /// connections.
+    pub ipv6_only: bool,
+    /// Disables Nagle's algorithm on accepted connections.
+    pub nodelay: bool,
+    /// Idle time before keepalive probes are sent on accepted connections.
+    pub keepalive: Option<Duration>,
+    /// Maximum number of pending connections.
+    pub backlog: Option<i32>,
+}
+
+/// Stream of the connections accepted by a listening socket.
+pub struct Listener {
+    listener: TcpListener,
+    options: Options,
+    delay: Option<Delay>,
+}
+
+impl Listener {
+    /// Listens on `addr`.
+    pub fn bind(addr: &SocketAddr, options: &Options) -> io::Result<Listener> {
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM,
+            None)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if options.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform"))
+        }
+        if addr.is_ipv6() {
+            socket.set_only_v6(options.ipv6_only)?;
+        }
+        socket.bind(&(*addr).into())?;
+        socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG))?;
+        let listener = TcpListener::from_std(socket.into(),
+            &Default::default())?;
+        Ok(Listener {listener, options: *options, delay: None})
+    }
+
+    /// Returns the address the listener is bound to, e.g. to learn the port
+    /// picked by the system when binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.options.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.options.keepalive {
+            stream.set_keepalive(Some(keepalive))?;
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Listener {
+    type Item = Connection;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Connection>, io::Error> {
+        if let Some(delay) = &mut self.delay {
+            match delay.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(e) => error!("Timer error: {}", e),
+            }
+            self.delay = None;
+        }
+        loop {
+            match self.listener.poll_accept() {
+                Ok(Async::Ready((stream, peer))) => {
+                    if let Err(e) = self.configure(&stream) {
+                        debug!("Failed to set socket options: {}", e);
+                    }
+                    // IPv4 clients of dual-stack sockets appear with mapped
+                    // IPv6 addresses.
+                    let peer = SocketAddr::new(peer.ip().to_canonical(),
+                        peer.port());
+                    return Ok(Async::Ready(Some(Connection {stream, peer})))
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) if is_connection_error(&e) => {
+                    debug!("Accepted connection already failed: {}", e);
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    let mut delay = Delay::new(Instant::now() + ERROR_DELAY);
+                    match delay.poll() {
+                        Ok(Async::NotReady) => {
+                            self.delay = Some(delay);
+                            return Ok(Async::NotReady)
+                        }
+                        Ok(Async::Ready(())) => {}
+                        Err(_) => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether an error accepting a connection only concerns this
+/// connection, so that the next one can be accepted right away.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset)
+}
+
+/// Accepted connection.
+pub struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+}
+
+impl Connection {
+    /// Returns the address of the client.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
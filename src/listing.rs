@@ -0,0 +1,583 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Directory listings.
+//!
+//! A listing is first described as data, then rendered by the built-in
+//! renderer, which streams entries as they are read, or by a Handlebars
+//! template. Templates receive:
+//!
+//! - `title`: title of the page
+//! - `path`: path of the directory
+//! - `breadcrumbs`: `name` and `href` of the root and of each directory
+//!   leading to this one
+//! - `entries`: `name`, `href`, `is_dir`, `kind` and `modified` time in
+//!   RFC 3339 format of each entry, with `size` in bytes, `pretty_size` and
+//!   `download_href` for files and the number of `items` of directories, with
+//!   the total size of their files when requested, as `disk_usage` with
+//!   `bytes` and whether the total is `complete`.
+//!   `kind` is one of `directory`, `symlink`, `image`, `audio`, `video`,
+//!   `archive`, `code`, `text` and `file`. When shown, `permissions` has the
+//!   `mode`, `owner` and `group` of the entry. Entries are sorted by name,
+//!   directories first unless listings are flat
+//! - `search`: `endpoint` of the search form and whether `content` can be
+//!   searched
+//! - `page`: `number` and `size` of the page, with the `previous` and `next`
+//!   links if there are such pages
+//! - `readme`: HTML rendering of the README of the directory, if it is shown
+//! - `messages`: messages in the language of the page, named as in catalogs
+
+use crate::disk_usage::{DiskUsage, Usage};
+use crate::i18n::Messages;
+use crate::icons::{self, Kind};
+use crate::permissions::Permissions;
+use crate::readme::Readme;
+use crate::style::Style;
+use crate::vhost::Site;
+use crate::{access_file, search, url_path, Page};
+use handlebars::Handlebars;
+use http::header::{self, HeaderMap};
+use http::Request;
+use hyper::Body;
+use nestxml::html;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fs::DirEntry;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Name of the user template.
+const TEMPLATE_NAME: &str = "listing";
+
+/// Script filtering the entries shown in a listing.
+pub const FILTER_SCRIPT: &str = include_str!("../data/filter.js");
+
+/// Page of the listing of a directory, without its entries.
+pub struct Listing {
+    /// Request path of the directory.
+    pub path: PathBuf,
+    pub page: Page,
+    /// Whether the contents of files can be searched.
+    pub content_search: bool,
+    /// README of the directory, shown below its entries.
+    pub readme: Option<Readme>,
+    /// Path prefix of links, without trailing slash.
+    pub base_path: String,
+    pub messages: Arc<Messages>,
+    pub format: Format,
+    /// Whether the permissions and owners of entries are shown.
+    pub show_permissions: bool,
+    /// Cache of directory sizes, if they are shown.
+    pub disk_usage: Option<Arc<DiskUsage>>,
+}
+
+/// Format of a listing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Html,
+    /// Aligned columns of names, sizes and modification times.
+    Text,
+}
+
+impl Format {
+    /// Returns the format requested by `request`, i.e. text if its query has
+    /// `format=txt` or if it accepts `text/plain` with a higher quality than
+    /// `text/html`.
+    pub fn of(request: &Request<Body>) -> Format {
+        if crate::query_param(request, "format").is_some_and(|f| f == "txt") {
+            return Format::Text
+        }
+        let headers = request.headers();
+        if quality(headers, "text", "plain") > quality(headers, "text", "html")
+        {
+            Format::Text
+        } else {
+            Format::Html
+        }
+    }
+}
+
+/// Entry of a directory.
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    name: String,
+    href: String,
+    is_dir: bool,
+    kind: Kind,
+    size: Option<u64>,
+    pretty_size: Option<String>,
+    /// Number of children of a directory.
+    items: Option<usize>,
+    /// Total size of the files below a directory.
+    disk_usage: Option<Usage>,
+    /// Modification time in RFC 3339 format.
+    modified: Option<String>,
+    permissions: Option<Permissions>,
+    download_href: Option<String>,
+}
+
+impl Entry {
+    /// Returns the size shown for the entry: the size of a file, or the
+    /// number of items of a directory preceded by the size of its files if
+    /// known. Incomplete totals start with `>`.
+    fn size_text(&self, messages: &Messages) -> Option<String> {
+        if let Some(size) = &self.pretty_size {
+            return Some(size.clone())
+        }
+        let items = messages.count(self.items?);
+        Some(match self.disk_usage {
+            Some(Usage {bytes, complete}) => format!("{}{} ({})",
+                if complete {""} else {"> "}, crate::pretty_size(bytes),
+                items),
+            None => items,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Link {
+    name: String,
+    href: String,
+}
+
+#[derive(Serialize)]
+struct SearchContext {
+    endpoint: String,
+    content: bool,
+}
+
+#[derive(Serialize)]
+struct PageContext {
+    number: usize,
+    size: usize,
+    previous: Option<String>,
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Context<'a> {
+    title: &'a str,
+    path: &'a str,
+    breadcrumbs: Vec<Link>,
+    entries: Vec<Entry>,
+    search: SearchContext,
+    page: PageContext,
+    readme: Option<String>,
+    messages: &'a Messages,
+}
+
+/// Returns the entries of `dir`, a directory of `site`, on the page of
+/// `listing`, hiding access files if `hide_access_files` is true. Entries
+/// are sorted by name, directories first if `directories_first` is true.
+/// Entries following the page are also returned, to tell whether there is a
+/// next page.
+pub fn entries<I>(site: &Arc<Site>, dir: &Path, dir_entries: I,
+    listing: &Listing, hide_access_files: bool, directories_first: bool)
+    -> impl Iterator<Item = Entry>
+where
+    I: IntoIterator<Item = DirEntry>,
+{
+    let site = site.clone();
+    let dir = dir.to_owned();
+    let req_path = listing.path.clone();
+    let base_path = listing.base_path.clone();
+    let show_permissions = listing.show_permissions;
+    let disk_usage = listing.disk_usage.clone()
+        .map(|cache| {
+            let deadline = Instant::now() + cache.budget;
+            (cache, deadline)
+        });
+    let mut dir_entries = dir_entries.into_iter()
+        .filter(|entry| !hide_access_files
+            || entry.file_name() != access_file::FILE_NAME)
+        .map(|entry| {
+            let is_dir = match entry.file_type() {
+                Ok(t) if t.is_symlink() => site.root_dir
+                    .open_file(&dir.join(entry.file_name()))
+                    .and_then(|file| file.metadata())
+                    .is_ok_and(|meta| meta.is_dir()),
+                Ok(t) => t.is_dir(),
+                Err(_) => false,
+            };
+            (is_dir, entry.file_name(), entry)
+        })
+        .collect::<Vec<_>>();
+    dir_entries.sort_by(|(a_dir, a_name, _), (b_dir, b_name, _)| {
+        let groups = if directories_first {
+            b_dir.cmp(a_dir)
+        } else {
+            Ordering::Equal
+        };
+        groups.then_with(|| a_name.cmp(b_name))
+    });
+    dir_entries.into_iter()
+        .skip(listing.page.skipped())
+        .map(move |(is_dir, name, entry)| {
+            let mut href = base_path.clone()
+                + &url_path::encode(&req_path.join(&name));
+            if is_dir {
+                href.push('/');
+            }
+            let is_symlink = entry.file_type()
+                .is_ok_and(|t| t.is_symlink());
+            let meta = entry.metadata().ok();
+            let size = meta.as_ref()
+                .filter(|meta| meta.is_file())
+                .map(|meta| meta.len());
+            let modified = meta.as_ref()
+                .and_then(|meta| meta.modified().ok())
+                .map(|t| humantime::format_rfc3339_seconds(t).to_string());
+            let permissions = meta.as_ref()
+                .filter(|_| show_permissions)
+                .map(Permissions::of);
+            let path = dir.join(&name);
+            let items = Some(&path)
+                .filter(|_| is_dir)
+                .and_then(|path| site.root_dir.read_dir(path).ok())
+                .map(|children| children.count());
+            let disk_usage = disk_usage.as_ref()
+                .filter(|_| is_dir)
+                .map(|(cache, deadline)| cache.measure(&site.root_dir, &path,
+                    *deadline));
+            Entry {
+                kind: Kind::of(Path::new(&name), is_dir, is_symlink),
+                name: name.to_string_lossy().into_owned(),
+                download_href: size.map(|_| format!("{}?download=1", href)),
+                href,
+                is_dir,
+                size,
+                pretty_size: size.map(crate::pretty_size),
+                items,
+                disk_usage,
+                modified,
+                permissions,
+            }
+        })
+}
+
+impl Listing {
+    /// Returns the names and links of the root and of the directories leading
+    /// to the listed one.
+    fn breadcrumbs(&self) -> Vec<Link> {
+        let mut links = self.path.ancestors()
+            .map(|p| {
+                let mut href = self.link(p);
+                if !href.ends_with('/') {
+                    href.push('/');
+                }
+                Link {
+                    name: p.file_name().map_or_else(|| "/".into(),
+                        |name| name.to_string_lossy().into_owned()),
+                    href,
+                }
+            })
+            .collect::<Vec<_>>();
+        links.reverse();
+        links
+    }
+
+    /// Returns the link to page `number` of the listing.
+    fn page_link(&self, number: usize) -> String {
+        let mut link = self.page.link(number);
+        if self.disk_usage.is_some() {
+            link.push_str("&du=1");
+        }
+        link
+    }
+
+    /// Returns the link to the request path `path`.
+    fn link(&self, path: &Path) -> String {
+        self.base_path.clone() + &url_path::encode(path)
+    }
+}
+
+/// Renderer of directory listings.
+pub enum Renderer {
+    BuiltIn,
+    Template(Box<Handlebars<'static>>),
+}
+
+impl Renderer {
+    /// Returns a renderer using a Handlebars template.
+    pub fn template(source: &str)
+        -> Result<Renderer, handlebars::TemplateError>
+    {
+        let mut registry = Handlebars::new();
+        registry.register_template_string(TEMPLATE_NAME, source)?;
+        Ok(Renderer::Template(Box::new(registry)))
+    }
+
+    /// Writes the page of `listing`, whose entries starting at its page are
+    /// `entries`. Templates are not styled by `style`, and text listings are
+    /// not rendered by templates.
+    pub fn render<W, I>(&self, listing: &Listing, entries: I, style: &Style,
+        out: W) -> io::Result<()>
+    where
+        W: Write,
+        I: Iterator<Item = Entry>,
+    {
+        if listing.format == Format::Text {
+            return write_text(listing, entries, out)
+        }
+        match self {
+            Renderer::BuiltIn => {
+                let title = &listing.messages.directory_contents;
+                crate::write_page(out, style, title, |out| {
+                    write_listing(listing, entries, out)
+                }).map_err(io::Error::other)
+            }
+            Renderer::Template(registry) => {
+                let page = listing.page;
+                let mut entries = entries.take(page.size + 1)
+                    .collect::<Vec<_>>();
+                let more = entries.len() > page.size;
+                entries.truncate(page.size);
+                let context = Context {
+                    title: &listing.messages.directory_contents,
+                    path: &listing.path.to_string_lossy(),
+                    breadcrumbs: listing.breadcrumbs(),
+                    entries,
+                    search: SearchContext {
+                        endpoint: listing.link(Path::new(search::ENDPOINT)),
+                        content: listing.content_search,
+                    },
+                    page: PageContext {
+                        number: page.number,
+                        size: page.size,
+                        previous: Some(page.number - 1)
+                            .filter(|&n| n > 0)
+                            .map(|n| listing.page_link(n)),
+                        next: Some(page.number + 1)
+                            .filter(|_| more)
+                            .map(|n| listing.page_link(n)),
+                    },
+                    readme: listing.readme.as_ref()
+                        .map(Readme::to_html)
+                        .transpose()
+                        .map_err(io::Error::other)?,
+                    messages: &listing.messages,
+                };
+                registry.render_to_write(TEMPLATE_NAME, &context, out)
+                    .map_err(io::Error::other)
+            }
+        }
+    }
+}
+
+fn write_listing<W, I>(listing: &Listing, mut entries: I,
+    out: &mut xml::EventWriter<W>) -> Result<(), xml::writer::Error>
+where
+    W: Write,
+    I: Iterator<Item = Entry>,
+{
+    let page = listing.page;
+    let messages = &listing.messages;
+    write_dir_title(listing, out)?;
+    write_search_box(listing, out)?;
+    nestxml::element(out, "input")
+        .attr("type", "search")
+        .attr("class", "filter")
+        .attr("placeholder", messages.filter.as_str())
+        .empty()?;
+    if listing.disk_usage.is_none() {
+        nestxml::element(out, "p").attr("class", "tools").write(|out| {
+            html::a(out)
+                .attr("href", format!("{}&du=1", page.link(page.number)))
+                .text(&messages.show_sizes)
+        })?;
+    }
+    html::table(out).attr("class", "listing").write(|out| {
+        html::tr(out).write(|out| {
+            html::th(out).text(&messages.filename)?;
+            if listing.show_permissions {
+                html::th(out).text(&messages.permissions)?;
+                html::th(out).text(&messages.owner)?;
+            }
+            html::th(out).attr("class", "size").text(&messages.size)
+        })?;
+        for entry in entries.by_ref().take(page.size) {
+            html::tr(out).attr("data-name", entry.name.as_str()).write(|out| {
+                html::td(out).write(|out| {
+                    icons::write(entry.kind, out)?;
+                    html::a(out).attr("href", entry.href.as_str())
+                        .text(&entry.name)?;
+                    let download = match &entry.download_href {
+                        Some(download) => download,
+                        None => return Ok(()),
+                    };
+                    html::a(out)
+                        .attr("class", "download")
+                        .attr("href", download.as_str())
+                        .attr("title", messages.download.as_str())
+                        .text("\u{2913}")
+                })?;
+                let size = entry.size_text(messages).unwrap_or_default();
+                if listing.show_permissions {
+                    let permissions = entry.permissions.as_ref();
+                    html::td(out).attr("class", "mode")
+                        .text(permissions.map_or("", |p| p.mode.as_str()))?;
+                    html::td(out).text(&permissions
+                        .map(Permissions::ownership)
+                        .unwrap_or_default())?;
+                }
+                html::td(out).attr("class", "size").text(&size)
+            })?;
+        }
+        Ok(())
+    })?;
+    let more = entries.next().is_some();
+    if page.number > 1 || more {
+        nestxml::element(out, "p").attr("class", "pages").write(|out| {
+            if page.number > 1 {
+                html::a(out).attr("href", listing.page_link(page.number - 1))
+                    .text(&messages.previous)?;
+            }
+            if more {
+                html::a(out).attr("href", listing.page_link(page.number + 1))
+                    .text(&messages.next)?;
+            }
+            Ok(())
+        })?;
+    }
+    if let Some(readme) = &listing.readme {
+        readme.write(out)?;
+    }
+    nestxml::element(out, "script").text(FILTER_SCRIPT)
+}
+
+fn write_text<W, I>(listing: &Listing, entries: I, mut out: W)
+    -> io::Result<()>
+where
+    W: Write,
+    I: Iterator<Item = Entry>,
+{
+    let page = listing.page;
+    let mut entries = entries.take(page.size + 1).collect::<Vec<_>>();
+    let more = entries.len() > page.size;
+    entries.truncate(page.size);
+    // Cells of each row, with the columns aligned to the right.
+    let mut right_aligned = vec![false];
+    if listing.show_permissions {
+        right_aligned.extend([false, false]);
+    }
+    right_aligned.extend([true, false]);
+    let rows = entries.into_iter()
+        .map(|entry| {
+            let mut name = entry.name.clone();
+            if entry.is_dir {
+                name.push('/');
+            }
+            let mut row = vec![name];
+            if listing.show_permissions {
+                let permissions = entry.permissions.as_ref();
+                row.push(permissions.map_or_else(String::new,
+                    |p| p.mode.clone()));
+                row.push(permissions.map(Permissions::ownership)
+                    .unwrap_or_default());
+            }
+            row.push(entry.size_text(&listing.messages)
+                .unwrap_or_else(|| "-".to_owned()));
+            row.push(entry.modified.unwrap_or_default());
+            row
+        })
+        .collect::<Vec<_>>();
+    let widths = (0..right_aligned.len())
+        .map(|i| rows.iter()
+            .map(|row| row[i].chars().count())
+            .max()
+            .unwrap_or(0))
+        .collect::<Vec<_>>();
+    for row in &rows {
+        let cells = row.iter().zip(&widths).zip(&right_aligned)
+            .map(|((cell, &width), &right)| if right {
+                format!("{:>width$}", cell)
+            } else {
+                format!("{:<width$}", cell)
+            })
+            .collect::<Vec<_>>();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    if more {
+        writeln!(out, "{}: {}&format=txt", listing.messages.next,
+            listing.page_link(page.number + 1))?;
+    }
+    Ok(())
+}
+
+/// Returns the quality of the most specific range of `Accept` headers
+/// matching the media type `main/sub`, or 0 if none does.
+pub fn quality(headers: &HeaderMap, main: &str, sub: &str) -> f32 {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let specificity = match range.split_once('/')? {
+                (m, s) if m == main && s == sub => 2,
+                (m, "*") if m == main => 1,
+                ("*", "*") => 0,
+                _ => return None,
+            };
+            let weight = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((specificity, weight))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .map_or(0.0, |(_, weight)| weight)
+}
+
+fn write_search_box<W: Write>(listing: &Listing,
+    out: &mut xml::EventWriter<W>) -> Result<(), xml::writer::Error>
+{
+    let form = nestxml::element(out, "form")
+        .attr("class", "search")
+        .attr("action", listing.link(Path::new(search::ENDPOINT)));
+    form.write(|out| {
+        nestxml::element(out, "input")
+            .attr("type", "search")
+            .attr("name", "q")
+            .attr("placeholder", listing.messages.search.as_str())
+            .empty()?;
+        nestxml::element(out, "input")
+            .attr("type", "hidden")
+            .attr("name", "path")
+            .attr("value", listing.path.to_string_lossy())
+            .empty()?;
+        nestxml::element(out, "input")
+            .attr("type", "hidden")
+            .attr("name", "format")
+            .attr("value", "html")
+            .empty()?;
+        if !listing.content_search {return Ok(())}
+        nestxml::element(out, "label").write(|out| {
+            nestxml::element(out, "input")
+                .attr("type", "checkbox")
+                .attr("name", "content")
+                .attr("value", "1")
+                .empty()?;
+            out.write(listing.messages.search_contents.as_str())
+        })
+    })
+}
+
+fn write_dir_title<W: Write>(listing: &Listing,
+    out: &mut xml::EventWriter<W>) -> Result<(), xml::writer::Error>
+{
+    let breadcrumbs = listing.breadcrumbs();
+    html::h1(out).write(|out| {
+        out.write(listing.messages.contents_of.as_str())?;
+        let mut links = breadcrumbs.iter();
+        if let Some(root) = links.next() {
+            html::a(out).attr("href", root.href.as_str()).text(&root.name)?;
+        }
+        for link in links {
+            html::a(out).attr("href", link.href.as_str()).text(&link.name)?;
+            out.write("/")?;
+        }
+        Ok(())
+    })
+}
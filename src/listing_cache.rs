@@ -0,0 +1,76 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Cache of rendered directory listings.
+//!
+//! A listing is reused while the modification time of its directory is
+//! unchanged and it is younger than the configured time to live. The time to
+//! live bounds how long changes to the size of files, which do not modify
+//! their directory, go unnoticed.
+
+use crate::Page;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Maximum number of listings kept.
+const MAX_ENTRIES: usize = 1024;
+
+struct Entry {
+    modified: SystemTime,
+    created: Instant,
+    page: Bytes,
+}
+
+/// Rendered listings by directory, request path, page and variant.
+pub struct ListingCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<(PathBuf, PathBuf, Page, String), Entry>>,
+}
+
+impl ListingCache {
+    /// Creates a cache keeping listings for at most `ttl`.
+    pub fn new(ttl: Duration) -> ListingCache {
+        ListingCache {ttl, entries: Default::default()}
+    }
+
+    /// Returns a page of the listing of `dir`, requested as `req_path` and
+    /// whose metadata is `meta`, calling `render` if it is not cached or is
+    /// stale. `variant` tells apart renderings of a page, e.g. in different
+    /// formats or languages.
+    pub fn get<F>(&self, dir: &Path, req_path: &Path, page: Page,
+        variant: &str, meta: &Metadata, render: F)
+        -> io::Result<Bytes>
+    where
+        F: FnOnce() -> io::Result<Vec<u8>>,
+    {
+        let modified = meta.modified()?;
+        let key = (dir.to_owned(), req_path.to_owned(), page,
+            variant.to_owned());
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.modified == modified && entry.created.elapsed() < self.ttl
+            {
+                return Ok(entry.page.clone())
+            }
+        }
+        let created = Instant::now();
+        let page = Bytes::from(render()?);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.created.elapsed() < ttl);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(key, Entry {
+            modified,
+            created,
+            page: page.clone(),
+        });
+        Ok(page)
+    }
+}
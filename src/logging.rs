@@ -0,0 +1,74 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Diagnostic and request logging.
+
+use http::HeaderMap;
+use std::io;
+use tracing::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// Name of the header carrying the request ID.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Maximum length of a request ID received from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Format of log lines.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Sets up logging to standard output, or to standard error if `stderr` is
+/// true. `verbosity` is 0 for the default level (info), and positive or
+/// negative to log more or less. `ansi` enables colors.
+pub fn init(verbosity: i64, format: Format, ansi: bool, stderr: bool) {
+    let level = match verbosity {
+        i64::MIN..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let writer = if stderr {
+        BoxMakeWriter::new(io::stderr)
+    } else {
+        BoxMakeWriter::new(io::stdout)
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match format {
+        Format::Text => builder.init(),
+        Format::Json => builder.json().with_current_span(true).init(),
+    }
+}
+
+/// Returns the ID of a request, taken from its `X-Request-Id` header if it
+/// is a reasonable token, or generated otherwise.
+pub fn request_id(headers: &HeaderMap) -> String {
+    let received = headers.get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()));
+    match received {
+        Some(id) => id.to_owned(),
+        None => {
+            let mut bytes = [0; 16];
+            getrandom::getrandom(&mut bytes).expect("No source of randomness");
+            crate::checksum::to_hex(&bytes)
+        }
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Login form for users of the htpasswd file.
+//!
+//! Browsers requesting pages that require authentication are redirected to
+//! `/_login`, where users enter their name and password instead of using the
+//! native prompt of the Basic scheme. Logged in users get a session, which
+//! they can end at `/_logout`.
+
+use crate::jwt::Claims;
+use crate::session::LOGOUT_PATH;
+use crate::{Config, ServerFuture};
+use futures::{future, Future, Stream};
+use http::header;
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use nestxml::html;
+use serde_json::json;
+use std::io;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+/// Path of the login form.
+pub const LOGIN_PATH: &str = "/_login";
+
+/// Maximum length of submitted forms.
+const MAX_FORM_LEN: usize = 4096;
+
+/// Redirects the browser sending `request` to the login form, which sends
+/// it back to the requested page once logged in.
+pub fn redirect<B>(config: &Config, request: &Request<B>)
+    -> ServerFuture<Response<Body>>
+{
+    let target = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("next", target)
+        .finish();
+    let location = format!("{}{}?{}", config.base_path, LOGIN_PATH, query);
+    let res = Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty());
+    Box::new(future::result(res))
+}
+
+/// Answers a request to the login form, showing it or checking the
+/// submitted credentials.
+pub fn handle(config: &Arc<Config>, request: Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    match *request.method() {
+        Method::GET | Method::HEAD => {
+            let query = request.uri().query().unwrap_or("");
+            let target = param(query.as_bytes(), "next");
+            Box::new(future::result(form(config, StatusCode::OK,
+                target.as_deref(), None)))
+        }
+        Method::POST => {
+            let body = request.into_body().map_err(io::Error::other)
+                .fold(Vec::new(), |mut body, chunk| {
+                    if body.len() + chunk.len() > MAX_FORM_LEN {
+                        return Err(io::Error::from(
+                            io::ErrorKind::FileTooLarge))
+                    }
+                    body.extend_from_slice(&chunk);
+                    Ok(body)
+                });
+            let config = config.clone();
+            Box::new(body.then(move |body| -> ServerFuture<_> {
+                let body = match body {
+                    Ok(body) => body,
+                    Err(_) => return Box::new(future::result(
+                        crate::problem::Problem::new(
+                            StatusCode::PAYLOAD_TOO_LARGE, "Request too large")
+                            .respond_with(&mut Response::builder()))),
+                };
+                // Password hashes are slow to compute on purpose.
+                let res = crate::blocking(move || log_in(&config, &body));
+                Box::new(res.then(|res| match res {
+                    Ok(res) => Box::new(future::result(res)),
+                    Err(e) => crate::io_error(e),
+                }))
+            }))
+        }
+        _ => crate::method_not_allowed(config),
+    }
+}
+
+/// Ends the session of the client and redirects it to the root.
+pub fn log_out(config: &Config) -> ServerFuture<Response<Body>> {
+    let mut res = Response::builder();
+    res.status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, format!("{}/", config.base_path))
+        .header(header::CACHE_CONTROL, "no-store");
+    if let Some(sessions) = &config.auth.sessions {
+        res.header(header::SET_COOKIE, sessions.end());
+    }
+    Box::new(future::result(res.body(Body::empty())))
+}
+
+fn log_in(config: &Config, body: &[u8]) -> http::Result<Response<Body>> {
+    let name = param(body, "user").unwrap_or_default();
+    let password = param(body, "password").unwrap_or_default();
+    let target = param(body, "next");
+    let verified = config.auth.htpasswd.as_ref()
+        .is_some_and(|htpasswd| htpasswd.verify(&name, &password));
+    let sessions = match &config.auth.sessions {
+        Some(sessions) if verified => sessions,
+        _ => return form(config, StatusCode::FORBIDDEN, target.as_deref(),
+            Some("Invalid user name or password")),
+    };
+    let mut claims = Claims::new();
+    claims.insert("sub".to_owned(), json!(name));
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, format!("{}{}", config.base_path,
+            safe_target(target.as_deref())))
+        .header(header::SET_COOKIE, sessions.start(claims))
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())
+}
+
+/// Returns the login page, with `error` explaining why the last attempt
+/// failed, if any.
+fn form(config: &Config, status: StatusCode, target: Option<&str>,
+    error: Option<&str>) -> http::Result<Response<Body>>
+{
+    let action = format!("{}{}", config.base_path, LOGIN_PATH);
+    let logout = format!("{}{}", config.base_path, LOGOUT_PATH);
+    let mut out = Vec::<u8>::new();
+    crate::write_page(&mut out, &config.style, "Log in", |out| {
+        html::h1(out).text("Log in")?;
+        if let Some(error) = error {
+            nestxml::element(out, "p").attr("class", "error").text(error)?;
+        }
+        let form = nestxml::element(out, "form")
+            .attr("class", "login")
+            .attr("method", "post")
+            .attr("action", action.as_str());
+        form.write(|out| {
+            nestxml::element(out, "p").write(|out| {
+                nestxml::element(out, "label").write(|out| {
+                    out.write("User ")?;
+                    nestxml::element(out, "input")
+                        .attr("name", "user")
+                        .attr("autocomplete", "username")
+                        .attr("required", "required")
+                        .empty()
+                })
+            })?;
+            nestxml::element(out, "p").write(|out| {
+                nestxml::element(out, "label").write(|out| {
+                    out.write("Password ")?;
+                    nestxml::element(out, "input")
+                        .attr("type", "password")
+                        .attr("name", "password")
+                        .attr("autocomplete", "current-password")
+                        .empty()
+                })
+            })?;
+            nestxml::element(out, "input")
+                .attr("type", "hidden")
+                .attr("name", "next")
+                .attr("value", safe_target(target))
+                .empty()?;
+            nestxml::element(out, "button")
+                .attr("type", "submit")
+                .text("Log in")
+        })?;
+        nestxml::element(out, "p").write(|out| {
+            html::a(out).attr("href", logout.as_str()).text("Log out")
+        })
+    }).unwrap();
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::TEXT_HTML_UTF_8.as_ref())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(out.into())
+}
+
+/// Returns `target` if it is a path of this server, which users can safely
+/// be sent back to, or the root otherwise.
+fn safe_target(target: Option<&str>) -> &str {
+    target.filter(|t| t.starts_with('/') && !t.starts_with("//")
+            && !t.contains('\\'))
+        .unwrap_or("/")
+}
+
+fn param(form: &[u8], name: &str) -> Option<String> {
+    form_urlencoded::parse(form)
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
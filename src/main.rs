@@ -2,193 +2,3557 @@
 
 #![deny(warnings)]
 
-use clap::{App, Arg};
+mod access;
+mod access_file;
+mod audit;
+mod auth;
+mod banner;
+mod beneath;
+mod checksum;
+mod cidr;
+mod charset;
+mod config_file;
+mod content_type;
+mod daemon;
+mod diff;
+mod disk_usage;
+mod downloads;
+mod dump;
+mod etag;
+mod fault;
+mod feed;
+mod file_cache;
+mod file_stream;
+mod fs_api;
+mod forwarded;
+mod https;
+mod i18n;
+mod icons;
+mod index;
+mod language;
+mod ip_filter;
+mod jwt;
+mod lifetime;
+mod listing;
+mod listener;
+mod listing_cache;
+mod logging;
+mod login;
+mod man;
+mod mirror;
+mod oidc;
+mod permissions;
+mod privileges;
+mod problem;
+mod proxy;
+mod range;
+mod readme;
+mod rewrite;
+mod sandbox;
+mod search;
+mod sitemap;
+mod service;
+mod session;
+mod signals;
+mod signing;
+mod sniff;
+mod stats;
+mod stdin;
+mod streaming;
+mod sync;
+mod style;
+mod telemetry;
+mod tls;
+mod trash;
+mod tus;
+mod upload;
+mod upload_filter;
+mod url_path;
+mod vhost;
+mod watch;
+mod webhook;
+mod well_known;
+
+use bytes::Bytes;
+use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
 use futures::{Future, Stream};
-use futures::future;
-use http::{Request, Response, StatusCode};
+use futures::{future, stream};
+use http::{Method, Request, Response, StatusCode};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use hyper::{Body, Server};
-use hyper::service::service_fn;
+use hyper::body::Payload;
+use hyper::service::{make_service_fn, service_fn, Service};
 use mime::Mime;
 use nestxml::html;
-use percent_encoding::percent_decode;
-use std::cell::Cell;
+use std::convert::TryFrom;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::{DirEntry, Metadata};
-use std::io::{self, Write};
-use std::net::{AddrParseError, IpAddr, Ipv4Addr};
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument;
 
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 const APP_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const APP_ABOUT: &str = "Serves a directory over HTTP";
 
 fn main() {
-    if let Err(e) = run() {
+    if let Err(e) = run(std::env::args_os()) {
         print_error(&e);
-        std::process::exit(1)
+        std::process::exit(e.exit_code())
+    }
+}
+
+fn print_error(mut e: &dyn Error) {
+    eprintln!("Error: {}", e);
+    while let Some(cause) = e.source() {
+        eprintln!("Because: {}", cause);
+        e = cause;
+    }
+}
+
+#[derive(Debug)]
+enum AppError {
+    BadAddress(AddrParseError),
+    BadPort,
+    InvalidArgument(&'static str),
+    Bind(io::Error),
+    ReadFile(PathBuf, io::Error),
+    CreateDir(PathBuf, io::Error),
+    OpenLog(PathBuf, io::Error),
+    ConfigFile(PathBuf, config_file::ConfigError),
+    RewriteRules(PathBuf, rewrite::RuleError),
+    ListingTemplate(PathBuf, handlebars::TemplateError),
+    Catalog(PathBuf, i18n::CatalogError),
+    NoAuthMethod,
+    Jwt(jwt::JwtError),
+    Oidc(oidc::OidcError),
+    NoDirectory,
+    ServerOptions(usize, clap::Error),
+    Tls(tls::TlsError),
+    Privileges(privileges::PrivilegeError),
+    Daemon(daemon::DaemonError),
+    Sandbox(sandbox::SandboxError),
+    Service(service::ServiceError),
+    Sync(sync::SyncError),
+    Runtime(io::Error),
+    Output(io::Error),
+}
+
+/// Exit statuses of failures, as defined by `sysexits.h`.
+mod exit_code {
+    pub const USAGE: i32 = 64;
+    pub const NO_INPUT: i32 = 66;
+    pub const UNAVAILABLE: i32 = 69;
+    pub const OS_ERROR: i32 = 71;
+    pub const CANNOT_CREATE: i32 = 73;
+    pub const IO_ERROR: i32 = 74;
+    pub const NO_PERMISSION: i32 = 77;
+    pub const CONFIG: i32 = 78;
+
+    /// Exit statuses with the failures they signal, as documented.
+    pub const DESCRIPTIONS: &[(i32, &str)] = &[
+        (0, "Success"),
+        (USAGE, "Invalid command-line arguments"),
+        (NO_INPUT, "A served directory or a file given as option cannot \
+            be read"),
+        (UNAVAILABLE, "An address cannot be listened at, or a remote \
+            server cannot be synced from"),
+        (OS_ERROR, "The server cannot run as a daemon or a service, or \
+            start its runtime"),
+        (CANNOT_CREATE, "A directory, log or synced file cannot be \
+            created"),
+        (IO_ERROR, "Output cannot be written"),
+        (NO_PERMISSION, "Privileges cannot be dropped or the sandbox set \
+            up"),
+        (CONFIG, "A configuration, rules, template, catalog or TLS file is \
+            invalid"),
+    ];
+}
+
+impl AppError {
+    /// Returns the exit status of the program failing with this error.
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::BadAddress(_)
+                | AppError::BadPort
+                | AppError::InvalidArgument(_)
+                | AppError::NoDirectory
+                => exit_code::USAGE,
+            AppError::ReadFile(..) => exit_code::NO_INPUT,
+            AppError::Bind(_) => exit_code::UNAVAILABLE,
+            AppError::CreateDir(..) | AppError::OpenLog(..) =>
+                exit_code::CANNOT_CREATE,
+            AppError::ConfigFile(..)
+                | AppError::RewriteRules(..)
+                | AppError::ListingTemplate(..)
+                | AppError::Catalog(..)
+                | AppError::NoAuthMethod
+                | AppError::Jwt(_)
+                | AppError::Oidc(_)
+                | AppError::ServerOptions(..)
+                | AppError::Tls(_)
+                => exit_code::CONFIG,
+            AppError::Privileges(_) | AppError::Sandbox(_) =>
+                exit_code::NO_PERMISSION,
+            AppError::Daemon(_) | AppError::Service(_) | AppError::Runtime(_) =>
+                exit_code::OS_ERROR,
+            AppError::Sync(sync::SyncError::Write(..)) =>
+                exit_code::CANNOT_CREATE,
+            AppError::Sync(_) => exit_code::UNAVAILABLE,
+            AppError::Output(_) => exit_code::IO_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::BadAddress(_) => f.write_str("Invalid address"),
+            AppError::BadPort => f.write_str("Invalid port"),
+            AppError::InvalidArgument(name) =>
+                write!(f, "Invalid value for --{}", name),
+            AppError::Bind(_) => f.write_str("Failed to bind listener"),
+            AppError::ReadFile(path, _) =>
+                write!(f, "Failed to read {}", path.display()),
+            AppError::CreateDir(path, _) =>
+                write!(f, "Failed to create {}", path.display()),
+            AppError::OpenLog(path, _) =>
+                write!(f, "Failed to open log {}", path.display()),
+            AppError::ConfigFile(path, _) =>
+                write!(f, "Invalid configuration file {}", path.display()),
+            AppError::RewriteRules(path, _) =>
+                write!(f, "Invalid rewrite rules file {}", path.display()),
+            AppError::ListingTemplate(path, _) =>
+                write!(f, "Invalid listing template {}", path.display()),
+            AppError::Catalog(path, _) =>
+                write!(f, "Invalid message catalog {}", path.display()),
+            AppError::NoAuthMethod => f.write_str("Authentication is \
+                required but no authentication method is configured"),
+            AppError::Jwt(_) =>
+                f.write_str("Failed to set up JSON Web Token validation"),
+            AppError::Oidc(_) => f.write_str("Failed to set up OpenID Connect"),
+            AppError::NoDirectory => f.write_str("No directory to serve"),
+            AppError::ServerOptions(n, _) =>
+                write!(f, "Invalid options for server {}", n),
+            AppError::Tls(_) => f.write_str("Failed to set up TLS"),
+            AppError::Privileges(_) =>
+                f.write_str("Failed to drop privileges"),
+            AppError::Daemon(_) => f.write_str("Failed to run as a daemon"),
+            AppError::Sandbox(_) => f.write_str("Failed to set up sandbox"),
+            AppError::Service(_) => f.write_str("Failed to manage service"),
+            AppError::Sync(_) => f.write_str("Failed to sync"),
+            AppError::Runtime(_) => f.write_str("Failed to start runtime"),
+            AppError::Output(_) => f.write_str("Failed to write output"),
+        }
+    }
+}
+
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::BadAddress(e) => Some(e),
+            AppError::Bind(e) => Some(e),
+            AppError::ReadFile(_, e) => Some(e),
+            AppError::CreateDir(_, e) => Some(e),
+            AppError::OpenLog(_, e) => Some(e),
+            AppError::ConfigFile(_, e) => Some(e),
+            AppError::RewriteRules(_, e) => Some(e),
+            AppError::ListingTemplate(_, e) => Some(e),
+            AppError::Catalog(_, e) => Some(e),
+            AppError::Jwt(e) => Some(e),
+            AppError::Oidc(e) => Some(e),
+            AppError::ServerOptions(_, e) => Some(e),
+            AppError::Tls(e) => Some(e),
+            AppError::Privileges(e) => Some(e),
+            AppError::Daemon(e) => Some(e),
+            AppError::Sandbox(e) => Some(e),
+            AppError::Service(e) => Some(e),
+            AppError::Sync(e) => Some(e),
+            AppError::Runtime(e) => Some(e),
+            AppError::Output(e) => Some(e),
+            AppError::BadPort
+                | AppError::InvalidArgument(_)
+                | AppError::NoAuthMethod
+                | AppError::NoDirectory
+                => None,
+        }
+    }
+}
+
+/// Server settings and caches shared by all requests.
+struct Config {
+    sites: vhost::Sites,
+    search_limit: usize,
+    search_timeout: Duration,
+    content_index: Option<Arc<RwLock<index::ContentIndex>>>,
+    digest_header: bool,
+    checksums: checksum::Cache,
+    etag: etag::Policy,
+    /// Whether clients are told not to cache responses, which are sent
+    /// without validators.
+    no_cache: bool,
+    content_types: content_type::Overrides,
+    /// Whether the type of files is recognized from their content when it
+    /// cannot be told from their name.
+    sniff_content: bool,
+    /// Whether the charset of text files is detected from their content.
+    detect_charset: bool,
+    file_cache: Option<file_cache::FileCache>,
+    chunk_size: usize,
+    /// Maximum size of request lines and headers.
+    max_header_size: usize,
+    /// Maximum length of request URIs.
+    max_uri_length: usize,
+    /// Size up to which files are read through memory mappings.
+    mmap_threshold: Option<u64>,
+    redirects: bool,
+    /// Name of the only file served, when serving a single file.
+    single_file: Option<OsString>,
+    listings: bool,
+    negotiate_language: bool,
+    /// Lowercase tag of the language of files without language variant.
+    default_language: Option<String>,
+    listing_cache: Option<Arc<listing_cache::ListingCache>>,
+    listing_renderer: listing::Renderer,
+    /// Whether directories are listed before files.
+    directories_first: bool,
+    show_permissions: bool,
+    /// Cache of directory sizes shown on request.
+    disk_usage: Arc<disk_usage::DiskUsage>,
+    render_readme: bool,
+    /// Whether PUT and DELETE requests change the served directory.
+    writable: bool,
+    max_upload_size: Option<u64>,
+    upload_quota: Option<upload::Quota>,
+    upload_filter: Option<upload_filter::Filter>,
+    /// Webhook notified of changes of served directories.
+    webhook: Option<webhook::Notifier>,
+    /// Whether the JSON API managing files is enabled.
+    api: bool,
+    /// Staging directory of resumable uploads, if enabled.
+    tus: Option<tus::Tus>,
+    /// Where deleted and overwritten files are moved, if enabled.
+    trash: Option<trash::Trash>,
+    /// Log of requests that may change served directories.
+    audit: Option<audit::Log>,
+    /// Destination of dumped requests and responses, if enabled.
+    dump: Option<Arc<dump::Dumper>>,
+    /// Artificial latency and failures, if enabled.
+    faults: Option<fault::Faults>,
+    /// Path prefix of generated links and redirects, without trailing slash.
+    base_path: String,
+    catalog: i18n::Catalog,
+    /// Snapshots of served directories for the diff endpoint, if enabled.
+    diff: Option<Arc<diff::Snapshots>>,
+    /// Whether the feed of recently modified files is served.
+    feed: bool,
+    /// Whether a sitemap is generated for sites without one.
+    sitemap: bool,
+    /// `robots.txt` served for sites without one.
+    robots: Option<well_known::Robots>,
+    /// Whether the server is reached over HTTPS.
+    https: bool,
+    style: style::Style,
+    stdin: Option<stdin::StdinFile>,
+    max_downloads: Option<u64>,
+    downloads: Arc<downloads::Counter>,
+    lifetime: Arc<lifetime::Lifetime>,
+    telemetry: Option<telemetry::Exporter>,
+    stats: Option<stats::Stats>,
+    proxy: Option<proxy::Proxy>,
+    mirror: Option<mirror::Mirror>,
+    rewrites: Option<rewrite::Rules>,
+    extra_headers: Vec<(HeaderName, HeaderValue)>,
+    trusted_proxies: Vec<cidr::Cidr>,
+    ip_filter: ip_filter::IpFilter,
+    url_signer: Option<signing::UrlSigner>,
+    auth: auth::Authenticator,
+    certificates: Option<Arc<tls::Certificates>>,
+    access_rules: access::AccessRules,
+    access_files: bool,
+    log_requests: bool,
+    /// Whether the details of internal errors are sent to clients.
+    verbose_errors: bool,
+}
+
+impl Config {
+    /// Reads again the files the configuration was loaded from.
+    fn reload(&self) {
+        self.auth.reload();
+        if let Some(certificates) = &self.certificates {
+            if let Err(e) = certificates.reload() {
+                error!("Failed to reload certificates: {}", e);
+            }
+        }
+    }
+}
+
+/// Runs the program with the command-line arguments `args`, starting with the
+/// program name.
+fn run<I>(args: I) -> Result<(), AppError>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let args = args.into_iter().collect::<Vec<_>>();
+    let address_help = format!("IP address to listen on (repeatable, \
+        default: {}, or {} without IPv6 support)", DEFAULT_ADDRESS,
+        Ipv4Addr::UNSPECIFIED);
+    let port_help = format!("Port to listen on (default: {})", DEFAULT_PORT);
+    let search_limit_help = format!("Maximum number of search results \
+        (default: {})", DEFAULT_SEARCH_LIMIT);
+    let search_timeout_help = format!("Maximum duration of a search in \
+        seconds (default: {})", DEFAULT_SEARCH_TIMEOUT);
+    let redirect_http_help = format!("Listen for plain HTTP on this port and \
+        redirect to HTTPS (default: {})", DEFAULT_REDIRECT_HTTP_PORT);
+    let hsts_help = format!("Send a Strict-Transport-Security header with \
+        this max-age in seconds (default: {})", DEFAULT_HSTS_MAX_AGE);
+    let index_refresh_help = format!("Interval in seconds between rescans \
+        of the tree for content indexing (default: {})",
+        DEFAULT_INDEX_REFRESH);
+    let access_files_help = format!("Apply the allow, deny and require-auth \
+        directives of {} files to their directory and its subtree",
+        access_file::FILE_NAME);
+    let mut app = App::new(APP_NAME)
+        .version(APP_VERSION)
+        .author(APP_AUTHORS)
+        .about(APP_ABOUT)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("DIRECTORY")
+                .help("Directory to serve, or file to serve alone")
+                .required_unless_one(&["stdin", "config"])
+                .env("SERVEDIR_ROOT")
+        )
+        .arg(
+            Arg::with_name("vhost")
+                .help("Serve this directory instead of DIRECTORY to requests \
+                    for this host, e.g. example.com=./site (repeatable; \
+                    content search only covers DIRECTORY)")
+                .long("vhost")
+                .value_name("HOST=DIRECTORY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["stdin", "chroot"])
+        )
+        .arg(
+            Arg::with_name("proxy-fallback")
+                .help("Forward requests that do not resolve to a file, or \
+                    whose method is not GET or HEAD, to this HTTP server, \
+                    e.g. http://localhost:3000")
+                .long("proxy-fallback")
+                .value_name("URL")
+                .takes_value(true)
+                .conflicts_with("stdin")
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .help("Forward requests for this path prefix and the paths \
+                    below it to an HTTP server, without the prefix, e.g. \
+                    /api=http://localhost:4000 (repeatable)")
+                .long("proxy")
+                .value_name("PREFIX=URL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("stdin")
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .help("Fetch files missing from the served directory from \
+                    this HTTP server and keep them there as its caching \
+                    headers allow, within --max-upload-size and \
+                    --upload-quota, e.g. https://example.com/artifacts/")
+                .long("mirror")
+                .value_name("URL")
+                .takes_value(true)
+                .conflicts_with_all(&["stdin", "single-file",
+                    "proxy-fallback"])
+        )
+        .arg(
+            Arg::with_name("stdin")
+                .help("Serve the standard input at /NAME to a single client \
+                    instead of serving a directory")
+                .long("stdin")
+                .value_name("NAME")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("stdin-replay")
+                .help("Keep the standard input in memory and serve it to \
+                    every client")
+                .long("stdin-replay")
+                .requires("stdin")
+        )
+        .arg(
+            Arg::with_name("config")
+                .help("TOML configuration file declaring access rules")
+                .short("c")
+                .long("config")
+                .env("SERVEDIR_CONFIG")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("negotiate-language")
+                .help("Serve the variant of files in the language preferred \
+                    by clients, e.g. page.fr.html for page.html, according \
+                    to Accept-Language")
+                .long("negotiate-language")
+        )
+        .arg(
+            Arg::with_name("default-language")
+                .help("Language of files without language in their name, \
+                    served to clients preferring it to the variants, e.g. en")
+                .long("default-language")
+                .value_name("TAG")
+                .takes_value(true)
+                .requires("negotiate-language")
+        )
+        .arg(
+            Arg::with_name("rewrites")
+                .help("File of rules rewriting or redirecting request paths, \
+                    e.g. \"rewrite ^/old/(.*)$ /new/$1\" or \"redirect 301 \
+                    /blog https://blog.example.com\", applied in order \
+                    before serving")
+                .long("rewrites")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("address")
+                .help(&address_help)
+                .short("a")
+                .long("address")
+                .env("SERVEDIR_ADDRESS")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("listen")
+                .help("Address and port to listen on, e.g. 127.0.0.1:8080 or \
+                    [::1]:8080 (repeatable)")
+                .long("listen")
+                .env("SERVEDIR_LISTEN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("port")
+                .help(&port_help)
+                .short("p")
+                .long("port")
+                .env("SERVEDIR_PORT")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ipv6-only")
+                .help("Do not accept IPv4 connections on IPv6 addresses such \
+                    as ::")
+                .long("ipv6-only")
+        )
+        .arg(
+            Arg::with_name("reuse-port")
+                .help("Allow other processes to listen on the same port, \
+                    sharing incoming connections (SO_REUSEPORT)")
+                .long("reuse-port")
+        )
+        .arg(
+            Arg::with_name("tcp-nodelay")
+                .help("Send small responses without delay (TCP_NODELAY)")
+                .long("tcp-nodelay")
+        )
+        .arg(
+            Arg::with_name("tcp-keepalive")
+                .help("Probe idle connections after this long, e.g. 60s")
+                .long("tcp-keepalive")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("backlog")
+                .help("Maximum number of connections waiting to be accepted \
+                    (default: 128)")
+                .long("backlog")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .help("PEM file with the certificate chain to serve HTTPS, \
+                    read again when it changes and on SIGHUP")
+                .long("tls-cert")
+                .env("SERVEDIR_TLS_CERT")
+                .takes_value(true)
+                .requires("tls-key")
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .help("PEM file with the private key to serve HTTPS")
+                .long("tls-key")
+                .env("SERVEDIR_TLS_KEY")
+                .takes_value(true)
+                .requires("tls-cert")
+        )
+        .arg(
+            Arg::with_name("user-root")
+                .help("Serve this directory instead of DIRECTORY to this \
+                    authenticated user, who cannot access files outside of \
+                    it, e.g. alice=./drop/alice (repeatable)")
+                .long("user-root")
+                .value_name("USER=DIRECTORY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&["stdin", "chroot", "single-file"])
+        )
+        .arg(
+            Arg::with_name("vhost-cert")
+                .help("PEM certificate chain and private key files to serve \
+                    HTTPS to clients naming this host, instead of --tls-cert \
+                    and --tls-key, e.g. example.com=cert.pem,key.pem \
+                    (repeatable)")
+                .long("vhost-cert")
+                .value_name("HOST=CERT,KEY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("tls-cert")
+        )
+        .arg(
+            Arg::with_name("redirect-http")
+                .help(&redirect_http_help)
+                .long("redirect-http")
+                .takes_value(true)
+                .min_values(0)
+                .requires("tls-cert")
+        )
+        .arg(
+            Arg::with_name("hsts")
+                .help(&hsts_help)
+                .long("hsts")
+                .takes_value(true)
+                .min_values(0)
+                .requires("tls-cert")
+        )
+        .arg(
+            Arg::with_name("search-limit")
+                .help(&search_limit_help)
+                .long("search-limit")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("search-timeout")
+                .help(&search_timeout_help)
+                .long("search-timeout")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("index-content")
+                .help("Index the contents of text files for full-text search")
+                .long("index-content")
+        )
+        .arg(
+            Arg::with_name("index-refresh")
+                .help(&index_refresh_help)
+                .long("index-refresh")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("digest-header")
+                .help("Send the SHA-256 digest of files in a Repr-Digest \
+                    header")
+                .long("digest-header")
+        )
+        .arg(
+            Arg::with_name("digest-cache")
+                .help("Keep the digests of files, used by --etag hash and \
+                    --digest-header, in this file so that they are not \
+                    computed again after a restart")
+                .long("digest-cache")
+                .takes_value(true)
+                .conflicts_with("sandbox")
+        )
+        .arg(
+            Arg::with_name("content-type")
+                .help("Serve files whose name matches a pattern with this \
+                    content type, e.g. \"*.vtt=text/vtt\", or with this \
+                    charset, e.g. \"*.txt=charset=shift_jis\" (repeatable; \
+                    the first match applies)")
+                .long("content-type")
+                .value_name("PATTERN=TYPE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("sniff-content")
+                .help("Recognize the type of files whose name does not tell \
+                    it from their first bytes, serving text files as plain \
+                    text")
+                .long("sniff-content")
+        )
+        .arg(
+            Arg::with_name("detect-charset")
+                .help("Detect the charset of text files, e.g. Latin-1 or \
+                    Shift_JIS, from their byte order mark or content, \
+                    instead of assuming UTF-8")
+                .long("detect-charset")
+        )
+        .arg(
+            Arg::with_name("etag")
+                .help("How ETags are derived from files: from their inode, \
+                    size and modification time (default), from a digest of \
+                    their content, or from their size and modification time \
+                    as weak tags")
+                .long("etag")
+                .possible_values(&["inode", "hash", "weak"])
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-downloads")
+                .help("Number of times each file can be downloaded before \
+                    it becomes unavailable")
+                .long("max-downloads")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .help("Keep up to this amount of small file contents in \
+                    memory, e.g. 64M")
+                .long("cache-size")
+                .env("SERVEDIR_CACHE_SIZE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("no-cache")
+                .help("Tell clients not to store responses and send files \
+                    without validators, so browsers always get their latest \
+                    version, e.g. during development")
+                .long("no-cache")
+                .conflicts_with_all(&["cache-size", "listing-cache-ttl"])
+        )
+        .arg(
+            Arg::with_name("threads")
+                .help("Number of threads handling connections (default: \
+                    number of CPUs)")
+                .long("threads")
+                .env("SERVEDIR_THREADS")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("blocking-threads")
+                .help("Maximum number of threads performing file operations \
+                    (default: 100)")
+                .long("blocking-threads")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-header-size")
+                .help("Reject requests whose request line and headers are \
+                    larger than this, e.g. 16K (default: 64K, minimum: 8K)")
+                .long("max-header-size")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("max-uri-length")
+                .help("Reject requests whose URI is longer than this number \
+                    of bytes (default: 8192)")
+                .long("max-uri-length")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("chunk-size")
+                .help("Size of the chunks read from files, e.g. 256K \
+                    (default: 128K)")
+                .long("chunk-size")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("mmap-threshold")
+                .help("Read files up to this size through memory mappings, \
+                    e.g. 1M. Files must not be truncated while being served")
+                .long("mmap-threshold")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("listing-cache-ttl")
+                .help("Reuse directory listings for up to this long while \
+                    their directory is unchanged, e.g. 10s")
+                .long("listing-cache-ttl")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("listing-template")
+                .help("Handlebars template rendering directory listings, \
+                    receiving title, path, breadcrumbs, entries, search and \
+                    page")
+                .long("listing-template")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("flat-listing")
+                .help("List directories among files in alphabetical order \
+                    instead of before them")
+                .long("flat-listing")
+        )
+        .arg(
+            Arg::with_name("show-permissions")
+                .help("Show the permissions and owner of entries in listings")
+                .long("show-permissions")
+        )
+        .arg(
+            Arg::with_name("render-readme")
+                .help("Show the README.md or README.txt file of directories \
+                    below their listing")
+                .long("render-readme")
+        )
+        .arg(
+            Arg::with_name("base-path")
+                .help("Path prefix under which a reverse proxy exposes the \
+                    server, prepended to generated links and redirects")
+                .long("base-path")
+                .env("SERVEDIR_BASE_PATH")
+                .value_name("PATH")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("feed")
+                .help("Serve an Atom feed of the most recently modified files \
+                    below the directory given by the path query parameter at \
+                    /_feed.atom")
+                .long("feed")
+        )
+        .arg(
+            Arg::with_name("diff")
+                .help("Serve the files added, modified and removed since an \
+                    earlier state at /_api/diff, for incremental sync \
+                    clients")
+                .long("diff")
+        )
+        .arg(
+            Arg::with_name("sitemap")
+                .help("Serve a sitemap of the HTML files of the served \
+                    directory at /sitemap.xml, unless it has a sitemap.xml \
+                    file")
+                .long("sitemap")
+        )
+        .arg(
+            Arg::with_name("robots")
+                .help("robots.txt served when the served directory has none: \
+                    allow-all, disallow-all or a file to serve")
+                .long("robots")
+                .value_name("disallow-all|allow-all|FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("lang")
+                .help("Language of generated pages, e.g. en, fr, de or es, \
+                    instead of the language preferred by each client")
+                .long("lang")
+                .value_name("TAG")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("lang-catalog")
+                .help("TOML file with a table of messages of generated pages \
+                    per language tag, adding or replacing languages")
+                .long("lang-catalog")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("style")
+                .help("Stylesheet applied on top of the built-in one to \
+                    generated pages, read from a file or linked from an \
+                    http(s) URL")
+                .long("style")
+                .value_name("FILE|URL")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("theme")
+                .help("Color theme of generated pages, auto following the \
+                    preference of the browser")
+                .long("theme")
+                .possible_values(&["light", "dark", "auto"])
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("exit-after-idle")
+                .help("Exit once no request has been received for this long, \
+                    e.g. 30s or 10m")
+                .long("exit-after-idle")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("exit-after-requests")
+                .help("Exit after serving this number of requests")
+                .long("exit-after-requests")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("single-file")
+                .help("Disable directory listings so that only files are \
+                    served")
+                .long("single-file")
+        )
+        .arg(
+            Arg::with_name("no-redirects")
+                .help("Do not redirect to add or remove the trailing slash \
+                    of directory and file paths")
+                .long("no-redirects")
+        )
+        .arg(
+            Arg::with_name("secure-headers")
+                .help("Send security headers (X-Content-Type-Options, \
+                    Referrer-Policy, Content-Security-Policy and \
+                    X-Frame-Options) with every response")
+                .long("secure-headers")
+        )
+        .arg(
+            Arg::with_name("content-security-policy")
+                .help("Content-Security-Policy header value, overriding the \
+                    default of --secure-headers (empty to omit)")
+                .long("content-security-policy")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("referrer-policy")
+                .help("Referrer-Policy header value, overriding the default \
+                    of --secure-headers (empty to omit)")
+                .long("referrer-policy")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("frame-options")
+                .help("X-Frame-Options header value, overriding the default \
+                    of --secure-headers (empty to omit)")
+                .long("frame-options")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("trusted-proxy")
+                .help("Network of reverse proxies whose Forwarded, \
+                    X-Forwarded-For and X-Real-IP headers are trusted to \
+                    identify clients (repeatable)")
+                .long("trusted-proxy")
+                .env("SERVEDIR_TRUSTED_PROXY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("allow")
+                .help("Accept clients from this network (repeatable, rules \
+                    are evaluated in order with --deny and the first match \
+                    wins; if any --allow is given, unmatched clients are \
+                    rejected)")
+                .long("allow")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("deny")
+                .help("Reject clients from this network (repeatable)")
+                .long("deny")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("token")
+                .help("Require this token in an Authorization: Bearer \
+                    header (repeatable)")
+                .long("token")
+                .env("SERVEDIR_AUTH")
+                .hide_env_values(true)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .help("Require one of the tokens listed in this file, one \
+                    per line, in an Authorization: Bearer header; the file \
+                    is read again on SIGHUP")
+                .long("token-file")
+                .env("SERVEDIR_TOKEN_FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("htpasswd")
+                .help("Require HTTP Basic authentication with the users of \
+                    this Apache htpasswd file (bcrypt or SHA-1 hashes); the \
+                    file is read again when it changes")
+                .long("htpasswd")
+                .env("SERVEDIR_HTPASSWD")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("jwks-url")
+                .help("Require JSON Web Tokens in an Authorization: Bearer \
+                    header, signed with a key of the JSON Web Key Set at \
+                    this https:// URL, http:// URL of the local host or path; \
+                    the subject of tokens names users")
+                .long("jwks-url")
+                .env("SERVEDIR_JWKS_URL")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("jwt-issuer")
+                .help("Require JSON Web Tokens issued by this issuer")
+                .long("jwt-issuer")
+                .env("SERVEDIR_JWT_ISSUER")
+                .takes_value(true)
+                .requires("jwks-url")
+        )
+        .arg(
+            Arg::with_name("jwt-audience")
+                .help("Require JSON Web Tokens intended for this audience")
+                .long("jwt-audience")
+                .env("SERVEDIR_JWT_AUDIENCE")
+                .takes_value(true)
+                .requires("jwks-url")
+        )
+        .arg(
+            Arg::with_name("oidc-issuer")
+                .help("Redirect browsers to log in with this OpenID Connect \
+                    provider, reached at an https:// URL or an http:// URL \
+                    of the local host, when authentication is required; \
+                    users log out at /_logout")
+                .long("oidc-issuer")
+                .env("SERVEDIR_OIDC_ISSUER")
+                .takes_value(true)
+                .requires_all(&["oidc-client-id", "oidc-redirect-url"])
+        )
+        .arg(
+            Arg::with_name("oidc-client-id")
+                .help("Client ID registered with the OpenID Connect provider")
+                .long("oidc-client-id")
+                .env("SERVEDIR_OIDC_CLIENT_ID")
+                .takes_value(true)
+                .requires("oidc-issuer")
+        )
+        .arg(
+            Arg::with_name("oidc-client-secret")
+                .help("Client secret registered with the OpenID Connect \
+                    provider, if any")
+                .long("oidc-client-secret")
+                .env("SERVEDIR_OIDC_CLIENT_SECRET")
+                .hide_env_values(true)
+                .takes_value(true)
+                .requires("oidc-issuer")
+        )
+        .arg(
+            Arg::with_name("oidc-redirect-url")
+                .help("URL of this server the OpenID Connect provider sends \
+                    users back to, as registered with it, e.g. \
+                    https://files.example.com/_oidc/callback")
+                .long("oidc-redirect-url")
+                .env("SERVEDIR_OIDC_REDIRECT_URL")
+                .takes_value(true)
+                .requires("oidc-issuer")
+        )
+        .arg(
+            Arg::with_name("auth-for")
+                .help("Requests for which clients must authenticate unless \
+                    access rules say otherwise: write lets anyone download \
+                    files, read lets anyone upload them (default: all)")
+                .long("auth-for")
+                .env("SERVEDIR_AUTH_FOR")
+                .takes_value(true)
+                .possible_values(&["write", "read", "all"])
+        )
+        .arg(
+            Arg::with_name("auth-scheme")
+                .help("Scheme with which users of --htpasswd authenticate; \
+                    digest uses SHA-256 and needs htdigest entries \
+                    USER:servedir:HA1 (default: basic)")
+                .long("auth-scheme")
+                .takes_value(true)
+                .possible_values(&["basic", "digest"])
+                .requires("htpasswd")
+        )
+        .arg(
+            Arg::with_name("login-form")
+                .help("Redirect browsers to a login form checking users of \
+                    --htpasswd when authentication is required, instead of \
+                    the Basic prompt; users log out at /_logout")
+                .long("login-form")
+                .requires("htpasswd")
+        )
+        .arg(
+            Arg::with_name("enable-access-files")
+                .help(&access_files_help)
+                .long("enable-access-files")
+        )
+        .arg(
+            Arg::with_name("user")
+                .help("User to run as once listening sockets are bound")
+                .long("user")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("group")
+                .help("Group to run as once listening sockets are bound \
+                    (default: primary group of --user)")
+                .long("group")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("chroot")
+                .help("Make the served directory the root of the file system \
+                    once listening sockets are bound; files given by path \
+                    cannot be reloaded afterwards")
+                .long("chroot")
+        )
+        .arg(
+            Arg::with_name("writable")
+                .help("Accept PUT requests storing files and DELETE requests \
+                    removing files or empty directories")
+                .long("writable")
+                .conflicts_with_all(&["stdin", "single-file"])
+        )
+        .arg(
+            Arg::with_name("max-upload-size")
+                .help("Reject uploads, and do not cache mirrored files, \
+                    larger than this, e.g. 100M")
+                .long("max-upload-size")
+                .env("SERVEDIR_MAX_UPLOAD_SIZE")
+                .takes_value(true)
+                .requires("stores-files")
+        )
+        .arg(
+            Arg::with_name("upload-quota")
+                .help("Reject uploads, and do not cache mirrored files, that \
+                    would make the files of a served directory larger than \
+                    this in total, e.g. 10G")
+                .long("upload-quota")
+                .env("SERVEDIR_UPLOAD_QUOTA")
+                .takes_value(true)
+                .requires("stores-files")
+        )
+        .group(
+            ArgGroup::with_name("stores-files")
+                .args(&["writable", "mirror"])
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("upload-allow")
+                .help("Accept only uploads named after one of these \
+                    comma-separated patterns, e.g. \"*.png,*.pdf\", whose \
+                    content, when its type is recognized, also matches")
+                .long("upload-allow")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("upload-deny")
+                .help("Reject uploads named after one of these \
+                    comma-separated patterns, e.g. \"*.exe\", or whose \
+                    content is of a type that would match")
+                .long("upload-deny")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("webhook")
+                .help("Post a JSON event to this http:// URL whenever an \
+                    upload, deletion or other change modifies the served \
+                    directory")
+                .long("webhook")
+                .env("SERVEDIR_WEBHOOK")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("api")
+                .help("Accept requests under /_api/fs/ to create directories \
+                    and to move, copy and delete files and directories")
+                .long("api")
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("tus-dir")
+                .help("Accept resumable uploads with the tus protocol under \
+                    /_tus/, keeping partial uploads in this directory")
+                .long("tus-dir")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("trash-dir")
+                .help("Move deleted and overwritten files to this directory \
+                    instead of losing them, listing them under /_api/trash \
+                    and restoring them with /_api/trash/restore")
+                .long("trash-dir")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("audit-log")
+                .help("Append a JSON line to this file for each request that \
+                    may change files, before it is processed, and for each \
+                    change it makes and its response")
+                .long("audit-log")
+                .takes_value(true)
+                .requires("writable")
+        )
+        .arg(
+            Arg::with_name("dump-requests")
+                .help("Write the headers of requests and responses to this \
+                    file, or to the standard error if -, with credentials \
+                    redacted, to debug clients")
+                .long("dump-requests")
+                .value_name("FILE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("dump-body-limit")
+                .help("Also dump up to this many bytes of the body of each \
+                    request and response (default: 0)")
+                .long("dump-body-limit")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("dump-requests")
+        )
+        .arg(
+            Arg::with_name("delay")
+                .help("Delay responses by this duration, e.g. 200ms, to test \
+                    clients")
+                .long("delay")
+                .value_name("DURATION")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("delay-jitter")
+                .help("Delay responses by up to this additional duration, \
+                    picked at random")
+                .long("delay-jitter")
+                .value_name("DURATION")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("fail-rate")
+                .help("Answer this fraction of requests, picked at random, \
+                    with 503 Service Unavailable, e.g. 0.05, to test clients")
+                .long("fail-rate")
+                .value_name("RATE")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("fault-paths")
+                .help("Delay and fail only requests for URL paths matching \
+                    one of these comma-separated patterns, e.g. \
+                    \"/assets/**\"")
+                .long("fault-paths")
+                .value_name("PATTERNS")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("watch")
+                .help("Run the --exec command when files in this directory \
+                    change, e.g. to rebuild the served files from their \
+                    sources")
+                .long("watch")
+                .value_name("DIRECTORY")
+                .takes_value(true)
+                .requires("exec")
+                .conflicts_with_all(&["sandbox", "chroot"])
+        )
+        .arg(
+            Arg::with_name("exec")
+                .help("Shell command run when the server starts and when \
+                    files in the --watch directory change, e.g. \
+                    \"npm run build\"")
+                .long("exec")
+                .value_name("COMMAND")
+                .takes_value(true)
+                .requires("watch")
+        )
+        .arg(
+            Arg::with_name("sandbox")
+                .help("Restrict the process to reading the served directory, \
+                    or also changing it with --writable, and to the system \
+                    calls needed to serve it (Linux only)")
+                .long("sandbox")
+                .conflicts_with("mirror")
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .help("Log more details; may be repeated")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Log only warnings, or only errors if repeated")
+                .short("q")
+                .long("quiet")
+                .multiple(true)
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("Format of log lines")
+                .long("log-format")
+                .env("SERVEDIR_LOG_FORMAT")
+                .possible_values(&["text", "json"])
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .help("Print a line describing the addresses listened at, \
+                    e.g. to learn the port picked with --port 0, and the \
+                    options in effect once the server is ready, and log to \
+                    the standard error instead")
+                .long("print-config")
+                .possible_values(&["json"])
+                .takes_value(true)
+                .conflicts_with("daemon")
+        )
+        .arg(
+            Arg::with_name("check")
+                .help("Check the configuration, i.e. that the served \
+                    directories are readable, that files given as options \
+                    are valid and that the server could listen, and exit with \
+                    a report instead of serving")
+                .long("check")
+                .conflicts_with_all(&["daemon", "stdin"])
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .help("Run in the background, detached from the terminal")
+                .long("daemon")
+                .conflicts_with("stdin")
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .help("File to write the process ID of the daemon to")
+                .long("pid-file")
+                .takes_value(true)
+                .requires("daemon")
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .help("File to append the logs of the daemon to")
+                .long("log-file")
+                .takes_value(true)
+                .requires("daemon")
+        )
+        .arg(
+            Arg::with_name("otlp-endpoint")
+                .help("Export request spans to this OpenTelemetry collector \
+                    over OTLP/HTTP, e.g. http://localhost:4318")
+                .long("otlp-endpoint")
+                .env("SERVEDIR_OTLP_ENDPOINT")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("stats")
+                .help("Serve live statistics at /_stats")
+                .long("stats")
+        )
+        .arg(
+            Arg::with_name("log-requests")
+                .help("Log each request")
+                .long("log-requests")
+        )
+        .arg(
+            Arg::with_name("verbose-errors")
+                .help("Send the details of internal errors, such as the \
+                    messages of the operating system, to clients instead of \
+                    only logging them")
+                .long("verbose-errors")
+        )
+        .arg(
+            Arg::with_name("hide-error-details")
+                .help("Keep the details of internal errors from clients, as \
+                    is now the default (deprecated)")
+                .long("hide-error-details")
+                .conflicts_with("verbose-errors")
+        )
+        .arg(
+            Arg::with_name("url-signing-key")
+                .help("Secret key used to verify signed URLs")
+                .long("url-signing-key")
+                .env("SERVEDIR_URL_SIGNING_KEY")
+                .hide_env_values(true)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("signed-prefix")
+                .help("Path prefix requiring signed URLs when \
+                    --url-signing-key is set (repeatable, default: /)")
+                .long("signed-prefix")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("url-signing-key")
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Prints a signed URL granting temporary access to a \
+                    path")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("URL path to sign, e.g. /private/report.pdf")
+                        .required(true)
+                )
+                .arg(
+                    Arg::with_name("url-signing-key")
+                        .help("Secret key the server was started with")
+                        .long("url-signing-key")
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::with_name("expires")
+                        .help("Validity of the URL, e.g. 30m, 1h or 7d \
+                            (default: 1h)")
+                        .long("expires")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("max-downloads")
+                        .help("Number of downloads allowed through the URL")
+                        .long("max-downloads")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("base-url")
+                        .help("Server URL to prepend, e.g. \
+                            http://example.com:8080")
+                        .long("base-url")
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about("Mirrors a directory served with --diff by another \
+                    instance, downloading only the files that changed")
+                .arg(
+                    Arg::with_name("URL")
+                        .help("http:// URL of the remote directory, e.g. \
+                            http://example.com:8080")
+                        .required(true)
+                )
+                .arg(
+                    Arg::with_name("DIRECTORY")
+                        .help("Local directory to update")
+                        .required(true)
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .help("Remove local files missing from the remote \
+                            directory")
+                        .long("delete")
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .help("Bearer token to authenticate with")
+                        .long("token")
+                        .env("SERVEDIR_SYNC_TOKEN")
+                        .hide_env_values(true)
+                        .takes_value(true)
+                        .conflicts_with("user")
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .help("User name and password to authenticate with, \
+                            as USER:PASSWORD")
+                        .long("user")
+                        .env("SERVEDIR_SYNC_USER")
+                        .hide_env_values(true)
+                        .takes_value(true)
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("service")
+                .about("Manages the Windows service running the server")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("install")
+                        .about("Installs a service running the server with \
+                            the given arguments at boot")
+                        .setting(AppSettings::TrailingVarArg)
+                        .arg(
+                            Arg::with_name("ARGS")
+                                .help("Server arguments, with absolute paths, \
+                                    e.g. C:\\site --port 8080")
+                                .multiple(true)
+                                .allow_hyphen_values(true)
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("uninstall")
+                        .about("Stops and removes the service")
+                )
+                .subcommand(
+                    SubCommand::with_name("run")
+                        .about("Runs the server on behalf of the Service \
+                            Control Manager")
+                        .setting(AppSettings::TrailingVarArg)
+                        .arg(
+                            Arg::with_name("ARGS")
+                                .help("Server arguments")
+                                .multiple(true)
+                                .allow_hyphen_values(true)
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a shell completion script")
+                .arg(
+                    Arg::with_name("SHELL")
+                        .help("Shell to complete commands for")
+                        .required(true)
+                        .possible_values(&clap::Shell::variants())
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("man")
+                .about("Prints the man page")
+        );
+    let matches = app.clone().get_matches_from_safe(&args)
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed
+                | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(exit_code::USAGE)
+            }
+        });
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell = matches.value_of("SHELL").unwrap().parse().unwrap();
+        app.gen_completions_to(APP_NAME, shell, &mut io::stdout());
+        return Ok(())
+    }
+    if matches.subcommand_matches("man").is_some() {
+        return man::write(&app, io::stdout()).map_err(AppError::Output)
+    }
+    if let Some(matches) = matches.subcommand_matches("sign") {
+        return sign_url(matches)
+    }
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        return sync_dir(matches)
+    }
+    if let Some(matches) = matches.subcommand_matches("service") {
+        return manage_service(matches)
+    }
+    let log_format = matches.value_of("log-format")
+        .map_or(Some(logging::Format::Text), logging::Format::from_name)
+        .ok_or(AppError::InvalidArgument("log-format"))?;
+    logging::init(matches.occurrences_of("verbose") as i64
+        - matches.occurrences_of("quiet") as i64, log_format,
+        !matches.is_present("log-file"), matches.is_present("print-config"));
+    let check = matches.is_present("check");
+    let mut instances = server_options(&app, &matches, &args)?
+        .into_iter()
+        .map(|matches| Instance::prepare(matches, check))
+        .collect::<Result<Vec<_>, _>>()?;
+    let threads = thread_count(&matches, "threads")?;
+    let blocking_threads = thread_count(&matches, "blocking-threads")?;
+    let mut daemon = if matches.is_present("daemon") {
+        Some(daemon::detach(&daemon::Settings {
+            pid_file: matches.value_of("pid-file").map(Path::new),
+            log_file: matches.value_of("log-file").map(Path::new),
+        }).map_err(AppError::Daemon)?)
+    } else {
+        None
+    };
+    // Privileges are not dropped when checking, as nothing is served.
+    let chroot = matches.is_present("chroot") && !check;
+    if chroot && instances.len() > 1 {
+        return Err(AppError::InvalidArgument("chroot"))
+    }
+    if !check {
+        privileges::apply(&privileges::Settings {
+            user: matches.value_of("user"),
+            group: matches.value_of("group"),
+            chroot: if chroot {Some(&instances[0].dir)} else {None},
+        }).map_err(AppError::Privileges)?;
+    }
+    let sites = instances.iter_mut()
+        .map(|instance| instance.open_sites(chroot))
+        .collect::<Result<Vec<_>, _>>()?;
+    if check {
+        for (instance, sites) in instances.iter().zip(&sites) {
+            instance.check(sites)?;
+        }
+        return writeln!(io::stdout(), "Configuration is valid")
+            .map_err(AppError::Output)
+    }
+    if matches.is_present("sandbox") {
+        let roots = sites.iter().flat_map(vhost::Sites::roots)
+            .collect::<Vec<_>>();
+        let writable_dirs = instances.iter().zip(&sites)
+            .filter(|(instance, _)| instance.matches.is_present("writable"))
+            .flat_map(|(instance, sites)| sites.roots()
+                .chain(instance.matches.value_of("tus-dir").map(Path::new))
+                .chain(instance.matches.value_of("trash-dir").map(Path::new)))
+            .collect::<Vec<_>>();
+        let extra_files = instances.iter()
+            .flat_map(|instance| instance.matches.value_of("token-file")
+                .into_iter()
+                .chain(instance.matches.value_of("htpasswd"))
+                .chain(instance.matches.value_of("jwks-url")
+                    .filter(|url| !url.contains("://")))
+                .chain(instance.matches.value_of("tls-cert"))
+                .chain(instance.matches.value_of("tls-key"))
+                .map(Path::new)
+                .chain(instance.matches.values_of("vhost-cert").into_iter()
+                    .flatten()
+                    .filter_map(tls::HostCert::parse)
+                    .flat_map(|host| vec![host.cert, host.key])))
+            .collect::<Vec<_>>();
+        sandbox::apply(&roots, &writable_dirs, &extra_files)
+            .map_err(AppError::Sandbox)?;
+    }
+    let mut runtime = tokio::runtime::Builder::new();
+    if let Some(n) = threads {
+        runtime.core_threads(n);
+    }
+    if let Some(n) = blocking_threads {
+        runtime.blocking_threads(n);
+    }
+    let mut runtime = runtime.build().map_err(AppError::Runtime)?;
+    let print_config = matches.is_present("print-config");
+    let configs = instances.into_iter().zip(sites)
+        .map(|(instance, sites)| {
+            instance.serve(sites, &mut runtime, print_config)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let lifetimes = configs.iter()
+        .map(|config| config.lifetime.clone())
+        .collect::<Vec<_>>();
+    signals::handle(move || {
+        for lifetime in &lifetimes {
+            lifetime.shutdown();
+        }
+    }, move || {
+        info!("Configuration reload requested");
+        for config in &configs {
+            config.reload();
+        }
+    });
+    if let Some(daemon) = &mut daemon {
+        daemon.notify_ready();
+    }
+    runtime.shutdown_on_idle().wait().unwrap();
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Returns the options of each server declared in the configuration file,
+/// added to the options given on the command line, or only the latter if
+/// the file declares no server.
+fn server_options<'a>(app: &App<'a, '_>, matches: &clap::ArgMatches<'a>,
+    args: &[OsString]) -> Result<Vec<clap::ArgMatches<'a>>, AppError>
+{
+    let servers = match matches.value_of("config") {
+        Some(path) => config_file::ConfigFile::load(Path::new(path))
+            .and_then(|file| file.server_args())
+            .map_err(|e| AppError::ConfigFile(path.into(), e))?,
+        None => Vec::new(),
+    };
+    if servers.is_empty() {
+        if !matches.is_present("DIRECTORY") && !matches.is_present("stdin") {
+            return Err(AppError::NoDirectory)
+        }
+        return Ok(vec![matches.clone()])
+    }
+    // Options of servers override the single-valued options given on the
+    // command line.
+    let app = app.clone().setting(AppSettings::AllArgsOverrideSelf);
+    servers.into_iter().enumerate()
+        .map(|(i, server)| {
+            let args = args.iter().cloned().chain(server);
+            app.clone().get_matches_from_safe(args)
+                .map_err(|e| AppError::ServerOptions(i + 1, e))
+        })
+        .collect()
+}
+
+/// Server set up from its options, listening but not serving yet.
+struct Instance<'a> {
+    matches: clap::ArgMatches<'a>,
+    check: bool,
+    target: PathBuf,
+    dir: PathBuf,
+    single_file: Option<OsString>,
+    stdin: Option<stdin::StdinFile>,
+    endpoints: Vec<SocketAddr>,
+    redirect_endpoints: Vec<SocketAddr>,
+    incomings: Vec<listener::Listener>,
+    redirect_incomings: Vec<listener::Listener>,
+    https_port: u16,
+    certificates: Option<Arc<tls::Certificates>>,
+    access_rules: access::AccessRules,
+    rewrites: Option<rewrite::Rules>,
+    search_limit: usize,
+    search_timeout: u64,
+    index_refresh: u64,
+    checksums: checksum::Cache,
+    etag: etag::Policy,
+    no_cache: bool,
+    content_types: content_type::Overrides,
+    file_cache: Option<file_cache::FileCache>,
+    chunk_size: usize,
+    max_header_size: usize,
+    max_uri_length: usize,
+    mmap_threshold: Option<u64>,
+    listing_cache: Option<Arc<listing_cache::ListingCache>>,
+    listing_renderer: listing::Renderer,
+    max_upload_size: Option<u64>,
+    upload_quota: Option<upload::Quota>,
+    upload_filter: Option<upload_filter::Filter>,
+    webhook: Option<webhook::Endpoint>,
+    tus: Option<tus::Tus>,
+    trash: Option<trash::Trash>,
+    audit: Option<audit::Log>,
+    dump: Option<Arc<dump::Dumper>>,
+    faults: Option<fault::Faults>,
+    base_path: String,
+    catalog: i18n::Catalog,
+    robots: Option<well_known::Robots>,
+    style: style::Style,
+    max_downloads: Option<u64>,
+    exit_after_idle: Option<Duration>,
+    exit_after_requests: Option<u64>,
+    proxy: Option<proxy::Proxy>,
+    mirror: Option<mirror::Mirror>,
+    extra_headers: Vec<(HeaderName, HeaderValue)>,
+    trusted_proxies: Vec<cidr::Cidr>,
+    ip_filter: ip_filter::IpFilter,
+    url_signer: Option<signing::UrlSigner>,
+    auth: auth::Authenticator,
+}
+
+impl<'a> Instance<'a> {
+    /// Loads the files named in `matches` and binds the listeners of the
+    /// server. `check` is true if the server is only checked.
+    fn prepare(matches: clap::ArgMatches<'a>, check: bool)
+        -> Result<Instance<'a>, AppError>
+    {
+        let target =
+            PathBuf::from(matches.value_of("DIRECTORY").unwrap_or("."));
+        let stdin = match matches.value_of("stdin") {
+            Some(name) => {
+                let mut parts = Path::new(name).components();
+                match (parts.next(), parts.next()) {
+                    (Some(std::path::Component::Normal(_)), None) => {}
+                    _ => return Err(AppError::InvalidArgument("stdin")),
+                }
+                let replay = matches.is_present("stdin-replay");
+                Some(stdin::StdinFile::new(name, replay))
+            }
+            None => None,
+        };
+        let (dir, single_file) = if target.is_file() {
+            let dir = match target.parent() {
+                Some(dir) if dir != Path::new("") => dir.to_owned(),
+                _ => PathBuf::from("."),
+            };
+            (dir, target.file_name().map(OsStr::to_owned))
+        } else {
+            (target.clone(), None)
+        };
+        let config_file = match matches.value_of("config") {
+            Some(path) => config_file::ConfigFile::load(Path::new(path))
+                .map_err(|e| AppError::ConfigFile(path.into(), e))?,
+            None => Default::default(),
+        };
+        let access_rules = config_file.access_rules().map_err(|e| {
+            AppError::ConfigFile(matches.value_of("config").unwrap().into(), e)
+        })?;
+        let rewrites = matches.value_of("rewrites")
+            .map(|path| rewrite::Rules::load(Path::new(path))
+                .map_err(|e| AppError::RewriteRules(path.into(), e)))
+            .transpose()?;
+        let port = match matches.value_of("port") {
+            Some(p) => p.parse().map_err(|_| AppError::BadPort)?,
+            None => DEFAULT_PORT,
+        };
+        let mut endpoints = endpoints(&matches, DEFAULT_ADDRESS, port)?;
+        let search_limit = match matches.value_of("search-limit") {
+            Some(n) => n.parse()
+                .map_err(|_| AppError::InvalidArgument("search-limit"))?,
+            None => DEFAULT_SEARCH_LIMIT,
+        };
+        let search_timeout = match matches.value_of("search-timeout") {
+            Some(t) => t.parse()
+                .map_err(|_| AppError::InvalidArgument("search-timeout"))?,
+            None => DEFAULT_SEARCH_TIMEOUT,
+        };
+        let index_refresh = match matches.value_of("index-refresh") {
+            Some(t) => t.parse()
+                .ok()
+                .filter(|&t| t > 0)
+                .ok_or(AppError::InvalidArgument("index-refresh"))?,
+            None => DEFAULT_INDEX_REFRESH,
+        };
+        let host_certs = matches.values_of("vhost-cert").into_iter().flatten()
+            .map(|spec| tls::HostCert::parse(spec)
+                .ok_or(AppError::InvalidArgument("vhost-cert")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let certificates = match (matches.value_of("tls-cert"),
+            matches.value_of("tls-key"))
+        {
+            (Some(cert), Some(key)) => Some(tls::Certificates::load(
+                Path::new(cert), Path::new(key), &host_certs)
+                .map_err(AppError::Tls)?),
+            _ => None,
+        };
+        let redirect_http_port = match matches.values_of("redirect-http") {
+            Some(mut p) => Some(p.next()
+                .map_or(Ok(DEFAULT_REDIRECT_HTTP_PORT), |p| p.parse())
+                .map_err(|_| AppError::InvalidArgument("redirect-http"))?),
+            None => None,
+        };
+        let max_downloads = matches.value_of("max-downloads")
+            .map(|n| n.parse()
+                .map_err(|_| AppError::InvalidArgument("max-downloads")))
+            .transpose()?;
+        let file_cache = matches.value_of("cache-size")
+            .map(|size| parse_size(size)
+                .map(file_cache::FileCache::new)
+                .ok_or(AppError::InvalidArgument("cache-size")))
+            .transpose()?;
+        let chunk_size = match matches.value_of("chunk-size") {
+            Some(size) => parse_size(size)
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|&size| size > 0)
+                .ok_or(AppError::InvalidArgument("chunk-size"))?,
+            None => file_stream::DEFAULT_CHUNK_SIZE,
+        };
+        let max_header_size = match matches.value_of("max-header-size") {
+            Some(size) => parse_size(size)
+                .and_then(|size| usize::try_from(size).ok())
+                .filter(|&size| size >= MIN_MAX_HEADER_SIZE)
+                .ok_or(AppError::InvalidArgument("max-header-size"))?,
+            None => DEFAULT_MAX_HEADER_SIZE,
+        };
+        let max_uri_length = match matches.value_of("max-uri-length") {
+            Some(len) => len.parse::<usize>().ok()
+                .filter(|&len| len > 0)
+                .ok_or(AppError::InvalidArgument("max-uri-length"))?,
+            None => DEFAULT_MAX_URI_LENGTH,
+        };
+        let mmap_threshold = matches.value_of("mmap-threshold")
+            .map(|size| parse_size(size)
+                .ok_or(AppError::InvalidArgument("mmap-threshold")))
+            .transpose()?;
+        let max_upload_size = matches.value_of("max-upload-size")
+            .map(|size| parse_size(size)
+                .ok_or(AppError::InvalidArgument("max-upload-size")))
+            .transpose()?;
+        let upload_quota = matches.value_of("upload-quota")
+            .map(|size| parse_size(size)
+                .map(upload::Quota::new)
+                .ok_or(AppError::InvalidArgument("upload-quota")))
+            .transpose()?;
+        let upload_filter = if matches.is_present("upload-allow")
+            || matches.is_present("upload-deny")
+        {
+            let patterns = |name| matches.value_of(name)
+                .map(upload_filter::patterns)
+                .unwrap_or(Ok(Vec::new()))
+                .map_err(|_| AppError::InvalidArgument(name));
+            Some(upload_filter::Filter::new(patterns("upload-allow")?,
+                patterns("upload-deny")?))
+        } else {
+            None
+        };
+        let webhook = matches.value_of("webhook")
+            .map(|url| webhook::Endpoint::parse(url)
+                .map_err(|_| AppError::InvalidArgument("webhook")))
+            .transpose()?;
+        let tus = matches.value_of("tus-dir")
+            .map(|dir| tus::Tus::new(dir.into())
+                .map_err(|e| AppError::CreateDir(dir.into(), e)))
+            .transpose()?;
+        let trash = matches.value_of("trash-dir")
+            .map(|dir| trash::Trash::new(dir.into())
+                .map_err(|e| AppError::CreateDir(dir.into(), e)))
+            .transpose()?;
+        let audit = matches.value_of("audit-log")
+            .map(|path| audit::Log::open(Path::new(path))
+                .map_err(|e| AppError::OpenLog(path.into(), e)))
+            .transpose()?;
+        let dump_body_limit = matches.value_of("dump-body-limit")
+            .map_or(Some(0), |limit| limit.parse().ok())
+            .ok_or(AppError::InvalidArgument("dump-body-limit"))?;
+        let dump = matches.value_of("dump-requests")
+            .map(|dest| dump::Dumper::open(Path::new(dest), dump_body_limit)
+                .map_err(|e| AppError::OpenLog(dest.into(), e)))
+            .transpose()?;
+        let faults = if matches.is_present("delay")
+            || matches.is_present("delay-jitter")
+            || matches.is_present("fail-rate")
+        {
+            let duration = |name| matches.value_of(name)
+                .map_or(Some(Duration::ZERO), parse_duration)
+                .ok_or(AppError::InvalidArgument(name));
+            let fail_rate = matches.value_of("fail-rate")
+                .map_or(Some(0.0), |rate| rate.parse().ok())
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or(AppError::InvalidArgument("fail-rate"))?;
+            let paths = matches.value_of("fault-paths")
+                .map(upload_filter::patterns)
+                .unwrap_or(Ok(Vec::new()))
+                .map_err(|_| AppError::InvalidArgument("fault-paths"))?;
+            Some(fault::Faults::new(duration("delay")?,
+                duration("delay-jitter")?, fail_rate, paths))
+        } else {
+            None
+        };
+        let listing_cache = matches.value_of("listing-cache-ttl")
+            .map(|ttl| parse_duration(ttl)
+                .map(|ttl| Arc::new(listing_cache::ListingCache::new(ttl)))
+                .ok_or(AppError::InvalidArgument("listing-cache-ttl")))
+            .transpose()?;
+        let listing_renderer = match matches.value_of("listing-template") {
+            Some(path) => {
+                let source = std::fs::read_to_string(path)
+                    .map_err(|e| AppError::ReadFile(path.into(), e))?;
+                listing::Renderer::template(&source)
+                    .map_err(|e| AppError::ListingTemplate(path.into(), e))?
+            }
+            None => listing::Renderer::BuiltIn,
+        };
+        let checksums = match matches.value_of("digest-cache") {
+            Some(path) => checksum::Cache::persistent(path.into())
+                .map_err(|e| AppError::ReadFile(path.into(), e))?,
+            None => checksum::Cache::default(),
+        };
+        let mut content_types = content_type::Overrides::default();
+        for spec in matches.values_of("content-type").into_iter().flatten() {
+            if !content_types.add(spec) {
+                return Err(AppError::InvalidArgument("content-type"))
+            }
+        }
+        let no_cache = matches.is_present("no-cache");
+        let etag = matches.value_of("etag")
+            .map_or(Some(etag::Policy::Inode), etag::Policy::from_name)
+            .ok_or(AppError::InvalidArgument("etag"))?;
+        let theme = matches.value_of("theme")
+            .map_or(Some(style::Theme::Light), style::Theme::from_name)
+            .ok_or(AppError::InvalidArgument("theme"))?;
+        let extra_style = match matches.value_of("style") {
+            Some(url) if style::Extra::is_url(url) =>
+                Some(style::Extra::Link(url.to_owned())),
+            Some(path) => Some(std::fs::read_to_string(path)
+                .map(style::Extra::Inline)
+                .map_err(|e| AppError::ReadFile(path.into(), e))?),
+            None => None,
+        };
+        let style = style::Style::new(theme, extra_style);
+        let robots = matches.value_of("robots")
+            .map(|value| well_known::Robots::load(value)
+                .map_err(|e| AppError::ReadFile(value.into(), e)))
+            .transpose()?;
+        let mut catalog = i18n::Catalog::default();
+        if let Some(path) = matches.value_of("lang-catalog") {
+            catalog.load(Path::new(path))
+                .map_err(|e| AppError::Catalog(path.into(), e))?;
+        }
+        if let Some(tag) = matches.value_of("lang") {
+            if !catalog.fix(tag) {
+                return Err(AppError::InvalidArgument("lang"))
+            }
+        }
+        let base_path = match matches.value_of("base-path") {
+            Some(path) if path.starts_with('/') && !path.contains(['?', '#']) =>
+                path.trim_end_matches('/').to_owned(),
+            Some(_) => return Err(AppError::InvalidArgument("base-path")),
+            None => String::new(),
+        };
+        let exit_after_idle = matches.value_of("exit-after-idle")
+            .map(|d| parse_duration(d)
+                .ok_or(AppError::InvalidArgument("exit-after-idle")))
+            .transpose()?;
+        let exit_after_requests = matches.value_of("exit-after-requests")
+            .map(|n| n.parse()
+                .map_err(|_| AppError::InvalidArgument("exit-after-requests")))
+            .transpose()?;
+        let mut extra_headers = security_headers(&matches)?;
+        if let Some(mut a) = matches.values_of("hsts") {
+            let max_age = a.next().map_or(Ok(DEFAULT_HSTS_MAX_AGE), |a| {
+                a.parse().map_err(|_| AppError::InvalidArgument("hsts"))
+            })?;
+            extra_headers.push((http::header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&format!("max-age={}", max_age))
+                    .unwrap()));
+        }
+        let listener_options = listener::Options {
+            reuse_port: matches.is_present("reuse-port"),
+            ipv6_only: matches.is_present("ipv6-only"),
+            nodelay: matches.is_present("tcp-nodelay"),
+            keepalive: matches.value_of("tcp-keepalive")
+                .map(|d| parse_duration(d)
+                    .filter(|d| d.as_secs() > 0)
+                    .ok_or(AppError::InvalidArgument("tcp-keepalive")))
+                .transpose()?,
+            backlog: matches.value_of("backlog")
+                .map(|n| n.parse().ok().filter(|&n| n > 0)
+                    .ok_or(AppError::InvalidArgument("backlog")))
+                .transpose()?,
+        };
+        let proxy = if matches.is_present("proxy-fallback")
+            || matches.is_present("proxy")
+        {
+            let mut proxy = proxy::Proxy::new(certificates.is_some());
+            if let Some(url) = matches.value_of("proxy-fallback") {
+                proxy.set_fallback(proxy::Backend::parse(url)
+                    .ok_or(AppError::InvalidArgument("proxy-fallback"))?);
+            }
+            for spec in matches.values_of("proxy").into_iter().flatten() {
+                let mut parts = spec.splitn(2, '=');
+                let prefix = parts.next().filter(|p| p.starts_with('/'));
+                let backend = parts.next().and_then(proxy::Backend::parse);
+                match (prefix, backend) {
+                    (Some(prefix), Some(backend)) =>
+                        proxy.mount(prefix, backend),
+                    _ => return Err(AppError::InvalidArgument("proxy")),
+                }
+            }
+            Some(proxy)
+        } else {
+            None
+        };
+        let mirror = matches.value_of("mirror")
+            .map(|url| mirror::Mirror::parse(url)
+                .ok_or(AppError::InvalidArgument("mirror")))
+            .transpose()?;
+        let scheme = scheme(certificates.is_some());
+        let default_address = !matches.is_present("listen")
+            && !matches.is_present("address");
+        let mut incomings = Vec::new();
+        for endpoint in &mut endpoints {
+            let incoming = match listener::Listener::bind(endpoint,
+                &listener_options)
+            {
+                // The default address requires IPv6 support.
+                Err(_) if default_address => {
+                    *endpoint = (Ipv4Addr::UNSPECIFIED, port).into();
+                    listener::Listener::bind(endpoint, &listener_options)
+                }
+                res => res,
+            };
+            let incoming = incoming.map_err(AppError::Bind)?;
+            // The system picks the port when 0 is given.
+            *endpoint = incoming.local_addr().map_err(AppError::Bind)?;
+            incomings.push(incoming);
+            let url = endpoint_url(scheme, endpoint);
+            let reach = match endpoint.ip() {
+                ip if !ip.is_unspecified() => "",
+                IpAddr::V4(_) => " (all IPv4 addresses)",
+                IpAddr::V6(_) if listener_options.ipv6_only =>
+                    " (all IPv6 addresses)",
+                IpAddr::V6(_) => " (all IPv4 and IPv6 addresses)",
+            };
+            match &stdin {
+                _ if check => {}
+                Some(stdin) => info!("Serving standard input at {}{}{}", url,
+                    stdin.path().display().to_string().trim_start_matches('/'),
+                    reach),
+                None =>
+                    info!("Serving {} at {}{}", target.display(), url, reach),
+            }
+        }
+        let mut redirect_incomings = Vec::new();
+        let mut redirect_endpoints = Vec::new();
+        if let Some(redirect_port) = redirect_http_port {
+            let mut addresses = endpoints.iter()
+                .map(|e| e.ip())
+                .collect::<Vec<_>>();
+            addresses.sort();
+            addresses.dedup();
+            for address in addresses {
+                let incoming = listener::Listener::bind(
+                    &(address, redirect_port).into(), &listener_options)
+                    .map_err(AppError::Bind)?;
+                let redirect_endpoint = incoming.local_addr()
+                    .map_err(AppError::Bind)?;
+                redirect_incomings.push(incoming);
+                if !check {
+                    info!("Redirecting HTTP on {} to HTTPS", redirect_endpoint);
+                }
+                redirect_endpoints.push(redirect_endpoint);
+            }
+        }
+        let https_port = endpoints[0].port();
+        let trusted_proxies = values_of(&matches, "trusted-proxy")
+            .map(|net| net.parse()
+                .map_err(|_| AppError::InvalidArgument("trusted-proxy")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ip_filter = ip_filter::IpFilter::new(ip_rules(&matches)?);
+        let url_signer = matches.value_of("url-signing-key").map(|key| {
+            let prefixes = matches.values_of("signed-prefix").into_iter()
+                .flatten()
+                .map(absolute_url_path)
+                .collect();
+            signing::UrlSigner::new(key, prefixes)
+        });
+        let mut auth = auth::Authenticator::default();
+        if matches.is_present("token") || matches.is_present("token-file") {
+            let fixed = values_of(&matches, "token")
+                .map(str::to_owned)
+                .collect();
+            let file = matches.value_of("token-file").map(PathBuf::from);
+            let tokens = auth::BearerTokens::new(fixed, file.clone())
+                .map_err(|e| AppError::ReadFile(file.unwrap_or_default(), e))?;
+            auth.tokens = Some(tokens);
+        }
+        if let Some(path) = matches.value_of("htpasswd") {
+            let path = Path::new(path);
+            auth.htpasswd = Some(auth::Htpasswd::open(path)
+                .map_err(|e| AppError::ReadFile(path.to_owned(), e))?);
+        }
+        let auth_scheme = matches.value_of("auth-scheme")
+            .map_or(Some(auth::Scheme::Basic), auth::Scheme::parse)
+            .ok_or(AppError::InvalidArgument("auth-scheme"))?;
+        if auth_scheme == auth::Scheme::Digest {
+            auth.digest = Some(auth::Nonces::new());
+        }
+        if let Some(url) = matches.value_of("jwks-url") {
+            let issuer = matches.value_of("jwt-issuer").map(str::to_owned);
+            let audience = matches.value_of("jwt-audience").map(str::to_owned);
+            auth.jwt = Some(jwt::Validator::new(url, issuer, audience)
+                .map_err(AppError::Jwt)?);
+        }
+        let oidc_issuer = matches.value_of("oidc-issuer");
+        auth.login_form = matches.is_present("login-form");
+        if oidc_issuer.is_some() || auth.login_form {
+            let secure = certificates.is_some()
+                || matches.value_of("oidc-redirect-url")
+                    .is_some_and(|url| url.starts_with("https://"));
+            auth.sessions = Some(Arc::new(session::Sessions::new(&base_path,
+                secure)));
+        }
+        if let (Some(issuer), Some(sessions)) = (oidc_issuer, &auth.sessions) {
+            auth.oidc = Some(oidc::Provider::discover(issuer,
+                matches.value_of("oidc-client-id").unwrap(),
+                matches.value_of("oidc-client-secret"),
+                matches.value_of("oidc-redirect-url").unwrap(),
+                &base_path, sessions.clone()).map_err(AppError::Oidc)?);
+        }
+        auth.scope = matches.value_of("auth-for")
+            .map_or(Some(auth::Scope::All), auth::Scope::parse)
+            .ok_or(AppError::InvalidArgument("auth-for"))?;
+        let auth_required = access_rules.require_auth()
+            || matches.is_present("user-root")
+            || matches.is_present("auth-for");
+        if auth_required && !auth.is_enabled() {
+            return Err(AppError::NoAuthMethod)
+        }
+        Ok(Instance {
+            matches,
+            check,
+            target,
+            dir,
+            single_file,
+            stdin,
+            endpoints,
+            redirect_endpoints,
+            incomings,
+            redirect_incomings,
+            https_port,
+            certificates,
+            access_rules,
+            rewrites,
+            search_limit,
+            search_timeout,
+            index_refresh,
+            checksums,
+            etag,
+            no_cache,
+            content_types,
+            file_cache,
+            chunk_size,
+            max_header_size,
+            max_uri_length,
+            mmap_threshold,
+            listing_cache,
+            listing_renderer,
+            max_upload_size,
+            upload_quota,
+            upload_filter,
+            webhook,
+            tus,
+            trash,
+            audit,
+            dump,
+            faults,
+            base_path,
+            catalog,
+            robots,
+            style,
+            max_downloads,
+            exit_after_idle,
+            exit_after_requests,
+            proxy,
+            mirror,
+            extra_headers,
+            trusted_proxies,
+            ip_filter,
+            url_signer,
+            auth,
+        })
+    }
+
+    /// Opens the served directories, once the process is confined to the
+    /// first one if `chroot` is true.
+    fn open_sites(&mut self, chroot: bool) -> Result<vhost::Sites, AppError> {
+        if chroot {
+            self.dir = PathBuf::from("/");
+        }
+        let (matches, dir, check) = (&self.matches, &self.dir, self.check);
+        let single_file = &self.single_file;
+        let mut sites = vhost::Sites::new(vhost::Site::open(dir.clone())
+            .map_err(|e| AppError::ReadFile(dir.clone(), e))?);
+        for spec in matches.values_of("vhost").into_iter().flatten() {
+            let (host, root) = vhost::parse(spec)
+                .filter(|(_, root)| root.is_dir() && single_file.is_none())
+                .ok_or(AppError::InvalidArgument("vhost"))?;
+            if !check {
+                info!("Serving {} for {}", root.display(), host);
+            }
+            sites.insert(host, vhost::Site::open(root.clone())
+                .map_err(|e| AppError::ReadFile(root, e))?);
+        }
+        for spec in matches.values_of("user-root").into_iter().flatten() {
+            let (user, root) = vhost::parse(spec)
+                .filter(|(_, root)| root.is_dir())
+                .ok_or(AppError::InvalidArgument("user-root"))?;
+            if !check {
+                info!("Serving {} to user {}", root.display(), user);
+            }
+            sites.insert_user(user, vhost::Site::open(root.clone())
+                .map_err(|e| AppError::ReadFile(root, e))?);
+        }
+        Ok(sites)
+    }
+
+    /// Reports the configuration of the server checked with `--check`.
+    fn check(&self, sites: &vhost::Sites) -> Result<(), AppError> {
+        let scheme = scheme(self.certificates.is_some());
+        let endpoints = self.endpoints.iter()
+            .map(|e| endpoint_url(scheme, e))
+            .chain(self.redirect_endpoints.iter()
+                .map(|e| endpoint_url("HTTP", e)))
+            .collect::<Vec<_>>();
+        let file = self.single_file.as_ref().map(|_| self.target.as_path());
+        check_config(&self.matches, sites, file, &endpoints)
+    }
+
+    /// Serves `sites` with `runtime`, and describes the server on the
+    /// standard output if `print_config` is true.
+    fn serve(self, sites: vhost::Sites, runtime: &mut tokio::runtime::Runtime,
+        print_config: bool) -> Result<Arc<Config>, AppError>
+    {
+        let Instance {
+            matches,
+            single_file,
+            stdin,
+            endpoints,
+            redirect_endpoints,
+            incomings,
+            redirect_incomings,
+            https_port,
+            certificates,
+            access_rules,
+            rewrites,
+            search_limit,
+            search_timeout,
+            index_refresh,
+            checksums,
+            etag,
+            no_cache,
+            content_types,
+            file_cache,
+            chunk_size,
+            max_header_size,
+            max_uri_length,
+            mmap_threshold,
+            listing_cache,
+            listing_renderer,
+            max_upload_size,
+            upload_quota,
+            upload_filter,
+            webhook,
+            tus,
+            trash,
+            audit,
+            dump,
+            faults,
+            base_path,
+            catalog,
+            robots,
+            style,
+            max_downloads,
+            exit_after_idle,
+            exit_after_requests,
+            proxy,
+            mirror,
+            extra_headers,
+            trusted_proxies,
+            ip_filter,
+            url_signer,
+            auth,
+            ..
+        } = self;
+        let tls_config = certificates.clone().map(tls::server_config);
+        if let Some(quota) = &upload_quota {
+            info!("Measuring the size of the served directories");
+            sites.roots().for_each(|root| quota.measure(root));
+            if let Some(tus) = &tus {
+                tus.reserve_pending(quota);
+            }
+        }
+        let content_index = if matches.is_present("index-content") {
+            info!("Indexing file contents");
+            Some(index::ContentIndex::spawn(sites.default_site().clone(),
+                Duration::from_secs(index_refresh)))
+        } else {
+            None
+        };
+        let telemetry = matches.value_of("otlp-endpoint")
+            .map(|endpoint| telemetry::Exporter::new(endpoint)
+                .map_err(|_| AppError::InvalidArgument("otlp-endpoint")))
+            .transpose()?;
+        let webhook = webhook.map(webhook::Notifier::start);
+        checksums.start_saving();
+        if let Some(stdin) = &stdin {
+            stdin.start_reading();
+        }
+        if let Some(jwt) = &auth.jwt {
+            jwt.start_refreshing();
+        }
+        if let Some(oidc) = &auth.oidc {
+            oidc.start_refreshing();
+        }
+        if let Some(sources) = matches.value_of("watch") {
+            let command = matches.value_of("exec").unwrap().to_owned();
+            let roots = sites.roots().collect::<Vec<_>>();
+            watch::spawn(Path::new(sources), command, &roots)
+                .map_err(|e| AppError::ReadFile(sources.into(), e))?;
+        }
+        let (lifetime, term_receiver) =
+            lifetime::Lifetime::new(exit_after_requests);
+        let config = Arc::new(Config {
+            sites,
+            search_limit,
+            search_timeout: Duration::from_secs(search_timeout),
+            content_index,
+            digest_header: matches.is_present("digest-header"),
+            checksums,
+            etag,
+            no_cache,
+            content_types,
+            sniff_content: matches.is_present("sniff-content"),
+            detect_charset: matches.is_present("detect-charset"),
+            file_cache,
+            chunk_size,
+            max_header_size,
+            max_uri_length,
+            mmap_threshold,
+            redirects: !matches.is_present("no-redirects"),
+            single_file,
+            listings: !matches.is_present("single-file"),
+            negotiate_language: matches.is_present("negotiate-language"),
+            default_language: matches.value_of("default-language")
+                .map(str::to_ascii_lowercase),
+            listing_cache,
+            listing_renderer,
+            directories_first: !matches.is_present("flat-listing"),
+            show_permissions: matches.is_present("show-permissions"),
+            disk_usage: Arc::new(disk_usage::DiskUsage::new(
+                Duration::from_secs(search_timeout))),
+            render_readme: matches.is_present("render-readme"),
+            writable: matches.is_present("writable"),
+            max_upload_size,
+            upload_quota,
+            upload_filter,
+            webhook,
+            api: matches.is_present("api"),
+            tus,
+            trash,
+            audit,
+            dump,
+            faults,
+            base_path,
+            catalog,
+            diff: Some(Arc::default())
+                .filter(|_| matches.is_present("diff")),
+            feed: matches.is_present("feed"),
+            sitemap: matches.is_present("sitemap"),
+            robots,
+            https: tls_config.is_some(),
+            style,
+            stdin,
+            max_downloads,
+            downloads: Default::default(),
+            lifetime: Arc::new(lifetime),
+            telemetry,
+            stats: if matches.is_present("stats") {
+                Some(Default::default())
+            } else {
+                None
+            },
+            proxy,
+            mirror,
+            rewrites,
+            extra_headers,
+            trusted_proxies,
+            ip_filter,
+            url_signer,
+            auth,
+            certificates,
+            access_rules,
+            access_files: matches.is_present("enable-access-files"),
+            log_requests: matches.is_present("log-requests"),
+            verbose_errors: matches.is_present("verbose-errors"),
+        });
+        if let Some(limit) = exit_after_idle {
+            config.lifetime.exit_when_idle(limit);
+        }
+        let term_receiver = term_receiver.then(|_| {
+            info!("Graceful shutdown requested");
+            Ok::<(), ()>(())
+        }).shared();
+        let shutdown = move || term_receiver.clone().then(|_| Ok::<(), ()>(()));
+        let mut servers = Vec::<Box<dyn Future<Item = (), Error = ()> + Send>>
+            ::new();
+        for incoming in incomings {
+            let config = config.clone();
+            match &tls_config {
+                Some(tls_config) => {
+                    let incoming = tls::accept(incoming, tls_config.clone());
+                    let server = Server::builder(incoming)
+                        .http1_max_buf_size(max_header_size)
+                        .serve(make_service_fn(move |conn: &tls::Connection| {
+                            let peer = conn.get_ref().0.remote_addr();
+                            Ok::<_, io::Error>(new_service(&config, peer))
+                        }))
+                        .with_graceful_shutdown(shutdown());
+                    servers.push(Box::new(server.map_err(log_server_error)));
+                }
+                None => {
+                    let server = Server::builder(incoming)
+                        .http1_max_buf_size(max_header_size)
+                        .serve(make_service_fn(move |conn| {
+                            let conn: &listener::Connection = conn;
+                            let peer = conn.remote_addr();
+                            Ok::<_, io::Error>(new_service(&config, peer))
+                        }))
+                        .with_graceful_shutdown(shutdown());
+                    servers.push(Box::new(server.map_err(log_server_error)));
+                }
+            }
+        }
+        for incoming in redirect_incomings {
+            let server = Server::builder(incoming)
+                .serve(move || service_fn(move |req| {
+                    tls::redirect_to_https(https_port, &req)
+                }))
+                .with_graceful_shutdown(shutdown());
+            servers.push(Box::new(server.map_err(log_server_error)));
+        }
+        runtime.spawn(future::join_all(servers).map(|_| ()));
+        if print_config {
+            let scheme = scheme(tls_config.is_some());
+            let endpoints = endpoints.iter()
+                .map(|&address| banner::Endpoint {
+                    address,
+                    url: endpoint_url(scheme, &address),
+                })
+                .collect::<Vec<_>>();
+            banner::print(&config, &endpoints, &redirect_endpoints,
+                tls_config.is_some()).map_err(AppError::Output)?;
+        }
+        Ok(config)
+    }
+}
+
+fn scheme(tls: bool) -> &'static str {
+    if tls {"HTTPS"} else {"HTTP"}
+}
+
+/// Options naming files that are loaded at startup, with their description.
+const CHECKED_FILES: &[(&str, &str)] = &[
+    ("config", "Configuration file"),
+    ("rewrites", "Rewrite rules"),
+    ("tls-cert", "TLS certificate"),
+    ("tls-key", "TLS private key"),
+    ("htpasswd", "Password file"),
+    ("token-file", "Token file"),
+    ("listing-template", "Listing template"),
+    ("lang-catalog", "Language catalog"),
+    ("digest-cache", "Digest cache"),
+];
+
+/// Checks that the served directories, and the file served if `file` is
+/// given, can be read, then reports the configuration checked by `--check`.
+fn check_config(matches: &clap::ArgMatches, sites: &vhost::Sites,
+    file: Option<&Path>, endpoints: &[String]) -> Result<(), AppError>
+{
+    let mut report = Vec::new();
+    for root in sites.roots() {
+        std::fs::read_dir(root)
+            .map_err(|e| AppError::ReadFile(root.to_owned(), e))?;
+        report.push(format!("Directory {} is readable", root.display()));
+    }
+    if let Some(file) = file {
+        File::open(file).map_err(|e| AppError::ReadFile(file.to_owned(), e))?;
+        report.push(format!("File {} is readable", file.display()));
+    }
+    for (name, description) in CHECKED_FILES {
+        for path in matches.values_of(name).into_iter().flatten() {
+            report.push(format!("{} {} is valid", description, path));
+        }
+    }
+    for spec in matches.values_of("vhost-cert").into_iter().flatten() {
+        report.push(format!("Certificate {} is valid", spec));
+    }
+    for endpoint in endpoints {
+        report.push(format!("Listening at {} is possible", endpoint));
+    }
+    let mut out = io::stdout().lock();
+    for line in &report {
+        writeln!(out, "ok: {}", line).map_err(AppError::Output)?;
+    }
+    Ok(())
+}
+
+fn log_server_error(e: hyper::Error) {
+    error!("Server error: {}", e);
+}
+
+/// Returns the methods accepted by the server, as listed in `Allow` headers.
+fn allowed_methods(config: &Config) -> &'static str {
+    if config.writable {
+        "GET, HEAD, OPTIONS, PUT, DELETE"
+    } else {
+        "GET, HEAD, OPTIONS"
+    }
+}
+
+/// Returns the security headers selected on the command line.
+fn security_headers(matches: &clap::ArgMatches)
+    -> Result<Vec<(HeaderName, HeaderValue)>, AppError>
+{
+    let style_src = matches.value_of("style")
+        .filter(|style| style::Extra::is_url(style))
+        .map_or_else(String::new, |url| format!(" {}", url));
+    let csp = format!("default-src 'self'; \
+        style-src 'self' 'unsafe-inline'{}; script-src 'self' '{}'",
+        style_src, script_hash(listing::FILTER_SCRIPT));
+    let defaults = [
+        ("content-security-policy", http::header::CONTENT_SECURITY_POLICY,
+            csp.as_str()),
+        ("referrer-policy", http::header::REFERRER_POLICY, "no-referrer"),
+        ("frame-options", http::header::X_FRAME_OPTIONS, "SAMEORIGIN"),
+    ];
+    let enabled = matches.is_present("secure-headers");
+    let mut headers = Vec::new();
+    if enabled {
+        headers.push((http::header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff")));
+    }
+    for (arg, name, default) in defaults.iter().cloned() {
+        let value = match matches.value_of(arg) {
+            Some("") => continue,
+            Some(value) => HeaderValue::from_str(value)
+                .map_err(|_| AppError::InvalidArgument(arg))?,
+            None if enabled => HeaderValue::from_str(default).unwrap(),
+            None => continue,
+        };
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+/// Returns the CSP source allowing an inline script.
+fn script_hash(script: &str) -> String {
+    use base64::Engine;
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(script.as_bytes());
+    format!("sha256-{}", base64::engine::general_purpose::STANDARD
+        .encode(digest))
+}
+
+/// Runs the `sign` subcommand.
+fn sign_url(matches: &clap::ArgMatches) -> Result<(), AppError> {
+    let signer = signing::UrlSigner::new(
+        matches.value_of("url-signing-key").unwrap(), Vec::new());
+    let validity = match matches.value_of("expires") {
+        Some(d) => parse_duration(d)
+            .ok_or(AppError::InvalidArgument("expires"))?,
+        None => Duration::from_secs(3600),
+    };
+    let expires = signing::unix_time_now() + validity.as_secs();
+    let path = absolute_url_path(matches.value_of("PATH").unwrap());
+    let max_downloads = matches.value_of("max-downloads")
+        .map(|n| n.parse()
+            .map_err(|_| AppError::InvalidArgument("max-downloads")))
+        .transpose()?;
+    let base_url = matches.value_of("base-url").unwrap_or("");
+    println!("{}{}", base_url.trim_end_matches('/'),
+        signer.sign(&path, expires, max_downloads));
+    Ok(())
+}
+
+/// Runs the `sync` subcommand.
+fn sync_dir(matches: &clap::ArgMatches) -> Result<(), AppError> {
+    use base64::Engine;
+    let authorization = match (matches.value_of("token"),
+        matches.value_of("user"))
+    {
+        (Some(token), _) => Some(format!("Bearer {}", token)),
+        (None, Some(user)) => Some(format!("Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(user))),
+        (None, None) => None,
+    };
+    let dir = Path::new(matches.value_of_os("DIRECTORY").unwrap());
+    let summary = sync::run(matches.value_of("URL").unwrap(), dir,
+        authorization.as_deref(), matches.is_present("delete"))
+        .map_err(AppError::Sync)?;
+    writeln!(io::stdout(), "Downloaded {} files ({}), {} unchanged, {} \
+        removed", summary.downloaded, pretty_size(summary.bytes),
+        summary.unchanged, summary.removed).map_err(AppError::Output)
+}
+
+/// Runs the `service` subcommand.
+fn manage_service(matches: &clap::ArgMatches) -> Result<(), AppError> {
+    let server_args = |matches: &clap::ArgMatches| matches.values_of_os("ARGS")
+        .map_or_else(Vec::new, |args| args.map(OsStr::to_owned).collect());
+    match matches.subcommand() {
+        ("install", Some(matches)) => service::install(server_args(matches)),
+        ("uninstall", _) => service::uninstall(),
+        ("run", Some(matches)) => service::run(server_args(matches)),
+        _ => unreachable!(),
+    }.map_err(AppError::Service)
+}
+
+/// Interprets a command-line path as an absolute URL path.
+fn absolute_url_path(path: &str) -> PathBuf {
+    Path::new("/").join(path)
+}
+
+/// Parses a duration made of a number and an optional unit among `ms`, `s`,
+/// `m`, `h` and `d`. Seconds are assumed without unit.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n = n.parse::<u64>().ok()?;
+    let secs = match unit {
+        "ms" => return Some(Duration::from_millis(n)),
+        "" | "s" => n,
+        "m" => n.checked_mul(60)?,
+        "h" => n.checked_mul(3600)?,
+        "d" => n.checked_mul(86400)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+/// Returns the endpoints to listen on, from `--listen` and from the
+/// addresses given with `--address` combined with `port`. `address` is used
+/// if no endpoint is given.
+/// Returns the values of the repeatable option `name`, taken from the command
+/// line or else from its environment variable. clap adds the value of the
+/// variable to the values given on the command line, which take precedence.
+fn values_of<'a>(matches: &'a clap::ArgMatches, name: &str)
+    -> impl Iterator<Item = &'a str>
+{
+    let given = match matches.occurrences_of(name) {
+        0 => usize::MAX,
+        n => n as usize,
+    };
+    matches.values_of(name).into_iter().flatten().take(given)
+}
+
+fn endpoints(matches: &clap::ArgMatches, address: IpAddr, port: u16)
+    -> Result<Vec<SocketAddr>, AppError>
+{
+    let mut endpoints = values_of(matches, "listen")
+        .map(|e| e.parse().map_err(|_| AppError::InvalidArgument("listen")))
+        .collect::<Result<Vec<SocketAddr>, _>>()?;
+    for a in values_of(matches, "address") {
+        endpoints.push((a.parse::<IpAddr>().map_err(AppError::BadAddress)?,
+            port).into());
+    }
+    if endpoints.is_empty() {
+        endpoints.push((address, port).into());
+    }
+    Ok(endpoints)
+}
+
+/// Returns the root URL of a server listening on `endpoint`.
+fn endpoint_url(scheme: &str, endpoint: &SocketAddr) -> String {
+    format!("{}://{}/", scheme.to_ascii_lowercase(), endpoint)
+}
+
+/// Maximum number of threads of each kind supported by the runtime.
+const MAX_THREADS: usize = 1 << 15;
+
+/// Returns the thread count given as `arg`, if any.
+fn thread_count(matches: &clap::ArgMatches, arg: &'static str)
+    -> Result<Option<usize>, AppError>
+{
+    matches.value_of(arg)
+        .map(|n| n.parse()
+            .ok()
+            .filter(|n| (1..=MAX_THREADS).contains(n))
+            .ok_or(AppError::InvalidArgument(arg)))
+        .transpose()
+}
+
+/// Parses a size made of a number and an optional unit among `K`, `M` and
+/// `G`, as powers of 1024. Bytes are assumed without unit.
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n = n.parse::<u64>().ok()?;
+    let shift = match unit {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return None,
+    };
+    n.checked_mul(1 << shift)
+}
+
+/// Returns the `--allow` and `--deny` rules in command-line order.
+fn ip_rules(matches: &clap::ArgMatches)
+    -> Result<Vec<ip_filter::Rule>, AppError>
+{
+    let mut rules = Vec::new();
+    for &(arg, allow) in &[("allow", true), ("deny", false)] {
+        let values = matches.values_of(arg).into_iter().flatten();
+        let indices = matches.indices_of(arg).into_iter().flatten();
+        for (net, index) in values.zip(indices) {
+            let net = net.parse().map_err(|_| AppError::InvalidArgument(arg))?;
+            let rule = if allow {
+                ip_filter::Rule::Allow(net)
+            } else {
+                ip_filter::Rule::Deny(net)
+            };
+            rules.push((index, rule));
+        }
+    }
+    rules.sort_by_key(|&(index, _)| index);
+    Ok(rules.into_iter().map(|(_, rule)| rule).collect())
+}
+
+type ServerFuture<T> = Box<dyn Future<Item = T, Error = http::Error> + Send>;
+
+/// Returns the service handling requests on a connection from `peer`.
+fn new_service(config: &Arc<Config>, peer: SocketAddr) -> impl Service<
+    ReqBody = Body,
+    ResBody = Body,
+    Error = http::Error,
+    Future = ServerFuture<Response<Body>>,
+> {
+    let config = config.clone();
+    let connection = config.stats.as_ref().map(|stats| stats.connection());
+    service_fn(move |req| {
+        let _ = &connection;
+        handle_request(config.clone(), peer, req)
+    })
+}
+
+/// Authenticates a request and processes it.
+fn handle_request(config: Arc<Config>, peer: SocketAddr,
+    request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    // Password hashes are slow to compute on purpose.
+    if config.auth.hashes_password(&request) {
+        let auth_config = config.clone();
+        let authenticated = blocking(move || {
+            let user = auth_config.auth.authenticate(&request);
+            (request, user)
+        });
+        return Box::new(authenticated.then(move |res| match res {
+            Ok((request, user)) =>
+                handle_authenticated_request(config, peer, request, user),
+            Err(e) => io_error(e),
+        }))
+    }
+    let user = if config.auth.is_enabled() {
+        config.auth.authenticate(&request)
+    } else {
+        None
+    };
+    handle_authenticated_request(config, peer, request, user)
+}
+
+/// Processes a request from `user` and adds the headers common to all
+/// responses.
+fn handle_authenticated_request(config: Arc<Config>, peer: SocketAddr,
+    mut request: Request<Body>, user: Option<auth::User>)
+    -> ServerFuture<Response<Body>>
+{
+    config.lifetime.on_request();
+    let client = forwarded::client_ip(&config.trusted_proxies, peer.ip(),
+        request.headers());
+    let request_id = logging::request_id(request.headers());
+    let span = info_span!("request", id = %request_id, %client);
+    let _entered = span.enter();
+    if let Some(dumper) = &config.dump {
+        dumper.request(&request_id, &mut request);
+    }
+    let request_line = format!("{} {}", request.method(), request.uri());
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let trace = config.telemetry.as_ref().map(|exporter| {
+        exporter.start(request.method().as_str(), request.uri().path(), client,
+            request.headers())
+    });
+    let user_name = user.as_ref().and_then(|u| u.name.clone());
+    let actor = audit::Actor {
+        request: request_id.clone(),
+        user: user_name.clone(),
+        client,
+    };
+    request.extensions_mut().insert(actor.clone());
+    if let Some(claims) = user.as_ref().and_then(|u| u.claims.clone()) {
+        request.extensions_mut().insert(claims);
+    }
+    let audited = config.audit.as_ref()
+        .filter(|_| audit::is_mutating(&method));
+    let logged = audited.map_or(Ok(()), |audit| {
+        let size = request.headers().get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        audit.request(&actor, &method, &path, size)
+    });
+    let errors = problem::Format::negotiate(request.headers());
+    let res = if !config.ip_filter.allows(client) {
+        forbidden()
+    } else if uri_len(request.uri()) > config.max_uri_length {
+        uri_too_long()
+    } else if head_len(&request) > config.max_header_size {
+        header_fields_too_large()
+    } else if let Err(e) = logged {
+        error!("Failed to write audit log: {}", e);
+        internal_server_error()
+    } else {
+        match config.faults.as_ref().filter(|faults| faults.applies(&path)) {
+            Some(faults) => faults.inject(|| process_request(&config, client,
+                user.is_some(), request)),
+            None => process_request(&config, client, user.is_some(), request),
+        }
+    };
+    let res = res.map(move |res| {
+        let mut res = problem::render(&config, errors, &path, res);
+        let headers = res.headers_mut();
+        for (name, value) in &config.extra_headers {
+            headers.insert(name.clone(), value.clone());
+        }
+        if let Ok(id) = HeaderValue::from_str(&request_id) {
+            headers.insert(logging::REQUEST_ID_HEADER, id);
+        }
+        if config.no_cache {
+            headers.insert(http::header::CACHE_CONTROL,
+                HeaderValue::from_static("no-store"));
+        }
+        if config.log_requests {
+            info!(user = user_name.as_deref().unwrap_or("-"),
+                status = res.status().as_u16(), "{}", request_line);
+        }
+        if let Some(audit) = config.audit.as_ref()
+            .filter(|_| audit::is_mutating(&method))
+        {
+            audit.response(&actor, &method, &path, res.status().as_u16());
+        }
+        // Wrapped bodies lose their length, which is kept in the headers.
+        if trace.is_some() || config.stats.is_some() || config.dump.is_some()
+        {
+            if let Some(len) = res.body().content_length() {
+                res.headers_mut().entry(http::header::CONTENT_LENGTH)
+                    .unwrap()
+                    .or_insert_with(|| HeaderValue::from(len));
+            }
+        }
+        let status = res.status().as_u16();
+        let res = match trace {
+            Some(trace) => {
+                res.headers_mut().insert("traceparent", trace.traceparent());
+                res.map(|body| trace.finish_with(status, body))
+            }
+            None => res,
+        };
+        let res = match &config.dump {
+            Some(dumper) => dumper.response(&request_id, res),
+            None => res,
+        };
+        match &config.stats {
+            Some(stats) => res.map(|body| stats.record(method.as_str(), &path,
+                status, body)),
+            None => res,
+        }
+    });
+    Box::new(res.instrument(span.clone()))
+}
+
+fn process_request(config: &Arc<Config>, client: IpAddr, authenticated: bool,
+    mut request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    if let Some(rules) = &config.rewrites {
+        match rules.apply(request.uri().path(), request.uri().query()) {
+            Some(rewrite::Outcome::Rewrite(target)) => match target.parse() {
+                Ok(uri) => *request.uri_mut() = uri,
+                Err(_) => return bad_request(),
+            },
+            Some(rewrite::Outcome::Redirect(status, location)) =>
+                return redirect_with(status, &location),
+            None => {}
+        }
     }
+    route_request(config, client, authenticated, request)
 }
 
-fn print_error(mut e: &dyn Error) {
-    eprintln!("Error: {}", e);
-    while let Some(cause) = e.source() {
-        eprintln!("Because: {}", cause);
-        e = cause;
+/// Processes `request` once its path is rewritten.
+fn route_request(config: &Arc<Config>, client: IpAddr, authenticated: bool,
+    request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    if let Some(oidc) = &config.auth.oidc {
+        if oidc.is_callback(request.uri().path()) {
+            return oidc.callback(&request)
+        }
+    }
+    if config.auth.sessions.is_some() {
+        if request.uri().path() == session::LOGOUT_PATH {
+            return login::log_out(config)
+        } else if config.auth.login_form
+            && request.uri().path() == login::LOGIN_PATH
+        {
+            return login::handle(config, request)
+        }
+    }
+    let site = config.sites.select(&request);
+    let fallback = config.proxy.as_ref()
+        .and_then(|proxy| Some((proxy, proxy.fallback()?)));
+    let route = config.proxy.as_ref()
+        .and_then(|proxy| Some((proxy, proxy.route(&request)?)));
+    let forwarded = route.is_some() || fallback.is_some()
+        && !matches!(*request.method(), Method::GET | Method::HEAD);
+    if !config.writable && !forwarded && has_body(request.headers()) {
+        return problem::Problem::new(StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body not accepted").respond()
+    }
+    match *request.method() {
+        Method::GET | Method::HEAD => {}
+        Method::PUT | Method::DELETE if config.writable => {}
+        _ if config.tus.is_some() && tus::is_endpoint(request.uri().path())
+            => {}
+        Method::POST if config.api
+            && request.uri().path().starts_with(fs_api::ENDPOINT) => {}
+        Method::POST if config.trash.is_some()
+            && fs_api::is_trash_endpoint(request.uri().path()) => {}
+        // Other methods are forwarded once access is granted.
+        _ if fallback.is_some() || route.is_some() => {}
+        Method::OPTIONS => return send_options(config),
+        _ => return method_not_allowed(config),
+    }
+    let root = &site.root;
+    let req_path = match url_path::decode(request.uri().path()) {
+        Some(p) => p,
+        None => return bad_request(),
+    };
+    let req_path = req_path.as_path();
+    let resource = match sanitize_path(req_path) {
+        Some(p) => p,
+        None => return bad_request(),
+    };
+    let resource = match &config.single_file {
+        Some(name) if resource == Path::new("") || resource == Path::new(name)
+            => Path::new(name),
+        Some(_) => return io_error(io::ErrorKind::NotFound.into()),
+        None => resource,
+    };
+    match config.access_rules.check(req_path, client, authenticated,
+        request.extensions().get(), config.auth.is_required(request.method()))
+    {
+        access::Decision::Allow => {}
+        access::Decision::Deny => return forbidden(),
+        access::Decision::RequireAuth => return require_auth(config, &request),
+    }
+    if let Some(stdin) = &config.stdin {
+        return stdin.send(&request, req_path, &config.content_types)
+    }
+    if let Some((proxy, (backend, path))) = route {
+        return proxy.forward(backend, &path, client, request)
+    }
+    if let Some((proxy, backend)) = fallback {
+        match *request.method() {
+            Method::GET | Method::HEAD => {}
+            _ => return forward_unchanged(proxy, backend, client, request),
+        }
+    }
+    if config.access_files {
+        if resource.file_name() == Some(OsStr::new(access_file::FILE_NAME)) {
+            return forbidden()
+        }
+        match access_file::check(root, resource, client, authenticated) {
+            access::Decision::Allow => {}
+            access::Decision::RequireAuth if config.auth.is_enabled() =>
+                return require_auth(config, &request),
+            access::Decision::Deny | access::Decision::RequireAuth =>
+                return forbidden(),
+        }
+    }
+    if let Some(tus) = &config.tus {
+        if tus::is_endpoint(request.uri().path()) {
+            return tus::handle(config, tus, site, client, authenticated,
+                request)
+        }
+    }
+    let api = config.api && request.uri().path().starts_with(fs_api::ENDPOINT)
+        || config.trash.is_some()
+            && fs_api::is_trash_endpoint(request.uri().path());
+    if api {
+        return fs_api::handle(config, client, authenticated, request)
+    }
+    if config.writable
+        && matches!(*request.method(), Method::PUT | Method::DELETE)
+    {
+        return upload::handle(config, site, resource, client, authenticated,
+            request)
+    }
+    if request.uri().path() == search::ENDPOINT {
+        return search::send_results(config, site, client, authenticated,
+            &request)
+    }
+    if let Some(snapshots) = &config.diff {
+        if request.uri().path() == diff::ENDPOINT {
+            return snapshots.send(config, site, client, authenticated,
+                &request)
+        }
+    }
+    if config.feed && request.uri().path() == feed::ENDPOINT {
+        return feed::send(config, site, client, authenticated, &request)
+    }
+    if config.sitemap && request.uri().path() == sitemap::ENDPOINT
+        && sitemap::is_generated(site)
+    {
+        return sitemap::send(config, site, client, authenticated, &request)
+    }
+    if let Some(res) = well_known::send(site, config.robots.as_ref(), &request)
+    {
+        return res
+    }
+    if let Some(stats) = &config.stats {
+        if request.uri().path() == stats::ENDPOINT {
+            return stats.send(&request, config.file_cache.as_ref(),
+                &config.style)
+        }
+    }
+    let verification = match &config.url_signer {
+        Some(signer) => signer.verify(req_path, request.uri().query()),
+        None => signing::Verification::Unprotected,
+    };
+    let download_limit = match verification {
+        signing::Verification::Invalid => return forbidden(),
+        signing::Verification::Valid {signature, max_downloads: Some(max)} =>
+            Some((signature, max)),
+        _ => config.max_downloads
+            .map(|max| (req_path.to_string_lossy().into_owned(), max)),
+    };
+    let path = root.join(resource.components().collect::<PathBuf>());
+    if !path.starts_with(root) {return bad_request()}
+    let mirror = config.mirror.as_ref()
+        .filter(|mirror| mirror.applies(&request));
+    let fetch = |mirror: &mirror::Mirror, request| {
+        let serve_config = config.clone();
+        mirror.fetch(config, root, resource, &path, request, move |request|
+            route_request(&serve_config, client, authenticated, request))
+    };
+    if let Some(mirror) = mirror.filter(|mirror| mirror.is_stale(&path)) {
+        return fetch(mirror, request)
+    }
+    let file = match site.root_dir.open_file(resource) {
+        Ok(file) => file,
+        Err(e) => match (fallback, mirror) {
+            (Some((proxy, backend)), _)
+                if e.kind() == io::ErrorKind::NotFound =>
+                return forward_unchanged(proxy, backend, client, request),
+            (_, Some(mirror)) if e.kind() == io::ErrorKind::NotFound =>
+                return fetch(mirror, request),
+            _ => return io_error(e),
+        },
+    };
+    let meta = match file.metadata() {
+        Ok(meta) => meta,
+        Err(e) => return io_error(e),
+    };
+    if config.redirects && config.single_file.is_none()
+        && resource != Path::new("")
+    {
+        // Built from the sanitized path rather than the request's, which
+        // may start with `//` and would then name another host.
+        let uri_path = request.uri().path();
+        let target = config.base_path.clone() + &url_path::encode(resource);
+        let location = if meta.is_dir() && !uri_path.ends_with('/') {
+            Some(target + "/")
+        } else if !meta.is_dir() && uri_path.ends_with('/') {
+            Some(target)
+        } else {
+            None
+        };
+        if let Some(mut location) = location {
+            if let Some(query) = request.uri().query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            return redirect(&location)
+        }
+    }
+    if meta.is_dir() && !config.listings {
+        io_error(io::ErrorKind::NotFound.into())
+    } else if meta.is_dir() {
+        let readme = Some(resource).filter(|_| config.render_readme)
+            .and_then(|dir| readme::find(&site.root_dir, dir));
+        send_dir(config, site, &request, resource, &meta, req_path, readme)
+    } else if let Some(algorithm) = query_param(&request, "checksum") {
+        send_checksum(config, path, file, meta, &algorithm)
+    } else {
+        let download = query_param(&request, "download")
+            .is_some_and(|v| v != "0");
+        if !config.negotiate_language {
+            return send_file(config, request.headers(), path, file, meta,
+                download, download_limit)
+        }
+        let variant = language::select(&site.root_dir, resource,
+            config.default_language.as_deref(), request.headers());
+        let (res, language) = match variant {
+            Some(variant) => (send_file(config, request.headers(),
+                root.join(&variant.resource), variant.file, variant.meta,
+                download, download_limit), Some(variant.language)),
+            None => (send_file(config, request.headers(), path, file, meta,
+                download, download_limit), config.default_language.clone()),
+        };
+        Box::new(res.map(move |mut res| {
+            let headers = res.headers_mut();
+            headers.insert(http::header::VARY,
+                HeaderValue::from_static("Accept-Language"));
+            if let Some(language) = language {
+                if let Ok(language) = HeaderValue::from_str(&language) {
+                    headers.insert(http::header::CONTENT_LANGUAGE, language);
+                }
+            }
+            res
+        }))
     }
 }
 
-#[derive(Debug)]
-enum AppError {
-    BadAddress(AddrParseError),
-    BadPort,
+/// Returns the scheme, host and base path under which `request` reached the
+/// server, to build absolute URLs.
+fn request_origin(config: &Config, request: &Request<Body>) -> String {
+    let host = request.headers().get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().authority_part().map(|a| a.as_str()))
+        .unwrap_or("localhost");
+    format!("{}://{}{}", if config.https {"https"} else {"http"}, host,
+        config.base_path)
 }
 
-impl fmt::Display for AppError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            AppError::BadAddress(_) => f.write_str("Invalid address"),
-            AppError::BadPort => f.write_str("Invalid port"),
-        }
-    }
+/// Returns the value of a query parameter of the request.
+fn query_param(request: &Request<Body>, name: &str) -> Option<String> {
+    let query = request.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
 }
 
-impl Error for AppError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        match self {
-            AppError::BadAddress(e) => Some(e),
-            AppError::BadPort => None,
-        }
-    }
+/// Returns the path relative to the served root designated by an absolute
+/// request path, or `None` if it could escape the root.
+fn sanitize_path(req_path: &Path) -> Option<&Path> {
+    let resource = req_path.strip_prefix("/").ok()?;
+    let goes_up = resource.components().any(|part| matches!(part,
+        std::path::Component::ParentDir
+            | std::path::Component::Prefix(_)
+            | std::path::Component::RootDir
+    ));
+    if goes_up {None} else {Some(resource)}
 }
 
-fn run() -> Result<(), AppError> {
-    let mut address = IpAddr::from(Ipv4Addr::UNSPECIFIED);
-    let address_help = format!("IP address to listen on (default: {})",
-        address);
-    let mut port = 80_u16;
-    let port_help = format!("Port to listen on (default: {})", port);
-    let matches = App::new(APP_NAME)
-        .version(APP_VERSION)
-        .author(APP_AUTHORS)
-        .about("Serves a directory over HTTP")
-        .arg(
-            Arg::with_name("DIRECTORY")
-                .help("Directory to serve")
-                .required(true)
-        )
-        .arg(
-            Arg::with_name("address")
-                .help(&address_help)
-                .short("a")
-                .long("address")
-                .takes_value(true)
-        )
-        .arg(
-            Arg::with_name("port")
-                .help(&port_help)
-                .short("p")
-                .long("port")
-                .takes_value(true)
-        )
-        .get_matches();
-    let dir = PathBuf::from(matches.value_of("DIRECTORY").unwrap());
-    if let Some(a) = matches.value_of("address") {
-        address = a.parse().map_err(AppError::BadAddress)?;
+/// Forwards `request` to `backend` with its path and query.
+fn forward_unchanged(proxy: &proxy::Proxy, backend: &proxy::Backend,
+    client: IpAddr, request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    let path = request.uri().path_and_query()
+        .map_or_else(|| "/".to_owned(), |p| p.as_str().to_owned());
+    proxy.forward(backend, &path, client, request)
+}
+
+/// Responds with the listing of the directory `resource` of `site`. Unless
+/// listings are cached, the listing is streamed as it is rendered; cached
+/// listings are rendered on the blocking pool.
+fn send_dir(config: &Arc<Config>, site: &Arc<vhost::Site>,
+    request: &Request<Body>, resource: &Path, meta: &Metadata,
+    req_path: &Path, readme: Option<readme::Readme>)
+    -> ServerFuture<Response<Body>>
+{
+    let page = match Page::from_query(request) {
+        Some(page) => page,
+        None => return bad_request(),
+    };
+    let listing = listing::Listing {
+        path: req_path.to_owned(),
+        page,
+        content_search: config.content_index.is_some(),
+        readme,
+        base_path: config.base_path.clone(),
+        messages: config.catalog.select(request.headers()),
+        format: listing::Format::of(request),
+        show_permissions: config.show_permissions,
+        disk_usage: Some(config.disk_usage.clone())
+            .filter(|_| query_param(request, "du").is_some_and(|v| v != "0")),
+    };
+    let listing_format = listing.format;
+    let mut variant = match listing_format {
+        listing::Format::Html => listing.messages.tag(),
+        listing::Format::Text => "text",
+    }.to_owned();
+    if listing.disk_usage.is_some() {
+        variant.push_str("+du");
     }
-    if let Some(p) = matches.value_of("port") {
-        port = p.parse().map_err(|_| AppError::BadPort)?;
+    let mut res = Response::builder();
+    res.header(http::header::VARY, "Accept");
+    if config.catalog.negotiates() {
+        res.header(http::header::VARY, "Accept-Language");
     }
-    let endpoint = (address, port).into();
-    println!("Serving {} over HTTP on {}", dir.display(), endpoint);
-    let new_service = move || {
-        let root = dir.clone();
-        service_fn(move |req| process_request(&root, req))
-    };
-    let (term_sender, term_receiver) = futures::sync::oneshot::channel();
-    let term_sender = Cell::new(Some(term_sender));
-    let _ = ctrlc::set_handler(move || {
-        if let Some(sender) = term_sender.take() {
-            let _ = sender.send(());
+    if listing_format == listing::Format::Text {
+        res.header(http::header::CONTENT_TYPE,
+            mime::TEXT_PLAIN_UTF_8.to_string());
+    }
+    let (config, site) = (config.clone(), site.clone());
+    let resource = resource.to_owned();
+    let page: Box<dyn Future<Item = Body, Error = io::Error> + Send> =
+        match config.listing_cache.clone()
+    {
+        Some(cache) => {
+            let (req_path, meta) = (req_path.to_owned(), meta.clone());
+            Box::new(blocking(move || {
+                let path = site.root.join(&resource);
+                cache.get(&path, &req_path, page, &variant, &meta, || {
+                    let entries = site.root_dir.read_dir(&resource)?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let entries = listing::entries(&site, &resource,
+                        entries, &listing, config.access_files,
+                        config.directories_first);
+                    let mut out = Vec::new();
+                    config.listing_renderer.render(&listing, entries,
+                        &config.style, &mut out)?;
+                    Ok(out)
+                })
+            }).and_then(|page| page).map(Body::from))
         }
-    });
-    let term_receiver = term_receiver.then(|_| {
-        println!("Graceful shutdown requested");
-        Ok::<(), ()>(())
-    });
-    let server = Server::bind(&endpoint)
-        .serve(new_service)
-        .with_graceful_shutdown(term_receiver);
-    hyper::rt::run(server.map_err(|e| eprintln!("Server error: {}", e)));
-    Ok(())
+        None => Box::new(future::result(site.root_dir.read_dir(&resource)
+            .map(|entries| streaming::body(move |out| {
+                let entries = entries.filter_map(|entry| entry
+                    .map_err(|e| warn!("Failed to read directory: {}", e))
+                    .ok());
+                let entries = listing::entries(&site, &resource, entries,
+                    &listing, config.access_files, config.directories_first);
+                config.listing_renderer.render(&listing, entries,
+                    &config.style, out)
+            })))),
+    };
+    Box::new(page.then(move |page| match page {
+        Ok(page) => Box::new(future::result(res.body(page))),
+        Err(e) => io_error(e),
+    }))
 }
 
-type ServerFuture<T> = Box<dyn Future<Item = T, Error = http::Error> + Send>;
+fn send_file(config: &Arc<Config>, headers: &HeaderMap, path: PathBuf,
+    file: File, meta: Metadata, download: bool,
+    limit: Option<(String, u64)>)
+    -> ServerFuture<Response<Body>>
+{
+    let mut algorithms = Vec::new();
+    if config.etag == etag::Policy::Hash {
+        algorithms.push(checksum::Algorithm::Blake3);
+    }
+    if config.digest_header {
+        algorithms.push(checksum::Algorithm::Sha256);
+    }
+    let digested = config.checksums.fill(&path, &file, &meta, &algorithms);
+    let config = config.clone();
+    let headers = headers.clone();
+    Box::new(digested.then(move |res| match res {
+        Ok(()) => send_digested_file(&config, &headers, path, file, meta,
+            download, limit),
+        Err(e) => io_error(e),
+    }))
+}
 
-fn process_request(root: &Path, request: Request<Body>)
+/// Responds with a file whose digests are cached.
+fn send_digested_file(config: &Config, headers: &HeaderMap, path: PathBuf,
+    file: File, meta: Metadata, download: bool,
+    limit: Option<(String, u64)>)
     -> ServerFuture<Response<Body>>
 {
-    let req_path = percent_decode(request.uri().path().as_bytes());
-    let req_path = match req_path.decode_utf8() {
-        Ok(p) => p,
-        Err(_) => return bad_request(),
+    let etag = match config.etag.tag(&config.checksums, &path, &file, &meta) {
+        Ok(etag) => etag,
+        Err(e) => return io_error(e),
     };
-    let req_path = Path::new(&*req_path);
-    let resource = match req_path.strip_prefix("/") {
-        Ok(p) => p,
-        Err(_) => return bad_request(),
+    let validators = range::Validators::new(etag, &meta);
+    let ranges = match range::requested(headers, meta.len(), &validators) {
+        range::Requested::Full => Vec::new(),
+        range::Requested::Ranges(ranges) => ranges,
+        range::Requested::Unsatisfiable =>
+            return range_not_satisfiable(meta.len()),
     };
-    let goes_up = resource.components().any(|part| match part {
-        std::path::Component::ParentDir
-            | std::path::Component::Prefix(_)
-            | std::path::Component::RootDir
-            => true,
-        _ => false,
-    });
-    if goes_up {return bad_request()}
-    let path = root.join(resource);
-    if !path.starts_with(root) {return bad_request()}
-    let meta = match path.metadata() {
-        Ok(meta) => meta,
-        Err(e) => return io_error(e),
+    let bytes = if ranges.is_empty() {
+        meta.len()
+    } else {
+        ranges.iter().map(|part| part.len()).sum()
+    };
+    let counted = match limit {
+        Some((key, max)) => {
+            match config.downloads.start(key, max, meta.len(), bytes) {
+                Some(download) => Some(download),
+                None => return gone(),
+            }
+        }
+        None => None,
+    };
+    let content_type = match config.content_types.get(&path) {
+        Some(content_type) => content_type,
+        None => match detect_content_type(config, &path, &file) {
+            Ok(content_type) => content_type,
+            Err(e) => return io_error(e),
+        },
+    };
+    let digest = if config.digest_header {
+        match config.checksums.digest(&path, &file, &meta,
+            checksum::Algorithm::Sha256)
+        {
+            Ok(digest) => Some(digest),
+            Err(e) => return io_error(e),
+        }
+    } else {
+        None
     };
-    if meta.is_dir() {
-        send_dir(&path, req_path)
+    let disposition = if download {
+        path.file_name()
+            .map(|name| content_disposition(&name.to_string_lossy()))
     } else {
-        send_file(path, meta)
+        None
+    };
+    let cached = match &config.file_cache {
+        Some(cache) => cache.get(&path, &file, &meta),
+        None => Ok(None),
+    };
+    let mappable = config.mmap_threshold
+        .is_some_and(|max| meta.len() > 0 && meta.len() <= max);
+    let in_memory = match cached {
+        Ok(Some(data)) => Some(InMemory::Cached(data)),
+        Ok(None) if mappable => Some(InMemory::Mapped(meta.len())),
+        Ok(None) => None,
+        Err(e) => return io_error(e),
+    };
+    let mut res = Response::builder();
+    let (chunks, len) = match ranges.as_slice() {
+        [] => {
+            let chunks = match read_range(in_memory.as_ref(), &file, None,
+                config.chunk_size)
+            {
+                Ok(chunks) => chunks,
+                Err(e) => return io_error(e),
+            };
+            res.header(http::header::CONTENT_TYPE, content_type.to_string());
+            (chunks, meta.len())
+        }
+        [part] => {
+            let chunks = match read_range(in_memory.as_ref(), &file,
+                Some(*part), config.chunk_size)
+            {
+                Ok(chunks) => chunks,
+                Err(e) => return io_error(e),
+            };
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .header(http::header::CONTENT_RANGE,
+                    part.content_range(meta.len()))
+                .header(http::header::CONTENT_TYPE, content_type.to_string());
+            (chunks, part.len())
+        }
+        _ => {
+            let multipart = range::Multipart::new(content_type.as_ref(),
+                meta.len());
+            let len = multipart.len(&ranges);
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .header(http::header::CONTENT_TYPE, multipart.content_type());
+            (multipart_chunks(&multipart, ranges, in_memory, file,
+                config.chunk_size), len)
+        }
+    };
+    let body = match counted {
+        Some(download) =>
+            Body::wrap_stream(downloads::Counted::new(chunks, len, download)),
+        None => Body::wrap_stream(chunks),
+    };
+    res.header(http::header::CONTENT_LENGTH, len)
+        .header(http::header::ACCEPT_RANGES, "bytes");
+    if !config.no_cache {
+        res.header(http::header::ETAG, validators.etag.as_str());
+        if let Some(last_modified) = validators.last_modified() {
+            res.header(http::header::LAST_MODIFIED, last_modified);
+        }
+    }
+    if let Some(digest) = digest {
+        res.header("Repr-Digest", checksum::repr_digest(&digest))
+            .header("Digest", checksum::legacy_digest(&digest));
     }
+    if let Some(disposition) = disposition {
+        res.header(http::header::CONTENT_DISPOSITION, disposition);
+    }
+    Box::new(future::result(res.body(body)))
 }
 
-fn send_dir(path: &Path, req_path: &Path) -> ServerFuture<Response<Body>> {
-    let entries = match read_dir(path) {
-        Ok(entries) => entries,
-        Err(e) => return io_error(e),
+type Chunks = Box<dyn Stream<Item = Bytes, Error = io::Error> + Send>;
+
+/// Way to read a file as a whole instead of in chunks.
+enum InMemory {
+    /// Contents of the file, already in memory.
+    Cached(Bytes),
+    /// Length of the file, to read through a memory mapping.
+    Mapped(u64),
+}
+
+/// Returns the chunks of `range` of a file, or of the whole file, reading
+/// it as a whole if `in_memory` says how to.
+fn read_range(in_memory: Option<&InMemory>, file: &File,
+    range: Option<range::ByteRange>, chunk_size: usize) -> io::Result<Chunks>
+{
+    let slice = move |data: Bytes| match range {
+        Some(range) => data.slice(range.start as usize, range.end as usize),
+        None => data,
     };
-    let page = format_file_list(&entries, req_path);
-    let res = Response::builder().body(page.into());
-    Box::new(future::result(res))
+    match in_memory {
+        Some(InMemory::Cached(data)) =>
+            return Ok(Box::new(stream::once(Ok(slice(data.clone()))))),
+        Some(&InMemory::Mapped(len)) => {
+            // Copying the mapping may fault, so it is done on the blocking
+            // pool.
+            let file = file.try_clone()?;
+            let data = blocking(move || file_stream::map(&file, len))
+                .and_then(|data| data)
+                .map(slice);
+            return Ok(Box::new(data.into_stream()))
+        }
+        None => {}
+    }
+    let mut file = file.try_clone()?;
+    if let Some(range) = range {
+        file.seek(SeekFrom::Start(range.start))?;
+    }
+    let chunks = file_stream::FileStream::new(tokio_fs::File::from_std(file),
+        chunk_size);
+    Ok(match range {
+        Some(range) => Box::new(chunks.limit(range.len())),
+        None => Box::new(chunks),
+    })
 }
 
-fn read_dir(path: &Path) -> Result<Vec<DirEntry>, io::Error> {
-    path.read_dir()?.collect()
+/// Returns the chunks of a `multipart/byteranges` body. The file is read
+/// for each range once the previous parts are sent.
+fn multipart_chunks(multipart: &range::Multipart,
+    ranges: Vec<range::ByteRange>, in_memory: Option<InMemory>, file: File,
+    chunk_size: usize) -> Chunks
+{
+    let parts = ranges.into_iter()
+        .map(|range| (Bytes::from(multipart.part_header(&range)), range))
+        .collect::<Vec<_>>();
+    let trailer = Bytes::from(multipart.trailer());
+    let parts = stream::iter_ok::<_, io::Error>(parts);
+    let parts = parts.map(move |(header, range)| {
+        let data = read_range(in_memory.as_ref(), &file, Some(range),
+            chunk_size)
+            .unwrap_or_else(|e| Box::new(stream::once(Err(e))));
+        stream::once(Ok(header)).chain(data)
+    });
+    Box::new(parts.flatten().chain(stream::once(Ok(trailer))))
 }
 
-fn send_file(path: PathBuf, meta: Metadata) -> ServerFuture<Response<Body>> {
-    let content_type = get_content_type(&path);
-    let resp = tokio_fs::File::open(path)
-        .map(move |file| {
-            let chunks = tokio_codec::FramedRead::new(file,
-                tokio_codec::BytesCodec::new());
-            let chunks = chunks.map(|buf| buf.freeze());
-            let body = Body::wrap_stream(chunks);
-            Response::builder()
-                .header(http::header::CONTENT_LENGTH, meta.len())
-                .header(http::header::CONTENT_TYPE, content_type.to_string())
-                .body(body)
-                .unwrap()
+/// Returns a `Content-Disposition` value asking to save the response as
+/// `file_name`, with an ASCII fallback and an RFC 5987 encoded name.
+fn content_disposition(file_name: &str) -> String {
+    let fallback = file_name.chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
         })
-        .or_else(io_error);
-    Box::new(resp)
+        .collect::<String>();
+    let mut encoded = String::new();
+    for &b in file_name.as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$'
+                | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~'
+                => encoded.push(char::from(b)),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback,
+        encoded)
+}
+
+fn send_checksum(config: &Arc<Config>, path: PathBuf, file: File,
+    meta: Metadata, algorithm: &str) -> ServerFuture<Response<Body>>
+{
+    let algorithm = match checksum::Algorithm::from_name(algorithm) {
+        Some(algorithm) => algorithm,
+        None => return bad_request(),
+    };
+    let digested = config.checksums.fill(&path, &file, &meta, &[algorithm]);
+    let config = config.clone();
+    Box::new(digested.then(move |res| {
+        let digest = match res.and_then(|_| config.checksums.digest(&path,
+            &file, &meta, algorithm))
+        {
+            Ok(digest) => digest,
+            Err(e) => return io_error(e),
+        };
+        let res = Response::builder()
+            .header(http::header::CONTENT_TYPE,
+                mime::TEXT_PLAIN_UTF_8.to_string())
+            .body(format!("{}\n", checksum::to_hex(&digest)).into());
+        Box::new(future::result(res))
+    }))
+}
+
+/// Returns the type of `file`, found at `path`, from its name and, if
+/// enabled, from its content.
+fn detect_content_type(config: &Config, path: &Path, file: &File)
+    -> io::Result<Mime>
+{
+    let mut content_type = get_content_type(path);
+    if config.sniff_content && content_type == mime::APPLICATION_OCTET_STREAM {
+        content_type = sniff::file_content_type(file)?.unwrap_or(content_type);
+    }
+    if config.detect_charset && content_type.type_() == mime::TEXT {
+        if let Some(charset) = charset::detect_file(file)? {
+            content_type = content_type::with_charset(content_type, charset);
+        }
+    }
+    Ok(content_type)
 }
 
 fn get_content_type(p: &Path) -> Mime {
@@ -206,95 +3570,278 @@ fn get_content_type(p: &Path) -> Mime {
     }
 }
 
-fn bad_request() -> ServerFuture<Response<Body>> {
-    let res = Response::builder().status(StatusCode::BAD_REQUEST)
-        .body("Bad request".into());
+fn send_options(config: &Config) -> ServerFuture<Response<Body>> {
+    let res = Response::builder()
+        .header(http::header::ALLOW, allowed_methods(config))
+        .header(http::header::CONTENT_LENGTH, 0)
+        .body(Body::empty());
     Box::new(future::result(res))
 }
 
-fn io_error(e: io::Error) -> ServerFuture<Response<Body>> {
-    let code = match e.kind() {
-        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
-        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
-        _ => StatusCode::INTERNAL_SERVER_ERROR,
-    };
-    let res = Response::builder().status(code)
-        .body(format!("IO error: {}", e).into());
+fn method_not_allowed(config: &Config) -> ServerFuture<Response<Body>> {
+    let res = problem::Problem::new(StatusCode::METHOD_NOT_ALLOWED,
+        "Method not allowed")
+        .respond_with(Response::builder()
+            .header(http::header::ALLOW, allowed_methods(config)));
     Box::new(future::result(res))
 }
 
-fn format_file_list(entries: &[DirEntry], req_path: &Path) -> String {
-    let mut out = Vec::<u8>::new();
-    write_page(&mut out, "Directory contents", |out| {
-        write_file_list(entries, req_path, out)
-    }).unwrap();
-    String::from_utf8(out).unwrap()
+fn redirect(location: &str) -> ServerFuture<Response<Body>> {
+    redirect_with(StatusCode::MOVED_PERMANENTLY, location)
 }
 
-fn write_file_list<W: Write>(entries: &[DirEntry], req_path: &Path,
-    out: &mut xml::EventWriter<W>) -> Result<(), xml::writer::Error>
+fn redirect_with(status: StatusCode, location: &str)
+    -> ServerFuture<Response<Body>>
 {
-    write_dir_title(req_path, out)?;
-    html::table(out).write(|out| {
-        html::tr(out).write(|out| {
-            html::th(out).text("Filename")?;
-            html::th(out).attr("class", "size").text("Size")
-        })?;
-        for (filename, entry) in entries.iter()
-            .filter_map(|entry| entry.path().file_name()
-                .and_then(|s| s.to_str())
-                .map(|s| (s.to_owned(), entry))
-            )
+    let res = Response::builder().status(status)
+        .header(http::header::LOCATION, location)
+        .body(Body::empty());
+    Box::new(future::result(res))
+}
+
+/// Decides which files of a site a client may read, with the checks applied
+/// to requests for them: access rules, then access files.
+struct ReadAccess<'a> {
+    config: &'a Config,
+    client: IpAddr,
+    authenticated: bool,
+    claims: Option<&'a jwt::Claims>,
+    files: access_file::Checker<'a>,
+}
+
+impl<'a> ReadAccess<'a> {
+    /// Returns the access of a client to the files of `site`, given the
+    /// claims of its token if any.
+    fn new(config: &'a Config, site: &'a vhost::Site, client: IpAddr,
+        authenticated: bool, claims: Option<&'a jwt::Claims>)
+        -> ReadAccess<'a>
+    {
+        ReadAccess {
+            config,
+            client,
+            authenticated,
+            claims,
+            files: access_file::Checker::new(&site.root, client,
+                authenticated),
+        }
+    }
+
+    /// Decides whether the client may read `resource`.
+    fn check(&mut self, resource: &Path) -> access::Decision {
+        let config = self.config;
+        let decision = config.access_rules.check(
+            &Path::new("/").join(resource), self.client, self.authenticated,
+            self.claims, config.auth.is_required(&Method::GET));
+        if !matches!(decision, access::Decision::Allow) || !config.access_files
         {
-            let rel_path = match req_path.join(&filename).to_str() {
-                Some(s) => s.to_owned(),
-                None => continue,
-            };
-            #[cfg(windows)]
-            let rel_path = rel_path.replace("\\", "/");
-            html::tr(out).write(|out| {
-                html::td(out).write(|out| {
-                    html::a(out).attr("href", rel_path).text(&filename)
-                })?;
-                let size = entry.metadata().ok().and_then(|meta| {
-                    if meta.is_file() {
-                        Some(pretty_size(meta.len()))
-                    } else {
-                        None
-                    }
-                });
-                let size = size.unwrap_or(String::new());
-                html::td(out).attr("class", "size").text(&size)
-            })?;
+            return decision
         }
-        Ok(())
-    })
+        if resource.file_name() == Some(OsStr::new(access_file::FILE_NAME)) {
+            return access::Decision::Deny
+        }
+        match self.files.check(resource) {
+            access::Decision::RequireAuth if !config.auth.is_enabled() =>
+                access::Decision::Deny,
+            decision => decision,
+        }
+    }
+
+    /// Returns whether the client may read `resource`.
+    fn allows(&mut self, resource: &Path) -> bool {
+        matches!(self.check(resource), access::Decision::Allow)
+    }
 }
 
-fn write_dir_title<W: Write>(path: &Path, out: &mut xml::EventWriter<W>)
-    -> Result<(), xml::writer::Error>
+/// Answers `request` when its client may not read the requested resource
+/// according to `decision`, if so.
+fn deny(config: &Config, decision: access::Decision, request: &Request<Body>)
+    -> Option<ServerFuture<Response<Body>>>
+{
+    match decision {
+        access::Decision::Allow => None,
+        access::Decision::Deny => Some(forbidden()),
+        access::Decision::RequireAuth => Some(require_auth(config, request)),
+    }
+}
+
+/// Asks the client of `request` to authenticate, redirecting browsers to log
+/// in with OpenID Connect or the login form if enabled.
+fn require_auth(config: &Config, request: &Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let browser = matches!(*request.method(), Method::GET | Method::HEAD)
+        && problem::Format::negotiate(request.headers())
+            == problem::Format::Html;
+    match &config.auth.oidc {
+        Some(oidc) if browser => oidc.login(request),
+        None if browser && config.auth.login_form =>
+            login::redirect(config, request),
+        _ => unauthorized(&config.auth.challenges(request)),
+    }
+}
+
+fn unauthorized(challenges: &[String]) -> ServerFuture<Response<Body>> {
+    let mut res = Response::builder();
+    res.status(StatusCode::UNAUTHORIZED);
+    for challenge in challenges {
+        res.header(http::header::WWW_AUTHENTICATE, challenge.as_str());
+    }
+    let problem = problem::Problem::new(StatusCode::UNAUTHORIZED,
+        "Unauthorized");
+    Box::new(future::result(problem.respond_with(&mut res)))
+}
+
+fn forbidden() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::FORBIDDEN, "Forbidden").respond()
+}
+
+fn gone() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::GONE, "Gone").respond()
+}
+
+fn range_not_satisfiable(len: u64) -> ServerFuture<Response<Body>> {
+    let res = problem::Problem::new(StatusCode::RANGE_NOT_SATISFIABLE,
+        "Range not satisfiable")
+        .respond_with(Response::builder()
+            .header(http::header::CONTENT_RANGE, format!("bytes */{}", len)));
+    Box::new(future::result(res))
+}
+
+fn bad_request() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::BAD_REQUEST, "Bad request").respond()
+}
+
+fn uri_too_long() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::URI_TOO_LONG, "URI too long").respond()
+}
+
+/// Returns the length of `uri` as received.
+fn uri_len(uri: &http::Uri) -> usize {
+    let scheme = uri.scheme_part().map_or(0, |s| s.as_str().len() + 3);
+    let authority = uri.authority_part().map_or(0, |a| a.as_str().len());
+    let rest = uri.path_and_query().map_or(0, |p| p.as_str().len());
+    scheme + authority + rest
+}
+
+/// Returns the size of the request line and headers of `request`, as
+/// received with HTTP/1.1. hyper only bounds it roughly, as it checks the
+/// size of its buffer before reading more.
+fn head_len(request: &Request<Body>) -> usize {
+    let line = request.method().as_str().len() + uri_len(request.uri())
+        + " HTTP/1.1\r\n".len();
+    let headers = request.headers().iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum::<usize>();
+    line + headers + 2
+}
+
+fn header_fields_too_large() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        "Request header fields too large").respond()
+}
+
+/// Returns whether a request with `headers` has a body.
+fn has_body(headers: &HeaderMap) -> bool {
+    headers.contains_key(http::header::TRANSFER_ENCODING)
+        || headers.get(http::header::CONTENT_LENGTH)
+            .is_some_and(|len| len != "0")
+}
+
+fn internal_server_error() -> ServerFuture<Response<Body>> {
+    problem::Problem::new(StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error").respond()
+}
+
+/// Runs `f` on the blocking pool of the runtime, as it may wait for the
+/// file system for a while.
+fn blocking<T, F>(f: F) -> impl Future<Item = T, Error = io::Error> + Send
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
 {
-    let mut parts = path.ancestors()
-        .map(|p| {
-            let file_name = p.file_name().and_then(|s| s.to_str())
-                .unwrap_or("");
-            (p.to_str().unwrap_or(""), file_name)
+    let mut f = Some(f);
+    future::poll_fn(move || tokio_threadpool::blocking(|| {
+        f.take().expect("Blocking closure called twice")()
+    })).map_err(io::Error::other)
+}
+
+fn io_error(e: io::Error) -> ServerFuture<Response<Body>> {
+    let code = match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    problem::Problem::new(code, format!("IO error: {}", e)).internal()
+        .respond()
+}
+
+/// Address listened at by default.
+const DEFAULT_ADDRESS: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+const DEFAULT_PORT: u16 = 80;
+
+/// Default maximum number of search results.
+const DEFAULT_SEARCH_LIMIT: usize = 1000;
+
+/// Default maximum duration of a search, in seconds.
+const DEFAULT_SEARCH_TIMEOUT: u64 = 5;
+
+/// Port listened at by default for plain HTTP redirected to HTTPS.
+const DEFAULT_REDIRECT_HTTP_PORT: u16 = 80;
+
+/// Default max-age of `Strict-Transport-Security` headers, in seconds.
+const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+/// Default interval between rescans of the content index, in seconds.
+const DEFAULT_INDEX_REFRESH: u64 = 30;
+
+/// Number of entries listed per page by default.
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Default maximum size of request lines and headers.
+const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Smallest maximum size of request lines and headers supported by hyper.
+const MIN_MAX_HEADER_SIZE: usize = 8 * 1024;
+
+/// Default maximum length of request URIs.
+const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+
+/// Part of a directory listing requested with `page` and `per-page`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct Page {
+    /// Page number, starting at 1.
+    number: usize,
+    size: usize,
+}
+
+impl Page {
+    fn from_query(request: &Request<Body>) -> Option<Page> {
+        let param = |name, default| match query_param(request, name) {
+            Some(n) => n.parse().ok().filter(|&n| n > 0),
+            None => Some(default),
+        };
+        Some(Page {
+            number: param("page", 1)?,
+            size: param("per-page", DEFAULT_PAGE_SIZE)?,
         })
-        .collect::<Vec<_>>();
-    parts.pop();
-    parts.reverse();
-    html::h1(out).write(|out| {
-        out.write("Contents of ")?;
-        html::a(out).attr("href", "/").text("/")?;
-        for (link, name) in parts {
-            html::a(out).attr("href", link).text(name)?;
-            out.write("/")?;
-        }
-        Ok(())
-    })
+    }
+
+    fn skipped(self) -> usize {
+        (self.number - 1).saturating_mul(self.size)
+    }
+
+    fn link(self, number: usize) -> String {
+        if self.size == DEFAULT_PAGE_SIZE {
+            format!("?page={}", number)
+        } else {
+            format!("?page={}&per-page={}", number, self.size)
+        }
+    }
 }
 
-fn write_page<W, F>(out: W, title: &str, f: F) -> Result<(), xml::writer::Error>
+fn write_page<W, F>(out: W, style: &style::Style, title: &str, f: F)
+    -> Result<(), xml::writer::Error>
 where
     W: Write,
     F: FnOnce(&mut xml::EventWriter<W>) -> Result<(), xml::writer::Error>,
@@ -303,13 +3850,12 @@ where
         .perform_indent(true)
         .write_document_declaration(false)
         .create_writer(out);
-    const STYLESHEET: &str = include_str!("../data/style.css");
     html::write_doctype(&mut out)?;
     html::html(&mut out).write(|out| {
         html::head(out).write(|out| {
             html::title(out).text(title)?;
             html::meta(out).attr("charset", "UTF-8").empty()?;
-            html::style(out).text(STYLESHEET)
+            style.write(out)
         })?;
         html::body(out).write(f)
     })?;
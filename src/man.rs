@@ -0,0 +1,58 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Generation of a man page from the command-line definitions.
+//!
+//! The long help of the application is laid out with each description on its
+//! own line, and converted to roff section by section.
+
+use clap::{App, AppSettings};
+use std::io::{self, Write};
+
+/// Writes the man page of `app` in roff format.
+pub fn write<W: Write>(app: &App, mut out: W) -> io::Result<()> {
+    let mut app = app.clone()
+        .setting(AppSettings::NextLineHelp)
+        .set_term_width(0);
+    let mut help = Vec::new();
+    app.write_long_help(&mut help).map_err(io::Error::other)?;
+    let help = String::from_utf8_lossy(&help);
+    let name = crate::APP_NAME;
+    writeln!(out, ".TH {} 1 \"\" \"{} {}\"", name.to_uppercase(), name,
+        crate::APP_VERSION)?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(out, "{} \\- {}", name, escape(crate::APP_ABOUT))?;
+    // The first lines repeat the name, version, authors and description.
+    let mut section = "";
+    for line in help.lines().skip(3) {
+        if line.trim().is_empty() {
+            continue
+        }
+        if !line.starts_with(' ') {
+            section = line.trim_end_matches(':');
+            let title = if section == "USAGE" {"SYNOPSIS"} else {section};
+            writeln!(out, ".SH {}", title)?;
+        } else if line.starts_with("            ") {
+            writeln!(out, "{}", escape(line.trim()))?;
+        } else if section == "USAGE" {
+            writeln!(out, "{}\n.br", escape(line.trim()))?;
+        } else {
+            writeln!(out, ".TP\n\\fB{}\\fR", escape(line.trim()))?;
+        }
+    }
+    writeln!(out, ".SH \"EXIT STATUS\"")?;
+    for (code, description) in crate::exit_code::DESCRIPTIONS {
+        writeln!(out, ".TP\n\\fB{}\\fR\n{}", code, escape(description))?;
+    }
+    writeln!(out, ".SH AUTHORS")?;
+    writeln!(out, "{}", escape(crate::APP_AUTHORS))
+}
+
+/// Escapes `text` so that it is output verbatim.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Pull-through cache of an upstream server.
+//!
+//! With `--mirror`, GET and HEAD requests for files missing from the served
+//! directory are fetched from the upstream server. Files are stored in the
+//! served directory, unless upstream caching headers forbid it with
+//! `Cache-Control: no-store` or `private`, and then served like any other
+//! file. Other responses, e.g. listings or errors, are passed through.
+//!
+//! Cached files count towards `--max-upload-size` and `--upload-quota`.
+//! Larger responses are passed through without being cached.
+//!
+//! Cached files are fresh for the `s-maxage` or `max-age` of their response,
+//! or until its `Expires` date, and forever without any of these, as build
+//! artifacts rarely change. Stale files are revalidated with their `ETag` or
+//! `Last-Modified` date, and still served if the upstream server cannot be
+//! reached. Freshness is tracked in memory, so files cached before a restart
+//! are served as local files.
+
+use crate::beneath::RootDir;
+use crate::https;
+use crate::problem::Problem;
+use crate::{upload, Config, ServerFuture};
+use futures::{future, Future, Stream};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::uri::{Authority, Scheme, Uri};
+use http::{Request, Response, StatusCode, Version};
+use hyper::{Body, Client};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Freshness and validators of a cached file.
+struct Entry {
+    /// Time after which the file must be revalidated, if any.
+    expires: Option<SystemTime>,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+/// Marker of requests for which the upstream server was already consulted.
+#[derive(Clone, Copy, Debug)]
+struct Consulted;
+
+/// Upstream server whose files are cached in the served directory.
+pub struct Mirror {
+    client: Client<https::Connector>,
+    scheme: Scheme,
+    authority: Authority,
+    /// Path prepended to request paths, without trailing slash.
+    path: String,
+    /// Files cached since the server started, by path.
+    entries: Arc<Mutex<HashMap<PathBuf, Entry>>>,
+}
+
+impl Mirror {
+    /// Parses the URL of the upstream server, e.g.
+    /// `https://example.com/artifacts/`.
+    pub fn parse(url: &str) -> Option<Mirror> {
+        let uri = url.parse::<Uri>().ok()?;
+        let scheme = uri.scheme_part()
+            .filter(|&s| *s == Scheme::HTTP || *s == Scheme::HTTPS)?;
+        if uri.query().is_some() {
+            return None
+        }
+        Some(Mirror {
+            client: https::client(),
+            scheme: scheme.clone(),
+            authority: uri.authority_part()?.clone(),
+            path: uri.path().trim_end_matches('/').to_owned(),
+            entries: Default::default(),
+        })
+    }
+
+    /// Returns whether `request` may be answered from the upstream server.
+    pub fn applies(&self, request: &Request<Body>) -> bool {
+        request.extensions().get::<Consulted>().is_none()
+    }
+
+    /// Returns whether the file at `path` was cached and must be
+    /// revalidated.
+    pub fn is_stale(&self, path: &Path) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.get(path)
+            .and_then(|entry| entry.expires)
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+
+    /// Fetches the file requested by `request` from the upstream server,
+    /// caches it at `resource` in `root`, whose path is `path`, and answers
+    /// the request with `serve`, which serves the cached file.
+    pub fn fetch<F>(&self, config: &Arc<Config>, root: &Path,
+        resource: &Path, path: &Path, mut request: Request<Body>, serve: F)
+        -> ServerFuture<Response<Body>>
+    where
+        F: FnOnce(Request<Body>) -> ServerFuture<Response<Body>>
+            + Send + 'static,
+    {
+        request.extensions_mut().insert(Consulted);
+        let uri = format!("{}://{}{}{}", self.scheme, self.authority,
+            self.path, request.uri().path());
+        let mut upstream = Request::get(uri.as_str());
+        let revalidated = {
+            let entries = self.entries.lock().unwrap();
+            entries.get(path).map(|entry| {
+                if let Some(etag) = &entry.etag {
+                    upstream.header(header::IF_NONE_MATCH, etag.clone());
+                }
+                if let Some(date) = &entry.last_modified {
+                    upstream.header(header::IF_MODIFIED_SINCE, date.clone());
+                }
+            })
+            .is_some()
+        };
+        let upstream = match upstream.body(Body::empty()) {
+            Ok(upstream) => upstream,
+            Err(_) => return crate::bad_request(),
+        };
+        let cacheable = resource != Path::new("")
+            && !request.uri().path().ends_with('/');
+        let cache = Cache {
+            config: config.clone(),
+            entries: self.entries.clone(),
+            root: root.to_owned(),
+            resource: resource.to_owned(),
+            path: path.to_owned(),
+        };
+        let res = self.client.request(upstream).then(move |res| {
+            let res = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("Failed to fetch {}: {}", uri, e);
+                    return if revalidated {serve(request)} else {bad_gateway()}
+                }
+            };
+            match res.status() {
+                StatusCode::NOT_MODIFIED if revalidated => {
+                    cache.refresh(res.headers());
+                    serve(request)
+                }
+                StatusCode::OK if cacheable && is_cacheable(res.headers())
+                    && cache.fits(res.headers()) =>
+                    cache.store(res, request, serve),
+                _ => pass_through(res),
+            }
+        });
+        Box::new(res)
+    }
+}
+
+/// Location of a file being cached.
+struct Cache {
+    config: Arc<Config>,
+    entries: Arc<Mutex<HashMap<PathBuf, Entry>>>,
+    root: PathBuf,
+    resource: PathBuf,
+    path: PathBuf,
+}
+
+impl Cache {
+    /// Updates the freshness of the cached file after a revalidation.
+    fn refresh(&self, headers: &HeaderMap) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.path) {
+            entry.expires = expiry(headers);
+        }
+    }
+
+    /// Returns whether a response with `headers` fits in the served
+    /// directory, as far as its announced length tells.
+    fn fits(&self, headers: &HeaderMap) -> bool {
+        let len = match headers.get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok())
+        {
+            Some(len) => len,
+            None => return true,
+        };
+        self.config.max_upload_size.is_none_or(|max| len <= max)
+            && self.config.upload_quota.as_ref()
+                .is_none_or(|quota| quota.fits(&self.root, len))
+    }
+
+    /// Stores the body of `res` and answers `request` with `serve`, or with
+    /// a bad gateway error if the body turns out too large.
+    fn store<F>(self, res: Response<Body>, request: Request<Body>, serve: F)
+        -> ServerFuture<Response<Body>>
+    where
+        F: FnOnce(Request<Body>) -> ServerFuture<Response<Body>>
+            + Send + 'static,
+    {
+        let (parts, body) = res.into_parts();
+        let entry = Entry {
+            expires: expiry(&parts.headers),
+            etag: parts.headers.get(header::ETAG).cloned(),
+            last_modified: parts.headers.get(header::LAST_MODIFIED).cloned(),
+        };
+        let modified = entry.last_modified.as_ref()
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok());
+        let target = match writable_path(&self.root, &self.resource) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!("Failed to cache {}: {}", self.path.display(), e);
+                return crate::io_error(e)
+            }
+        };
+        let replaced = match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_file() => meta.len(),
+            Ok(_) => return pass_through(Response::from_parts(parts, body)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => {
+                warn!("Failed to cache {}: {}", self.path.display(), e);
+                return crate::io_error(e)
+            }
+        };
+        let created = upload::TempFile::create(&target, self.config.clone(),
+            self.root.clone());
+        let (temp, file) = match created {
+            Ok(temp) => temp,
+            Err(e) => {
+                warn!("Failed to cache {}: {}", self.path.display(), e);
+                return crate::io_error(e)
+            }
+        };
+        let file = tokio_fs::File::from_std(file);
+        let resource = self.resource.clone();
+        let written = body.map_err(io::Error::other)
+            .fold((file, temp), |(file, mut temp), chunk| {
+                let accepted = temp.receive(&chunk);
+                future::result(accepted.map(|()| (file, chunk)))
+                    .and_then(|(file, chunk)| {
+                        tokio_io::io::write_all(file, chunk)
+                    })
+                    .map(|(file, _)| (file, temp))
+            })
+            .and_then(move |(file, temp)| {
+                if let Some(modified) = modified {
+                    file.into_std().set_modified(modified)?;
+                }
+                temp.persist(&resource, &target, None)
+            });
+        let res = written.then(move |res| match res {
+            Ok(()) => {
+                if let Some(quota) = &self.config.upload_quota {
+                    quota.release(&self.root, replaced);
+                }
+                self.entries.lock().unwrap().insert(self.path, entry);
+                serve(request)
+            }
+            Err(e) => {
+                warn!("Failed to cache {}: {}", self.path.display(), e);
+                bad_gateway()
+            }
+        });
+        Box::new(res)
+    }
+}
+
+/// Returns the path at which to store `resource` in `root`, creating its
+/// parent directories inside `root` if needed.
+fn writable_path(root: &Path, resource: &Path) -> io::Result<PathBuf> {
+    let root_dir = RootDir::open(root)?;
+    let parent = resource.parent().unwrap_or_else(|| Path::new(""));
+    let mut dir = PathBuf::new();
+    for part in parent.components() {
+        dir.push(part);
+        match fs::create_dir(root_dir.writable_path(&dir)?) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists =>
+                return Err(e),
+            _ => {}
+        }
+    }
+    root_dir.writable_path(resource)
+}
+
+/// Returns the directives of the `Cache-Control` headers, with lowercase
+/// names and unquoted values.
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers.get_all(header::CACHE_CONTROL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = parts.next()
+                .map(|value| value.trim().trim_matches('"').to_owned());
+            (name, value)
+        })
+        .collect()
+}
+
+/// Returns whether a response with `headers` may be stored.
+fn is_cacheable(headers: &HeaderMap) -> bool {
+    let forbidden = cache_control(headers).iter()
+        .any(|(name, _)| name == "no-store" || name == "private");
+    !forbidden
+        && !headers.contains_key(header::CONTENT_ENCODING)
+        && headers.get(header::VARY).is_none_or(|vary| vary != "*")
+}
+
+/// Returns the time until which a response with `headers` is fresh, or
+/// `None` if it does not expire.
+fn expiry(headers: &HeaderMap) -> Option<SystemTime> {
+    let now = SystemTime::now();
+    let directives = cache_control(headers);
+    if directives.iter().any(|(name, _)| name == "no-cache") {
+        return Some(now)
+    }
+    let max_age = |key: &str| directives.iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_deref()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(0));
+    if let Some(max_age) = max_age("s-maxage").or_else(|| max_age("max-age")) {
+        let age = headers.get(header::AGE)
+            .and_then(|age| age.to_str().ok())
+            .and_then(|age| age.parse::<u64>().ok())
+            .unwrap_or(0);
+        return Some(now + Duration::from_secs(max_age.saturating_sub(age)))
+    }
+    headers.get(header::EXPIRES).map(|expires| expires.to_str().ok()
+        .and_then(|expires| httpdate::parse_http_date(expires).ok())
+        .unwrap_or(now))
+}
+
+/// Returns an upstream response to the client.
+fn pass_through(mut res: Response<Body>) -> ServerFuture<Response<Body>> {
+    *res.version_mut() = Version::default();
+    crate::proxy::remove_hop_by_hop_headers(res.headers_mut());
+    Box::new(futures::future::ok(res))
+}
+
+fn bad_gateway() -> ServerFuture<Response<Body>> {
+    Problem::new(StatusCode::BAD_GATEWAY, "Bad gateway").respond()
+}
@@ -0,0 +1,300 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Login of browser users with OpenID Connect.
+//!
+//! Browsers requesting pages that require authentication are redirected to
+//! the identity provider, which sends users back to the redirect URL with an
+//! authorization code once they log in. The code is exchanged for an ID
+//! token, validated like bearer tokens, and its claims are kept in a
+//! session.
+//!
+//! The provider is discovered from its issuer URL. Its endpoints must be
+//! `https://` URLs, or `http://` URLs of a provider running on the same
+//! host. The authorization code flow uses PKCE, so the client secret is
+//! optional.
+
+use crate::https;
+use crate::jwt::{self, Claims};
+use crate::problem::Problem;
+use crate::session::{self, Sessions};
+use crate::ServerFuture;
+use futures::{future, Future, Stream};
+use http::header;
+use http::{Request, Response, StatusCode, Uri};
+use hyper::{Body, Client};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tracing::warn;
+use url::form_urlencoded;
+
+/// Cookie keeping the state of a login in progress.
+const LOGIN_COOKIE: &str = "servedir_login";
+
+/// Number of seconds users have to log in with the provider.
+const LOGIN_LIFETIME: u64 = 600;
+
+#[derive(Debug)]
+pub enum OidcError {
+    InvalidUrl(&'static str),
+    Discovery(String),
+    Jwt(jwt::JwtError),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OidcError::InvalidUrl(name) => write!(f, "The {} must be an \
+                https:// URL or an http:// URL of the local host", name),
+            OidcError::Discovery(e) =>
+                write!(f, "Failed to discover provider: {}", e),
+            OidcError::Jwt(_) => f.write_str("Failed to read provider keys"),
+        }
+    }
+}
+
+impl Error for OidcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OidcError::Jwt(e) => Some(e),
+            OidcError::InvalidUrl(_) | OidcError::Discovery(_) => None,
+        }
+    }
+}
+
+/// Provider metadata, as published by discovery.
+#[derive(Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Login in progress.
+#[derive(Deserialize, Serialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    verifier: String,
+    /// Path and query to return to.
+    target: String,
+    exp: u64,
+}
+
+/// Identity provider users log in with.
+pub struct Provider {
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    /// Path of requests to the redirect URL.
+    callback_path: String,
+    base_path: String,
+    authorization_endpoint: String,
+    token_endpoint: Uri,
+    validator: jwt::Validator,
+    sessions: Arc<Sessions>,
+    client: Client<https::Connector>,
+}
+
+impl Provider {
+    /// Discovers the provider identified by `issuer`. Users are sent back
+    /// to `redirect_url`, which must be registered with the provider for
+    /// `client_id`. Logged in users get a session from `sessions`.
+    pub fn discover(issuer: &str, client_id: &str,
+        client_secret: Option<&str>, redirect_url: &str, base_path: &str,
+        sessions: Arc<Sessions>) -> Result<Arc<Provider>, OidcError>
+    {
+        let discovery = format!("{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/'));
+        let discovery = secure_uri(&discovery)
+            .ok_or(OidcError::InvalidUrl("issuer"))?;
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+        let metadata = jwt::get(&mut runtime, &discovery)
+            .map_err(OidcError::Discovery)?;
+        let metadata = serde_json::from_slice::<Metadata>(&metadata)
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+        let token_endpoint = secure_uri(&metadata.token_endpoint)
+            .ok_or(OidcError::InvalidUrl("token endpoint"))?;
+        let validator = jwt::Validator::new(&metadata.jwks_uri,
+            Some(metadata.issuer), Some(client_id.to_owned()))
+            .map_err(OidcError::Jwt)?;
+        let redirect_path = redirect_url.parse::<Uri>()
+            .map_err(|_| OidcError::InvalidUrl("redirect URL"))?
+            .path()
+            .to_owned();
+        let callback_path = redirect_path.strip_prefix(base_path)
+            .filter(|path| path.starts_with('/'))
+            .unwrap_or(&redirect_path)
+            .to_owned();
+        Ok(Arc::new(Provider {
+            client_id: client_id.to_owned(),
+            client_secret: client_secret.map(str::to_owned),
+            redirect_url: redirect_url.to_owned(),
+            callback_path,
+            base_path: base_path.to_owned(),
+            authorization_endpoint: metadata.authorization_endpoint,
+            token_endpoint,
+            validator,
+            sessions,
+            client: https::client(),
+        }))
+    }
+
+    /// Keeps the provider keys up to date from a background thread. The
+    /// thread is spawned here, so this must be called once the server is
+    /// detached.
+    pub fn start_refreshing(&self) {
+        self.validator.start_refreshing();
+    }
+
+    /// Returns whether `path` is that of the redirect URL.
+    pub fn is_callback(&self, path: &str) -> bool {
+        path == self.callback_path
+    }
+
+    /// Redirects the browser sending `request` to the provider to log in.
+    pub fn login<B>(&self, request: &Request<B>)
+        -> ServerFuture<Response<Body>>
+    {
+        let login = Login {
+            state: random_string(),
+            nonce: random_string(),
+            verifier: random_string(),
+            target: request.uri().path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_owned(),
+            exp: crate::signing::unix_time_now() + LOGIN_LIFETIME,
+        };
+        let challenge =
+            session::base64_url(&Sha256::digest(login.verifier.as_bytes()));
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let separator =
+            if self.authorization_endpoint.contains('?') {'&'} else {'?'};
+        let location = format!("{}{}{}", self.authorization_endpoint,
+            separator, query);
+        let res = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, location)
+            .header(header::SET_COOKIE, self.sessions.set_cookie(LOGIN_COOKIE,
+                &self.sessions.seal(&login), LOGIN_LIFETIME))
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::empty());
+        Box::new(future::result(res))
+    }
+
+    /// Completes the login of the user sent back by the provider with
+    /// `request`.
+    pub fn callback<B>(self: &Arc<Self>, request: &Request<B>)
+        -> ServerFuture<Response<Body>>
+    {
+        let query = request.uri().query().unwrap_or("");
+        let param = |name| form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned());
+        let login = session::cookie(request.headers(), LOGIN_COOKIE)
+            .and_then(|login| self.sessions.open::<Login>(login))
+            .filter(|login| login.exp > crate::signing::unix_time_now());
+        let login = match (login, param("state")) {
+            (Some(login), Some(state)) if state == login.state => login,
+            _ => return Problem::new(StatusCode::BAD_REQUEST,
+                "Invalid or expired login").respond(),
+        };
+        let code = match param("code") {
+            Some(code) => code,
+            None => return Problem::new(StatusCode::FORBIDDEN,
+                "Login failed").respond(),
+        };
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "authorization_code")
+            .append_pair("code", &code)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("code_verifier", &login.verifier);
+        if let Some(secret) = &self.client_secret {
+            form.append_pair("client_secret", secret);
+        }
+        let token_request = Request::post(self.token_endpoint.clone())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from(form.finish()));
+        let token_request = match token_request {
+            Ok(token_request) => token_request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let provider = self.clone();
+        let res = self.client.request(token_request)
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, body))
+            })
+            .then(move |res| {
+                let claims = match res {
+                    Ok((status, body)) if status.is_success() =>
+                        serde_json::from_slice::<TokenResponse>(&body).ok()
+                            .and_then(|token| {
+                                provider.validator.validate(&token.id_token)
+                            }),
+                    Ok((status, _)) => {
+                        warn!("Token endpoint responded with {}", status);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Failed to request token: {}", e);
+                        None
+                    }
+                };
+                let claims = claims.filter(|claims| {
+                    claims.get("nonce").and_then(|n| n.as_str())
+                        == Some(login.nonce.as_str())
+                });
+                match claims {
+                    Some(claims) => provider.start_session(claims,
+                        &login.target),
+                    None => Problem::new(StatusCode::FORBIDDEN,
+                        "Login failed").respond_with(&mut Response::builder()),
+                }
+            });
+        Box::new(res)
+    }
+
+    fn start_session(&self, claims: Claims, target: &str)
+        -> http::Result<Response<Body>>
+    {
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, format!("{}{}", self.base_path, target))
+            .header(header::SET_COOKIE, self.sessions.start(claims))
+            .header(header::SET_COOKIE,
+                self.sessions.set_cookie(LOGIN_COOKIE, "", 0))
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+    }
+}
+
+fn secure_uri(url: &str) -> Option<Uri> {
+    url.parse::<Uri>().ok().filter(https::is_secure)
+}
+
+fn random_string() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness");
+    session::base64_url(&bytes)
+}
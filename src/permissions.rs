@@ -0,0 +1,147 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Permissions and owners of files, as shown in listings.
+//!
+//! On Unix, modes are shown as by `ls -l` and owners are resolved to names
+//! when possible. Elsewhere, only whether files are read-only is known.
+
+use serde::Serialize;
+use std::fs::Metadata;
+
+/// Permissions and owner of a file.
+#[derive(Debug, Serialize)]
+pub struct Permissions {
+    /// Mode as shown by `ls -l`, e.g. `drwxr-xr-x`.
+    pub mode: String,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Permissions {
+    /// Returns the permissions of a file with metadata `meta`, not following
+    /// symbolic links.
+    #[cfg(unix)]
+    pub fn of(meta: &Metadata) -> Permissions {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() {
+            'd'
+        } else if file_type.is_symlink() {
+            'l'
+        } else if file_type.is_block_device() {
+            'b'
+        } else if file_type.is_char_device() {
+            'c'
+        } else if file_type.is_fifo() {
+            'p'
+        } else if file_type.is_socket() {
+            's'
+        } else {
+            '-'
+        };
+        let bits = meta.mode();
+        let mut mode = String::with_capacity(10);
+        mode.push(kind);
+        // Permission bits of the user, group and others, with the special
+        // bit of each class and its letters when the class can execute or not.
+        let classes = [
+            (6, 0o4000, 's', 'S'),
+            (3, 0o2000, 's', 'S'),
+            (0, 0o1000, 't', 'T'),
+        ];
+        for (shift, special, exec_letter, letter) in classes {
+            let class = (bits >> shift) & 0o7;
+            mode.push(if class & 0o4 != 0 {'r'} else {'-'});
+            mode.push(if class & 0o2 != 0 {'w'} else {'-'});
+            mode.push(match (class & 0o1 != 0, bits & special != 0) {
+                (true, true) => exec_letter,
+                (false, true) => letter,
+                (true, false) => 'x',
+                (false, false) => '-',
+            });
+        }
+        Permissions {
+            mode,
+            owner: Some(user_name(meta.uid())
+                .unwrap_or_else(|| meta.uid().to_string())),
+            group: Some(group_name(meta.gid())
+                .unwrap_or_else(|| meta.gid().to_string())),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(meta: &Metadata) -> Permissions {
+        let kind = if meta.is_dir() {'d'} else {'-'};
+        let access = if meta.permissions().readonly() {"r-"} else {"rw"};
+        Permissions {
+            mode: format!("{}{}", kind, access),
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Returns the owner and group separated by a colon.
+    pub fn ownership(&self) -> String {
+        match (&self.owner, &self.group) {
+            (Some(owner), Some(group)) => format!("{}:{}", owner, group),
+            (Some(owner), None) => owner.clone(),
+            (None, Some(group)) => format!(":{}", group),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Size above which buffers for user and group entries are not grown.
+#[cfg(unix)]
+const MAX_ENTRY_BUFFER: usize = 1 << 16;
+
+#[cfg(unix)]
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut entry = unsafe {std::mem::zeroed::<libc::passwd>()};
+        let mut result = std::ptr::null_mut();
+        let res = unsafe {
+            libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(),
+                &mut result)
+        };
+        match res {
+            0 if result.is_null() => return None,
+            0 => return Some(unsafe {c_string(entry.pw_name)}),
+            libc::ERANGE if buf.len() < MAX_ENTRY_BUFFER =>
+                buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn group_name(gid: libc::gid_t) -> Option<String> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut entry = unsafe {std::mem::zeroed::<libc::group>()};
+        let mut result = std::ptr::null_mut();
+        let res = unsafe {
+            libc::getgrgid_r(gid, &mut entry, buf.as_mut_ptr(), buf.len(),
+                &mut result)
+        };
+        match res {
+            0 if result.is_null() => return None,
+            0 => return Some(unsafe {c_string(entry.gr_name)}),
+            libc::ERANGE if buf.len() < MAX_ENTRY_BUFFER =>
+                buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
+
+/// Converts a C string, replacing invalid UTF-8.
+///
+/// # Safety
+///
+/// `s` must point to a nul-terminated string.
+#[cfg(unix)]
+unsafe fn c_string(s: *const libc::c_char) -> String {
+    std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned()
+}
@@ -0,0 +1,150 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Dropping of privileges once listening sockets are bound.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Identity and confinement to adopt.
+#[derive(Debug, Default)]
+pub struct Settings<'a> {
+    pub user: Option<&'a str>,
+    pub group: Option<&'a str>,
+    /// Directory to make the root of the file system.
+    pub chroot: Option<&'a Path>,
+}
+
+impl Settings<'_> {
+    fn is_empty(&self) -> bool {
+        self.user.is_none() && self.group.is_none() && self.chroot.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum PrivilegeError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    Chroot(io::Error),
+    SetGroups(io::Error),
+    SetGid(io::Error),
+    SetUid(io::Error),
+    #[cfg(not(unix))]
+    Unsupported,
+}
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrivilegeError::UnknownUser(name) =>
+                write!(f, "Unknown user {}", name),
+            PrivilegeError::UnknownGroup(name) =>
+                write!(f, "Unknown group {}", name),
+            PrivilegeError::Chroot(_) =>
+                f.write_str("Failed to change root directory"),
+            PrivilegeError::SetGroups(_) =>
+                f.write_str("Failed to set supplementary groups"),
+            PrivilegeError::SetGid(_) => f.write_str("Failed to change group"),
+            PrivilegeError::SetUid(_) => f.write_str("Failed to change user"),
+            #[cfg(not(unix))]
+            PrivilegeError::Unsupported =>
+                f.write_str("Dropping privileges is not supported on this \
+                    platform"),
+        }
+    }
+}
+
+impl Error for PrivilegeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PrivilegeError::Chroot(e)
+                | PrivilegeError::SetGroups(e)
+                | PrivilegeError::SetGid(e)
+                | PrivilegeError::SetUid(e)
+                => Some(e),
+            PrivilegeError::UnknownUser(_) | PrivilegeError::UnknownGroup(_)
+                => None,
+            #[cfg(not(unix))]
+            PrivilegeError::Unsupported => None,
+        }
+    }
+}
+
+/// Confines the process and switches to the requested user and group. Names
+/// are resolved before changing the root directory. When only a user is
+/// given, its primary group is used.
+#[cfg(unix)]
+pub fn apply(settings: &Settings) -> Result<(), PrivilegeError> {
+    use std::ffi::CString;
+
+    if settings.is_empty() {return Ok(())}
+    let user = settings.user.map(|name| {
+        lookup_user(name)
+            .ok_or_else(|| PrivilegeError::UnknownUser(name.into()))
+    }).transpose()?;
+    let gid = match settings.group {
+        Some(name) => Some(lookup_group(name)
+            .ok_or_else(|| PrivilegeError::UnknownGroup(name.into()))?),
+        None => user.map(|(_, gid)| gid),
+    };
+    if let Some(gid) = gid {
+        let res = match settings.user {
+            Some(name) => {
+                let name = CString::new(name)
+                    .map_err(|_| PrivilegeError::UnknownUser(name.into()))?;
+                unsafe {libc::initgroups(name.as_ptr(), gid as _)}
+            }
+            None => unsafe {libc::setgroups(1, &gid)},
+        };
+        check(res).map_err(PrivilegeError::SetGroups)?;
+    }
+    if let Some(dir) = settings.chroot {
+        std::os::unix::fs::chroot(dir).map_err(PrivilegeError::Chroot)?;
+        std::env::set_current_dir("/").map_err(PrivilegeError::Chroot)?;
+    }
+    if let Some(gid) = gid {
+        check(unsafe {libc::setgid(gid)}).map_err(PrivilegeError::SetGid)?;
+    }
+    if let Some((uid, _)) = user {
+        check(unsafe {libc::setuid(uid)}).map_err(PrivilegeError::SetUid)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(settings: &Settings) -> Result<(), PrivilegeError> {
+    if settings.is_empty() {Ok(())} else {Err(PrivilegeError::Unsupported)}
+}
+
+#[cfg(unix)]
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res == 0 {Ok(())} else {Err(io::Error::last_os_error())}
+}
+
+/// Returns the user and primary group IDs of a user name or numeric ID.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let entry = unsafe {libc::getpwnam(c_name.as_ptr())};
+    if !entry.is_null() {
+        let entry = unsafe {&*entry};
+        return Some((entry.pw_uid, entry.pw_gid))
+    }
+    let uid = name.parse().ok()?;
+    let entry = unsafe {libc::getpwuid(uid)};
+    if entry.is_null() {return None}
+    Some((uid, unsafe {(*entry).pw_gid}))
+}
+
+/// Returns the ID of a group name or numeric ID.
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Option<libc::gid_t> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let entry = unsafe {libc::getgrnam(c_name.as_ptr())};
+    if entry.is_null() {
+        name.parse().ok()
+    } else {
+        Some(unsafe {(*entry).gr_gid})
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Error responses suited to the clients receiving them.
+//!
+//! Errors are explained in plain text unless clients prefer HTML, as
+//! browsers do, in which case they get a page styled like listings, or JSON,
+//! in which case they get problem details as defined by RFC 9457.
+//!
+//! Details of internal errors, such as the messages of the operating system,
+//! may reveal paths and are logged rather than sent to clients, unless
+//! verbose errors are enabled.
+
+use crate::{Config, ServerFuture};
+use futures::future;
+use http::{Response, StatusCode};
+use http::header::{self, HeaderMap};
+use http::response::Builder;
+use hyper::Body;
+use nestxml::html;
+use serde_json::json;
+use tracing::{debug, warn};
+
+/// Error explained by a response, rendered once the client is known.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    status: StatusCode,
+    detail: String,
+    /// Whether the detail reveals the inner workings of the server.
+    internal: bool,
+}
+
+impl Problem {
+    pub fn new<S: Into<String>>(status: StatusCode, detail: S) -> Problem {
+        Problem {status, detail: detail.into(), internal: false}
+    }
+
+    /// Marks the detail as only sent to clients with verbose errors, and
+    /// logs it.
+    pub fn internal(mut self) -> Problem {
+        log(self.status, &self.detail);
+        self.internal = true;
+        self
+    }
+
+    /// Returns a response with the headers set on `builder`.
+    pub fn respond_with(self, builder: &mut Builder)
+        -> http::Result<Response<Body>>
+    {
+        let body = self.detail.clone();
+        builder.status(self.status).extension(self).body(body.into())
+    }
+
+    pub fn respond(self) -> ServerFuture<Response<Body>> {
+        Box::new(future::result(self.respond_with(&mut Response::builder())))
+    }
+
+    fn title(&self) -> &'static str {
+        self.status.canonical_reason().unwrap_or("Error")
+    }
+}
+
+/// Format of error responses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Html,
+    Json,
+}
+
+impl Format {
+    /// Returns the format preferred by a client sending `headers`, i.e. the
+    /// format it accepts with the highest quality, or text if it accepts
+    /// several of them equally.
+    pub fn negotiate(headers: &HeaderMap) -> Format {
+        let text = crate::listing::quality(headers, "text", "plain");
+        let html = crate::listing::quality(headers, "text", "html");
+        let json = crate::listing::quality(headers, "application", "json")
+            .max(crate::listing::quality(headers, "application",
+                "problem+json"));
+        if html > text && html >= json {
+            Format::Html
+        } else if json > text && json > html {
+            Format::Json
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// Renders the problem `res` explains, if any, in `format`. `instance` is
+/// the path of the request.
+pub fn render(config: &Config, format: Format, instance: &str,
+    mut res: Response<Body>) -> Response<Body>
+{
+    let problem = match res.extensions_mut().remove::<Problem>() {
+        Some(problem) => problem,
+        None => return res,
+    };
+    let title = problem.title();
+    let detail = Some(problem.detail.as_str())
+        .filter(|_| !problem.internal || config.verbose_errors);
+    let (content_type, body) = match format {
+        Format::Text => match detail {
+            Some(_) => (None, None),
+            None => (None, Some(title.to_owned())),
+        },
+        Format::Html => {
+            let heading = format!("{} {}", problem.status.as_u16(), title);
+            let mut out = Vec::<u8>::new();
+            crate::write_page(&mut out, &config.style, &heading, |out| {
+                html::h1(out).text(&heading)?;
+                match detail {
+                    Some(detail) if detail != title =>
+                        nestxml::element(out, "p").text(detail),
+                    _ => Ok(()),
+                }
+            }).unwrap();
+            let page = String::from_utf8(out).unwrap();
+            (Some(mime::TEXT_HTML_UTF_8.to_string()), Some(page))
+        }
+        Format::Json => {
+            let mut value = json!({
+                "type": "about:blank",
+                "title": title,
+                "status": problem.status.as_u16(),
+                "instance": instance,
+            });
+            if let Some(detail) = detail {
+                value["detail"] = detail.into();
+            }
+            let content_type = "application/problem+json".to_owned();
+            (Some(content_type), Some(value.to_string()))
+        }
+    };
+    let headers = res.headers_mut();
+    headers.append(header::VARY, header::HeaderValue::from_static("Accept"));
+    if let Some(content_type) = content_type {
+        if let Ok(value) = content_type.parse() {
+            headers.insert(header::CONTENT_TYPE, value);
+        }
+    }
+    if let Some(body) = body {
+        headers.remove(header::CONTENT_LENGTH);
+        *res.body_mut() = body.into();
+    }
+    res
+}
+
+/// Logs the details of an internal error answered with `status`.
+pub fn log(status: StatusCode, detail: &str) {
+    if status.is_server_error() {
+        warn!("{}", detail);
+    } else {
+        debug!("{}", detail);
+    }
+}
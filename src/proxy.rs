@@ -0,0 +1,193 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Forwarding of requests to backend HTTP servers.
+//!
+//! Bodies are streamed in both directions. Backends receive the client
+//! address as determined by this server in `X-Forwarded-For`, along with
+//! `X-Forwarded-Host` and `X-Forwarded-Proto`, replacing any such headers
+//! sent by the client.
+
+use crate::{https, ServerFuture};
+use futures::Future;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::uri::{Authority, Scheme, Uri};
+use http::{Request, Response, StatusCode, Version};
+use hyper::{Body, Client};
+use std::net::IpAddr;
+use tracing::warn;
+
+/// Headers concerning a single connection, which are not forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers describing the original request, which are set by the proxy.
+const FORWARDED_HEADERS: &[&str] = &[
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+];
+
+/// Server that requests are forwarded to.
+#[derive(Debug)]
+pub struct Backend {
+    authority: Authority,
+    /// Path prepended to forwarded request paths, without trailing slash.
+    path: String,
+}
+
+impl Backend {
+    /// Parses the URL of a backend, e.g. `http://localhost:3000/app`.
+    pub fn parse(url: &str) -> Option<Backend> {
+        let uri = url.parse::<Uri>().ok()?;
+        if uri.scheme_part() != Some(&Scheme::HTTP) || uri.query().is_some() {
+            return None
+        }
+        Some(Backend {
+            authority: uri.authority_part()?.clone(),
+            path: uri.path().trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+/// Backend serving the request paths starting with a prefix.
+#[derive(Debug)]
+struct Mount {
+    /// Path prefix, without trailing slash.
+    prefix: String,
+    backend: Backend,
+}
+
+impl Mount {
+    /// Returns the remainder of `path` after the prefix of this mount, if
+    /// `path` starts with it.
+    fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') || rest.starts_with('?') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+}
+
+/// Client forwarding requests to backends.
+pub struct Proxy {
+    client: Client<https::Connector>,
+    https: bool,
+    fallback: Option<Backend>,
+    /// Mounts, by decreasing prefix length.
+    mounts: Vec<Mount>,
+}
+
+impl Proxy {
+    /// Creates a proxy for a server reached over HTTPS if `https` is true.
+    pub fn new(https: bool) -> Proxy {
+        Proxy {
+            client: https::client(),
+            https,
+            fallback: None,
+            mounts: Vec::new(),
+        }
+    }
+
+    /// Forwards requests for `prefix` and the paths below it to `backend`,
+    /// without the prefix.
+    pub fn mount(&mut self, prefix: &str, backend: Backend) {
+        let prefix = prefix.trim_end_matches('/').to_owned();
+        let i = self.mounts.iter()
+            .position(|mount| mount.prefix.len() < prefix.len())
+            .unwrap_or(self.mounts.len());
+        self.mounts.insert(i, Mount {prefix, backend});
+    }
+
+    /// Returns the backend of the longest prefix of the path of `request`,
+    /// with the path and query to request from it.
+    pub fn route(&self, request: &Request<Body>) -> Option<(&Backend, String)>
+    {
+        let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+        self.mounts.iter().find_map(|mount| {
+            let rest = mount.strip(path)?;
+            let rest = if rest.starts_with('/') {
+                rest.to_owned()
+            } else {
+                format!("/{}", rest)
+            };
+            Some((&mount.backend, rest))
+        })
+    }
+
+    /// Forwards requests that do not resolve to a file to `backend`.
+    pub fn set_fallback(&mut self, backend: Backend) {
+        self.fallback = Some(backend);
+    }
+
+    /// Returns the backend for requests that do not resolve to a file.
+    pub fn fallback(&self) -> Option<&Backend> {
+        self.fallback.as_ref()
+    }
+
+    /// Forwards `request` from `client` to `backend`, requesting `path` with
+    /// its query relative to the backend URL.
+    pub fn forward(&self, backend: &Backend, path: &str, client: IpAddr,
+        mut request: Request<Body>) -> ServerFuture<Response<Body>>
+    {
+        let uri = format!("http://{}{}{}", backend.authority, backend.path,
+            path);
+        *request.uri_mut() = match uri.parse() {
+            Ok(uri) => uri,
+            Err(_) => return crate::bad_request(),
+        };
+        *request.version_mut() = Version::HTTP_11;
+        let headers = request.headers_mut();
+        remove_hop_by_hop_headers(headers);
+        for name in FORWARDED_HEADERS {
+            headers.remove(*name);
+        }
+        if let Some(host) = headers.remove(header::HOST) {
+            headers.insert("x-forwarded-host", host);
+        }
+        if let Ok(client) = HeaderValue::from_str(&client.to_string()) {
+            headers.insert("x-forwarded-for", client);
+        }
+        headers.insert("x-forwarded-proto", HeaderValue::from_static(
+            if self.https {"https"} else {"http"}));
+        let res = self.client.request(request).then(|res| match res {
+            Ok(mut res) => {
+                *res.version_mut() = Version::default();
+                remove_hop_by_hop_headers(res.headers_mut());
+                Ok(res)
+            }
+            Err(e) => {
+                warn!("Failed to forward request: {}", e);
+                Response::builder().status(StatusCode::BAD_GATEWAY)
+                    .body("Bad gateway".into())
+            }
+        });
+        Box::new(res)
+    }
+}
+
+/// Removes the standard hop-by-hop headers and those listed in `Connection`.
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed = headers.get_all(header::CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Range requests.
+//!
+//! Files are sent with an `ETag`, see `etag`, so that a client resuming a
+//! download with `If-Range` gets the whole file again if it changed in the
+//! meantime.
+//!
+//! Several ranges are sent as a `multipart/byteranges` body. Requests for
+//! too many ranges, or for ranges adding up to more than the file, get the
+//! whole file instead.
+
+use http::header::{HeaderMap, IF_RANGE, RANGE};
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of ranges served in a response.
+const MAX_RANGES: usize = 32;
+
+/// Range of bytes of a file, ending before `end`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Returns the value of the `Content-Range` header for this range of a
+    /// file of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end - 1, total)
+    }
+}
+
+/// Part of a file requested.
+#[derive(Debug)]
+pub enum Requested {
+    /// The whole file.
+    Full,
+    /// Satisfiable ranges, in the order requested.
+    Ranges(Vec<ByteRange>),
+    /// None of the requested ranges is within the file.
+    Unsatisfiable,
+}
+
+/// Validators of a file version.
+#[derive(Debug)]
+pub struct Validators {
+    pub etag: String,
+    pub modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new(etag: String, meta: &Metadata) -> Validators {
+        Validators {etag, modified: meta.modified().ok()}
+    }
+
+    /// Returns the value of the `Last-Modified` header.
+    pub fn last_modified(&self) -> Option<String> {
+        self.modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Returns whether an `If-Range` value designates this version. Weak
+    /// tags never match, and dates must be those of `Last-Modified`.
+    fn matches(&self, if_range: &str) -> bool {
+        if if_range.starts_with('"') {
+            return if_range == self.etag
+        }
+        match (httpdate::parse_http_date(if_range), self.modified) {
+            (Ok(date), Some(modified)) => {
+                let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .ok();
+                seconds(date) == seconds(modified)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns the part of a file of `len` bytes requested with `headers`.
+pub fn requested(headers: &HeaderMap, len: u64, validators: &Validators)
+    -> Requested
+{
+    let range = match headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) => range,
+        None => return Requested::Full,
+    };
+    if let Some(if_range) = headers.get(IF_RANGE) {
+        let matches = if_range.to_str().ok()
+            .is_some_and(|v| validators.matches(v.trim()));
+        if !matches {
+            return Requested::Full
+        }
+    }
+    match parse(range, len) {
+        Some(ranges) if ranges.is_empty() => Requested::Unsatisfiable,
+        Some(ranges) if ranges.len() > MAX_RANGES => Requested::Full,
+        Some(ranges) if ranges.iter().map(ByteRange::len).sum::<u64>() > len =>
+            Requested::Full,
+        Some(ranges) => Requested::Ranges(ranges),
+        None => Requested::Full,
+    }
+}
+
+/// Parses a `Range` header value, keeping the satisfiable ranges. Returns
+/// `None` if the value is invalid, in which case it is ignored.
+fn parse(value: &str, len: u64) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = value.split_once('=')?;
+    let mut specs = specs.split(',').map(str::trim).filter(|s| !s.is_empty())
+        .peekable();
+    if !unit.trim().eq_ignore_ascii_case("bytes") || specs.peek().is_none() {
+        return None
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            let suffix = parse_position(last)?;
+            if suffix == 0 || len == 0 {
+                continue
+            }
+            ByteRange {start: len.saturating_sub(suffix), end: len}
+        } else {
+            let start = parse_position(first)?;
+            let end = match last {
+                "" => len,
+                last => {
+                    let last = parse_position(last)?;
+                    if last < start {
+                        return None
+                    }
+                    last.saturating_add(1).min(len)
+                }
+            };
+            if start >= len {
+                continue
+            }
+            ByteRange {start, end}
+        };
+        ranges.push(range);
+    }
+    Some(ranges)
+}
+
+/// Parses a position in a range, made of decimal digits only.
+fn parse_position(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+    s.parse().ok()
+}
+
+/// Layout of a `multipart/byteranges` body.
+#[derive(Debug)]
+pub struct Multipart {
+    boundary: String,
+    content_type: String,
+    total: u64,
+}
+
+impl Multipart {
+    /// Creates the layout of the parts of a file of `total` bytes with type
+    /// `content_type`.
+    pub fn new(content_type: &str, total: u64) -> Multipart {
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes).expect("No source of randomness");
+        Multipart {
+            boundary: crate::checksum::to_hex(&bytes),
+            content_type: content_type.to_owned(),
+            total,
+        }
+    }
+
+    /// Returns the value of the `Content-Type` header of the response.
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Returns the delimiter and headers preceding the part for `range`.
+    pub fn part_header(&self, range: &ByteRange) -> String {
+        format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary, self.content_type, range.content_range(self.total))
+    }
+
+    /// Returns the delimiter ending the body.
+    pub fn trailer(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Returns the length of the body made of `ranges`.
+    pub fn len(&self, ranges: &[ByteRange]) -> u64 {
+        ranges.iter()
+            .map(|r| self.part_header(r).len() as u64 + r.len())
+            .sum::<u64>() + self.trailer().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ByteRange};
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange {start, end}
+    }
+
+    #[test]
+    fn single_ranges_are_parsed() {
+        assert_eq!(parse("bytes=0-9", 100), Some(vec![range(0, 10)]));
+        assert_eq!(parse("bytes=90-", 100), Some(vec![range(90, 100)]));
+        assert_eq!(parse("bytes=-10", 100), Some(vec![range(90, 100)]));
+        assert_eq!(parse(" BYTES = 1 - 2 ", 100), Some(vec![range(1, 3)]));
+    }
+
+    #[test]
+    fn several_ranges_are_kept_in_order() {
+        assert_eq!(parse("bytes=5-6, 0-1,,", 100),
+            Some(vec![range(5, 7), range(0, 2)]));
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_file() {
+        assert_eq!(parse("bytes=90-200", 100), Some(vec![range(90, 100)]));
+        assert_eq!(parse("bytes=-200", 100), Some(vec![range(0, 100)]));
+        assert_eq!(parse("bytes=99-99", 100), Some(vec![range(99, 100)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_dropped() {
+        assert_eq!(parse("bytes=-0", 100), Some(vec![]));
+        assert_eq!(parse("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse("bytes=100-200, 0-0", 100), Some(vec![range(0, 1)]));
+        assert_eq!(parse("bytes=0-", 0), Some(vec![]));
+        assert_eq!(parse("bytes=-1", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn overflowing_positions_are_handled() {
+        assert_eq!(parse("bytes=0-18446744073709551615", 100),
+            Some(vec![range(0, 100)]));
+        assert_eq!(parse("bytes=-18446744073709551615", 100),
+            Some(vec![range(0, 100)]));
+        assert_eq!(parse("bytes=0-18446744073709551616", 100), None);
+        assert_eq!(parse("bytes=18446744073709551616-", 100), None);
+    }
+
+    #[test]
+    fn malformed_values_are_ignored() {
+        for value in ["bytes", "bytes=", "bytes= , ", "items=0-1", "bytes=1",
+            "bytes=-", "bytes=5-4", "bytes=a-b", "bytes=--1", "bytes=+1-2",
+            "bytes=1-+2", "bytes=-+1", "bytes=0-1;2-3", "bytes=1.5-2"]
+        {
+            assert_eq!(parse(value, 100), None, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! README files rendered below directory listings.
+//!
+//! `README.md` is rendered from Markdown and `README.txt` is shown as
+//! preformatted text. HTML embedded in Markdown is shown as text, and links
+//! are only followed for the `http`, `https` and `mailto` schemes.
+
+use crate::beneath::RootDir;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use std::io::{Read, Write};
+use std::path::Path;
+use xml::writer::XmlEvent;
+
+/// Names of README files, by decreasing precedence.
+const FILE_NAMES: &[&str] = &["README.md", "README.txt"];
+
+/// Size above which README files are not rendered.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Schemes of the links followed from README files.
+const SAFE_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+/// Contents of a README file.
+#[derive(Clone, Debug)]
+pub enum Readme {
+    Markdown(String),
+    Text(String),
+}
+
+/// Returns the README of the directory `dir`, relative to the served
+/// directory, if it has one.
+pub fn find(root_dir: &RootDir, dir: &Path) -> Option<Readme> {
+    FILE_NAMES.iter().find_map(|name| {
+        let file = root_dir.open_file(&dir.join(name)).ok()?;
+        let meta = file.metadata().ok()?;
+        if !meta.is_file() || meta.len() > MAX_SIZE {
+            return None
+        }
+        let mut contents = String::new();
+        file.take(MAX_SIZE).read_to_string(&mut contents).ok()?;
+        Some(if name.ends_with(".md") {
+            Readme::Markdown(contents)
+        } else {
+            Readme::Text(contents)
+        })
+    })
+}
+
+impl Readme {
+    /// Writes the README as an HTML section.
+    pub fn write<W: Write>(&self, out: &mut xml::EventWriter<W>)
+        -> Result<(), xml::writer::Error>
+    {
+        nestxml::element(out, "section").attr("class", "readme")
+            .write(|out| match self {
+                Readme::Markdown(text) => write_markdown(text, out),
+                Readme::Text(text) =>
+                    nestxml::element(out, "pre").text(text),
+            })
+    }
+
+    /// Returns the README as an HTML fragment.
+    pub fn to_html(&self) -> Result<String, xml::writer::Error> {
+        let mut out = Vec::new();
+        let mut writer = xml::EmitterConfig::new()
+            .write_document_declaration(false)
+            .create_writer(&mut out);
+        self.write(&mut writer)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+}
+
+/// Writes the HTML rendering of Markdown `text`.
+fn write_markdown<W: Write>(text: &str, out: &mut xml::EventWriter<W>)
+    -> Result<(), xml::writer::Error>
+{
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+    // Elements closed by the end of each open tag.
+    let mut open = Vec::<&[&str]>::new();
+    let mut in_table_head = false;
+    // Source, title and alternative text of the image being read, with the
+    // depth of the tags nested in it.
+    let mut image = None::<(String, String, String, usize)>;
+    for event in Parser::new_ext(text, options) {
+        if let Some((src, title, alt, depth)) = &mut image {
+            match event {
+                Event::Start(_) => *depth += 1,
+                Event::End(_) if *depth > 0 => *depth -= 1,
+                Event::End(_) => {
+                    let mut element = XmlEvent::start_element("img")
+                        .attr("src", safe_url(src))
+                        .attr("alt", alt);
+                    if !title.is_empty() {
+                        element = element.attr("title", title);
+                    }
+                    out.write(element)?;
+                    out.write(XmlEvent::end_element())?;
+                    image = None;
+                }
+                Event::Text(text) | Event::Code(text) => alt.push_str(&text),
+                _ => {}
+            }
+            continue
+        }
+        match event {
+            Event::Start(Tag::Image {dest_url, title, ..}) => {
+                image = Some((dest_url.into_string(), title.into_string(),
+                    String::new(), 0));
+            }
+            Event::Start(tag) => {
+                let names: &[&str] = match &tag {
+                    Tag::Paragraph => &["p"],
+                    Tag::Heading {level, ..} => match *level as usize {
+                        1 => &["h1"],
+                        2 => &["h2"],
+                        3 => &["h3"],
+                        4 => &["h4"],
+                        5 => &["h5"],
+                        _ => &["h6"],
+                    },
+                    Tag::BlockQuote(_) => &["blockquote"],
+                    Tag::CodeBlock(_) => &["pre"],
+                    Tag::List(Some(_)) => &["ol"],
+                    Tag::List(None) => &["ul"],
+                    Tag::Item => &["li"],
+                    Tag::Table(_) => &["table"],
+                    Tag::TableHead => {
+                        in_table_head = true;
+                        &["thead", "tr"]
+                    }
+                    Tag::TableRow => &["tr"],
+                    Tag::TableCell if in_table_head => &["th"],
+                    Tag::TableCell => &["td"],
+                    Tag::Emphasis => &["em"],
+                    Tag::Strong => &["strong"],
+                    Tag::Strikethrough => &["del"],
+                    Tag::Link {..} => &["a"],
+                    _ => &[],
+                };
+                for name in names {
+                    let mut element = XmlEvent::start_element(*name);
+                    match &tag {
+                        Tag::List(Some(start)) if *start != 1 => {
+                            out.write(element.attr("start",
+                                &start.to_string()))?;
+                            continue
+                        }
+                        Tag::Link {dest_url, title, ..} => {
+                            element = element.attr("href", safe_url(dest_url));
+                            if !title.is_empty() {
+                                element = element.attr("title", title);
+                            }
+                        }
+                        _ => {}
+                    }
+                    out.write(element)?;
+                }
+                open.push(names);
+            }
+            Event::End(end) => {
+                if end == TagEnd::TableHead {
+                    in_table_head = false;
+                }
+                for _ in open.pop().unwrap_or_default() {
+                    out.write(XmlEvent::end_element())?;
+                }
+            }
+            Event::Text(text) | Event::Html(text) | Event::InlineHtml(text) =>
+                out.write(&*text)?,
+            Event::Code(text) => nestxml::element(out, "code").text(&text)?,
+            Event::SoftBreak => out.write("\n")?,
+            Event::HardBreak => nestxml::element(out, "br").empty()?,
+            Event::Rule => nestxml::element(out, "hr").empty()?,
+            Event::TaskListMarker(checked) => {
+                let mut input = nestxml::element(out, "input")
+                    .attr("type", "checkbox")
+                    .attr("disabled", "disabled");
+                if checked {
+                    input = input.attr("checked", "checked");
+                }
+                input.empty()?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns `url` if its scheme is safe to follow, or a link to the current
+/// page otherwise.
+fn safe_url(url: &str) -> &str {
+    let scheme = url.split(['/', '?', '#']).next()
+        .and_then(|prefix| prefix.split_once(':'))
+        .map(|(scheme, _)| scheme.to_ascii_lowercase());
+    match scheme {
+        Some(scheme) if !SAFE_SCHEMES.contains(&scheme.as_str()) => "#",
+        _ => url,
+    }
+}
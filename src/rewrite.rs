@@ -0,0 +1,130 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! URL rewrite and redirect rules.
+//!
+//! ```text
+//! # Serve /new/... for requests of /old/...
+//! rewrite ^/old/(.*)$ /new/$1
+//! redirect 301 /blog https://blog.example.com
+//! ```
+//!
+//! Patterns are regular expressions matched against the whole request path,
+//! as sent by the client. Targets may refer to the groups of the pattern as
+//! `$1`, `$2`, etc. Rules are evaluated in order and the first match applies.
+//! The query of the request is kept unless the target has one.
+
+use http::StatusCode;
+use regex::Regex;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+enum Action {
+    Rewrite(String),
+    Redirect(StatusCode, String),
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    action: Action,
+}
+
+/// Result of applying rules to a request.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Request path and query to serve instead.
+    Rewrite(String),
+    /// Status and location of the redirection to send.
+    Redirect(StatusCode, String),
+}
+
+/// Ordered rules.
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+#[derive(Debug)]
+pub enum RuleError {
+    Read(io::Error),
+    Syntax(usize),
+    InvalidPattern(usize, regex::Error),
+    InvalidStatus(usize),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleError::Read(_) => f.write_str("Failed to read file"),
+            RuleError::Syntax(line) =>
+                write!(f, "Invalid rule on line {}", line),
+            RuleError::InvalidPattern(line, _) =>
+                write!(f, "Invalid pattern on line {}", line),
+            RuleError::InvalidStatus(line) =>
+                write!(f, "Invalid redirection status on line {}", line),
+        }
+    }
+}
+
+impl Error for RuleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RuleError::Read(e) => Some(e),
+            RuleError::InvalidPattern(_, e) => Some(e),
+            RuleError::Syntax(_) | RuleError::InvalidStatus(_) => None,
+        }
+    }
+}
+
+impl Rules {
+    /// Reads rules from a file. Empty lines and lines starting with `#` are
+    /// ignored.
+    pub fn load(path: &Path) -> Result<Rules, RuleError> {
+        let contents = fs::read_to_string(path).map_err(RuleError::Read)?;
+        let mut rules = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line_number = i + 1;
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (pattern, action) = match fields[..] {
+                [] => continue,
+                [first, ..] if first.starts_with('#') => continue,
+                ["rewrite", pattern, target] =>
+                    (pattern, Action::Rewrite(target.to_owned())),
+                ["redirect", status, pattern, target] => {
+                    let status = status.parse::<StatusCode>().ok()
+                        .filter(StatusCode::is_redirection)
+                        .ok_or(RuleError::InvalidStatus(line_number))?;
+                    (pattern, Action::Redirect(status, target.to_owned()))
+                }
+                _ => return Err(RuleError::Syntax(line_number)),
+            };
+            let pattern = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| RuleError::InvalidPattern(line_number, e))?;
+            rules.push(Rule {pattern, action});
+        }
+        Ok(Rules(rules))
+    }
+
+    /// Applies the first rule matching `path`, whose query is `query`.
+    pub fn apply(&self, path: &str, query: Option<&str>) -> Option<Outcome> {
+        self.0.iter().find_map(|rule| {
+            let captures = rule.pattern.captures(path)?;
+            let expand = |target: &str| {
+                let mut expanded = String::new();
+                captures.expand(target, &mut expanded);
+                match query {
+                    Some(query) if !expanded.contains('?') =>
+                        format!("{}?{}", expanded, query),
+                    _ => expanded,
+                }
+            };
+            Some(match &rule.action {
+                Action::Rewrite(target) => Outcome::Rewrite(expand(target)),
+                Action::Redirect(status, target) =>
+                    Outcome::Redirect(*status, expand(target)),
+            })
+        })
+    }
+}
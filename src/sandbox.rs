@@ -0,0 +1,221 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Confinement of the process with Landlock and seccomp on Linux.
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum SandboxError {
+    #[cfg(target_os = "linux")]
+    Landlock(landlock::RulesetError),
+    #[cfg(target_os = "linux")]
+    Seccomp(seccompiler::Error),
+    #[cfg(not(target_os = "linux"))]
+    Unsupported,
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(target_os = "linux")]
+            SandboxError::Landlock(_) =>
+                f.write_str("Failed to restrict file system access"),
+            #[cfg(target_os = "linux")]
+            SandboxError::Seccomp(_) =>
+                f.write_str("Failed to restrict system calls"),
+            #[cfg(not(target_os = "linux"))]
+            SandboxError::Unsupported =>
+                f.write_str("Sandboxing is not supported on this platform"),
+        }
+    }
+}
+
+impl Error for SandboxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(target_os = "linux")]
+            SandboxError::Landlock(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            SandboxError::Seccomp(e) => Some(e),
+            #[cfg(not(target_os = "linux"))]
+            SandboxError::Unsupported => None,
+        }
+    }
+}
+
+/// Restricts file system access to reading the `roots` and `extra_files`
+/// and to changing files below `writable_dirs`, and system calls to those
+/// needed to serve files, and to change them if `writable_dirs` is not
+/// empty. Applies to the calling thread and the threads it later spawns, so
+/// this must run before any other thread is started.
+#[cfg(target_os = "linux")]
+pub fn apply(roots: &[&Path], writable_dirs: &[&Path], extra_files: &[&Path])
+    -> Result<(), SandboxError>
+{
+    restrict_files(roots, writable_dirs, extra_files)
+        .map_err(SandboxError::Landlock)?;
+    restrict_syscalls(!writable_dirs.is_empty())
+        .map_err(SandboxError::Seccomp)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_roots: &[&Path], _writable_dirs: &[&Path],
+    _extra_files: &[&Path]) -> Result<(), SandboxError>
+{
+    Err(SandboxError::Unsupported)
+}
+
+#[cfg(target_os = "linux")]
+fn restrict_files(roots: &[&Path], writable_dirs: &[&Path],
+    extra_files: &[&Path]) -> Result<(), landlock::RulesetError>
+{
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr,
+        RulesetCreatedAttr, RulesetStatus, ABI,
+    };
+
+    let abi = ABI::V3;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(roots, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(writable_dirs, AccessFs::from_all(abi)))?
+        .add_rules(path_beneath_rules(extra_files, AccessFs::from_read(abi)))?
+        .restrict_self()?;
+    if status.ruleset == RulesetStatus::NotEnforced {
+        tracing::warn!("Landlock is not supported by the kernel; file system \
+            access is not restricted");
+    }
+    Ok(())
+}
+
+/// System calls allowed in the sandbox.
+#[cfg(target_os = "linux")]
+const SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_connect,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_futex,
+    libc::SYS_getcwd,
+    libc::SYS_getdents64,
+    libc::SYS_getpeername,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_gettid,
+    libc::SYS_ioctl,
+    libc::SYS_listen,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_openat2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_pread64,
+    libc::SYS_prlimit64,
+    libc::SYS_read,
+    libc::SYS_readlinkat,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_statx,
+    libc::SYS_write,
+    libc::SYS_writev,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+];
+
+/// System calls also allowed in the sandbox when files may be changed.
+#[cfg(target_os = "linux")]
+const WRITE_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_copy_file_range,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fdatasync,
+    libc::SYS_fsync,
+    libc::SYS_ftruncate,
+    libc::SYS_mkdirat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_unlinkat,
+    libc::SYS_utimensat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+#[cfg(target_os = "linux")]
+fn restrict_syscalls(writable: bool) -> Result<(), seccompiler::Error> {
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+    use std::convert::TryInto;
+
+    let write_syscalls = if writable {WRITE_SYSCALLS} else {&[]};
+    let rules = SYSCALLS.iter().chain(write_syscalls)
+        .map(|&n| (n, Vec::new()))
+        .collect();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter(&program)
+}
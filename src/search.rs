@@ -0,0 +1,277 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Search over the names and contents of served files.
+//!
+//! Results only include files the client may read, and searching below a
+//! directory requires being allowed to read it. Symbolic links leading out
+//! of the served directory are not followed, and searches run on the
+//! blocking pool.
+
+use crate::{jwt, Config, ReadAccess, ServerFuture};
+use crate::beneath::RootDir;
+use crate::i18n::Messages;
+use crate::index::ContentIndex;
+use crate::url_path;
+use crate::vhost::Site;
+use futures::{future, Future};
+use glob::{MatchOptions, Pattern};
+use http::{HeaderMap, Request, Response};
+use hyper::Body;
+use nestxml::html;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use url::form_urlencoded;
+
+/// Path of the search endpoint.
+pub const ENDPOINT: &str = "/_api/search";
+
+struct Query {
+    text: String,
+    base: PathBuf,
+    glob: bool,
+    case_sensitive: bool,
+    content: bool,
+    html: bool,
+}
+
+impl Query {
+    fn parse(query: &str) -> Option<Query> {
+        let mut text = None;
+        let mut base = PathBuf::new();
+        let mut glob = false;
+        let mut case_sensitive = false;
+        let mut content = false;
+        let mut html = false;
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "q" => text = Some(value.into_owned()),
+                "path" => base = crate::sanitize_path(Path::new(&*value))?
+                    .to_owned(),
+                "glob" => glob = is_set(&value),
+                "case-sensitive" => case_sensitive = is_set(&value),
+                "content" => content = is_set(&value),
+                "format" => html = value == "html",
+                _ => {}
+            }
+        }
+        let text = text.filter(|t| !t.is_empty())?;
+        Some(Query {text, base, glob, case_sensitive, content, html})
+    }
+}
+
+fn is_set(value: &str) -> bool {
+    value != "0" && value != "false"
+}
+
+enum Matcher {
+    Substring(String, bool),
+    Glob(Pattern, MatchOptions),
+}
+
+impl Matcher {
+    fn new(query: &Query) -> Option<Matcher> {
+        if query.glob {
+            let options = MatchOptions {
+                case_sensitive: query.case_sensitive,
+                require_literal_separator: false,
+                require_literal_leading_dot: false,
+            };
+            Pattern::new(&query.text).ok()
+                .map(|p| Matcher::Glob(p, options))
+        } else if query.case_sensitive {
+            Some(Matcher::Substring(query.text.clone(), true))
+        } else {
+            Some(Matcher::Substring(query.text.to_lowercase(), false))
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Matcher::Substring(s, true) => name.contains(s.as_str()),
+            Matcher::Substring(s, false) =>
+                name.to_lowercase().contains(s.as_str()),
+            Matcher::Glob(p, options) => p.matches_with(name, *options),
+        }
+    }
+}
+
+/// Way to find matching files.
+enum Searcher {
+    /// Matching the names of files while walking the tree.
+    Names(Matcher),
+    Contents(Arc<RwLock<ContentIndex>>),
+}
+
+struct Outcome {
+    paths: Vec<PathBuf>,
+    truncated: bool,
+    timed_out: bool,
+}
+
+/// Walks the tree below the requested path and returns matching entries
+/// readable with `access`.
+fn search(root_dir: &RootDir, base: &Path, matcher: &Matcher,
+    access: &mut ReadAccess, limit: usize, timeout: Duration) -> Outcome
+{
+    let deadline = Instant::now() + timeout;
+    let mut outcome = Outcome {
+        paths: Vec::new(),
+        truncated: false,
+        timed_out: false,
+    };
+    for entry in root_dir.walk(base) {
+        if Instant::now() >= deadline {
+            outcome.timed_out = true;
+            return outcome;
+        }
+        let name = entry.path.file_name().unwrap_or_default();
+        if !matcher.matches(&name.to_string_lossy())
+            || !access.allows(&entry.path)
+        {
+            continue
+        }
+        if outcome.paths.len() == limit {
+            outcome.truncated = true;
+            return outcome;
+        }
+        outcome.paths.push(entry.path);
+    }
+    outcome
+}
+
+/// Looks up the query terms in the content index, keeping the files
+/// readable with `access`.
+fn search_contents(index: &ContentIndex, query: &Query,
+    access: &mut ReadAccess, limit: usize) -> Outcome
+{
+    let mut matches = index.search(&query.text, &query.base).into_iter()
+        .filter(|path| access.allows(path));
+    let paths = matches.by_ref().take(limit).collect();
+    let truncated = matches.next().is_some();
+    Outcome {paths, truncated, timed_out: false}
+}
+
+/// Answers a search request from `client`.
+pub fn send_results(config: &Arc<Config>, site: &Arc<Site>, client: IpAddr,
+    authenticated: bool, request: &Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let query = match Query::parse(request.uri().query().unwrap_or("")) {
+        Some(query) => query,
+        None => return crate::bad_request(),
+    };
+    let claims = request.extensions().get::<jwt::Claims>();
+    let mut access = ReadAccess::new(config, site, client, authenticated,
+        claims);
+    if let Some(res) = crate::deny(config, access.check(&query.base), request)
+    {
+        return res
+    }
+    let searcher = if query.content {
+        match &config.content_index {
+            Some(index) if config.sites.is_default(site) =>
+                Searcher::Contents(index.clone()),
+            _ => return crate::bad_request(),
+        }
+    } else {
+        match Matcher::new(&query) {
+            Some(matcher) => Searcher::Names(matcher),
+            None => return crate::bad_request(),
+        }
+    };
+    let (config, site) = (config.clone(), site.clone());
+    let claims = claims.cloned();
+    let headers = request.headers().clone();
+    let res = crate::blocking(move || {
+        let mut access = ReadAccess::new(&config, &site, client,
+            authenticated, claims.as_ref());
+        let outcome = match searcher {
+            Searcher::Names(matcher) => search(&site.root_dir, &query.base,
+                &matcher, &mut access, config.search_limit,
+                config.search_timeout),
+            Searcher::Contents(index) => search_contents(
+                &index.read().unwrap(), &query, &mut access,
+                config.search_limit),
+        };
+        respond(&config, &headers, &query, &outcome)
+    });
+    Box::new(res.then(|res| match res {
+        Ok(res) => Box::new(future::result(res)),
+        Err(e) => crate::io_error(e),
+    }))
+}
+
+/// Returns the response listing the results of `query`.
+fn respond(config: &Config, headers: &HeaderMap, query: &Query,
+    outcome: &Outcome) -> http::Result<Response<Body>>
+{
+    if query.html {
+        let messages = config.catalog.select(headers);
+        let mut res = Response::builder();
+        res.header(http::header::CONTENT_TYPE,
+            mime::TEXT_HTML_UTF_8.to_string());
+        if config.catalog.negotiates() {
+            res.header(http::header::VARY, "Accept-Language");
+        }
+        res.body(format_results(config, &messages, query, outcome).into())
+    } else {
+        let results = outcome.paths.iter()
+            .map(|path| url_path::encode(path))
+            .collect::<Vec<_>>();
+        let body = serde_json::json!({
+            "query": query.text,
+            "path": url_path::encode(&query.base),
+            "results": results,
+            "truncated": outcome.truncated,
+            "timed_out": outcome.timed_out,
+        });
+        Response::builder()
+            .header(http::header::CONTENT_TYPE,
+                mime::APPLICATION_JSON.to_string())
+            .body(body.to_string().into())
+    }
+}
+
+fn format_results(config: &Config, messages: &Messages, query: &Query,
+    outcome: &Outcome) -> String
+{
+    let mut out = Vec::<u8>::new();
+    let title = &messages.search_results;
+    crate::write_page(&mut out, &config.style, title, |out| {
+        write_results(&config.base_path, messages, query, outcome, out)
+    }).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn write_results<W: Write>(base_path: &str, messages: &Messages,
+    query: &Query, outcome: &Outcome, out: &mut xml::EventWriter<W>)
+    -> Result<(), xml::writer::Error>
+{
+    let link = |path: &Path| base_path.to_owned() + &url_path::encode(path);
+    html::h1(out).write(|out| {
+        out.write(messages.results_for(&query.text).as_str())?;
+        html::a(out).attr("href", link(&query.base))
+            .text(&format!("/{}", query.base.display()))
+    })?;
+    if outcome.paths.is_empty() {
+        return nestxml::element(out, "p").text(&messages.no_matches);
+    }
+    html::ul(out).write(|out| {
+        for path in &outcome.paths {
+            html::li(out).write(|out| {
+                html::a(out).attr("href", link(path))
+                    .text(&format!("/{}", path.display()))
+            })?;
+        }
+        Ok(())
+    })?;
+    if outcome.timed_out {
+        nestxml::element(out, "p").text(&messages.search_timed_out)?;
+    } else if outcome.truncated {
+        nestxml::element(out, "p").text(&messages.search_truncated)?;
+    }
+    Ok(())
+}
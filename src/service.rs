@@ -0,0 +1,205 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Running of the server as a Windows service.
+//!
+//! The installed service runs `servedir service run` followed by the server
+//! arguments given at installation. Stopping the service, or shutting down
+//! the system, shuts the server down gracefully.
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+#[cfg(windows)]
+use std::io;
+
+/// Name of the installed service.
+#[cfg(windows)]
+const NAME: &str = "servedir";
+
+#[derive(Debug)]
+pub enum ServiceError {
+    #[cfg(windows)]
+    Executable(io::Error),
+    #[cfg(windows)]
+    Install(windows_service::Error),
+    #[cfg(windows)]
+    Uninstall(windows_service::Error),
+    #[cfg(windows)]
+    Dispatch(windows_service::Error),
+    #[cfg(not(windows))]
+    Unsupported,
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            ServiceError::Executable(_) =>
+                f.write_str("Failed to locate executable"),
+            #[cfg(windows)]
+            ServiceError::Install(_) =>
+                f.write_str("Failed to install service"),
+            #[cfg(windows)]
+            ServiceError::Uninstall(_) =>
+                f.write_str("Failed to uninstall service"),
+            #[cfg(windows)]
+            ServiceError::Dispatch(_) => f.write_str("Failed to connect to the \
+                Service Control Manager (the service must be started by it)"),
+            #[cfg(not(windows))]
+            ServiceError::Unsupported =>
+                f.write_str("Services are only supported on Windows"),
+        }
+    }
+}
+
+impl Error for ServiceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(windows)]
+            ServiceError::Executable(e) => Some(e),
+            #[cfg(windows)]
+            ServiceError::Install(e)
+                | ServiceError::Uninstall(e)
+                | ServiceError::Dispatch(e)
+                => Some(e),
+            #[cfg(not(windows))]
+            ServiceError::Unsupported => None,
+        }
+    }
+}
+
+/// Installs a service starting automatically at boot and running the server
+/// with `args`.
+#[cfg(windows)]
+pub fn install(args: Vec<OsString>) -> Result<(), ServiceError> {
+    use windows_service::service::{ServiceAccess, ServiceErrorControl,
+        ServiceInfo, ServiceStartType, ServiceType};
+    use windows_service::service_manager::{ServiceManager,
+        ServiceManagerAccess};
+
+    let executable_path = std::env::current_exe()
+        .map_err(ServiceError::Executable)?;
+    let manager = ServiceManager::local_computer(None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .map_err(ServiceError::Install)?;
+    let launch_arguments = ["service", "run"].iter().map(OsString::from)
+        .chain(args)
+        .collect();
+    let info = ServiceInfo {
+        name: NAME.into(),
+        display_name: NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(ServiceError::Install)?;
+    service.set_description(crate::APP_ABOUT)
+        .map_err(ServiceError::Install)
+}
+
+/// Stops and removes the installed service.
+#[cfg(windows)]
+pub fn uninstall() -> Result<(), ServiceError> {
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{ServiceManager,
+        ServiceManagerAccess};
+
+    let manager = ServiceManager::local_computer(None::<&str>,
+        ServiceManagerAccess::CONNECT).map_err(ServiceError::Uninstall)?;
+    let service = manager.open_service(NAME, ServiceAccess::QUERY_STATUS
+        | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .map_err(ServiceError::Uninstall)?;
+    let status = service.query_status().map_err(ServiceError::Uninstall)?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().map_err(ServiceError::Uninstall)?;
+    }
+    // The service is removed once it has stopped.
+    service.delete().map_err(ServiceError::Uninstall)
+}
+
+/// Server arguments of the running service.
+#[cfg(windows)]
+static ARGS: std::sync::OnceLock<Vec<OsString>> = std::sync::OnceLock::new();
+
+/// Runs the server with `args` on behalf of the Service Control Manager,
+/// until the service is stopped.
+#[cfg(windows)]
+pub fn run(args: Vec<OsString>) -> Result<(), ServiceError> {
+    let _ = ARGS.set(args);
+    windows_service::service_dispatcher::start(NAME, ffi_service_main)
+        .map_err(ServiceError::Dispatch)
+}
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+#[cfg(windows)]
+fn service_main(_: Vec<OsString>) {
+    use std::time::Duration;
+    use windows_service::service::{ServiceControl, ServiceControlAccept,
+        ServiceExitCode, ServiceState, ServiceStatus, ServiceType};
+    use windows_service::service_control_handler::{self,
+        ServiceControlHandlerResult};
+
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            crate::signals::request_shutdown();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(NAME, handler) {
+        Ok(status) => status,
+        Err(_) => return,
+    };
+    let set_state = |current_state, exit_code| {
+        let controls_accepted = match current_state {
+            ServiceState::Running =>
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let _ = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        });
+    };
+    set_state(ServiceState::Running, ServiceExitCode::Win32(0));
+    let args = ARGS.get().cloned().unwrap_or_default();
+    let exit_code = match crate::run(
+        std::iter::once(OsString::from(crate::APP_NAME)).chain(args))
+    {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(e) => {
+            crate::print_error(&e);
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+    set_state(ServiceState::Stopped, exit_code);
+}
+
+#[cfg(not(windows))]
+pub fn install(_: Vec<OsString>) -> Result<(), ServiceError> {
+    Err(ServiceError::Unsupported)
+}
+
+#[cfg(not(windows))]
+pub fn uninstall() -> Result<(), ServiceError> {
+    Err(ServiceError::Unsupported)
+}
+
+#[cfg(not(windows))]
+pub fn run(_: Vec<OsString>) -> Result<(), ServiceError> {
+    Err(ServiceError::Unsupported)
+}
@@ -0,0 +1,114 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Sessions of browser users, kept in signed cookies.
+//!
+//! A session holds the claims identifying its user, signed with a key
+//! generated at startup. Sessions end after eight hours, when users log out
+//! at `/_logout`, or when the server restarts.
+
+use crate::jwt::{self, Claims};
+use hmac::{Hmac, Mac};
+use http::header::{self, HeaderMap, HeaderValue};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Path ending sessions.
+pub const LOGOUT_PATH: &str = "/_logout";
+
+const COOKIE: &str = "servedir_session";
+
+/// Number of seconds sessions last.
+const LIFETIME: u64 = 8 * 3600;
+
+#[derive(Deserialize, Serialize)]
+struct Session {
+    exp: u64,
+    claims: Claims,
+}
+
+/// Issues and checks session cookies.
+pub struct Sessions {
+    key: [u8; 32],
+    base_path: String,
+    /// Whether cookies are only sent over HTTPS.
+    secure: bool,
+}
+
+impl Sessions {
+    pub fn new(base_path: &str, secure: bool) -> Sessions {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).expect("No source of randomness");
+        Sessions {key, base_path: base_path.to_owned(), secure}
+    }
+
+    /// Returns the claims of the session of the client sending `headers`,
+    /// if any.
+    pub fn get(&self, headers: &HeaderMap) -> Option<Claims> {
+        let session = self.open::<Session>(cookie(headers, COOKIE)?)?;
+        let valid = session.exp > crate::signing::unix_time_now();
+        Some(session.claims).filter(|_| valid)
+    }
+
+    /// Returns the `Set-Cookie` header value starting a session for the
+    /// user with `claims`.
+    pub fn start(&self, claims: Claims) -> HeaderValue {
+        let session = Session {
+            exp: crate::signing::unix_time_now() + LIFETIME,
+            claims,
+        };
+        self.set_cookie(COOKIE, &self.seal(&session), LIFETIME)
+    }
+
+    /// Returns the `Set-Cookie` header value ending the session.
+    pub fn end(&self) -> HeaderValue {
+        self.set_cookie(COOKIE, "", 0)
+    }
+
+    /// Returns the `Set-Cookie` header value setting cookie `name` to
+    /// `value` for `max_age` seconds.
+    pub fn set_cookie(&self, name: &str, value: &str, max_age: u64)
+        -> HeaderValue
+    {
+        let path = if self.base_path.is_empty() {"/"} else {&self.base_path};
+        let secure = if self.secure {"; Secure"} else {""};
+        let cookie = format!("{}={}; Path={}; Max-Age={}; HttpOnly; \
+            SameSite=Lax{}", name, value, path, max_age, secure);
+        HeaderValue::from_str(&cookie).unwrap()
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Serializes `value` into a signed cookie value.
+    pub fn seal<T: Serialize>(&self, value: &T) -> String {
+        let payload = base64_url(&serde_json::to_vec(value).unwrap());
+        let tag = self.mac(&payload).finalize().into_bytes();
+        format!("{}.{}", payload, base64_url(&tag))
+    }
+
+    /// Returns the value serialized into `sealed` if it was signed by this.
+    pub fn open<T: DeserializeOwned>(&self, sealed: &str) -> Option<T> {
+        let (payload, tag) = sealed.split_once('.')?;
+        self.mac(payload).verify_slice(&jwt::decode(tag)?).ok()?;
+        serde_json::from_slice(&jwt::decode(payload)?).ok()
+    }
+}
+
+/// Returns the value of the cookie `name` sent with `headers`.
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|&(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+pub fn base64_url(bytes: &[u8]) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,77 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Process signal handling.
+
+/// Calls `shutdown` once when termination is requested, and `reload` each
+/// time a configuration reload is requested (`SIGHUP`).
+#[cfg(unix)]
+pub fn handle<S, R>(shutdown: S, mut reload: R)
+where
+    S: FnOnce() + Send + 'static,
+    R: FnMut() + Send + 'static,
+{
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    let mut signals = match signal_hook::iterator::Signals::new(
+        [SIGHUP, SIGINT, SIGTERM])
+    {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::error!("Failed to install signal handlers: {}", e);
+            return
+        }
+    };
+    std::thread::spawn(move || {
+        let mut shutdown = Some(shutdown);
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                reload();
+            } else if let Some(shutdown) = shutdown.take() {
+                shutdown();
+            }
+        }
+    });
+}
+
+/// Shutdown handler, or whether shutdown was requested before a handler was
+/// installed.
+#[cfg(not(unix))]
+enum Shutdown {
+    Pending(Option<Box<dyn FnOnce() + Send>>),
+    Requested,
+}
+
+#[cfg(not(unix))]
+static SHUTDOWN: std::sync::Mutex<Shutdown> =
+    std::sync::Mutex::new(Shutdown::Pending(None));
+
+/// Calls `shutdown` once when termination is requested. Reloading is not
+/// supported on this platform.
+#[cfg(not(unix))]
+pub fn handle<S, R>(shutdown: S, _reload: R)
+where
+    S: FnOnce() + Send + 'static,
+    R: FnMut() + Send + 'static,
+{
+    let mut state = SHUTDOWN.lock().unwrap();
+    match &mut *state {
+        Shutdown::Pending(handler) => *handler = Some(Box::new(shutdown)),
+        Shutdown::Requested => {
+            drop(state);
+            shutdown();
+            return
+        }
+    }
+    drop(state);
+    let _ = ctrlc::set_handler(request_shutdown);
+}
+
+/// Requests termination, as when interrupted. Only the first request has an
+/// effect.
+#[cfg(not(unix))]
+pub fn request_shutdown() {
+    let state = std::mem::replace(&mut *SHUTDOWN.lock().unwrap(),
+        Shutdown::Requested);
+    if let Shutdown::Pending(Some(shutdown)) = state {
+        shutdown();
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Signed, expiring URLs.
+//!
+//! A signed URL carries an `expires` query parameter (seconds since the Unix
+//! epoch) and a `signature` parameter holding the hex-encoded HMAC-SHA256 of
+//! the percent-encoded path and expiration time. An optional `downloads`
+//! parameter, also covered by the signature, limits the number of downloads
+//! through the URL.
+
+use crate::url_path;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::form_urlencoded;
+
+/// Outcome of the verification of a request.
+pub enum Verification {
+    /// The path does not require a signature.
+    Unprotected,
+    /// The URL is validly signed.
+    Valid {
+        /// Hex-encoded signature, identifying the URL.
+        signature: String,
+        max_downloads: Option<u64>,
+    },
+    Invalid,
+}
+
+/// Signs URLs and checks signatures of requests below protected prefixes.
+pub struct UrlSigner {
+    key: Vec<u8>,
+    prefixes: Vec<PathBuf>,
+}
+
+impl UrlSigner {
+    /// Returns a signer using `key`. Signatures are required for paths below
+    /// `prefixes`, or for all paths if `prefixes` is empty.
+    pub fn new(key: &str, prefixes: Vec<PathBuf>) -> UrlSigner {
+        let prefixes = if prefixes.is_empty() {
+            vec![PathBuf::from("/")]
+        } else {
+            prefixes
+        };
+        UrlSigner {key: key.as_bytes().to_vec(), prefixes}
+    }
+
+    fn mac(&self, path: &Path, expires: u64, max_downloads: Option<u64>)
+        -> Hmac<Sha256>
+    {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(url_path::encode(path).as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.to_string().as_bytes());
+        if let Some(n) = max_downloads {
+            mac.update(b"\n");
+            mac.update(n.to_string().as_bytes());
+        }
+        mac
+    }
+
+    /// Returns the URL path and query granting access to `path` until
+    /// `expires`, for at most `max_downloads` downloads if given.
+    pub fn sign(&self, path: &Path, expires: u64, max_downloads: Option<u64>)
+        -> String
+    {
+        let signature = self.mac(path, expires, max_downloads).finalize()
+            .into_bytes();
+        let signature = crate::checksum::to_hex(&signature);
+        let downloads = max_downloads
+            .map_or_else(String::new, |n| format!("&downloads={}", n));
+        format!("{}?expires={}{}&signature={}", url_path::encode(path), expires,
+            downloads, signature)
+    }
+
+    /// Checks whether a request for `path` with the given query string may
+    /// proceed.
+    pub fn verify(&self, path: &Path, query: Option<&str>) -> Verification {
+        if !self.prefixes.iter().any(|prefix| path.starts_with(prefix)) {
+            return Verification::Unprotected
+        }
+        let mut expires = None;
+        let mut max_downloads = None;
+        let mut signature = None;
+        let query = query.unwrap_or("");
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "expires" => expires = value.parse::<u64>().ok(),
+                "downloads" => match value.parse::<u64>() {
+                    Ok(n) => max_downloads = Some(n),
+                    Err(_) => return Verification::Invalid,
+                },
+                "signature" => signature = crate::checksum::from_hex(&value),
+                _ => {}
+            }
+        }
+        let (expires, signature) = match (expires, signature) {
+            (Some(expires), Some(signature)) => (expires, signature),
+            _ => return Verification::Invalid,
+        };
+        if expires < unix_time_now() {return Verification::Invalid}
+        let mac = self.mac(path, expires, max_downloads);
+        if mac.verify_slice(&signature).is_err() {
+            return Verification::Invalid
+        }
+        Verification::Valid {
+            signature: crate::checksum::to_hex(&signature),
+            max_downloads,
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn unix_time_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,109 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Sitemap of the HTML files of the served directory.
+//!
+//! The tree is scanned on the blocking pool when the sitemap is requested,
+//! within the time allowed for searches, for pages the client may read,
+//! without leaving the served directory. A `sitemap.xml` file in the served
+//! directory is served instead.
+
+use crate::{jwt, url_path, Config, ReadAccess, ServerFuture};
+use crate::vhost::Site;
+use futures::{future, Future};
+use http::{Request, Response};
+use hyper::Body;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+/// Path of the sitemap.
+pub const ENDPOINT: &str = "/sitemap.xml";
+
+/// Maximum number of URLs in a sitemap.
+const MAX_URLS: usize = 50_000;
+
+const SITEMAP_NAMESPACE: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+
+/// Returns whether `site` has no sitemap file, which is then generated.
+pub fn is_generated(site: &Site) -> bool {
+    !site.root.join(&ENDPOINT[1..]).is_file()
+}
+
+/// Answers a sitemap request from `client`.
+pub fn send(config: &Arc<Config>, site: &Arc<Site>, client: IpAddr,
+    authenticated: bool, request: &Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let origin = crate::request_origin(config, request);
+    let (config, site) = (config.clone(), site.clone());
+    let claims = request.extensions().get::<jwt::Claims>().cloned();
+    let res = crate::blocking(move || {
+        let mut access = ReadAccess::new(&config, &site, client,
+            authenticated, claims.as_ref());
+        let pages = scan(&site, &config, &mut access);
+        let mut out = Vec::new();
+        write_sitemap(&origin, &pages, &mut out).unwrap();
+        Response::builder()
+            .header(http::header::CONTENT_TYPE, mime::TEXT_XML.to_string())
+            .body(out.into())
+    });
+    Box::new(res.then(|res| match res {
+        Ok(res) => Box::new(future::result(res)),
+        Err(e) => crate::io_error(e),
+    }))
+}
+
+/// Returns the HTML files of `site` readable with `access`, with their
+/// modification times.
+fn scan(site: &Site, config: &Config, access: &mut ReadAccess)
+    -> Vec<(PathBuf, Option<SystemTime>)>
+{
+    let deadline = Instant::now() + config.search_timeout;
+    let mut pages = Vec::new();
+    for entry in site.root_dir.walk(Path::new("")) {
+        if Instant::now() >= deadline || pages.len() == MAX_URLS {
+            return pages
+        }
+        if entry.meta.is_file() && is_html(&entry.path)
+            && access.allows(&entry.path)
+        {
+            pages.push((entry.path, entry.meta.modified().ok()));
+        }
+    }
+    pages.sort();
+    pages
+}
+
+fn is_html(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("html")
+            || e.eq_ignore_ascii_case("htm"))
+}
+
+fn write_sitemap<W: Write>(origin: &str,
+    pages: &[(PathBuf, Option<SystemTime>)], out: W)
+    -> Result<(), xml::writer::Error>
+{
+    let mut out = xml::EmitterConfig::new()
+        .perform_indent(true)
+        .create_writer(out);
+    let out = &mut out;
+    nestxml::element(out, "urlset").attr("xmlns", SITEMAP_NAMESPACE)
+        .write(|out| {
+            for (path, modified) in pages {
+                nestxml::element(out, "url").write(|out| {
+                    let loc = format!("{}{}", origin, url_path::encode(path));
+                    nestxml::element(out, "loc").text(&loc)?;
+                    match modified {
+                        Some(modified) => nestxml::element(out, "lastmod")
+                            .text(&humantime::format_rfc3339_seconds(
+                                *modified).to_string()),
+                        None => Ok(()),
+                    }
+                })?;
+            }
+            Ok(())
+        })
+}
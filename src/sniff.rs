@@ -0,0 +1,97 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Recognition of file types from their first bytes.
+//!
+//! Files are compared with the signatures of common binary formats, and
+//! files made of UTF-8 text without control characters are recognized as
+//! plain text. Markup is deliberately never recognized, so that no file is
+//! served as HTML because of its content.
+
+use mime::Mime;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Number of bytes of files inspected to recognize their type.
+pub const SNIFF_LEN: usize = 512;
+
+/// Signature of a file type: offset, magic bytes, content type and
+/// extensions.
+type Signature = (usize, &'static [u8], &'static str, &'static [&'static str]);
+
+const SIGNATURES: &[Signature] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png", &["png"]),
+    (0, b"\xff\xd8\xff", "image/jpeg", &["jpg", "jpeg"]),
+    (0, b"GIF87a", "image/gif", &["gif"]),
+    (0, b"GIF89a", "image/gif", &["gif"]),
+    (8, b"WEBP", "image/webp", &["webp"]),
+    (0, b"%PDF-", "application/pdf", &["pdf"]),
+    (0, b"PK\x03\x04", "application/zip", &["zip", "docx", "xlsx", "pptx",
+        "odt", "ods", "odp", "epub", "jar", "apk"]),
+    (0, b"\x1f\x8b", "application/gzip", &["gz", "tgz"]),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed", &["7z"]),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar", &["rar"]),
+    (0, b"ID3", "audio/mpeg", &["mp3"]),
+    (0, b"OggS", "application/ogg", &["ogg", "oga", "ogv", "opus"]),
+    (0, b"fLaC", "audio/flac", &["flac"]),
+    (4, b"ftyp", "video/mp4", &["mp4", "m4a", "m4v", "mov"]),
+    (0, b"\x00asm", "application/wasm", &["wasm"]),
+    (0, b"MZ", BINARY, &["exe", "dll", "com", "scr", "sys"]),
+    (0, b"\x7fELF", BINARY, &["elf", "so", "bin"]),
+    (0, b"\xfe\xed\xfa\xce", BINARY, &["dylib", "bin"]),
+    (0, b"\xfe\xed\xfa\xcf", BINARY, &["dylib", "bin"]),
+    (0, b"\xce\xfa\xed\xfe", BINARY, &["dylib", "bin"]),
+    (0, b"\xcf\xfa\xed\xfe", BINARY, &["dylib", "bin"]),
+    (0, b"\xca\xfe\xba\xbe", BINARY, &["dylib", "class", "bin"]),
+    (0, b"#!", "text/plain", &["sh"]),
+];
+
+const BINARY: &str = "application/octet-stream";
+
+fn signature(head: &[u8]) -> Option<&'static Signature> {
+    SIGNATURES.iter()
+        .find(|(offset, magic, _, _)| head.get(*offset..)
+            .is_some_and(|bytes| bytes.starts_with(magic)))
+}
+
+/// Returns the extensions of the type of a file starting with `head`, if
+/// recognized.
+pub fn extensions(head: &[u8]) -> Option<&'static [&'static str]> {
+    signature(head).map(|(_, _, _, extensions)| *extensions)
+}
+
+/// Returns the type of a file starting with `head`, if recognized.
+pub fn content_type(head: &[u8]) -> Option<Mime> {
+    if let Some((_, _, content_type, _)) = signature(head) {
+        return content_type.parse().ok()
+    }
+    if is_text(head) {
+        return Some(mime::TEXT_PLAIN_UTF_8)
+    }
+    None
+}
+
+/// Returns the type of `file`, if recognized. The file position is reset to
+/// the start.
+pub fn file_content_type(mut file: &File) -> io::Result<Option<Mime>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(content_type(&head))
+}
+
+/// Returns whether `head` is the start of UTF-8 text without control
+/// characters other than whitespace.
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // The last character may be cut.
+        Err(e) if e.error_len().is_none() =>
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return false,
+    };
+    text.chars().all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'
+        | '\x0c'))
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Server statistics and the page presenting them.
+
+use crate::file_cache::FileCache;
+use crate::style::Style;
+use crate::ServerFuture;
+use futures::{future, Async, Poll, Stream};
+use http::{Request, Response};
+use hyper::{Body, Chunk};
+use nestxml::html;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Path of the statistics page.
+pub const ENDPOINT: &str = "/_stats";
+
+/// Number of distinct paths counted, to bound memory use.
+const MAX_PATHS: usize = 10_000;
+
+/// Number of paths shown in the ranking of the most requested paths.
+const TOP_PATHS: usize = 10;
+
+/// Number of recent errors kept.
+const RECENT_ERRORS: usize = 20;
+
+struct ErrorEntry {
+    time: Instant,
+    status: u16,
+    request: String,
+}
+
+/// Counters updated as requests are served.
+pub struct Stats {
+    start: Instant,
+    /// Responses by status class, from 1xx to 5xx.
+    responses: [AtomicU64; 5],
+    bytes: Arc<AtomicU64>,
+    connections: Arc<AtomicU64>,
+    paths: Mutex<HashMap<String, u64>>,
+    errors: Mutex<VecDeque<ErrorEntry>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            start: Instant::now(),
+            responses: Default::default(),
+            bytes: Default::default(),
+            connections: Default::default(),
+            paths: Default::default(),
+            errors: Default::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Counts an open connection until the returned guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.connections.clone())
+    }
+
+    /// Records a response and returns its body, counting the bytes sent.
+    pub fn record(&self, method: &str, path: &str, status: u16, body: Body)
+        -> Body
+    {
+        if let Some(count) = self.responses.get(usize::from(status / 100) - 1) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        if status < 400 {
+            let mut paths = self.paths.lock().unwrap();
+            if paths.len() < MAX_PATHS || paths.contains_key(path) {
+                *paths.entry(path.to_owned()).or_default() += 1;
+            }
+        } else {
+            let mut errors = self.errors.lock().unwrap();
+            if errors.len() == RECENT_ERRORS {
+                errors.pop_front();
+            }
+            errors.push_back(ErrorEntry {
+                time: Instant::now(),
+                status,
+                request: format!("{} {}", method, path),
+            });
+        }
+        Body::wrap_stream(CountedBody {body, bytes: self.bytes.clone()})
+    }
+
+    fn top_paths(&self) -> Vec<(String, u64)> {
+        let mut paths = self.paths.lock().unwrap().iter()
+            .map(|(path, &n)| (path.clone(), n))
+            .collect::<Vec<_>>();
+        paths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        paths.truncate(TOP_PATHS);
+        paths
+    }
+
+    /// Responds with the statistics page, or with JSON if the query has
+    /// `format=json`. The counters of `cache` are included if given.
+    pub fn send(&self, request: &Request<Body>, cache: Option<&FileCache>,
+        style: &Style) -> ServerFuture<Response<Body>>
+    {
+        let json = crate::query_param(request, "format")
+            .is_some_and(|f| f == "json");
+        let res = if json {
+            Response::builder()
+                .header(http::header::CONTENT_TYPE,
+                    mime::APPLICATION_JSON.to_string())
+                .body(self.to_json(cache).to_string().into())
+        } else {
+            Response::builder()
+                .header(http::header::CONTENT_TYPE,
+                    mime::TEXT_HTML_UTF_8.to_string())
+                .body(self.format_page(cache, style).into())
+        };
+        Box::new(future::result(res))
+    }
+
+    fn to_json(&self, cache: Option<&FileCache>) -> serde_json::Value {
+        let responses = (1..=5)
+            .map(|class| (format!("{}xx", class),
+                self.responses[class - 1].load(Ordering::Relaxed).into()))
+            .collect::<serde_json::Map<_, _>>();
+        let top_paths = self.top_paths().into_iter()
+            .map(|(path, n)| serde_json::json!({"path": path, "requests": n}))
+            .collect::<Vec<_>>();
+        let errors = self.errors.lock().unwrap().iter()
+            .map(|e| serde_json::json!({
+                "request": e.request,
+                "status": e.status,
+                "age_seconds": e.time.elapsed().as_secs(),
+            }))
+            .collect::<Vec<_>>();
+        let mut stats = serde_json::json!({
+            "uptime_seconds": self.start.elapsed().as_secs(),
+            "responses": responses,
+            "bytes_served": self.bytes.load(Ordering::Relaxed),
+            "connections": self.connections.load(Ordering::Relaxed),
+            "top_paths": top_paths,
+            "recent_errors": errors,
+        });
+        if let Some(cache) = cache {
+            stats["cache"] = serde_json::json!({
+                "hits": cache.hits(),
+                "misses": cache.misses(),
+            });
+        }
+        stats
+    }
+
+    fn format_page(&self, cache: Option<&FileCache>, style: &Style)
+        -> String
+    {
+        let mut out = Vec::<u8>::new();
+        crate::write_page(&mut out, style, "Statistics",
+            |out| self.write_page(out, cache)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn write_page<W: Write>(&self, out: &mut xml::EventWriter<W>,
+        cache: Option<&FileCache>) -> Result<(), xml::writer::Error>
+    {
+        html::h1(out).text("Statistics")?;
+        html::table(out).write(|out| {
+            let uptime = format_duration(self.start.elapsed());
+            write_row(out, "Uptime", &uptime)?;
+            for (class, count) in self.responses.iter().enumerate() {
+                write_row(out, &format!("{}xx responses", class + 1),
+                    &count.load(Ordering::Relaxed).to_string())?;
+            }
+            let bytes = crate::pretty_size(self.bytes.load(Ordering::Relaxed));
+            write_row(out, "Bytes served", &bytes)?;
+            write_row(out, "Open connections",
+                &self.connections.load(Ordering::Relaxed).to_string())?;
+            if let Some(cache) = cache {
+                write_row(out, "Cache hits", &cache.hits().to_string())?;
+                write_row(out, "Cache misses", &cache.misses().to_string())?;
+            }
+            Ok(())
+        })?;
+        html::h2(out).text("Most requested paths")?;
+        html::table(out).write(|out| {
+            html::tr(out).write(|out| {
+                html::th(out).text("Path")?;
+                html::th(out).attr("class", "size").text("Requests")
+            })?;
+            for (path, n) in self.top_paths() {
+                html::tr(out).write(|out| {
+                    html::td(out).write(|out| {
+                        html::a(out).attr("href", path.as_str()).text(&path)
+                    })?;
+                    html::td(out).attr("class", "size").text(&n.to_string())
+                })?;
+            }
+            Ok(())
+        })?;
+        html::h2(out).text("Recent errors")?;
+        html::table(out).write(|out| {
+            html::tr(out).write(|out| {
+                html::th(out).text("Request")?;
+                html::th(out).text("Status")?;
+                html::th(out).text("Age")
+            })?;
+            for e in self.errors.lock().unwrap().iter().rev() {
+                html::tr(out).write(|out| {
+                    html::td(out).text(&e.request)?;
+                    html::td(out).text(&e.status.to_string())?;
+                    html::td(out).text(&format_duration(e.time.elapsed()))
+                })?;
+            }
+            Ok(())
+        })
+    }
+}
+
+fn write_row<W: Write>(out: &mut xml::EventWriter<W>, name: &str, value: &str)
+    -> Result<(), xml::writer::Error>
+{
+    html::tr(out).write(|out| {
+        html::th(out).text(name)?;
+        html::td(out).text(value)
+    })
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}d {:02}:{:02}:{:02}", secs / 86400, secs / 3600 % 24,
+        secs / 60 % 60, secs % 60)
+}
+
+/// Keeps a connection counted while alive.
+pub struct ConnectionGuard(Arc<AtomicU64>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct CountedBody {
+    body: Body,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Stream for CountedBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let item = self.body.poll()?;
+        if let Async::Ready(Some(chunk)) = &item {
+            self.bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        Ok(item)
+    }
+}
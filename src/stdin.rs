@@ -0,0 +1,166 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Serving of the standard input as a file.
+//!
+//! The standard input is read as it arrives. Unless it can be replayed, it is
+//! served to a single client and only a bounded amount of it is kept in
+//! memory, so that producers writing to the pipe wait for the download.
+
+use crate::content_type::Overrides;
+use crate::ServerFuture;
+use futures::{future, task, Async, Poll, Stream};
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tracing::error;
+
+/// Amount of buffered data beyond which reading stops until the client
+/// catches up, when not replaying.
+const BUFFER_LIMIT: usize = 1024 * 1024;
+
+/// Standard input served at a single path.
+pub struct StdinFile {
+    path: PathBuf,
+    replay: bool,
+    taken: AtomicBool,
+    reading: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    replay: bool,
+    state: Mutex<State>,
+    space: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    data: Vec<u8>,
+    /// Offset in the input of the first byte of `data`.
+    base: usize,
+    done: bool,
+    tasks: Vec<task::Task>,
+}
+
+impl StdinFile {
+    /// Serves the standard input at `/name`. If `replay` is true, the input
+    /// is kept in memory and served to every client.
+    pub fn new(name: &str, replay: bool) -> StdinFile {
+        StdinFile {
+            path: Path::new("/").join(name),
+            replay,
+            taken: AtomicBool::new(false),
+            reading: AtomicBool::new(false),
+            shared: Arc::new(Shared {
+                replay,
+                state: Default::default(),
+                space: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Starts reading the standard input. The thread reading it is spawned
+    /// here, so this must be called once the server is detached.
+    pub fn start_reading(&self) {
+        if !self.reading.swap(true, Ordering::SeqCst) {
+            let reader = self.shared.clone();
+            thread::spawn(move || reader.read_stdin());
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Responds to a request for `path`.
+    pub fn send(&self, request: &Request<Body>, path: &Path,
+        content_types: &Overrides) -> ServerFuture<Response<Body>>
+    {
+        if path != self.path {
+            return crate::io_error(io::ErrorKind::NotFound.into())
+        }
+        let mut res = Response::builder();
+        res.header(http::header::CONTENT_TYPE,
+            content_types.get(&self.path)
+                .unwrap_or_else(|| crate::get_content_type(&self.path))
+                .to_string());
+        if request.method() == Method::HEAD {
+            return Box::new(future::result(res.body(Body::empty())))
+        }
+        if !self.replay && self.taken.swap(true, Ordering::SeqCst) {
+            let res = res.status(StatusCode::GONE).body(Body::empty());
+            return Box::new(future::result(res))
+        }
+        let body = Body::wrap_stream(InputStream {
+            shared: self.shared.clone(),
+            pos: 0,
+        });
+        Box::new(future::result(res.body(body)))
+    }
+}
+
+impl Shared {
+    fn read_stdin(&self) {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.push(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error!("Failed to read standard input: {}", e);
+                    break
+                }
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.done = true;
+        state.tasks.drain(..).for_each(|task| task.notify());
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        while !self.replay && state.data.len() >= BUFFER_LIMIT {
+            state = self.space.wait(state).unwrap();
+        }
+        state.data.extend_from_slice(data);
+        state.tasks.drain(..).for_each(|task| task.notify());
+    }
+}
+
+/// Body streaming the standard input from the start.
+struct InputStream {
+    shared: Arc<Shared>,
+    pos: usize,
+}
+
+impl Stream for InputStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        let offset = self.pos - state.base;
+        if offset < state.data.len() {
+            let chunk = state.data[offset..].to_vec();
+            self.pos += chunk.len();
+            if !self.shared.replay {
+                state.base = self.pos;
+                state.data.clear();
+                self.shared.space.notify_all();
+            }
+            Ok(Async::Ready(Some(chunk)))
+        } else if state.done {
+            Ok(Async::Ready(None))
+        } else {
+            state.tasks.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Response bodies written incrementally by blocking code.
+
+use futures::{future, Future};
+use hyper::Body;
+use hyper::body::Sender;
+use std::io::{self, Write};
+use std::mem;
+use std::thread;
+
+/// Amount of data buffered before being sent as a chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns a body receiving what `f` writes from a separate thread. The body
+/// ends when `f` returns and is aborted if `f` fails.
+pub fn body<F>(f: F) -> Body
+where
+    F: FnOnce(&mut BodyWriter) -> io::Result<()> + Send + 'static,
+{
+    let (sender, body) = Body::channel();
+    thread::spawn(move || {
+        let mut writer = BodyWriter {sender, buf: Vec::new()};
+        if f(&mut writer).and_then(|_| writer.flush()).is_err() {
+            writer.sender.abort();
+        }
+    });
+    body
+}
+
+/// Writer sending data to a response body in chunks.
+pub struct BodyWriter {
+    sender: Sender,
+    buf: Vec<u8>,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    /// Sends the buffered data, waiting for the client to accept it.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {return Ok(())}
+        let sender = &mut self.sender;
+        future::poll_fn(|| sender.poll_ready()).wait()
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let chunk = mem::take(&mut self.buf);
+        sender.send_data(chunk.into())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
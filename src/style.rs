@@ -0,0 +1,99 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Appearance of generated pages.
+
+use nestxml::html;
+use std::io::Write;
+
+/// Built-in stylesheet.
+const STYLESHEET: &str = include_str!("../data/style.css");
+
+/// Rules applied on top of the built-in stylesheet by the dark theme.
+const DARK_STYLESHEET: &str = include_str!("../data/dark.css");
+
+/// Color theme of generated pages.
+#[derive(Clone, Copy, Debug)]
+pub enum Theme {
+    Light,
+    Dark,
+    /// Dark or light according to the preference of the client.
+    Auto,
+}
+
+impl Theme {
+    pub fn from_name(name: &str) -> Option<Theme> {
+        match name {
+            "light" => Some(Theme::Light),
+            "dark" => Some(Theme::Dark),
+            "auto" => Some(Theme::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Stylesheet supplementing the built-in one.
+#[derive(Debug)]
+pub enum Extra {
+    /// Rules included in pages.
+    Inline(String),
+    /// URL of a stylesheet linked from pages.
+    Link(String),
+}
+
+impl Extra {
+    /// Returns whether a `--style` value is a URL rather than a file path.
+    pub fn is_url(value: &str) -> bool {
+        value.starts_with("http://") || value.starts_with("https://")
+    }
+}
+
+/// Styling of generated pages.
+#[derive(Debug)]
+pub struct Style {
+    css: String,
+    link: Option<String>,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style::new(Theme::Light, None)
+    }
+}
+
+impl Style {
+    pub fn new(theme: Theme, extra: Option<Extra>) -> Style {
+        let mut css = STYLESHEET.to_owned();
+        match theme {
+            Theme::Light => {}
+            Theme::Dark => css.push_str(DARK_STYLESHEET),
+            Theme::Auto => {
+                css.push_str("@media (prefers-color-scheme: dark) {\n");
+                css.push_str(DARK_STYLESHEET);
+                css.push_str("}\n");
+            }
+        }
+        let link = match extra {
+            Some(Extra::Inline(rules)) => {
+                css.push_str(&rules);
+                None
+            }
+            Some(Extra::Link(url)) => Some(url),
+            None => None,
+        };
+        Style {css, link}
+    }
+
+    /// Writes the style elements of the head of a page.
+    pub fn write<W: Write>(&self, out: &mut xml::EventWriter<W>)
+        -> Result<(), xml::writer::Error>
+    {
+        html::style(out).text(&self.css)?;
+        match &self.link {
+            Some(url) => nestxml::element(out, "link")
+                .attr("rel", "stylesheet")
+                .attr("href", url.as_str())
+                .empty(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Mirroring of a directory served by another instance.
+//!
+//! `servedir sync URL DIRECTORY` lists the remote files with the diff
+//! endpoint, which the remote server must enable with `--diff`, and
+//! downloads those missing from the local directory or differing from their
+//! local copy. Files of the same size are compared by modification time,
+//! then by SHA-256 digest, obtained from the remote server with the
+//! `checksum` query parameter. Downloaded files are given their remote
+//! modification time, so that they are not compared again. With `--delete`,
+//! local files missing from the remote directory are removed.
+//!
+//! The remote server is reached without TLS, so its URL must be an
+//! `http://` URL.
+
+use crate::url_path;
+use futures::Stream;
+use http::header::{self, HeaderValue};
+use http::{Request, Uri};
+use hyper::{Body, Client};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::runtime::current_thread::Runtime;
+
+/// Suffix of the files being downloaded.
+const PARTIAL_SUFFIX: &str = ".servedir-partial";
+
+#[derive(Debug)]
+pub enum SyncError {
+    InvalidUrl,
+    /// The remote server could not be reached or answered with an error.
+    Remote(String),
+    InvalidListing(serde_json::Error),
+    Write(PathBuf, io::Error),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::InvalidUrl =>
+                f.write_str("The remote URL must be an http:// URL"),
+            SyncError::Remote(e) => write!(f, "Remote request failed: {}", e),
+            SyncError::InvalidListing(_) =>
+                f.write_str("Invalid list of remote files"),
+            SyncError::Write(path, _) =>
+                write!(f, "Failed to write {}", path.display()),
+        }
+    }
+}
+
+impl Error for SyncError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SyncError::InvalidListing(e) => Some(e),
+            SyncError::Write(_, e) => Some(e),
+            SyncError::InvalidUrl | SyncError::Remote(_) => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Listing {
+    added: Vec<RemoteFile>,
+}
+
+#[derive(Deserialize)]
+struct RemoteFile {
+    path: String,
+    size: u64,
+    modified: String,
+}
+
+/// Counts of the files handled by a sync.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub downloaded: usize,
+    pub bytes: u64,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Client of the remote server.
+struct Remote {
+    runtime: Runtime,
+    client: Client<hyper::client::HttpConnector>,
+    /// URL of the served directory, without trailing slash.
+    base: String,
+    authorization: Option<HeaderValue>,
+}
+
+impl Remote {
+    /// Sends a GET request for `path`, with `query` if not empty, and
+    /// returns the response body.
+    fn get(&mut self, path: &str, query: &str) -> Result<Body, SyncError> {
+        let mut url = format!("{}{}", self.base, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(query);
+        }
+        let uri = url.parse::<Uri>().map_err(|_| SyncError::InvalidUrl)?;
+        let mut request = Request::get(uri);
+        if let Some(authorization) = &self.authorization {
+            request.header(header::AUTHORIZATION, authorization.clone());
+        }
+        let request = request.body(Body::empty())
+            .map_err(|e| SyncError::Remote(e.to_string()))?;
+        let res = self.runtime.block_on(self.client.request(request))
+            .map_err(|e| SyncError::Remote(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(SyncError::Remote(format!("{} responded with {}", path,
+                res.status())))
+        }
+        Ok(res.into_body())
+    }
+
+    fn get_bytes(&mut self, path: &str, query: &str)
+        -> Result<Vec<u8>, SyncError>
+    {
+        let body = self.get(path, query)?;
+        let body = self.runtime.block_on(body.concat2())
+            .map_err(|e| SyncError::Remote(e.to_string()))?;
+        Ok(body.to_vec())
+    }
+
+    /// Downloads `path` to `target`, through a partial file replacing it
+    /// once complete, and returns the number of bytes downloaded.
+    fn download(&mut self, path: &str, target: &Path, modified: SystemTime)
+        -> Result<u64, SyncError>
+    {
+        let body = self.get(path, "")?;
+        let write_error = |e| SyncError::Write(target.to_owned(), e);
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir).map_err(write_error)?;
+        }
+        let mut partial = target.as_os_str().to_owned();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+        let mut file = File::create(&partial).map_err(write_error)?;
+        let written = body
+            .map_err(|e| SyncError::Remote(e.to_string()))
+            .fold(0, |written, chunk| {
+                file.write_all(&chunk)
+                    .map(|_| written + chunk.len() as u64)
+                    .map_err(|e| SyncError::Write(partial.clone(), e))
+            });
+        let res = self.runtime.block_on(written)
+            .and_then(|written| {
+                file.set_modified(modified)
+                    .and_then(|_| fs::rename(&partial, target))
+                    .map(|_| written)
+                    .map_err(write_error)
+            });
+        if res.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        res
+    }
+}
+
+/// Mirrors the directory served at `url` into `dir`, sending
+/// `authorization` with requests if given, and removing local files missing
+/// remotely if `delete` is true.
+pub fn run(url: &str, dir: &Path, authorization: Option<&str>, delete: bool)
+    -> Result<Summary, SyncError>
+{
+    let base = url.trim_end_matches('/').to_owned();
+    let uri = base.parse::<Uri>().map_err(|_| SyncError::InvalidUrl)?;
+    if uri.scheme_part().map(|s| s.as_str()) != Some("http") {
+        return Err(SyncError::InvalidUrl)
+    }
+    let authorization = authorization
+        .map(|value| HeaderValue::from_str(value)
+            .map_err(|_| SyncError::InvalidUrl))
+        .transpose()?;
+    let runtime = Runtime::new().map_err(|e| SyncError::Remote(e.to_string()))?;
+    let mut remote = Remote {runtime, client: Client::new(), base,
+        authorization};
+    let listing = remote.get_bytes(crate::diff::ENDPOINT, "")?;
+    let listing = serde_json::from_slice::<Listing>(&listing)
+        .map_err(SyncError::InvalidListing)?;
+    let mut summary = Summary::default();
+    let mut mirrored = HashSet::new();
+    for file in listing.added {
+        let resource = match url_path::decode(&file.path)
+            .and_then(|path| crate::sanitize_path(&path).map(Path::to_owned))
+        {
+            Some(resource) => resource,
+            None => return Err(SyncError::Remote(format!(
+                "Invalid remote path {}", file.path))),
+        };
+        let modified = humantime::parse_rfc3339(&file.modified)
+            .map_err(|e| SyncError::Remote(e.to_string()))?;
+        let target = dir.join(&resource);
+        mirrored.insert(resource);
+        if is_unchanged(&mut remote, &file, &target, modified)? {
+            summary.unchanged += 1;
+            continue
+        }
+        summary.bytes += remote.download(&file.path, &target, modified)?;
+        summary.downloaded += 1;
+        println!("Downloaded {}", file.path);
+    }
+    if delete {
+        remove_others(dir, Path::new(""), &mirrored, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+/// Returns whether `target` has the content of the remote `file`, giving it
+/// the remote modification time if only that differs.
+fn is_unchanged(remote: &mut Remote, file: &RemoteFile, target: &Path,
+    modified: SystemTime) -> Result<bool, SyncError>
+{
+    let meta = match fs::metadata(target) {
+        Ok(meta) if meta.is_file() && meta.len() == file.size => meta,
+        _ => return Ok(false),
+    };
+    let seconds = |time: SystemTime| time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if meta.modified().is_ok_and(|local| seconds(local) == seconds(modified))
+    {
+        return Ok(true)
+    }
+    let digest = remote.get_bytes(&file.path, "checksum=sha256")?;
+    let digest = String::from_utf8_lossy(&digest);
+    let write_error = |e| SyncError::Write(target.to_owned(), e);
+    let mut local = File::open(target).map_err(write_error)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut local, &mut hasher).map_err(write_error)?;
+    let local_digest = crate::checksum::to_hex(&hasher.finalize());
+    if digest.trim() != local_digest {
+        return Ok(false)
+    }
+    File::options().write(true).open(target)
+        .and_then(|file| file.set_modified(modified))
+        .map_err(write_error)?;
+    Ok(true)
+}
+
+/// Removes the files below `dir` in `root` that are not `mirrored`.
+fn remove_others(root: &Path, dir: &Path, mirrored: &HashSet<PathBuf>,
+    summary: &mut Summary) -> Result<(), SyncError>
+{
+    let path = root.join(dir);
+    let entries = match fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(SyncError::Write(path, e)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(|e| SyncError::Write(path.clone(), e))?;
+        let resource = dir.join(entry.file_name());
+        let file_type = entry.file_type()
+            .map_err(|e| SyncError::Write(entry.path(), e))?;
+        if file_type.is_dir() {
+            remove_others(root, &resource, mirrored, summary)?;
+        } else if !mirrored.contains(&resource) {
+            fs::remove_file(entry.path())
+                .map_err(|e| SyncError::Write(entry.path(), e))?;
+            summary.removed += 1;
+            println!("Removed /{}", resource.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,272 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Export of request spans to an OpenTelemetry collector.
+//!
+//! Spans are sent in batches over OTLP/HTTP with JSON encoding. The W3C
+//! `traceparent` header of a request, if any, makes its span a child of the
+//! caller's span, and the response carries a `traceparent` header naming the
+//! server span.
+
+use crate::checksum::to_hex;
+use futures::{Async, Poll, Stream};
+use http::header::{HeaderMap, HeaderValue};
+use http::{Request, Uri};
+use hyper::{Body, Chunk, Client};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Maximum number of spans sent in one request.
+const BATCH_SIZE: usize = 512;
+
+/// Maximum delay before a finished span is sent.
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct InvalidEndpoint;
+
+impl fmt::Display for InvalidEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The OTLP endpoint must be an http:// URL")
+    }
+}
+
+impl Error for InvalidEndpoint {}
+
+/// Sends finished spans to a collector from a background thread.
+pub struct Exporter {
+    sender: mpsc::SyncSender<SpanData>,
+}
+
+impl Exporter {
+    /// Starts exporting to the collector at `endpoint`, e.g.
+    /// `http://localhost:4318`.
+    pub fn new(endpoint: &str) -> Result<Exporter, InvalidEndpoint> {
+        let uri = format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            .parse::<Uri>()
+            .map_err(|_| InvalidEndpoint)?;
+        if uri.scheme_part().map(|s| s.as_str()) != Some("http") {
+            return Err(InvalidEndpoint)
+        }
+        let (sender, receiver) = mpsc::sync_channel(4 * BATCH_SIZE);
+        thread::spawn(move || export(uri, receiver));
+        Ok(Exporter {sender})
+    }
+
+    /// Starts the span of a request.
+    pub fn start(&self, method: &str, path: &str, client: IpAddr,
+        headers: &HeaderMap) -> RequestSpan
+    {
+        let parent = headers.get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+        let trace_id = match parent {
+            Some((trace_id, _)) => trace_id,
+            None => random_bytes(),
+        };
+        RequestSpan {
+            sender: self.sender.clone(),
+            data: Some(SpanData {
+                trace_id,
+                span_id: random_bytes(),
+                parent_span_id: parent.map(|(_, span_id)| span_id),
+                method: method.to_owned(),
+                path: path.to_owned(),
+                client,
+                status: 0,
+                bytes: 0,
+                start: SystemTime::now(),
+                end: SystemTime::now(),
+            }),
+        }
+    }
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    method: String,
+    path: String,
+    client: IpAddr,
+    status: u16,
+    bytes: u64,
+    start: SystemTime,
+    end: SystemTime,
+}
+
+impl SpanData {
+    fn to_json(&self) -> Value {
+        let attributes = json!([
+            {"key": "http.request.method",
+                "value": {"stringValue": self.method}},
+            {"key": "url.path", "value": {"stringValue": self.path}},
+            {"key": "client.address",
+                "value": {"stringValue": self.client.to_string()}},
+            {"key": "http.response.status_code",
+                "value": {"intValue": self.status.to_string()}},
+            {"key": "http.response.body.size",
+                "value": {"intValue": self.bytes.to_string()}},
+        ]);
+        let mut span = json!({
+            "traceId": to_hex(&self.trace_id),
+            "spanId": to_hex(&self.span_id),
+            "name": self.method,
+            "kind": 2,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes,
+            "status": {"code": if self.status >= 500 {2} else {0}},
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = to_hex(parent).into();
+        }
+        span
+    }
+}
+
+/// Span of a request in progress.
+pub struct RequestSpan {
+    sender: mpsc::SyncSender<SpanData>,
+    data: Option<SpanData>,
+}
+
+impl RequestSpan {
+    /// Returns the `traceparent` header value naming this span.
+    pub fn traceparent(&self) -> HeaderValue {
+        let data = self.data.as_ref().unwrap();
+        let value = format!("00-{}-{}-01", to_hex(&data.trace_id),
+            to_hex(&data.span_id));
+        HeaderValue::from_str(&value).unwrap()
+    }
+
+    /// Returns a body ending the span once `body` is sent or dropped.
+    pub fn finish_with(mut self, status: u16, body: Body) -> Body {
+        self.data.as_mut().unwrap().status = status;
+        Body::wrap_stream(SpanBody {body, span: Some(self)})
+    }
+}
+
+impl Drop for RequestSpan {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = SystemTime::now();
+            // Spans are dropped rather than blocking requests when the
+            // collector falls behind.
+            let _ = self.sender.try_send(data);
+        }
+    }
+}
+
+struct SpanBody {
+    body: Body,
+    span: Option<RequestSpan>,
+}
+
+impl Stream for SpanBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let item = self.body.poll()?;
+        match &item {
+            Async::Ready(Some(chunk)) => {
+                if let Some(data) = self.span.as_mut()
+                    .and_then(|span| span.data.as_mut())
+                {
+                    data.bytes += chunk.len() as u64;
+                }
+            }
+            Async::Ready(None) => self.span = None,
+            Async::NotReady => {}
+        }
+        Ok(item)
+    }
+}
+
+fn export(uri: Uri, receiver: mpsc::Receiver<SpanData>) {
+    let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            warn!("Failed to start trace exporter: {}", e);
+            return
+        }
+    };
+    let client = Client::new();
+    let mut batch = Vec::new();
+    let mut closed = false;
+    while !closed {
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < BATCH_SIZE {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(span) => batch.push(span),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    closed = true;
+                    break
+                }
+            }
+        }
+        if batch.is_empty() {continue}
+        let spans = batch.drain(..).map(|span| span.to_json())
+            .collect::<Vec<_>>();
+        let payload = json!({"resourceSpans": [{
+            "resource": {"attributes": [
+                {"key": "service.name", "value": {"stringValue": "servedir"}},
+            ]},
+            "scopeSpans": [{
+                "scope": {"name": "servedir", "version": crate::APP_VERSION},
+                "spans": spans,
+            }],
+        }]});
+        let request = Request::post(uri.clone())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap();
+        match runtime.block_on(client.request(request)) {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => warn!("Trace collector responded with {}", res.status()),
+            Err(e) => warn!("Failed to export traces: {}", e),
+        }
+    }
+}
+
+/// Parses a W3C `traceparent` header into trace and parent span IDs.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    parts.next()?;
+    if version.len() != 2 || version == "ff" {return None}
+    let mut trace = [0; 16];
+    let mut span = [0; 8];
+    decode_hex(trace_id, &mut trace)?;
+    decode_hex(span_id, &mut span)?;
+    if trace == [0; 16] || span == [0; 8] {return None}
+    Some((trace, span))
+}
+
+fn decode_hex(s: &str, out: &mut [u8]) -> Option<()> {
+    if s.len() != 2 * out.len() {return None}
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness");
+    bytes
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
@@ -0,0 +1,346 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! TLS support.
+//!
+//! Certificates and keys are read again when their files change, as when
+//! renewed, and on SIGHUP. New connections use the new certificates while
+//! established ones are unaffected. Files failing to load leave the previous
+//! certificates in use.
+
+use futures::{Future, Stream};
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{NoClientAuth, ResolvesServerCert, ServerConfig, SignatureScheme};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_rustls::{server, TlsAcceptor};
+
+/// Connection accepted by the TLS listener.
+pub type Connection = server::TlsStream<crate::listener::Connection>;
+
+/// Maximum number of concurrent TLS handshakes.
+const MAX_HANDSHAKES: usize = 128;
+
+/// Minimum time between checks for changes to certificate and key files.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum TlsError {
+    ReadCertificate(io::Error),
+    ReadKey(io::Error),
+    InvalidCertificate,
+    InvalidKey,
+    KeyMismatch,
+    Config(rustls::TLSError),
+    Host(String, Box<TlsError>),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::ReadCertificate(_) =>
+                f.write_str("Failed to read certificate"),
+            TlsError::ReadKey(_) => f.write_str("Failed to read private key"),
+            TlsError::InvalidCertificate => f.write_str("Invalid certificate"),
+            TlsError::InvalidKey => f.write_str("Invalid private key"),
+            TlsError::KeyMismatch =>
+                f.write_str("Private key does not match certificate"),
+            TlsError::Config(_) => f.write_str("Invalid TLS configuration"),
+            TlsError::Host(host, _) =>
+                write!(f, "Invalid certificate or key for {}", host),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::ReadCertificate(e) | TlsError::ReadKey(e) => Some(e),
+            TlsError::Config(e) => Some(e),
+            TlsError::Host(_, e) => Some(e),
+            TlsError::InvalidCertificate
+                | TlsError::InvalidKey
+                | TlsError::KeyMismatch
+                => None,
+        }
+    }
+}
+
+/// Certificate chain and private key files served to clients naming a host.
+#[derive(Debug)]
+pub struct HostCert<'a> {
+    pub host: &'a str,
+    pub cert: &'a Path,
+    pub key: &'a Path,
+}
+
+impl HostCert<'_> {
+    /// Parses a `HOST=CERT,KEY` command-line value.
+    pub fn parse(spec: &str) -> Option<HostCert<'_>> {
+        let mut parts = spec.splitn(2, '=');
+        let host = parts.next().filter(|host| !host.is_empty())?;
+        let mut files = parts.next()?.splitn(2, ',');
+        let cert = files.next().filter(|cert| !cert.is_empty())?;
+        let key = files.next().filter(|key| !key.is_empty())?;
+        Some(HostCert {host, cert: Path::new(cert), key: Path::new(key)})
+    }
+}
+
+/// Certificates and keys loaded from files, selected by the server name
+/// indicated by clients.
+pub struct Certificates {
+    files: Vec<KeyFiles>,
+    keys: RwLock<Keys>,
+    state: Mutex<CheckState>,
+}
+
+/// Certificate chain and private key files, for a host or by default.
+struct KeyFiles {
+    host: Option<String>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Certificates by server name, with a default for clients indicating no
+/// known name.
+struct Keys {
+    default: CertifiedKey,
+    hosts: HashMap<String, CertifiedKey>,
+}
+
+struct CheckState {
+    checked: Instant,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl Certificates {
+    /// Loads the PEM certificate chain and private key files `cert` and
+    /// `key`, used for clients naming none of the `host_certs`.
+    pub fn load(cert: &Path, key: &Path, host_certs: &[HostCert])
+        -> Result<Arc<Certificates>, TlsError>
+    {
+        let default = KeyFiles {
+            host: None,
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+        };
+        let files = Some(default).into_iter()
+            .chain(host_certs.iter().map(|host_cert| KeyFiles {
+                host: Some(host_cert.host.to_ascii_lowercase()),
+                cert: host_cert.cert.to_owned(),
+                key: host_cert.key.to_owned(),
+            }))
+            .collect::<Vec<_>>();
+        let state = CheckState {
+            checked: Instant::now(),
+            modified: modified_times(&files),
+        };
+        let keys = load_keys(&files)?;
+        Ok(Arc::new(Certificates {
+            files,
+            keys: RwLock::new(keys),
+            state: Mutex::new(state),
+        }))
+    }
+
+    /// Reads the files again.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let modified = modified_times(&self.files);
+        self.state.lock().unwrap().modified = modified;
+        let keys = load_keys(&self.files)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    fn reload_if_modified(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.checked.elapsed() < CERT_CHECK_INTERVAL {return}
+            state.checked = Instant::now();
+            if modified_times(&self.files) == state.modified {return}
+        }
+        match self.reload() {
+            Ok(()) => tracing::info!("Certificates reloaded"),
+            Err(e) => tracing::error!("Failed to reload certificates: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, server_name: Option<webpki::DNSNameRef>,
+        _: &[SignatureScheme])
+        -> Option<CertifiedKey>
+    {
+        self.reload_if_modified();
+        let keys = self.keys.read().unwrap();
+        let host = server_name.and_then(|name| {
+            let name: &str = name.into();
+            keys.hosts.get(&name.to_ascii_lowercase())
+        });
+        Some(host.unwrap_or(&keys.default).clone())
+    }
+}
+
+fn modified_times(files: &[KeyFiles]) -> Vec<Option<SystemTime>> {
+    let modified = |path: &Path| std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok();
+    files.iter()
+        .flat_map(|files| vec![modified(&files.cert), modified(&files.key)])
+        .collect()
+}
+
+fn load_keys(files: &[KeyFiles]) -> Result<Keys, TlsError> {
+    let mut default = None;
+    let mut hosts = HashMap::new();
+    for files in files {
+        let certified_key = load_certified_key(&files.cert, &files.key);
+        match &files.host {
+            None => {
+                let certified_key = certified_key?;
+                certified_key.cross_check_end_entity_cert(None)
+                    .map_err(TlsError::Config)?;
+                default = Some(certified_key);
+            }
+            Some(host) => {
+                let host_error = |e| TlsError::Host(host.clone(), Box::new(e));
+                let certified_key = certified_key.map_err(host_error)?;
+                let invalid_name = || host_error(TlsError::Config(
+                    rustls::TLSError::General("Invalid host name".into())));
+                let name = webpki::DNSNameRef::try_from_ascii_str(host)
+                    .map_err(|_| invalid_name())?;
+                certified_key.cross_check_end_entity_cert(Some(name))
+                    .map_err(|e| host_error(TlsError::Config(e)))?;
+                hosts.insert(host.clone(), certified_key);
+            }
+        }
+    }
+    let default = default.ok_or(TlsError::InvalidCertificate)?;
+    Ok(Keys {default, hosts})
+}
+
+/// Builds a server configuration serving `certificates`.
+pub fn server_config(certificates: Arc<Certificates>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = certificates;
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    Arc::new(config)
+}
+
+fn load_certified_key(cert: &Path, key: &Path)
+    -> Result<CertifiedKey, TlsError>
+{
+    let certs = File::open(cert).map_err(TlsError::ReadCertificate)?;
+    let certs = pemfile::certs(&mut BufReader::new(certs))
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or(TlsError::InvalidCertificate)?;
+    let key = sign::any_supported_type(&read_key(key)?)
+        .map_err(|_| TlsError::InvalidKey)?;
+    let certified_key = CertifiedKey::new(certs, Arc::new(key));
+    check_key_pair(&certified_key)?;
+    Ok(certified_key)
+}
+
+/// Checks that the private key of `certified_key` matches its certificate,
+/// which files replaced one at a time may briefly fail to, by verifying a
+/// signature made with the key.
+fn check_key_pair(certified_key: &CertifiedKey) -> Result<(), TlsError> {
+    const ALGORITHMS: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+    ];
+    let schemes = ALGORITHMS.iter()
+        .map(|&(scheme, _)| scheme)
+        .collect::<Vec<_>>();
+    let signer = certified_key.key.choose_scheme(&schemes)
+        .ok_or(TlsError::InvalidKey)?;
+    let algorithm = ALGORITHMS.iter()
+        .find(|&&(scheme, _)| scheme == signer.get_scheme())
+        .map(|&(_, algorithm)| algorithm)
+        .ok_or(TlsError::InvalidKey)?;
+    let message = b"servedir key pair check";
+    let signature = signer.sign(message).map_err(TlsError::Config)?;
+    let cert = webpki::EndEntityCert::from(certified_key.cert[0].as_ref())
+        .map_err(|_| TlsError::InvalidCertificate)?;
+    cert.verify_signature(algorithm, message, &signature)
+        .map_err(|_| TlsError::KeyMismatch)
+}
+
+fn read_key(path: &Path) -> Result<rustls::PrivateKey, TlsError> {
+    let read = |parse: fn(&mut dyn io::BufRead)
+        -> Result<Vec<rustls::PrivateKey>, ()>|
+    {
+        let file = File::open(path).map_err(TlsError::ReadKey)?;
+        Ok(parse(&mut BufReader::new(file)).unwrap_or_default())
+    };
+    let mut keys = read(pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read(pemfile::rsa_private_keys)?;
+    }
+    keys.into_iter().next().ok_or(TlsError::InvalidKey)
+}
+
+/// Performs the TLS handshake on incoming connections, dropping those that
+/// fail.
+pub fn accept<I, S>(incoming: I, config: Arc<ServerConfig>)
+    -> impl Stream<Item = server::TlsStream<S>, Error = io::Error>
+where
+    I: Stream<Item = S, Error = io::Error>,
+    S: tokio_io::AsyncRead + tokio_io::AsyncWrite,
+{
+    let acceptor = TlsAcceptor::from(config);
+    incoming
+        .map(move |stream| acceptor.accept(stream).then(|res| match res {
+            Ok(stream) => Ok(Some(stream)),
+            Err(e) => {
+                tracing::warn!("TLS handshake failed: {}", e);
+                Ok(None)
+            }
+        }))
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|stream| stream)
+}
+
+/// Redirects a plain HTTP request to the same resource over HTTPS on `port`.
+pub fn redirect_to_https(port: u16, request: &Request<Body>)
+    -> http::Result<Response<Body>>
+{
+    let host = request.headers().get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(strip_port)
+        .or_else(|| request.uri().host());
+    let host = match host {
+        Some(host) => host,
+        None => return Response::builder().status(StatusCode::BAD_REQUEST)
+            .body("Bad request".into()),
+    };
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let location = if port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, port, path)
+    };
+    Response::builder().status(StatusCode::MOVED_PERMANENTLY)
+        .header(http::header::LOCATION, location)
+        .body(Body::empty())
+}
+
+/// Removes the port from the value of a `Host` header.
+pub fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
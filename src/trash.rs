@@ -0,0 +1,146 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Trash of files deleted or overwritten in writable mode.
+//!
+//! Each file or directory is moved to a folder of the trash directory named
+//! after the time it was discarded, next to a description of where it came
+//! from. Items can then be listed and restored through the API.
+
+use crate::fs_api;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Name of the discarded file or directory in its folder.
+const ITEM: &str = "item";
+
+/// Name of the description of an item in its folder.
+const INFO: &str = "info.json";
+
+/// Description of a discarded file or directory.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Info {
+    /// Former path, relative to the served directory and starting with a
+    /// slash.
+    pub path: String,
+    /// Served directory the item was in.
+    root: PathBuf,
+    /// Time the item was discarded, in RFC 3339 format.
+    pub discarded: String,
+    /// `delete` or `overwrite`.
+    pub reason: String,
+    pub size: u64,
+    pub directory: bool,
+}
+
+/// Directory of discarded items.
+#[derive(Debug)]
+pub struct Trash {
+    dir: PathBuf,
+}
+
+impl Trash {
+    /// Keeps discarded items in `dir`, which is created if needed.
+    pub fn new(dir: PathBuf) -> io::Result<Trash> {
+        fs::create_dir_all(&dir)?;
+        Ok(Trash {dir})
+    }
+
+    /// Moves `target`, the `resource` of the served directory `root`, to
+    /// the trash. `reason` tells why it is discarded.
+    pub fn discard(&self, root: &Path, resource: &Path, target: &Path,
+        size: u64, reason: &str) -> io::Result<()>
+    {
+        let now = SystemTime::now();
+        let mut bytes = [0; 4];
+        getrandom::getrandom(&mut bytes).expect("No source of randomness");
+        let id = format!("{}-{}", humantime::format_rfc3339_millis(now)
+            .to_string().replace(['-', ':', '.'], ""),
+            crate::checksum::to_hex(&bytes));
+        let folder = self.dir.join(&id);
+        fs::create_dir(&folder)?;
+        let directory = fs::symlink_metadata(target)?.is_dir();
+        let info = Info {
+            path: format!("/{}", resource.to_string_lossy()),
+            root: root.to_owned(),
+            discarded: humantime::format_rfc3339_seconds(now).to_string(),
+            reason: reason.to_owned(),
+            size,
+            directory,
+        };
+        let moved = serde_json::to_vec_pretty(&info)
+            .map_err(io::Error::other)
+            .and_then(|info| fs::write(folder.join(INFO), info))
+            .and_then(|()| move_item(target, &folder.join(ITEM), directory));
+        if moved.is_err() {
+            let _ = fs::remove_dir_all(&folder);
+        }
+        moved
+    }
+
+    /// Returns the items discarded from the served directory `root`, most
+    /// recent first, with their IDs.
+    pub fn list(&self, root: &Path) -> io::Result<Vec<(String, Info)>> {
+        let mut items = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let id = entry.file_name().into_string().ok()?;
+                let info = self.info(&id).ok()?;
+                Some((id, info))
+            })
+            .filter(|(_, info)| info.root == root)
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(items)
+    }
+
+    /// Returns the description of the item `id` discarded from `root`.
+    pub fn item(&self, root: &Path, id: &str) -> io::Result<Info> {
+        let valid = !id.is_empty()
+            && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        let info = Some(id).filter(|_| valid)
+            .map(|id| self.info(id))
+            .unwrap_or_else(|| Err(io::ErrorKind::NotFound.into()))?;
+        if info.root != root {
+            return Err(io::ErrorKind::NotFound.into())
+        }
+        Ok(info)
+    }
+
+    fn info(&self, id: &str) -> io::Result<Info> {
+        let info = fs::read(self.dir.join(id).join(INFO))?;
+        serde_json::from_slice(&info).map_err(io::Error::other)
+    }
+
+    /// Moves the item `id` to `target` and forgets it.
+    pub fn restore(&self, id: &str, target: &Path, directory: bool)
+        -> io::Result<()>
+    {
+        let folder = self.dir.join(id);
+        move_item(&folder.join(ITEM), target, directory)?;
+        fs::remove_dir_all(folder)
+    }
+}
+
+/// Moves a file or directory, copying it if it is on another file system.
+fn move_item(from: &Path, to: &Path, directory: bool) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        res => return res,
+    }
+    if directory {
+        fs_api::copy_dir(from, to)
+            .inspect_err(|_| {
+                let _ = fs::remove_dir_all(to);
+            })?;
+        fs::remove_dir_all(from)
+    } else {
+        fs::copy(from, to)
+            .inspect_err(|_| {
+                let _ = fs::remove_file(to);
+            })?;
+        fs::remove_file(from)
+    }
+}
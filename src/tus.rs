@@ -0,0 +1,498 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Resumable uploads with the tus 1.0 protocol.
+//!
+//! An upload is created with a `POST` to `/_tus/` giving its length in
+//! `Upload-Length` and its path below the served directory as the `filename`
+//! metadata. Its contents are then sent in any number of `PATCH` requests
+//! to the returned location, each continuing at the offset reported by
+//! `HEAD`. Partial uploads are kept in a staging directory, and moved into
+//! the served directory once complete, with the checks applied to `PUT`
+//! uploads. The length of an upload counts against the upload quota from its
+//! creation. Uploads are abandoned with a `DELETE` request, or once they
+//! receive no data for a day.
+
+use crate::audit::Actor;
+use crate::beneath::RootDir;
+use crate::problem::Problem;
+use crate::upload::Quota;
+use crate::vhost::Site;
+use crate::{sniff, upload, Config, ServerFuture};
+use base64::Engine;
+use futures::{future, Future, Stream};
+use http::header::{HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_TYPE,
+    LOCATION};
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Path under which uploads are created.
+pub const ENDPOINT: &str = "/_tus/";
+
+const VERSION: &str = "1.0.0";
+
+const PATCH_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// Time after which uploads receiving no data are removed.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// Returns whether `path` is handled by the tus server.
+pub fn is_endpoint(path: &str) -> bool {
+    path.starts_with(ENDPOINT) || path == &ENDPOINT[..ENDPOINT.len() - 1]
+}
+
+/// Staging directory of partial uploads.
+#[derive(Debug)]
+pub struct Tus {
+    dir: PathBuf,
+    /// Uploads receiving data.
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Description of an upload, stored next to its data.
+#[derive(Debug, Deserialize, Serialize)]
+struct Info {
+    length: u64,
+    /// Served directory the upload goes to.
+    root: PathBuf,
+    /// Path of the file relative to the served directory.
+    resource: PathBuf,
+}
+
+impl Tus {
+    /// Keeps partial uploads in `dir`, which is created if needed.
+    pub fn new(dir: PathBuf) -> io::Result<Tus> {
+        fs::create_dir_all(&dir)?;
+        let tus = Tus {dir, busy: Default::default()};
+        tus.remove_stale(None);
+        Ok(tus)
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn info(&self, id: &str) -> io::Result<Info> {
+        let info = fs::read(self.info_path(id))?;
+        serde_json::from_slice(&info).map_err(io::Error::other)
+    }
+
+    fn remove(&self, id: &str) {
+        let _ = fs::remove_file(self.data_path(id));
+        let _ = fs::remove_file(self.info_path(id));
+    }
+
+    /// Removes an upload that did not complete, no longer counting its
+    /// length against `quota`.
+    fn abandon(&self, id: &str, quota: Option<&Quota>) {
+        if let (Some(quota), Ok(info)) = (quota, self.info(id)) {
+            quota.release(&info.root, info.length);
+        }
+        self.remove(id);
+    }
+
+    /// Returns the identifiers of the uploads in the staging directory.
+    fn ids(&self) -> Vec<String> {
+        let entries = match self.dir.read_dir() {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut ids = entries.filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let id = name.strip_suffix(".part")
+                    .or_else(|| name.strip_suffix(".json"))?;
+                Some(id.to_owned()).filter(|id| is_id(id))
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Removes the uploads that received no data for `STALE_AFTER`.
+    fn remove_stale(&self, quota: Option<&Quota>) {
+        let now = SystemTime::now();
+        for id in self.ids() {
+            let modified = fs::metadata(self.data_path(&id))
+                .and_then(|meta| meta.modified());
+            let stale = match modified {
+                Ok(modified) => now.duration_since(modified)
+                    .is_ok_and(|age| age > STALE_AFTER),
+                Err(ref e) => e.kind() == io::ErrorKind::NotFound,
+            };
+            if stale && !self.busy.lock().unwrap().contains(&id) {
+                self.abandon(&id, quota);
+            }
+        }
+    }
+
+    /// Counts the lengths of the uploads left from a previous run against
+    /// `quota`, abandoning those that no longer fit.
+    pub fn reserve_pending(&self, quota: &Quota) {
+        for id in self.ids() {
+            let fits = self.info(&id)
+                .is_ok_and(|info| quota.reserve(&info.root, info.length));
+            if !fits {
+                warn!("Abandoning upload {} exceeding the upload quota", id);
+                self.remove(&id);
+            }
+        }
+    }
+}
+
+/// Answers a tus request.
+pub fn handle(config: &Arc<Config>, tus: &Tus, site: &Site, client: IpAddr,
+    authenticated: bool, request: Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let id = request.uri().path().strip_prefix(ENDPOINT).unwrap_or_default()
+        .to_owned();
+    let res = if request.method() == Method::OPTIONS {
+        options(config)
+    } else if request.headers().get("Tus-Resumable")
+        .is_none_or(|v| v != VERSION)
+    {
+        let res = Problem::new(StatusCode::PRECONDITION_FAILED,
+            "Unsupported tus version")
+            .respond_with(Response::builder().header("Tus-Version", VERSION));
+        Box::new(future::result(res))
+    } else if let Some(res) =
+        upload::check_writer(config, authenticated, &request)
+    {
+        res
+    } else if id.is_empty() {
+        match *request.method() {
+            Method::POST =>
+                create(config, tus, site, client, authenticated, &request),
+            _ => crate::method_not_allowed(config),
+        }
+    } else if !is_id(&id) {
+        crate::io_error(io::ErrorKind::NotFound.into())
+    } else {
+        match *request.method() {
+            Method::HEAD | Method::GET => status(tus, &id),
+            Method::PATCH =>
+                append(config.clone(), tus, id, client, request),
+            Method::DELETE => terminate(config, tus, &id),
+            _ => crate::method_not_allowed(config),
+        }
+    };
+    Box::new(res.map(|mut res| {
+        res.headers_mut().insert("Tus-Resumable",
+            HeaderValue::from_static(VERSION));
+        res
+    }))
+}
+
+fn options(config: &Config) -> ServerFuture<Response<Body>> {
+    let mut res = Response::builder();
+    res.status(StatusCode::NO_CONTENT)
+        .header("Tus-Version", VERSION)
+        .header("Tus-Extension", "creation,termination");
+    if let Some(max) = config.max_upload_size {
+        res.header("Tus-Max-Size", max);
+    }
+    Box::new(future::result(res.body(Body::empty())))
+}
+
+/// Creates an upload.
+fn create(config: &Config, tus: &Tus, site: &Site, client: IpAddr,
+    authenticated: bool, request: &Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    let headers = request.headers();
+    let length = match header_u64(headers, "Upload-Length") {
+        Some(length) => length,
+        None => return crate::bad_request(),
+    };
+    if config.max_upload_size.is_some_and(|max| length > max) {
+        return upload::payload_too_large()
+    }
+    let name = match file_name(headers) {
+        Some(name) => name,
+        None => return crate::bad_request(),
+    };
+    let req_path = Path::new("/").join(&name);
+    let resource = match crate::sanitize_path(&req_path) {
+        Some(resource) if resource != Path::new("") => resource,
+        _ => return crate::bad_request(),
+    };
+    if !upload::may_write(config, site, resource, client, authenticated,
+        request.extensions().get())
+    {
+        return crate::forbidden()
+    }
+    if let Some(filter) = &config.upload_filter {
+        let name = resource.file_name().unwrap_or_default().to_string_lossy();
+        if !filter.accepts_name(&name) {
+            return upload::unsupported_media_type()
+        }
+    }
+    match site.root_dir.writable_path(resource) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            return upload::conflict(),
+        Err(e) => return crate::io_error(e),
+    }
+    let quota = config.upload_quota.as_ref();
+    tus.remove_stale(quota);
+    if quota.is_some_and(|quota| !quota.reserve(&site.root, length)) {
+        return upload::payload_too_large()
+    }
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness");
+    let id = crate::checksum::to_hex(&bytes);
+    let info = Info {
+        length,
+        root: site.root.clone(),
+        resource: resource.to_owned(),
+    };
+    let created = OpenOptions::new().write(true).create_new(true)
+        .open(tus.data_path(&id))
+        .and_then(|_| {
+            let info = serde_json::to_vec(&info).map_err(io::Error::other)?;
+            fs::write(tus.info_path(&id), info)
+        });
+    if let Err(e) = created {
+        tus.remove(&id);
+        if let Some(quota) = quota {
+            quota.release(&site.root, length);
+        }
+        return crate::io_error(e)
+    }
+    let location = format!("{}{}{}", config.base_path, ENDPOINT, id);
+    let res = Response::builder().status(StatusCode::CREATED)
+        .header(LOCATION, location)
+        .body(Body::empty());
+    Box::new(future::result(res))
+}
+
+/// Reports the progress of an upload.
+fn status(tus: &Tus, id: &str) -> ServerFuture<Response<Body>> {
+    let progress = tus.info(id).and_then(|info| {
+        let offset = fs::metadata(tus.data_path(id))?.len();
+        Ok((info.length, offset))
+    });
+    let (length, offset) = match progress {
+        Ok(progress) => progress,
+        Err(e) => return crate::io_error(e),
+    };
+    let res = Response::builder()
+        .header("Upload-Offset", offset)
+        .header("Upload-Length", length)
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::empty());
+    Box::new(future::result(res))
+}
+
+/// Abandons an upload.
+fn terminate(config: &Config, tus: &Tus, id: &str)
+    -> ServerFuture<Response<Body>>
+{
+    let _busy = match Busy::start(&tus.busy, id) {
+        Some(busy) => busy,
+        None => return upload::status(StatusCode::LOCKED,
+            "Upload in progress"),
+    };
+    if let Err(e) = fs::metadata(tus.info_path(id)) {
+        return crate::io_error(e)
+    }
+    tus.abandon(id, config.upload_quota.as_ref());
+    upload::status(StatusCode::NO_CONTENT, "")
+}
+
+/// Appends the body of a `PATCH` request to an upload.
+fn append(config: Arc<Config>, tus: &Tus, id: String, client: IpAddr,
+    request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    let actor = Actor::of(&request, client);
+    let headers = request.headers();
+    if headers.get(CONTENT_TYPE).is_none_or(|v| v != PATCH_CONTENT_TYPE) {
+        return upload::unsupported_media_type()
+    }
+    let offset = match header_u64(headers, "Upload-Offset") {
+        Some(offset) => offset,
+        None => return crate::bad_request(),
+    };
+    let info = match tus.info(&id) {
+        Ok(info) => info,
+        Err(e) => return crate::io_error(e),
+    };
+    let busy = match Busy::start(&tus.busy, &id) {
+        Some(busy) => busy,
+        None => return upload::status(StatusCode::LOCKED,
+            "Upload in progress"),
+    };
+    let data = tus.data_path(&id);
+    let file = fs::metadata(&data)
+        .and_then(|meta| if meta.len() == offset {
+            OpenOptions::new().append(true).open(&data).map(Some)
+        } else {
+            Ok(None)
+        });
+    let file = match file {
+        Ok(Some(file)) => tokio_fs::File::from_std(file),
+        Ok(None) => return upload::status(StatusCode::CONFLICT,
+            "Offset mismatch"),
+        Err(e) => return crate::io_error(e),
+    };
+    let length = info.length;
+    let write = request.into_body().map_err(io::Error::other)
+        .fold((file, offset), move |(file, offset), chunk| {
+            let end = offset + chunk.len() as u64;
+            let chunk = if end > length {
+                Err(io::Error::from(io::ErrorKind::FileTooLarge))
+            } else {
+                Ok(chunk)
+            };
+            future::result(chunk)
+                .and_then(|chunk| tokio_io::io::write_all(file, chunk))
+                .map(move |(file, _)| (file, end))
+        });
+    let info_path = tus.info_path(&id);
+    let res = write.then(move |res| {
+        let _busy = busy;
+        let offset = match res {
+            Ok((_, offset)) => offset,
+            Err(ref e) if e.kind() == io::ErrorKind::FileTooLarge =>
+                return upload::payload_too_large(),
+            Err(e) => return crate::io_error(e),
+        };
+        if offset == info.length {
+            let res = complete(&config, &info, &data);
+            let _ = fs::remove_file(&data);
+            let _ = fs::remove_file(&info_path);
+            let released = match res {
+                Ok(replaced) => replaced.unwrap_or(0),
+                Err(_) => info.length,
+            };
+            if let Some(quota) = &config.upload_quota {
+                quota.release(&info.root, released);
+            }
+            if let Err(res) = res {
+                return res
+            }
+            let change = upload::Change::new("upload", &info.resource, &actor)
+                .with_size(info.length);
+            upload::report(&config, change);
+        }
+        let res = Response::builder().status(StatusCode::NO_CONTENT)
+            .header("Upload-Offset", offset)
+            .body(Body::empty());
+        Box::new(future::result(res))
+    });
+    Box::new(res)
+}
+
+/// Moves a complete upload into the served directory, resolving its path
+/// again as it may have changed since the upload was created. Returns the
+/// size of the file it replaces, if any.
+fn complete(config: &Config, info: &Info, data: &Path)
+    -> Result<Option<u64>, ServerFuture<Response<Body>>>
+{
+    let target = RootDir::open(&info.root)
+        .and_then(|root_dir| root_dir.writable_path(&info.resource));
+    let target = match target {
+        Ok(target) => target,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            return Err(upload::conflict()),
+        Err(e) => return Err(crate::io_error(e)),
+    };
+    if let Some(filter) = &config.upload_filter {
+        let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
+        let read = fs::File::open(data).and_then(|file| {
+            file.take(sniff::SNIFF_LEN as u64).read_to_end(&mut head)
+        });
+        if let Err(e) = read {
+            return Err(crate::io_error(e))
+        }
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        if !filter.accepts_content(&name, &head) {
+            return Err(upload::unsupported_media_type())
+        }
+    }
+    let replaced = match fs::symlink_metadata(&target) {
+        Ok(meta) if meta.is_dir() => return Err(upload::conflict()),
+        Ok(meta) if meta.is_file() => Some(meta.len()),
+        Ok(_) => Some(0),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(crate::io_error(e)),
+    };
+    replaced
+        .map_or(Ok(()), |replaced| upload::set_aside(config, &info.root,
+            &info.resource, &target, replaced))
+        .and_then(|()| move_upload(data, &target))
+        .map(|()| replaced)
+        .map_err(crate::io_error)
+}
+
+/// Moves the data of an upload to `target`, copying it if it is on another
+/// file system.
+fn move_upload(data: &Path, target: &Path) -> io::Result<()> {
+    match fs::rename(data, target) {
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        res => return res,
+    }
+    let temp = upload::temp_path(target);
+    fs::copy(data, &temp)
+        .and_then(|_| fs::rename(&temp, target))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+}
+
+/// Marks an upload as receiving data until dropped.
+struct Busy {
+    busy: Arc<Mutex<HashSet<String>>>,
+    id: String,
+}
+
+impl Busy {
+    fn start(busy: &Arc<Mutex<HashSet<String>>>, id: &str) -> Option<Busy> {
+        if !busy.lock().unwrap().insert(id.to_owned()) {
+            return None
+        }
+        Some(Busy {busy: busy.clone(), id: id.to_owned()})
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn is_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Returns the `filename` entry of the `Upload-Metadata` header.
+fn file_name(headers: &HeaderMap) -> Option<String> {
+    let metadata = headers.get("Upload-Metadata")?.to_str().ok()?;
+    let value = metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("filename"), Some(value)) => Some(value.trim()),
+            _ => None,
+        }
+    })?;
+    let value = base64::engine::general_purpose::STANDARD.decode(value).ok()?;
+    String::from_utf8(value).ok()
+}
@@ -0,0 +1,548 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Changes to the served directory in writable mode.
+//!
+//! `PUT` stores the request body at the request path, writing it to a
+//! temporary file in the same directory that is renamed once complete.
+//! `DELETE` removes a file or an empty directory. When authentication is
+//! enabled, only authenticated clients may write.
+//!
+//! Uploads may be limited in size, and the total size of the files of each
+//! served directory may be capped by a quota. The quota starts from the size
+//! of the files found when the directory is first written to, and counts
+//! uploads as they are received, so that a body is rejected as soon as it
+//! would exceed it.
+//!
+//! With a trash directory, deleted and overwritten files are moved there
+//! instead of being lost, see `trash`.
+//!
+//! Requests may be made conditional on the current version of the file,
+//! see `etag`, and `PUT` responses carry the tag of the stored file.
+//!
+//! The names and types of uploads may also be restricted, see
+//! `upload_filter`. The type is checked once the upload is complete, before
+//! it is moved into place.
+//!
+//! Requests are checked before their body is read, and rejections are
+//! answered at once. hyper writes the `100 Continue` expected by clients
+//! along with such responses, so clients get the rejection before sending
+//! the body.
+
+use crate::access::Decision;
+use crate::audit::Actor;
+use crate::jwt::Claims;
+use crate::{access_file, checksum, disk_usage, etag, range, sniff};
+use crate::problem::Problem;
+use crate::vhost::Site;
+use crate::{Config, ServerFuture};
+use futures::{future, Future, Stream};
+use http::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, ETAG, EXPECT};
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Cap on the total size of the files of served directories.
+#[derive(Debug)]
+pub struct Quota {
+    limit: u64,
+    /// Bytes used by each served directory written to.
+    used: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Quota {
+        Quota {limit, used: Default::default()}
+    }
+
+    /// Walks the tree of `root` to count the bytes already used under it.
+    /// This must be done for each served directory before serving it.
+    pub fn measure(&self, root: &Path) {
+        let total = disk_usage::total(root);
+        self.used.lock().unwrap().insert(root.to_owned(), total);
+    }
+
+    /// Counts `bytes` more under `root`. Returns false, counting nothing, if
+    /// the quota would be exceeded.
+    pub fn reserve(&self, root: &Path, bytes: u64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let used = used.entry(root.to_owned()).or_default();
+        match used.checked_add(bytes) {
+            Some(total) if total <= self.limit => {
+                *used = total;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Counts `bytes` less under `root`.
+    pub fn release(&self, root: &Path, bytes: u64) {
+        if let Some(used) = self.used.lock().unwrap().get_mut(root) {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    /// Returns whether `bytes` more fit under `root`.
+    pub fn fits(&self, root: &Path, bytes: u64) -> bool {
+        self.reserve(root, bytes) && {
+            self.release(root, bytes);
+            true
+        }
+    }
+}
+
+/// Answers a request changing `resource`.
+pub fn handle(config: &Arc<Config>, site: &Site, resource: &Path,
+    client: IpAddr, authenticated: bool, request: Request<Body>)
+    -> ServerFuture<Response<Body>>
+{
+    if let Some(res) = check_writer(config, authenticated, &request) {
+        return res
+    }
+    let len = request.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(len) = len {
+        let too_large = config.max_upload_size.is_some_and(|max| len > max)
+            || config.upload_quota.as_ref()
+                .is_some_and(|quota| !quota.fits(&site.root, len));
+        if too_large {
+            return payload_too_large()
+        }
+    }
+    let target = match site.root_dir.writable_path(resource) {
+        Ok(target) => target,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            return conflict(),
+        Err(e) => return crate::io_error(e),
+    };
+    if !etag::has_preconditions(request.headers()) {
+        return write(config, &site.root, resource, target, client, request)
+    }
+    let config = config.clone();
+    let root = site.root.clone();
+    let resource = resource.to_owned();
+    let res = fill_tag(&config, &target).then(move |res| {
+        let holds = res.and_then(|_| preconditions_hold(&config,
+            request.headers(), &target));
+        match holds {
+            Ok(true) =>
+                write(&config, &root, &resource, target, client, request),
+            Ok(false) => status(StatusCode::PRECONDITION_FAILED,
+                "Precondition failed"),
+            Err(e) => crate::io_error(e),
+        }
+    });
+    Box::new(res)
+}
+
+/// Stores or removes `target`, the `resource` of the served directory
+/// `root`, once the preconditions of `request` hold.
+fn write(config: &Arc<Config>, root: &Path, resource: &Path, target: PathBuf,
+    client: IpAddr, request: Request<Body>) -> ServerFuture<Response<Body>>
+{
+    if let Some(filter) = &config.upload_filter {
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        if request.method() == Method::PUT && !filter.accepts_name(&name) {
+            return unsupported_media_type()
+        }
+    }
+    let actor = Actor::of(&request, client);
+    match *request.method() {
+        Method::PUT => {
+            let change = Change::new("upload", resource, &actor);
+            put(config.clone(), root.to_owned(), resource.to_owned(), target,
+                change, request.into_body())
+        }
+        Method::DELETE => delete(config, root, resource, &target,
+            Change::new("delete", resource, &actor)),
+        _ => crate::method_not_allowed(config),
+    }
+}
+
+/// Change of the served directory, as reported to webhooks.
+#[derive(Clone, Debug, Serialize)]
+pub struct Change {
+    pub action: &'static str,
+    /// Path changed, relative to the served directory and starting with a
+    /// slash.
+    pub path: String,
+    /// Former path of a moved file, or path of a copied file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// Size of the file written or removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(flatten)]
+    pub actor: Actor,
+    /// Time of the change, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+impl Change {
+    /// Describes a change of `resource`, relative to the served directory,
+    /// made now by `actor`.
+    pub fn new(action: &'static str, resource: &Path, actor: &Actor)
+        -> Change
+    {
+        Change {
+            action,
+            path: format!("/{}", resource.to_string_lossy()),
+            from: None,
+            size: None,
+            actor: actor.clone(),
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now())
+                .to_string(),
+        }
+    }
+
+    pub fn with_size(mut self, size: u64) -> Change {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_source(mut self, resource: &Path) -> Change {
+        self.from = Some(format!("/{}", resource.to_string_lossy()));
+        self
+    }
+}
+
+/// Reports a change of the served directory.
+pub fn report(config: &Config, change: Change) {
+    if let Some(audit) = &config.audit {
+        audit.change(&change);
+    }
+    if let Some(webhook) = &config.webhook {
+        webhook.send(&change);
+    }
+}
+
+/// Returns a path for a hidden temporary file next to `target`.
+pub fn temp_path(target: &Path) -> PathBuf {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness");
+    target.with_file_name(format!(".servedir-upload-{}",
+        crate::checksum::to_hex(&bytes)))
+}
+
+/// Removes `target`, the `resource` of the served directory `root`, moving
+/// it to the trash if there is one. A directory must be empty unless
+/// `recursive`. Returns the size of the files removed.
+pub fn remove(config: &Config, root: &Path, resource: &Path, target: &Path,
+    recursive: bool) -> io::Result<u64>
+{
+    let meta = fs::symlink_metadata(target)?;
+    let len = if meta.is_dir() {
+        disk_usage::total(target)
+    } else if meta.is_file() {
+        meta.len()
+    } else {
+        0
+    };
+    if let Some(trash) = &config.trash {
+        if meta.is_dir() && !recursive && fs::read_dir(target)?.next().is_some()
+        {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into())
+        }
+        trash.discard(root, resource, target, len, "delete")?;
+    } else if meta.is_dir() && recursive {
+        fs::remove_dir_all(target)?;
+    } else if meta.is_dir() {
+        fs::remove_dir(target)?;
+    } else {
+        fs::remove_file(target)?;
+    }
+    if let Some(quota) = &config.upload_quota {
+        quota.release(root, len);
+    }
+    Ok(len)
+}
+
+/// Moves the file `target`, the `resource` of the served directory `root`,
+/// of `len` bytes, to the trash if there is one, before it is overwritten.
+pub fn set_aside(config: &Config, root: &Path, resource: &Path, target: &Path,
+    len: u64) -> io::Result<()>
+{
+    let trash = match &config.trash {
+        Some(trash) => trash,
+        None => return Ok(()),
+    };
+    match trash.discard(root, resource, target, len, "overwrite") {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Returns whether the client may write `resource` of `site` according to
+/// access rules and access files.
+pub fn may_write(config: &Config, site: &Site, resource: &Path,
+    client: IpAddr, authenticated: bool, claims: Option<&Claims>) -> bool
+{
+    let req_path = Path::new("/").join(resource);
+    let decision = config.access_rules.check(&req_path, client, authenticated,
+        claims, config.auth.is_required(&Method::PUT));
+    if !matches!(decision, Decision::Allow) {
+        return false
+    }
+    !config.access_files || (
+        resource.file_name() != Some(OsStr::new(access_file::FILE_NAME))
+        && matches!(access_file::check(&site.root, resource, client,
+            authenticated), Decision::Allow)
+    )
+}
+
+/// Rejects `request` if its client may not write or if it has expectations
+/// other than `100-continue`.
+pub fn check_writer(config: &Config, authenticated: bool,
+    request: &Request<Body>) -> Option<ServerFuture<Response<Body>>>
+{
+    if config.auth.is_required(request.method()) && !authenticated {
+        return Some(crate::unauthorized(&config.auth.challenges(request)))
+    }
+    if let Some(expect) = request.headers().get(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Some(status(StatusCode::EXPECTATION_FAILED,
+                "Expectation failed"))
+        }
+    }
+    None
+}
+
+/// Caches the digest of the file at `target`, if tags are digests, so that
+/// `tag` does not read the file on the thread serving requests.
+fn fill_tag(config: &Config, target: &Path)
+    -> Box<dyn Future<Item = (), Error = io::Error> + Send>
+{
+    if config.etag != etag::Policy::Hash {
+        return Box::new(future::ok(()))
+    }
+    // Errors are found again when the tag is needed.
+    let file = match File::open(target) {
+        Ok(file) => file,
+        Err(_) => return Box::new(future::ok(())),
+    };
+    match file.metadata() {
+        Ok(meta) if meta.is_file() => config.checksums.fill(target, &file,
+            &meta, &[checksum::Algorithm::Blake3]),
+        _ => Box::new(future::ok(())),
+    }
+}
+
+/// Returns the tag of the file at `target`.
+fn tag(config: &Config, target: &Path) -> io::Result<String> {
+    let file = File::open(target)?;
+    let meta = file.metadata()?;
+    config.etag.tag(&config.checksums, target, &file, &meta)
+}
+
+/// Returns whether the preconditions of a request with `headers` hold for
+/// the current version of `target`.
+fn preconditions_hold(config: &Config, headers: &HeaderMap, target: &Path)
+    -> io::Result<bool>
+{
+    let validators = match fs::metadata(target) {
+        Ok(meta) if meta.is_file() =>
+            Some(range::Validators::new(tag(config, target)?, &meta)),
+        Ok(_) => None,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            return Ok(etag::preconditions_hold(headers, false, None)),
+        Err(e) => return Err(e),
+    };
+    Ok(etag::preconditions_hold(headers, true, validators.as_ref()))
+}
+
+/// Writes `body` to `target`, below the served directory `root`.
+fn put(config: Arc<Config>, root: PathBuf, resource: PathBuf, target: PathBuf,
+    change: Change, body: Body) -> ServerFuture<Response<Body>>
+{
+    let replaced = match fs::symlink_metadata(&target) {
+        Ok(meta) if meta.is_dir() => return conflict(),
+        Ok(meta) => Some(if meta.is_file() {meta.len()} else {0}),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return crate::io_error(e),
+    };
+    let (temp, file) = match TempFile::create(&target, config.clone(), root) {
+        Ok(temp) => temp,
+        Err(e) => return crate::io_error(e),
+    };
+    let file = tokio_fs::File::from_std(file);
+    let write = body.map_err(io::Error::other)
+        .fold((file, temp), |(file, mut temp), chunk| {
+            let accepted = temp.receive(&chunk);
+            future::result(accepted.map(|()| (file, chunk)))
+                .and_then(|(file, chunk)| tokio_io::io::write_all(file, chunk))
+                .map(|(file, _)| (file, temp))
+        });
+    let res = write.then(move |res| {
+        let temp = match res {
+            Ok((_, temp)) => temp,
+            Err(ref e) if is_too_large(e) => return payload_too_large(),
+            Err(e) => return crate::io_error(e),
+        };
+        if let Some(filter) = &config.upload_filter {
+            let name = target.file_name().unwrap_or_default()
+                .to_string_lossy();
+            if !filter.accepts_content(&name, &temp.head) {
+                return unsupported_media_type()
+            }
+        }
+        let len = temp.len;
+        if let Err(e) = temp.persist(&resource, &target, replaced) {
+            return crate::io_error(e)
+        }
+        report(&config, change.with_size(len));
+        let res = if replaced.is_some() {
+            Response::builder().status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+        } else {
+            Response::builder().status(StatusCode::CREATED)
+                .body("Created".into())
+        };
+        let res = fill_tag(&config, &target).then(move |filled| {
+            res.map(|mut res| {
+                let etag = filled.and_then(|_| tag(&config, &target)).ok()
+                    .and_then(|etag| HeaderValue::from_str(&etag).ok());
+                if let Some(etag) = etag {
+                    res.headers_mut().insert(ETAG, etag);
+                }
+                res
+            })
+        });
+        Box::new(res) as ServerFuture<_>
+    });
+    Box::new(res)
+}
+
+/// Removes the file or empty directory `target`, the `resource` of the
+/// served directory `root`.
+fn delete(config: &Config, root: &Path, resource: &Path, target: &Path,
+    change: Change) -> ServerFuture<Response<Body>>
+{
+    match remove(config, root, resource, target, false) {
+        Ok(bytes) => {
+            report(config, change.with_size(bytes));
+            status(StatusCode::NO_CONTENT, "")
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::DirectoryNotEmpty =>
+            conflict(),
+        Err(e) => crate::io_error(e),
+    }
+}
+
+/// File an upload is written to, removed unless it is moved into place.
+pub struct TempFile {
+    path: Option<PathBuf>,
+    config: Arc<Config>,
+    /// Served directory the file is in.
+    root: PathBuf,
+    /// Bytes received.
+    len: u64,
+    /// First bytes received, to recognize the type of the file.
+    head: Vec<u8>,
+}
+
+impl TempFile {
+    /// Creates a hidden temporary file next to `target`.
+    pub fn create(target: &Path, config: Arc<Config>, root: PathBuf)
+        -> io::Result<(TempFile, File)>
+    {
+        let path = temp_path(target);
+        let file = OpenOptions::new().write(true).create_new(true)
+            .open(&path)?;
+        let temp = TempFile {
+            path: Some(path),
+            config,
+            root,
+            len: 0,
+            head: Vec::new(),
+        };
+        Ok((temp, file))
+    }
+
+    /// Takes note of `chunk` being received, failing if the upload or the
+    /// served directory would become too large.
+    pub fn receive(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let bytes = chunk.len() as u64;
+        let len = self.len + bytes;
+        if self.config.max_upload_size.is_some_and(|max| len > max) {
+            return Err(io::ErrorKind::FileTooLarge.into())
+        }
+        if let Some(quota) = &self.config.upload_quota {
+            if !quota.reserve(&self.root, bytes) {
+                return Err(io::ErrorKind::QuotaExceeded.into())
+            }
+        }
+        self.len = len;
+        let missing = sniff::SNIFF_LEN.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        Ok(())
+    }
+
+    /// Moves the file to `target`, the `resource` of the served directory,
+    /// replacing any file there of `replaced` bytes.
+    pub fn persist(mut self, resource: &Path, target: &Path,
+        replaced: Option<u64>) -> io::Result<()>
+    {
+        if let Some(replaced) = replaced {
+            set_aside(&self.config, &self.root, resource, target, replaced)?;
+        }
+        let path = self.path.take().expect("Temporary file exists");
+        fs::rename(&path, target).inspect_err(|_| {
+            let _ = fs::remove_file(&path);
+            self.release(self.len);
+        })?;
+        self.release(replaced.unwrap_or(0));
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        if let Some(quota) = &self.config.upload_quota {
+            quota.release(&self.root, bytes);
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+            self.release(self.len);
+        }
+    }
+}
+
+fn is_too_large(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::FileTooLarge
+        | io::ErrorKind::QuotaExceeded)
+}
+
+pub fn unsupported_media_type() -> ServerFuture<Response<Body>> {
+    status(StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "File name or type not allowed")
+}
+
+pub fn payload_too_large() -> ServerFuture<Response<Body>> {
+    status(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large")
+}
+
+pub fn conflict() -> ServerFuture<Response<Body>> {
+    status(StatusCode::CONFLICT, "Conflict")
+}
+
+pub fn status(status: StatusCode, message: &'static str)
+    -> ServerFuture<Response<Body>>
+{
+    if status.is_client_error() || status.is_server_error() {
+        return Problem::new(status, message).respond()
+    }
+    let res = Response::builder().status(status).body(message.into());
+    Box::new(future::result(res))
+}
@@ -0,0 +1,69 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Restrictions on the names and types of uploaded files.
+//!
+//! Names are matched against the allowed and denied patterns, ignoring
+//! case. The first bytes of uploads are then compared with the signatures of
+//! common file types. When the type is recognized, the upload is checked
+//! again as if it had the name it would have with each extension of the
+//! type, so that an executable named `notes.png` is rejected when `*.exe` is
+//! denied or only `*.png` is allowed.
+
+use crate::sniff;
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Patterns of the names of files that may be uploaded.
+#[derive(Debug)]
+pub struct Filter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl Filter {
+    /// Creates a filter from allowed and denied patterns. No allowed
+    /// pattern allows all names.
+    pub fn new(allow: Vec<Pattern>, deny: Vec<Pattern>) -> Filter {
+        Filter {allow, deny}
+    }
+
+    /// Returns whether a file named `name` may be uploaded.
+    pub fn accepts_name(&self, name: &str) -> bool {
+        let matches = |p: &Pattern| p.matches_with(name, MATCH_OPTIONS);
+        !self.deny.iter().any(matches)
+            && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+
+    /// Returns whether an upload named `name` starting with `head` may be
+    /// stored.
+    pub fn accepts_content(&self, name: &str, head: &[u8]) -> bool {
+        let extensions = match sniff::extensions(head) {
+            Some(extensions) => extensions,
+            None => return true,
+        };
+        let stem = Path::new(name).file_stem()
+            .map_or_else(|| name.into(), |stem| stem.to_string_lossy());
+        let names = extensions.iter()
+            .map(|extension| format!("{}.{}", stem, extension))
+            .collect::<Vec<_>>();
+        let matches = |p: &Pattern| names.iter()
+            .any(|name| p.matches_with(name, MATCH_OPTIONS));
+        !self.deny.iter().any(matches)
+            && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+/// Parses a comma-separated list of patterns.
+pub fn patterns(list: &str) -> Result<Vec<Pattern>, glob::PatternError> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(Pattern::new)
+        .collect()
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Conversions between URL paths and filesystem paths.
+//!
+//! File names are encoded from their platform representation (raw bytes on
+//! Unix, WTF-8 on Windows) so that names that are not valid UTF-8 survive
+//! the round trip through a URL.
+
+use percent_encoding::percent_decode;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+/// Returns the URL path of `path`, percent-encoding each segment.
+///
+/// Only normal components are kept, so absolute request paths and paths
+/// relative to the served root give the same result.
+pub fn encode(path: &Path) -> String {
+    let mut url = String::new();
+    for part in path.components() {
+        if let Component::Normal(segment) = part {
+            url.push('/');
+            url.push_str(&encode_segment(segment));
+        }
+    }
+    if url.is_empty() {
+        url.push('/');
+    }
+    url
+}
+
+/// Percent-encodes a single path segment.
+pub fn encode_segment(segment: &OsStr) -> String {
+    let mut encoded = String::new();
+    for &b in segment.as_encoded_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_'
+                | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*'
+                | b'+' | b',' | b';' | b'=' | b':' | b'@'
+                => encoded.push(char::from(b)),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Decodes a percent-encoded URL path.
+pub fn decode(url_path: &str) -> Option<PathBuf> {
+    let bytes = percent_decode(url_path.as_bytes()).collect::<Vec<_>>();
+    os_string_from_bytes(bytes).map(PathBuf::from)
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+/// Decodes WTF-8, i.e. UTF-8 that may also encode unpaired surrogates.
+#[cfg(windows)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    let mut wide = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let (len, mut c) = match b {
+            0x00..=0x7f => (1, u32::from(b)),
+            0xc2..=0xdf => (2, u32::from(b & 0x1f)),
+            0xe0..=0xef => (3, u32::from(b & 0x0f)),
+            0xf0..=0xf4 => (4, u32::from(b & 0x07)),
+            _ => return None,
+        };
+        let tail = bytes.get(i + 1..i + len)?;
+        for &cont in tail {
+            if cont & 0xc0 != 0x80 {return None}
+            c = (c << 6) | u32::from(cont & 0x3f);
+        }
+        let min = [0, 0, 0x80, 0x800, 0x10000][len];
+        if c < min || c > 0x10ffff {return None}
+        if c >= 0x10000 {
+            let c = c - 0x10000;
+            wide.push(0xd800 | (c >> 10) as u16);
+            wide.push(0xdc00 | (c & 0x3ff) as u16);
+        } else {
+            wide.push(c as u16);
+        }
+        i += len;
+    }
+    Some(OsString::from_wide(&wide))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, encode_segment};
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn paths_are_encoded_by_segment() {
+        assert_eq!(encode(Path::new("/a b/c%d")), "/a%20b/c%25d");
+        assert_eq!(encode(Path::new("a/./b/")), "/a/b");
+        assert_eq!(encode(Path::new("/")), "/");
+        assert_eq!(encode(Path::new("")), "/");
+        assert_eq!(encode(Path::new("/é")), "/%C3%A9");
+    }
+
+    #[test]
+    fn reserved_characters_are_encoded() {
+        assert_eq!(encode_segment(OsStr::new("a?b#c")), "a%3Fb%23c");
+        assert_eq!(encode_segment(OsStr::new("100%")), "100%25");
+        assert_eq!(encode_segment(OsStr::new("a&b=c+d")), "a&b=c+d");
+        assert_eq!(encode_segment(OsStr::new("\\\"<>")), "%5C%22%3C%3E");
+    }
+
+    #[test]
+    fn paths_are_decoded() {
+        assert_eq!(decode("/a%20b/c"), Some(PathBuf::from("/a b/c")));
+        assert_eq!(decode("/%C3%A9"), Some(PathBuf::from("/é")));
+        assert_eq!(decode("/a%2Fb"), Some(PathBuf::from("/a/b")));
+        assert_eq!(decode("/a%2fb"), Some(PathBuf::from("/a/b")));
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(decode("/100%"), Some(PathBuf::from("/100%")));
+        assert_eq!(decode("/%4"), Some(PathBuf::from("/%4")));
+        assert_eq!(decode("/%zz"), Some(PathBuf::from("/%zz")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_survive_the_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        let name = OsStr::from_bytes(b"a\xffb");
+        let path = Path::new("/").join(name);
+        assert_eq!(encode(&path), "/a%FFb");
+        assert_eq!(decode("/a%FFb"), Some(path));
+    }
+}
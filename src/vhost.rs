@@ -0,0 +1,115 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Selection of the served directory by the host named in requests.
+//!
+//! Authenticated users can also be given their own directory, which they
+//! are served instead, whatever the host. These users never see nor modify
+//! files outside of it.
+
+use crate::audit::Actor;
+use crate::beneath::RootDir;
+use http::Request;
+use hyper::Body;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory served for a host.
+#[derive(Debug)]
+pub struct Site {
+    pub root: PathBuf,
+    pub root_dir: RootDir,
+}
+
+impl Site {
+    pub fn open(root: PathBuf) -> io::Result<Site> {
+        let root_dir = RootDir::open(&root)?;
+        Ok(Site {root, root_dir})
+    }
+}
+
+/// Sites by host name, with a default for unknown hosts.
+#[derive(Debug)]
+pub struct Sites {
+    default: Arc<Site>,
+    hosts: HashMap<String, Arc<Site>>,
+    /// Directories of users confined to them.
+    users: HashMap<String, Arc<Site>>,
+}
+
+impl Sites {
+    /// Creates a set of sites serving `default` for every host.
+    pub fn new(default: Site) -> Sites {
+        Sites {
+            default: Arc::new(default),
+            hosts: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    /// Serves `site` for `host`.
+    pub fn insert(&mut self, host: &str, site: Site) {
+        self.hosts.insert(normalize(host), Arc::new(site));
+    }
+
+    /// Serves `site` to `user`, whatever the host.
+    pub fn insert_user(&mut self, user: &str, site: Site) {
+        self.users.insert(user.to_owned(), Arc::new(site));
+    }
+
+    /// Returns the site of the user authenticated with `request` if confined
+    /// to one, or the site for the host named in the target or `Host`
+    /// header.
+    pub fn select(&self, request: &Request<Body>) -> &Arc<Site> {
+        let user = request.extensions().get::<Actor>()
+            .and_then(|actor| actor.user.as_ref())
+            .and_then(|user| self.users.get(user));
+        if let Some(site) = user {
+            return site
+        }
+        request_host(request)
+            .and_then(|host| self.hosts.get(&normalize(host)))
+            .unwrap_or(&self.default)
+    }
+
+    /// Returns the site served for unknown hosts.
+    pub fn default_site(&self) -> &Arc<Site> {
+        &self.default
+    }
+
+    /// Returns whether `site` is the one served for unknown hosts.
+    pub fn is_default(&self, site: &Site) -> bool {
+        std::ptr::eq(site, &*self.default)
+    }
+
+    /// Returns the directories of all sites.
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        Some(&self.default).into_iter()
+            .chain(self.hosts.values())
+            .chain(self.users.values())
+            .map(|site| site.root.as_path())
+    }
+}
+
+/// Parses a `HOST=DIRECTORY` or `USER=DIRECTORY` command-line value.
+pub fn parse(spec: &str) -> Option<(&str, PathBuf)> {
+    let mut parts = spec.splitn(2, '=');
+    let host = parts.next().filter(|host| !host.is_empty())?;
+    let dir = parts.next().filter(|dir| !dir.is_empty())?;
+    Some((host, PathBuf::from(dir)))
+}
+
+/// Returns the host named in `request`, without port.
+fn request_host(request: &Request<Body>) -> Option<&str> {
+    if let Some(host) = request.uri().host() {
+        return Some(host)
+    }
+    let host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+    Some(crate::tls::strip_port(host))
+}
+
+/// Returns the canonical form of a host name, to compare host names.
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,109 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Rebuilding of served files when their sources change.
+//!
+//! With `--watch` and `--exec`, the command is run by the shell when the
+//! server starts and whenever files in the watched directory change.
+//! Changes are detected by polling the sizes and modification times of
+//! files, and the command runs once they settle, so that saving several
+//! files triggers a single build. Hidden files and the served directories
+//! are ignored, so that the output of the build does not trigger another
+//! one.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Delay between checks for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time files must stay unchanged before the command runs.
+const SETTLE_DELAY: Duration = Duration::from_millis(300);
+
+/// Runs `command` in a background thread when files in `dir`, except those
+/// in `ignored` directories, change.
+pub fn spawn(dir: &Path, command: String, ignored: &[&Path])
+    -> io::Result<()>
+{
+    let dir = dir.canonicalize()?;
+    let ignored = ignored.iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .collect::<Vec<_>>();
+    thread::spawn(move || {
+        run(&command);
+        let mut last = fingerprint(&dir, &ignored);
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let mut current = fingerprint(&dir, &ignored);
+            if current == last {continue}
+            loop {
+                thread::sleep(SETTLE_DELAY);
+                let next = fingerprint(&dir, &ignored);
+                if next == current {break}
+                current = next;
+            }
+            run(&command);
+            last = fingerprint(&dir, &ignored);
+        }
+    });
+    Ok(())
+}
+
+fn run(command: &str) {
+    info!("Running {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    match shell.arg(command).status() {
+        Ok(status) if status.success() => info!("{} succeeded", command),
+        Ok(status) => warn!("{} failed: {}", command, status),
+        Err(e) => warn!("Failed to run {}: {}", command, e),
+    }
+}
+
+/// Returns a digest of the names, sizes and modification times of the
+/// files in `dir`.
+fn fingerprint(dir: &Path, ignored: &[PathBuf]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_dir(dir, ignored, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_dir(dir: &Path, ignored: &[PathBuf], hasher: &mut DefaultHasher) {
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(Result::ok).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.')
+            || ignored.contains(&path)
+        {
+            continue
+        }
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        path.hash(hasher);
+        if meta.is_dir() {
+            hash_dir(&path, ignored, hasher);
+        } else {
+            meta.len().hash(hasher);
+            meta.modified().ok().hash(hasher);
+        }
+    }
+}
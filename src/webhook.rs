@@ -0,0 +1,111 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Notification of changes of the served directory to a webhook.
+//!
+//! Each change is posted as a JSON object from a background thread, in the
+//! order changes happen. Failed deliveries are retried with exponential
+//! backoff before the event is dropped.
+
+use crate::upload::Change;
+use http::{Request, Uri};
+use hyper::{Body, Client};
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// Maximum number of events waiting to be sent.
+const QUEUE_LEN: usize = 1024;
+
+/// Number of attempts to deliver an event.
+const ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for each further retry.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct InvalidUrl;
+
+impl fmt::Display for InvalidUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The webhook must be an http:// URL")
+    }
+}
+
+impl Error for InvalidUrl {}
+
+/// URL events are posted to.
+#[derive(Debug)]
+pub struct Endpoint(Uri);
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Endpoint, InvalidUrl> {
+        let uri = url.parse::<Uri>().map_err(|_| InvalidUrl)?;
+        if uri.scheme_part().map(|s| s.as_str()) != Some("http") {
+            return Err(InvalidUrl)
+        }
+        Ok(Endpoint(uri))
+    }
+}
+
+/// Sends events to a webhook from a background thread.
+#[derive(Debug)]
+pub struct Notifier {
+    sender: mpsc::SyncSender<Change>,
+}
+
+impl Notifier {
+    /// Starts posting events to `endpoint`. The thread delivering them is
+    /// spawned here, so this must be called once the server is detached.
+    pub fn start(endpoint: Endpoint) -> Notifier {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        thread::spawn(move || deliver(endpoint.0, receiver));
+        Notifier {sender}
+    }
+
+    /// Queues `change` for delivery, dropping it if too many events are
+    /// waiting.
+    pub fn send(&self, change: &Change) {
+        if self.sender.try_send(change.clone()).is_err() {
+            warn!("Webhook queue is full, dropping event");
+        }
+    }
+}
+
+fn deliver(uri: Uri, receiver: mpsc::Receiver<Change>) {
+    let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            warn!("Failed to start webhook notifier: {}", e);
+            return
+        }
+    };
+    let client = Client::new();
+    for change in receiver {
+        let payload = match serde_json::to_string(&change) {
+            Ok(payload) => payload,
+            Err(_) => continue,
+        };
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            let request = Request::post(uri.clone())
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(payload.clone()))
+                .unwrap();
+            let error = match runtime.block_on(client.request(request)) {
+                Ok(ref res) if res.status().is_success() => break,
+                Ok(res) => format!("responded with {}", res.status()),
+                Err(e) => e.to_string(),
+            };
+            if attempt == ATTEMPTS {
+                warn!("Dropping webhook event after {} attempts: {}",
+                    ATTEMPTS, error);
+            } else {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
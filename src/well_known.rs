@@ -0,0 +1,56 @@
+// Copyright (C) 2019 Stephane Raux. Distributed under the MIT license.
+
+//! Default responses for `/robots.txt` and `/favicon.ico`, sent when the
+//! served directory has no such file.
+
+use crate::vhost::Site;
+use crate::ServerFuture;
+use futures::future;
+use http::{Request, Response};
+use hyper::Body;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const ROBOTS_PATH: &str = "/robots.txt";
+const FAVICON_PATH: &str = "/favicon.ico";
+
+const FAVICON: &[u8] = include_bytes!("../data/favicon.ico");
+
+/// Contents of the default `robots.txt`.
+#[derive(Debug)]
+pub struct Robots(String);
+
+impl Robots {
+    /// Parses a `--robots` value: `allow-all`, `disallow-all` or a file to
+    /// read.
+    pub fn load(value: &str) -> io::Result<Robots> {
+        let text = match value {
+            "allow-all" => "User-agent: *\nDisallow:\n".to_owned(),
+            "disallow-all" => "User-agent: *\nDisallow: /\n".to_owned(),
+            path => fs::read_to_string(path)?,
+        };
+        Ok(Robots(text))
+    }
+}
+
+/// Answers `request` if it is for a default file missing from `site`.
+pub fn send(site: &Site, robots: Option<&Robots>, request: &Request<Body>)
+    -> Option<ServerFuture<Response<Body>>>
+{
+    let path = request.uri().path();
+    let (content_type, body) = match robots {
+        Some(Robots(text)) if path == ROBOTS_PATH =>
+            (mime::TEXT_PLAIN_UTF_8.to_string(), Body::from(text.clone())),
+        _ if path == FAVICON_PATH =>
+            ("image/x-icon".to_owned(), Body::from(FAVICON)),
+        _ => return None,
+    };
+    if site.root.join(Path::new(&path[1..])).is_file() {
+        return None
+    }
+    let res = Response::builder()
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(body);
+    Some(Box::new(future::result(res)))
+}